@@ -0,0 +1,790 @@
+use std::prelude::v1::*;
+
+use futures::future;
+use futures::stream::{FuturesUnordered, TryStreamExt};
+use futures::TryFutureExt;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::time::Duration;
+
+use crate::conn::*;
+
+/// Run each probe over its path concurrently, returning the outcomes in the same order.
+///
+/// Probes that share a path must use different ports, otherwise their servers will conflict.
+pub async fn connect_all(cases: &[(&dyn ConnPath, Probe)]) -> Result<Vec<ProbeOutcome>, io::Error> {
+    future::try_join_all(cases.iter().map(|(path, probe)| path.probe_outcome(probe))).await
+}
+
+/// When `connect_until` should stop.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum StopAt<'a> {
+    /// Stop at the first probe that is `Ok`, e.g. to ask whether any path is open.
+    FirstOk,
+    /// Stop at the first probe whose effect differs from the corresponding expected effect.
+    FirstUnexpected(&'a [ConnEffect]),
+}
+
+/// The outcomes of a `connect_until` run, which may have stopped early.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct PartialRun {
+    /// The outcome of each probe, or `None` if it was cancelled.
+    pub outcomes: Vec<Option<ProbeOutcome>>,
+    /// The index of the probe that met the stopping condition, if any did.
+    pub stopped_by: Option<usize>,
+}
+
+/// Like `connect_all`, but stop as soon as a probe meets the stopping condition, cancelling the
+/// probes that are still running, which closes their sockets.
+pub async fn connect_until(
+    cases: &[(&dyn ConnPath, Probe)],
+    stop_at: StopAt<'_>,
+) -> Result<PartialRun, io::Error> {
+    if let StopAt::FirstUnexpected(expected) = stop_at {
+        if cases.len() != expected.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Expected {} effects for {} cases",
+                    expected.len(),
+                    cases.len()
+                ),
+            ));
+        }
+    }
+    let mut outcomes = vec![None; cases.len()];
+    let mut running: FuturesUnordered<_> = cases
+        .iter()
+        .enumerate()
+        .map(|(i, (path, probe))| {
+            path.probe_outcome(probe)
+                .map_ok(move |outcome| (i, outcome))
+        })
+        .collect();
+    while let Some((i, outcome)) = running.try_next().await? {
+        let stop = match stop_at {
+            StopAt::FirstOk => matches!(outcome.effect, ConnEffect::Ok { .. }),
+            StopAt::FirstUnexpected(expected) => !outcome.effect.same_kind(&expected[i]),
+        };
+        outcomes[i] = Some(outcome);
+        if stop {
+            return Ok(PartialRun {
+                outcomes,
+                stopped_by: Some(i),
+            });
+        }
+    }
+    Ok(PartialRun {
+        outcomes,
+        stopped_by: None,
+    })
+}
+
+/// Make the same probe over each path in turn, and return the effects by source name, e.g. from
+/// sources in different subnets, each behind an interface of its own, to test a rule that
+/// treats the subnets differently.
+///
+/// The paths are probed sequentially, so they may share a target, but their source names must
+/// be distinct.
+pub async fn effects_by_source(
+    paths: &[&dyn ConnPath],
+    probe: &Probe,
+) -> Result<BTreeMap<String, ConnEffect>, io::Error> {
+    let mut ret = BTreeMap::new();
+    for (i, path) in paths.iter().enumerate() {
+        if paths[..i]
+            .iter()
+            .any(|other| other.source_name() == path.source_name())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("More than one path from {}", path.source_name()),
+            ));
+        }
+    }
+    for path in paths {
+        ret.insert(path.source_name().to_owned(), path.probe(probe).await?);
+    }
+    Ok(ret)
+}
+
+/// Make `total` connection attempts over the path, as `ConnPath::load_test` does, and measure
+/// the fraction that did not reach the target, e.g. to check a `numgen random` rule that drops a
+/// given proportion of packets, which a single probe cannot.
+pub async fn drop_rate(
+    path: &dyn ConnPath,
+    spec: ConnSpec,
+    total: usize,
+    concurrency: usize,
+) -> Result<DropRate, io::Error> {
+    let report = path.load_test(spec, total, concurrency).await?;
+    Ok(DropRate {
+        dropped: report.summary.unreachable,
+        total: report.summary.total,
+    })
+}
+
+/// Connect over the path, then over its reverse, from `ConnPath::reversed`, and return the
+/// effects in each direction, e.g. to catch a policy that allows inbound connections but
+/// filters the same connections outbound.
+pub async fn probe_both_ways(
+    path: &dyn ConnPath,
+    spec: ConnSpec,
+) -> Result<(ConnEffect, ConnEffect), io::Error> {
+    let reversed = path.reversed().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "The path from {} to {} cannot be reversed",
+                path.source_name(),
+                path.target_name()
+            ),
+        )
+    })?;
+    let forwards = path.connect(spec).await?;
+    let backwards = reversed.connect(spec).await?;
+    Ok((forwards, backwards))
+}
+
+/// Panic unless `probe_both_ways` has the same kind of effect in each direction, e.g. `Ok`,
+/// as it should for a symmetric policy. The source address of `Ok` depends on the direction,
+/// so only the kinds of effect are compared.
+pub async fn assert_symmetric(path: &dyn ConnPath, spec: ConnSpec) -> Result<(), io::Error> {
+    let (forwards, backwards) = probe_both_ways(path, spec).await?;
+    assert!(
+        mem::discriminant(&forwards) == mem::discriminant(&backwards),
+        "Expected {} between {} and {} to be symmetric, but it was {:?} forwards and {:?} backwards",
+        spec,
+        path.source_name(),
+        path.target_name(),
+        forwards,
+        backwards
+    );
+    Ok(())
+}
+
+/// Like `assert_symmetric`, but panic unless the effects differ in kind, as they should for a
+/// policy that only allows connections in one direction.
+pub async fn assert_asymmetric(path: &dyn ConnPath, spec: ConnSpec) -> Result<(), io::Error> {
+    let (forwards, backwards) = probe_both_ways(path, spec).await?;
+    assert!(
+        mem::discriminant(&forwards) != mem::discriminant(&backwards),
+        "Expected {} between {} and {} to be asymmetric, but it was {:?} both ways",
+        spec,
+        path.source_name(),
+        path.target_name(),
+        forwards
+    );
+    Ok(())
+}
+
+/// The result of `drop_rate`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct DropRate {
+    pub dropped: usize,
+    pub total: usize,
+}
+
+impl DropRate {
+    /// The fraction of the attempts that were dropped, or 0 if there were none.
+    pub fn fraction(&self) -> f64 {
+        match self.total {
+            0 => 0.0,
+            total => self.dropped as f64 / total as f64,
+        }
+    }
+
+    /// Panic unless the fraction dropped is within `tolerance` of `expected`.
+    ///
+    /// The standard error of the fraction is `sqrt(p * (1 - p) / total)` for a true probability
+    /// `p`, so a tolerance of three times that fails a correct rule about once in 370 runs.
+    pub fn assert_within(&self, expected: f64, tolerance: f64) {
+        assert!(
+            (self.fraction() - expected).abs() <= tolerance,
+            "Expected a drop rate of {} ± {}, but {}",
+            expected,
+            tolerance,
+            self
+        );
+    }
+}
+
+impl fmt::Display for DropRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} were dropped ({:.1}%)",
+            self.dropped,
+            self.total,
+            self.fraction() * 100.0
+        )
+    }
+}
+
+/// Summary statistics for the outcomes of a matrix run, for reporting.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct MatrixSummary {
+    pub total: usize,
+    pub ok: usize,
+    pub refused: usize,
+    pub unreachable: usize,
+    pub reset: usize,
+    /// The mean time taken by the probes that were `Ok`.
+    pub mean_ok_elapsed: Option<Duration>,
+    /// The index of the slowest probe.
+    pub slowest: Option<usize>,
+    /// The indices of the probes whose effect was not as expected.
+    pub unexpected: Vec<usize>,
+}
+
+impl MatrixSummary {
+    pub fn new(outcomes: &[ProbeOutcome]) -> MatrixSummary {
+        let mut ret = MatrixSummary {
+            total: outcomes.len(),
+            ..MatrixSummary::default()
+        };
+        let mut ok_elapsed = Duration::default();
+        for outcome in outcomes {
+            match outcome.effect {
+                ConnEffect::Ok { .. } => {
+                    ret.ok += 1;
+                    ok_elapsed += outcome.elapsed;
+                }
+                ConnEffect::Refused { .. } => ret.refused += 1,
+                // The probe did not reach the target
+                ConnEffect::Unreachable | ConnEffect::TtlExpired { .. } => ret.unreachable += 1,
+                ConnEffect::ResetDuringTransfer => ret.reset += 1,
+            }
+        }
+        if ret.ok > 0 {
+            ret.mean_ok_elapsed = Some(ok_elapsed / ret.ok as u32);
+        }
+        ret.slowest = (0..outcomes.len()).max_by_key(|&i| outcomes[i].elapsed);
+        ret
+    }
+
+    /// Like `new`, but also compares each outcome with the corresponding expected effect.
+    ///
+    /// Fails with `InvalidInput` if there is not exactly one expected effect per outcome.
+    pub fn with_expected(
+        outcomes: &[ProbeOutcome],
+        expected: &[ConnEffect],
+    ) -> Result<MatrixSummary, io::Error> {
+        if outcomes.len() != expected.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Expected {} effects for {} outcomes",
+                    expected.len(),
+                    outcomes.len()
+                ),
+            ));
+        }
+        Ok(MatrixSummary {
+            unexpected: (0..outcomes.len())
+                .filter(|&i| !outcomes[i].effect.same_kind(&expected[i]))
+                .collect(),
+            ..MatrixSummary::new(outcomes)
+        })
+    }
+}
+
+impl fmt::Display for MatrixSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} probes as expected ({} ok, {} refused, {} unreachable",
+            self.total - self.unexpected.len(),
+            self.total,
+            self.ok,
+            self.refused,
+            self.unreachable
+        )?;
+        if self.reset > 0 {
+            write!(f, ", {} reset", self.reset)?;
+        }
+        write!(f, ")")?;
+        if let Some(mean_ok_elapsed) = self.mean_ok_elapsed {
+            write!(f, ", mean ok time {:?}", mean_ok_elapsed)?;
+        }
+        Ok(())
+    }
+}
+
+/// The distribution of effects of a `ConnPath::load_test` run.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct LoadTestReport {
+    pub summary: MatrixSummary,
+    /// The time taken by the whole run.
+    pub elapsed: Duration,
+}
+
+impl LoadTestReport {
+    pub fn new(outcomes: &[ProbeOutcome], elapsed: Duration) -> LoadTestReport {
+        LoadTestReport {
+            summary: MatrixSummary::new(outcomes),
+            elapsed,
+        }
+    }
+
+    /// The fraction of the attempts that were `Ok`, or 0 if there were none.
+    pub fn success_rate(&self) -> f64 {
+        match self.summary.total {
+            0 => 0.0,
+            total => self.summary.ok as f64 / total as f64,
+        }
+    }
+}
+
+impl fmt::Display for LoadTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} probes in {:?} ({} ok, {} refused, {} unreachable",
+            self.summary.total,
+            self.elapsed,
+            self.summary.ok,
+            self.summary.refused,
+            self.summary.unreachable
+        )?;
+        if self.summary.reset > 0 {
+            write!(f, ", {} reset", self.summary.reset)?;
+        }
+        write!(f, "), {:.1}% ok", self.success_rate() * 100.0)
+    }
+}
+
+/// How each SYN of a `ConnPath::syn_flood` was answered, in the order they were sent.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct SynFloodReport {
+    /// `Ok` for a SYN-ACK, `Refused` for a reset, and `Unreachable` for no answer.
+    pub effects: Vec<ConnEffect>,
+    /// The time taken to send the SYNs and wait for the last replies.
+    pub elapsed: Duration,
+}
+
+impl SynFloodReport {
+    pub fn syn_acked(&self) -> usize {
+        self.count(|effect| matches!(effect, ConnEffect::Ok { .. }))
+    }
+
+    pub fn refused(&self) -> usize {
+        self.count(|effect| matches!(effect, ConnEffect::Refused { .. }))
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.count(|effect| matches!(effect, ConnEffect::Unreachable))
+    }
+
+    /// The index of the first SYN that was not answered, e.g. where a rate limit started to
+    /// drop them.
+    pub fn first_dropped(&self) -> Option<usize> {
+        self.effects
+            .iter()
+            .position(|effect| matches!(effect, ConnEffect::Unreachable))
+    }
+
+    fn count<P: Fn(&ConnEffect) -> bool>(&self, predicate: P) -> usize {
+        self.effects
+            .iter()
+            .filter(|effect| predicate(effect))
+            .count()
+    }
+}
+
+impl fmt::Display for SynFloodReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} SYNs in {:?} ({} SYN-ACK, {} reset, {} dropped)",
+            self.effects.len(),
+            self.elapsed,
+            self.syn_acked(),
+            self.refused(),
+            self.dropped()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    /// A path on which a UDP probe to port `n` takes `n` milliseconds, and is `Ok` if `n` is
+    /// even, or `Refused` otherwise.
+    struct FakePath;
+
+    #[async_trait]
+    impl ConnPath for FakePath {
+        fn source_name(&self) -> &str {
+            "source"
+        }
+
+        fn source_addr(&self) -> IpAddr {
+            Ipv4Addr::LOCALHOST.into()
+        }
+
+        fn target_name(&self) -> &str {
+            "target"
+        }
+
+        fn target_addr(&self) -> IpAddr {
+            Ipv4Addr::LOCALHOST.into()
+        }
+
+        async fn probe_outcome(&self, probe: &Probe) -> Result<ProbeOutcome, io::Error> {
+            let port = probe.spec().port();
+            let elapsed = Duration::from_millis(port.into());
+            tokio::time::sleep(elapsed).await;
+            let effect = if port & 1 == 0 {
+                ConnEffect::Ok {
+                    source_addr: self.source_addr(),
+                    target_port: port,
+                }
+            } else {
+                ConnEffect::Refused { icmp: None }
+            };
+            Ok(ProbeOutcome {
+                effect,
+                elapsed,
+                dscp: None,
+                listener: None,
+                slow: false,
+            })
+        }
+    }
+
+    /// A path with one effect forwards and another backwards.
+    #[derive(Copy, Clone)]
+    struct OneWayPath {
+        forwards: ConnEffect,
+        backwards: ConnEffect,
+    }
+
+    #[async_trait]
+    impl ConnPath for OneWayPath {
+        fn source_name(&self) -> &str {
+            "source"
+        }
+
+        fn source_addr(&self) -> IpAddr {
+            Ipv4Addr::LOCALHOST.into()
+        }
+
+        fn target_name(&self) -> &str {
+            "target"
+        }
+
+        fn target_addr(&self) -> IpAddr {
+            Ipv4Addr::LOCALHOST.into()
+        }
+
+        fn reversed(&self) -> Option<Box<dyn ConnPath + Send + '_>> {
+            Some(Box::new(OneWayPath {
+                forwards: self.backwards,
+                backwards: self.forwards,
+            }))
+        }
+
+        async fn probe_outcome(&self, _probe: &Probe) -> Result<ProbeOutcome, io::Error> {
+            Ok(outcome(self.forwards, 0))
+        }
+    }
+
+    fn fake_cases(ports: &[u16]) -> Vec<(&'static dyn ConnPath, Probe)> {
+        ports
+            .iter()
+            .map(|&port| {
+                let path: &'static dyn ConnPath = &FakePath;
+                (path, Probe::new(ConnSpec::Udp { port }))
+            })
+            .collect()
+    }
+
+    fn outcome(effect: ConnEffect, millis: u64) -> ProbeOutcome {
+        ProbeOutcome {
+            effect,
+            elapsed: Duration::from_millis(millis),
+            dscp: None,
+            listener: None,
+            slow: false,
+        }
+    }
+
+    #[test]
+    fn summary() {
+        let ok = ConnEffect::Ok {
+            source_addr: Ipv4Addr::LOCALHOST.into(),
+            target_port: 80,
+        };
+        let outcomes = vec![
+            outcome(ok, 10),
+            outcome(ok, 30),
+            outcome(ConnEffect::Refused { icmp: None }, 5),
+            outcome(ConnEffect::Unreachable, 5000),
+        ];
+        let expected = vec![
+            ok,
+            ok,
+            ConnEffect::Refused { icmp: None },
+            ConnEffect::Refused { icmp: None },
+        ];
+        let summary = MatrixSummary::with_expected(&outcomes, &expected).unwrap();
+        assert_eq!(
+            MatrixSummary {
+                total: 4,
+                ok: 2,
+                refused: 1,
+                unreachable: 1,
+                reset: 0,
+                mean_ok_elapsed: Some(Duration::from_millis(20)),
+                slowest: Some(3),
+                unexpected: vec![3],
+            },
+            summary
+        );
+        assert_eq!(
+            "3/4 probes as expected (2 ok, 1 refused, 1 unreachable), mean ok time 20ms",
+            summary.to_string()
+        );
+    }
+
+    #[test]
+    fn summary_with_reset() {
+        let outcomes = vec![outcome(ConnEffect::ResetDuringTransfer, 5)];
+        assert_eq!(
+            "0/1 probes as expected (0 ok, 0 refused, 0 unreachable, 1 reset)",
+            MatrixSummary::with_expected(&outcomes, &[ConnEffect::Refused { icmp: None }])
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn summary_with_too_few_expected() {
+        let outcomes = vec![outcome(ConnEffect::Unreachable, 5); 2];
+        let err = MatrixSummary::with_expected(&outcomes, &[ConnEffect::Unreachable]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn drop_rate_within_tolerance() {
+        let rate = DropRate {
+            dropped: 12,
+            total: 100,
+        };
+        assert_eq!(0.12, rate.fraction());
+        rate.assert_within(0.1, 0.05);
+        assert_eq!("12 of 100 were dropped (12.0%)", rate.to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a drop rate of 0.1 ± 0.05, but 20 of 100 were dropped")]
+    fn drop_rate_outside_tolerance() {
+        DropRate {
+            dropped: 20,
+            total: 100,
+        }
+        .assert_within(0.1, 0.05);
+    }
+
+    #[tokio::test]
+    async fn symmetry() -> Result<(), io::Error> {
+        let spec = ConnSpec::Tcp { port: 80 };
+        let ok = |addr: Ipv4Addr| ConnEffect::Ok {
+            source_addr: addr.into(),
+            target_port: 80,
+        };
+        let symmetric = OneWayPath {
+            forwards: ok(Ipv4Addr::new(192, 0, 2, 1)),
+            backwards: ok(Ipv4Addr::new(192, 0, 2, 2)),
+        };
+        assert_symmetric(&symmetric, spec).await?;
+        let asymmetric = OneWayPath {
+            forwards: ok(Ipv4Addr::new(192, 0, 2, 1)),
+            backwards: ConnEffect::Unreachable,
+        };
+        assert_asymmetric(&asymmetric, spec).await?;
+
+        let err = probe_both_ways(&FakePath, spec).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Expected tcp:80 between source and target to be symmetric")]
+    async fn asymmetric_path_is_not_symmetric() {
+        let path = OneWayPath {
+            forwards: ConnEffect::Refused { icmp: None },
+            backwards: ConnEffect::Unreachable,
+        };
+        assert_symmetric(&path, ConnSpec::Tcp { port: 80 })
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn syn_flood_report() {
+        let ok = ConnEffect::Ok {
+            source_addr: Ipv4Addr::LOCALHOST.into(),
+            target_port: 80,
+        };
+        let report = SynFloodReport {
+            effects: vec![
+                ok,
+                ok,
+                ConnEffect::Unreachable,
+                ConnEffect::Refused { icmp: None },
+                ConnEffect::Unreachable,
+            ],
+            elapsed: Duration::from_millis(40),
+        };
+        assert_eq!(2, report.syn_acked());
+        assert_eq!(1, report.refused());
+        assert_eq!(2, report.dropped());
+        assert_eq!(Some(2), report.first_dropped());
+        assert_eq!(
+            "5 SYNs in 40ms (2 SYN-ACK, 1 reset, 2 dropped)",
+            report.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_until_first_ok() -> Result<(), io::Error> {
+        let run = connect_until(&fake_cases(&[1, 1000, 50, 3]), StopAt::FirstOk).await?;
+        assert_eq!(Some(2), run.stopped_by);
+        assert_eq!(
+            vec![true, false, true, true],
+            run.outcomes.iter().map(Option::is_some).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_until_first_unexpected() -> Result<(), io::Error> {
+        let cases = fake_cases(&[1, 3]);
+        let run = connect_until(
+            &cases,
+            StopAt::FirstUnexpected(&[ConnEffect::Refused { icmp: None }; 2]),
+        )
+        .await?;
+        assert_eq!(None, run.stopped_by);
+        assert!(run.outcomes.iter().all(Option::is_some));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_until_too_few_expected() {
+        let cases = fake_cases(&[1, 3]);
+        let expected = [ConnEffect::Refused { icmp: None }];
+        let err = connect_until(&cases, StopAt::FirstUnexpected(&expected))
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[tokio::test]
+    async fn load_test_distribution() -> Result<(), io::Error> {
+        let report = FakePath.load_test(ConnSpec::Udp { port: 10 }, 3, 1).await?;
+        assert_eq!(3, report.summary.total);
+        assert_eq!(3, report.summary.ok);
+        // The probes were made one at a time
+        assert!(report.elapsed >= Duration::from_millis(30));
+        let spec = ConnSpec::Icmp {
+            kind: IcmpKind::Echo,
+        };
+        let report = FakePath.load_test(spec, 6, 2).await?;
+        assert_eq!(6, report.summary.total);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn load_test_invalid_concurrency() {
+        for &(spec, concurrency) in &[
+            (ConnSpec::Tcp { port: 10 }, 0),
+            (ConnSpec::Tcp { port: 10 }, 2),
+            (ConnSpec::Udp { port: 10 }, 2),
+        ] {
+            let err = FakePath.load_test(spec, 1, concurrency).await.unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_sweep_effects() -> Result<(), io::Error> {
+        let spec = ConnSpec::Tcp { port: 0 };
+        let effects = FakePath
+            .connect_sweep(spec, &[13, 10, 11, 10, 12], 2)
+            .await?;
+        let ok = |port| ConnEffect::Ok {
+            source_addr: Ipv4Addr::LOCALHOST.into(),
+            target_port: port,
+        };
+        let refused = ConnEffect::Refused { icmp: None };
+        assert_eq!(
+            vec![(10, ok(10)), (11, refused), (12, ok(12)), (13, refused)],
+            effects.into_iter().collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_sweep_invalid() {
+        for &(spec, parallelism) in &[
+            (ConnSpec::Tcp { port: 0 }, 0),
+            (ConnSpec::Ip { protocol: 50 }, 1),
+        ] {
+            let err = FakePath
+                .connect_sweep(spec, &[10], parallelism)
+                .await
+                .unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    fn load_test_report() {
+        let ok = ConnEffect::Ok {
+            source_addr: Ipv4Addr::LOCALHOST.into(),
+            target_port: 80,
+        };
+        let outcomes = vec![
+            outcome(ok, 10),
+            outcome(ok, 10),
+            outcome(ok, 10),
+            outcome(ConnEffect::Unreachable, 5000),
+        ];
+        let report = LoadTestReport::new(&outcomes, Duration::from_secs(5));
+        assert_eq!(0.75, report.success_rate());
+        assert_eq!(
+            "4 probes in 5s (3 ok, 0 refused, 1 unreachable), 75.0% ok",
+            report.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn effects_by_source_with_duplicate_source() {
+        let err = effects_by_source(
+            &[&FakePath, &FakePath],
+            &Probe::new(ConnSpec::Udp { port: 2 }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn empty_summary() {
+        let summary = MatrixSummary::new(&[]);
+        assert_eq!(MatrixSummary::default(), summary);
+        assert_eq!(
+            "0/0 probes as expected (0 ok, 0 refused, 0 unreachable)",
+            summary.to_string()
+        );
+    }
+}
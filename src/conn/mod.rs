@@ -1,11 +1,30 @@
 use std::prelude::v1::*;
 
 use async_trait::async_trait;
+use futures::future;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
 use std::io;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
+use crate::conn::matrix::{LoadTestReport, SynFloodReport};
+
+pub mod matrix;
 pub mod os;
 
+/// The length of the cookie that identifies a probe, which is the smallest payload it can carry.
+pub const COOKIE_LEN: usize = 16;
+
+/// How long `ConnPath::connect_happy_eyeballs` gives IPv6 before also trying IPv4: the
+/// connection attempt delay recommended by RFC 8305.
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
 #[async_trait]
 pub trait ConnPath: Sync {
     fn source_name(&self) -> &str;
@@ -13,18 +32,1436 @@ pub trait ConnPath: Sync {
     fn target_name(&self) -> &str;
     fn target_addr(&self) -> IpAddr;
 
-    async fn connect(&self, spec: ConnSpec) -> Result<ConnEffect, io::Error>;
+    async fn connect(&self, spec: ConnSpec) -> Result<ConnEffect, io::Error> {
+        self.probe(&Probe::new(spec)).await
+    }
+
+    /// Like `connect`, but classify the connection as `Unreachable` after the given timeout
+    /// rather than the default, e.g. a longer one for a slow CI machine, or a shorter one to
+    /// speed up a test that expects a drop.
+    async fn connect_with_timeout(
+        &self,
+        spec: ConnSpec,
+        timeout: Duration,
+    ) -> Result<ConnEffect, io::Error> {
+        self.probe(&Probe::new(spec).timeout(timeout)).await
+    }
+
+    async fn probe(&self, probe: &Probe) -> Result<ConnEffect, io::Error> {
+        Ok(self.probe_outcome(probe).await?.effect)
+    }
+
+    async fn probe_outcome(&self, probe: &Probe) -> Result<ProbeOutcome, io::Error>;
+
+    /// Make the given number of request/response exchanges over a single TCP connection, and
+    /// return the effect of each, e.g. to test a stateful rule that treats the first packet of a
+    /// connection differently from those that follow.
+    async fn probe_exchanges(
+        &self,
+        probe: &Probe,
+        count: usize,
+    ) -> Result<Vec<ConnEffect>, io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} exchanges are not supported for {:?}", count, probe),
+        ))
+    }
+
+    /// Open a TCP connection for the given probe, over which exchanges are made as by
+    /// `probe_exchanges`, but one at a time, e.g. to reload the firewall between them and check
+    /// that the established connection survives. The connection does not borrow the path, so the
+    /// path can be dropped to release its host while the connection is open.
+    async fn open_connection(&self, probe: &Probe) -> Result<Box<dyn OpenConnection>, io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Open connections are not supported for {:?}", probe),
+        ))
+    }
+
+    /// Connect once with each of the given timeouts, in turn, returning the effect at each, e.g.
+    /// to tell whether `Unreachable` is a drop or a timeout that is too short for a slow or
+    /// rate-limited path.
+    async fn connect_timeout_sweep(
+        &self,
+        spec: ConnSpec,
+        timeouts: &[Duration],
+    ) -> Result<Vec<ConnEffect>, io::Error> {
+        let mut ret = vec![];
+        // Sequentially, since concurrent probes of the same spec would share a server port
+        for &timeout in timeouts {
+            ret.push(self.connect_with_timeout(spec, timeout).await?);
+        }
+        Ok(ret)
+    }
+
+    /// Connect with the given spec every `interval`, until the stream is dropped, yielding when
+    /// each probe started and its effect, e.g. to watch a path for intermittent drops that a
+    /// single probe might miss. The probes run one at a time, so one that takes longer than the
+    /// interval delays the next. Each has a new socket, and so a new ephemeral source port, and
+    /// is a new connection to conntrack. The stream ends after an error.
+    fn probe_stream(
+        &self,
+        spec: ConnSpec,
+        interval: Duration,
+    ) -> BoxStream<'_, Result<(Instant, ConnEffect), io::Error>> {
+        stream::unfold(Some(Instant::now()), move |next| async move {
+            let next = next?;
+            tokio::time::sleep_until(tokio::time::Instant::from_std(next)).await;
+            let started = Instant::now();
+            match self.connect(spec).await {
+                Ok(effect) => Some((Ok((started, effect)), Some(next + interval))),
+                Err(err) => Some((Err(err), None)),
+            }
+        })
+        .boxed()
+    }
+
+    /// Make a TCP control connection for the given probe, over which a data port is announced as
+    /// by an FTP client and server in the given mode, then a data connection to that port, e.g.
+    /// to test that a `ct helper` assignment lets `ct state related` accept the data connection.
+    async fn probe_related(
+        &self,
+        probe: &Probe,
+        mode: FtpMode,
+        data_port: u16,
+    ) -> Result<RelatedEffects, io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{:?} related connections to port {} are not supported for {:?}",
+                mode, data_port, probe
+            ),
+        ))
+    }
+
+    /// Send `count` bare SYNs for the given `syn_only` probe at `rate` per second, each from its
+    /// own source port, and report how each was answered, e.g. to find where a SYN rate limit
+    /// starts to drop them, or to check that a `synproxy` rule answers them.
+    async fn syn_flood(
+        &self,
+        probe: &Probe,
+        count: usize,
+        rate: u32,
+    ) -> Result<SynFloodReport, io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "SYN floods of {} at {}/s are not supported for {:?}",
+                count, rate, probe
+            ),
+        ))
+    }
+
+    /// The same path over the other address family, if its source and target are dual-stack.
+    fn other_family(&self) -> Option<Box<dyn ConnPath + Send + '_>> {
+        None
+    }
+
+    /// The same path in the other direction, from the target to the source, if it supports that,
+    /// e.g. to check that a policy is symmetric with `matrix::assert_symmetric`.
+    fn reversed(&self) -> Option<Box<dyn ConnPath + Send + '_>> {
+        None
+    }
+
+    /// Connect to the given TCP port as a dual-stack client using Happy Eyeballs (RFC 8305)
+    /// would: try IPv6 first, then IPv4 as well after `HAPPY_EYEBALLS_DELAY` or as soon as IPv6
+    /// fails, and take the first to succeed, e.g. to check that a firewall that drops IPv6 only
+    /// delays clients rather than breaking them.
+    async fn connect_happy_eyeballs(&self, port: u16) -> Result<HappyEyeballsOutcome, io::Error> {
+        let other = self.other_family().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "No other address family from {} to {}",
+                    self.source_name(),
+                    self.target_name()
+                ),
+            )
+        })?;
+        if self.target_addr().is_ipv6() == other.target_addr().is_ipv6() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Both {} and {} are in the same address family",
+                    self.target_addr(),
+                    other.target_addr()
+                ),
+            ));
+        }
+        let probe = Probe::new(ConnSpec::Tcp { port });
+        // The attempts do not start until they are first polled
+        let ((v6_addr, mut v6_attempt), (v4_addr, v4_attempt)) = if self.target_addr().is_ipv6() {
+            (
+                (self.target_addr(), self.probe(&probe)),
+                (other.target_addr(), other.probe(&probe)),
+            )
+        } else {
+            (
+                (other.target_addr(), other.probe(&probe)),
+                (self.target_addr(), self.probe(&probe)),
+            )
+        };
+        let start = Instant::now();
+        let outcome = |target_addr, effect, fallback_after| HappyEyeballsOutcome {
+            target_addr,
+            effect,
+            elapsed: start.elapsed(),
+            fallback_after,
+        };
+        let v6_failed = match tokio::time::timeout(HAPPY_EYEBALLS_DELAY, &mut v6_attempt).await {
+            Ok(effect) => match effect? {
+                effect @ ConnEffect::Ok { .. } => return Ok(outcome(v6_addr, effect, None)),
+                _ => true,
+            },
+            Err(_) => false,
+        };
+        let fallback_after = Some(start.elapsed());
+        if v6_failed {
+            return Ok(outcome(v4_addr, v4_attempt.await?, fallback_after));
+        }
+        let (addr, effect) = match future::select(v6_attempt, v4_attempt).await {
+            future::Either::Left((v6_effect, v4_attempt)) => match v6_effect? {
+                effect @ ConnEffect::Ok { .. } => (v6_addr, effect),
+                _ => (v4_addr, v4_attempt.await?),
+            },
+            future::Either::Right((v4_effect, v6_attempt)) => match v4_effect? {
+                effect @ ConnEffect::Ok { .. } => (v4_addr, effect),
+                // Report the IPv4 failure, since a client would report its last attempt
+                v4_effect => match v6_attempt.await? {
+                    effect @ ConnEffect::Ok { .. } => (v6_addr, effect),
+                    _ => (v4_addr, v4_effect),
+                },
+            },
+        };
+        Ok(outcome(addr, effect, fallback_after))
+    }
+
+    /// Make `total` connection attempts, with at most `concurrency` in flight at once, and report
+    /// the distribution of their effects, e.g. to stress a conntrack table or a `ct count` rule.
+    ///
+    /// Concurrent TCP or UDP probes of the same port would race to bind their servers to it, so
+    /// only IP and ICMP probes, which the servers tell apart by their cookies, may run
+    /// concurrently. Fails if `concurrency` is 0, or more than 1 for a TCP or UDP spec.
+    async fn load_test(
+        &self,
+        spec: ConnSpec,
+        total: usize,
+        concurrency: usize,
+    ) -> Result<LoadTestReport, io::Error> {
+        let has_port = matches!(spec, ConnSpec::Tcp { .. } | ConnSpec::Udp { .. });
+        if concurrency == 0 || (concurrency > 1 && has_port) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid concurrency {} for {}", concurrency, spec),
+            ));
+        }
+        let start = Instant::now();
+        let next = AtomicUsize::new(0);
+        let workers = (0..concurrency.min(total)).map(|_| {
+            let next = &next;
+            async move {
+                let probe = Probe::new(spec);
+                let mut outcomes = vec![];
+                while next.fetch_add(1, Ordering::Relaxed) < total {
+                    outcomes.push(self.probe_outcome(&probe).await?);
+                }
+                Ok::<_, io::Error>(outcomes)
+            }
+        });
+        let outcomes: Vec<_> = future::try_join_all(workers)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok(LoadTestReport::new(&outcomes, start.elapsed()))
+    }
+
+    /// Connect to each of the given ports with the given TCP or UDP spec, with at most
+    /// `parallelism` probes in flight at once, and return the effect on each port, e.g. to test
+    /// a rule that matches a range of ports such as `tcp dport 8000-8010`.
+    ///
+    /// Each probe has its own client socket, so the limit also bounds the ephemeral source ports
+    /// in use. A port that is given more than once is probed once.
+    async fn connect_sweep(
+        &self,
+        spec: ConnSpec,
+        ports: &[u16],
+        parallelism: usize,
+    ) -> Result<BTreeMap<u16, ConnEffect>, io::Error> {
+        let with_port = |port| match spec {
+            ConnSpec::Tcp { .. } => Some(ConnSpec::Tcp { port }),
+            ConnSpec::Udp { .. } => Some(ConnSpec::Udp { port }),
+            ConnSpec::Ip { .. } | ConnSpec::Icmp { .. } => None,
+        };
+        if parallelism == 0 || with_port(0).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid sweep of {} with parallelism {}", spec, parallelism),
+            ));
+        }
+        let ports: BTreeSet<u16> = ports.iter().copied().collect();
+        stream::iter(ports)
+            .map(|port| async move {
+                let spec = with_port(port).expect("Sweeps are only of specs with ports");
+                Ok((port, self.connect(spec).await?))
+            })
+            .buffer_unordered(parallelism)
+            .try_collect()
+            .await
+    }
+
+    /// The MTU of the path as the source sees it: the path MTU that it cached after discovering
+    /// it, e.g. during a probe, or otherwise the MTU of the device the path leaves by.
+    fn effective_mtu(&self) -> Result<u32, io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "The effective MTU is not supported from {} to {}",
+                self.source_name(),
+                self.target_name()
+            ),
+        ))
+    }
+
+    /// Like `connect`, but for callers that are not already running in an async runtime.
+    ///
+    /// This creates and tears down a single-threaded runtime on every call, which is fine for
+    /// occasional use but wasteful in a loop.
+    fn connect_blocking(&self, spec: ConnSpec) -> Result<ConnEffect, io::Error> {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(self.connect(spec))
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum ConnSpec {
-    Tcp { port: u16 },
-    Udp { port: u16 },
+    Tcp {
+        port: u16,
+    },
+    Udp {
+        port: u16,
+    },
+    /// A raw IP datagram with the given protocol number, e.g. 50 for ESP.
+    Ip {
+        protocol: u8,
+    },
+    /// An ICMP message, or an ICMPv6 message to an IPv6 address.
+    Icmp {
+        kind: IcmpKind,
+    },
+}
+
+/// The kind of ICMP message that a `ConnSpec::Icmp` probe sends.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum IcmpKind {
+    /// An echo request, as sent by ping, with the cookie as its data, and with its identifier
+    /// and sequence number taken from the cookie, to match the echo reply.
+    Echo,
+}
+
+impl ConnSpec {
+    /// The destination port, or 0 for protocols without ports.
+    pub fn port(&self) -> u16 {
+        match *self {
+            ConnSpec::Tcp { port } | ConnSpec::Udp { port } => port,
+            ConnSpec::Ip { .. } | ConnSpec::Icmp { .. } => 0,
+        }
+    }
+
+    /// The length of the headers of a packet of this protocol to the given address, without IP
+    /// options or extension headers, or TCP options other than timestamps, when used. The total
+    /// length of the packet, as matched by `meta length`, is this plus the payload length.
+    pub fn header_len(&self, addr: IpAddr, tcp_timestamps: bool) -> usize {
+        let ip = match addr {
+            IpAddr::V4(_) => 20,
+            IpAddr::V6(_) => 40,
+        };
+        let transport = match *self {
+            ConnSpec::Tcp { .. } if tcp_timestamps => 32,
+            ConnSpec::Tcp { .. } => 20,
+            ConnSpec::Udp { .. } | ConnSpec::Icmp { .. } => 8,
+            ConnSpec::Ip { .. } => 0,
+        };
+        ip + transport
+    }
+}
+
+impl fmt::Display for ConnSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnSpec::Tcp { port } => write!(f, "tcp:{}", port),
+            ConnSpec::Udp { port } => write!(f, "udp:{}", port),
+            ConnSpec::Ip { protocol } => write!(f, "ip:{}", protocol),
+            ConnSpec::Icmp {
+                kind: IcmpKind::Echo,
+            } => write!(f, "icmp:echo"),
+        }
+    }
+}
+
+/// Parses the format produced by `Display`, e.g. `tcp:80`, `udp:53`, `ip:50` or `icmp:echo`, and
+/// `proto:50` and `icmp` as aliases of the last two.
+impl FromStr for ConnSpec {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<ConnSpec, io::Error> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid connection spec {:?}: {}", s, reason),
+            )
+        };
+        if s == "icmp" || s == "icmp:echo" {
+            return Ok(ConnSpec::Icmp {
+                kind: IcmpKind::Echo,
+            });
+        }
+        let (protocol, port) = s
+            .split_once(':')
+            .ok_or_else(|| invalid("expected <protocol>:<port>"))?;
+        if protocol == "sctp" {
+            return Err(invalid(
+                "SCTP is not supported, but ip:132 sends a raw datagram with protocol 132",
+            ));
+        }
+        if protocol == "ip" || protocol == "proto" {
+            let protocol = port
+                .parse()
+                .map_err(|_| invalid("invalid protocol number"))?;
+            return Ok(ConnSpec::Ip { protocol });
+        }
+        let port = port.parse().map_err(|_| invalid("invalid port"))?;
+        match protocol {
+            "tcp" => Ok(ConnSpec::Tcp { port }),
+            "udp" => Ok(ConnSpec::Udp { port }),
+            _ => Err(invalid("unknown protocol")),
+        }
+    }
+}
+
+/// A connection to attempt over a `ConnPath`, with options that control how it is made and how
+/// its effect is classified.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Probe {
+    spec: ConnSpec,
+    source_port: Option<u16>,
+    kernel_source_addr: bool,
+    udp_refused_as_unreachable: bool,
+    retries: u32,
+    external_server: bool,
+    user: Option<(u32, u32)>,
+    cgroup: Option<String>,
+    timeout: Option<Duration>,
+    fast_deadline: Option<Duration>,
+    refused_within: Option<Duration>,
+    syn_only: bool,
+    out_of_state: bool,
+    bidirectional: bool,
+    window_clamp: Option<u32>,
+    congestion: Option<String>,
+    payload_len: Option<usize>,
+    total_len: Option<usize>,
+    dont_fragment: Option<bool>,
+    broadcast: Option<Ipv4Addr>,
+    reuseport_listeners: Option<usize>,
+    server_port: Option<u16>,
+    fwmark: Option<u32>,
+    ttl: Option<u8>,
+    dscp: Option<u8>,
+}
+
+impl Probe {
+    pub fn new(spec: ConnSpec) -> Probe {
+        Probe {
+            spec,
+            source_port: None,
+            kernel_source_addr: false,
+            udp_refused_as_unreachable: false,
+            retries: 0,
+            external_server: false,
+            user: None,
+            cgroup: None,
+            timeout: Some(Duration::from_secs(5)),
+            fast_deadline: None,
+            refused_within: None,
+            syn_only: false,
+            out_of_state: false,
+            bidirectional: false,
+            window_clamp: None,
+            congestion: None,
+            payload_len: None,
+            total_len: None,
+            dont_fragment: None,
+            broadcast: None,
+            reuseport_listeners: None,
+            server_port: None,
+            fwmark: None,
+            ttl: None,
+            dscp: None,
+        }
+    }
+
+    pub fn spec(&self) -> ConnSpec {
+        self.spec
+    }
+
+    /// Bind the client to the given source port, rather than an ephemeral one.
+    pub fn source_port(self, port: u16) -> Probe {
+        Probe {
+            source_port: Some(port),
+            ..self
+        }
+    }
+
+    /// Bind the client to the unspecified address rather than the path's source address, so that
+    /// the kernel selects the source address, e.g. by the preferred source of a route. The
+    /// address that the server observed is reported as usual.
+    pub fn kernel_source_addr(self) -> Probe {
+        Probe {
+            kernel_source_addr: true,
+            ..self
+        }
+    }
+
+    /// Report an ICMP port unreachable in response to UDP as `Unreachable` rather than `Refused`.
+    ///
+    /// Whether "rejected" and "not reachable" are the same thing depends on the use case.
+    pub fn udp_refused_as_unreachable(self, enabled: bool) -> Probe {
+        Probe {
+            udp_refused_as_unreachable: enabled,
+            ..self
+        }
+    }
+
+    /// Retry up to the given number of times if the destination is reported unreachable, e.g.
+    /// because the first packet raced with neighbor discovery.
+    ///
+    /// This does not retry timeouts, which are assumed to be drops. The effect of the final
+    /// attempt is reported. With no retries, which is the default, the first unreachable error is
+    /// reported as `ConnEffect::Unreachable` rather than returned as an error.
+    pub fn retries(self, retries: u32) -> Probe {
+        Probe { retries, ..self }
+    }
+
+    /// Connect to a server that is already listening on the target, e.g. a real service, rather
+    /// than starting the built-in server.
+    ///
+    /// The effect is classified by the client alone, so only TCP is supported, and the reported
+    /// source address is the client's own address rather than the address the server observed.
+    pub fn external_server(self) -> Probe {
+        Probe {
+            external_server: true,
+            ..self
+        }
+    }
+
+    /// Create the client socket with the given effective user and group, within the source
+    /// namespace, so that rules matching `meta skuid` or `meta skgid` can be tested.
+    ///
+    /// The user and group must be mapped in the user namespace, if any.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn as_user(self, uid: u32, gid: u32) -> Probe {
+        Probe {
+            user: Some((uid, gid)),
+            ..self
+        }
+    }
+
+    /// Create the client socket in the given cgroup v2, e.g. `/firebreak/probe`, relative to the
+    /// root of the cgroup v2 hierarchy, so that rules matching `socket cgroupv2` can be tested.
+    ///
+    /// Only the thread that creates the socket is moved, so the cgroup must be threaded, and in
+    /// the same threaded domain as the process. The path must not contain whitespace.
+    pub fn in_cgroup(self, path: &str) -> Probe {
+        Probe {
+            cgroup: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Send TCP as a bare SYN rather than completing the handshake, e.g. to test a rule that
+    /// matches `tcp flags syn`, and classify the reply: a SYN-ACK is `Ok`, and a reset is
+    /// `Refused`.
+    pub fn syn_only(self) -> Probe {
+        Probe {
+            syn_only: true,
+            ..self
+        }
+    }
+
+    /// Send TCP as a lone FIN-ACK, as if midway through a connection that does not exist, which
+    /// conntrack classifies as `ct state invalid`, e.g. to test a rule that drops invalid packets.
+    /// The target answers it with a reset whether or not it has a listener, so only its arrival
+    /// is observed: it is `Ok` if it arrives, and otherwise `Unreachable`, even if rejected.
+    pub fn out_of_state(self) -> Probe {
+        Probe {
+            out_of_state: true,
+            ..self
+        }
+    }
+
+    /// Require TCP data to flow both ways for the probe to be `Ok`: the server echoes the cookie,
+    /// and the client waits to receive it, e.g. to catch a firewall that allows the connection
+    /// and the client's data but drops the server's. An echo that never arrives is reported as
+    /// `Unreachable`. With `external_server`, the server must be an echo service.
+    pub fn require_bidirectional(self) -> Probe {
+        Probe {
+            bidirectional: true,
+            ..self
+        }
+    }
+
+    /// Clamp the TCP window that the client advertises to the given number of bytes, with
+    /// `TCP_WINDOW_CLAMP`, e.g. to test a rule that matches small or zero windows.
+    ///
+    /// The kernel enforces a minimum clamp for real connections, but a `syn_only` probe
+    /// advertises exactly the clamp, up to the maximum unscaled window.
+    pub fn window_clamp(self, bytes: u32) -> Probe {
+        Probe {
+            window_clamp: Some(bytes),
+            ..self
+        }
+    }
+
+    /// Set the TCP congestion control algorithm of the client, e.g. `bbr` or `cubic`, with
+    /// `TCP_CONGESTION`, to reproduce behaviour that depends on pacing. The algorithm must be
+    /// available in the kernel, and a name containing whitespace cannot be recorded.
+    pub fn congestion(self, algorithm: &str) -> Probe {
+        Probe {
+            congestion: Some(algorithm.into()),
+            ..self
+        }
+    }
+
+    /// Pad a UDP cookie with zeros to exactly the given payload length, which must be at least
+    /// `COOKIE_LEN`, e.g. beyond the MTU, so that the client fragments the datagram and rules such
+    /// as `ip6 nexthdr ipv6-frag drop` can be tested. The server receives the datagram once it is
+    /// reassembled.
+    ///
+    /// A TCP cookie is padded likewise, and the server waits for the whole payload, e.g. to test
+    /// that MSS clamping avoids a path MTU black hole. A bare SYN cannot be padded.
+    ///
+    /// An unfragmented datagram has a total length, as matched by `meta length`, of the payload
+    /// length plus 28 bytes over IPv4 (a 20 byte header without options, and 8 bytes of UDP), or
+    /// plus 48 bytes over IPv6 (a 40 byte header without extensions).
+    pub fn payload_len(self, len: usize) -> Probe {
+        Probe {
+            payload_len: Some(len),
+            ..self
+        }
+    }
+
+    /// Pad the cookie as `payload_len` does, but to give the packet that carries it exactly the
+    /// given total length, as matched by `meta length`, e.g. to test either side of a boundary.
+    /// The payload length is found from the target's family with `ConnSpec::header_len`, and for
+    /// TCP, the timestamps option is counted if both ends enable it.
+    pub fn total_length(self, bytes: usize) -> Probe {
+        Probe {
+            total_len: Some(bytes),
+            ..self
+        }
+    }
+
+    /// Set or clear the don't-fragment bit of UDP, with `IP_MTU_DISCOVER` or `IPV6_MTU_DISCOVER`.
+    ///
+    /// When cleared, the client fragments a datagram that exceeds the MTU, e.g. to test that a
+    /// firewall lets fragments through. When set, such a datagram cannot be sent, and the probe
+    /// is `Unreachable`. By default, a padded IPv4 datagram is fragmented, and otherwise the
+    /// kernel's default applies.
+    pub fn dont_fragment(self, enabled: bool) -> Probe {
+        Probe {
+            dont_fragment: Some(enabled),
+            ..self
+        }
+    }
+
+    /// Send UDP to the given IPv4 broadcast address with `SO_BROADCAST`, rather than to the
+    /// target address, e.g. `255.255.255.255` or the broadcast address of the target's subnet,
+    /// so that rules matching broadcast traffic can be tested. The server is bound to the
+    /// broadcast address in the target namespace, so it only receives the broadcast.
+    pub fn broadcast(self, addr: Ipv4Addr) -> Probe {
+        Probe {
+            broadcast: Some(addr),
+            ..self
+        }
+    }
+
+    /// Run the server as a group of the given number of sockets bound to the same port with
+    /// `SO_REUSEPORT`, and report which of them received the connection in
+    /// `ProbeOutcome::listener`, e.g. to test a rule or kernel policy that balances the load
+    /// between them. Only TCP and UDP are supported, with at least one listener.
+    pub fn reuseport_listeners(self, count: usize) -> Probe {
+        Probe {
+            reuseport_listeners: Some(count),
+            ..self
+        }
+    }
+
+    /// Run the server on the given port rather than the target port, e.g. to test a rule that
+    /// redirects the connection to another port.
+    pub fn server_port(self, port: u16) -> Probe {
+        Probe {
+            server_port: Some(port),
+            ..self
+        }
+    }
+
+    /// Set the firewall mark of the client's packets with `SO_MARK`, e.g. to classify them into a
+    /// traffic control class, or to match them with `meta mark` in the source namespace.
+    pub fn fwmark(self, mark: u32) -> Probe {
+        Probe {
+            fwmark: Some(mark),
+            ..self
+        }
+    }
+
+    /// Send the client's packets with the given TTL or hop limit, e.g. 1 so that they expire at
+    /// the first router, to test a rule that matches `ip ttl` or `ip6 hoplimit`.
+    pub fn ttl(self, ttl: u8) -> Probe {
+        Probe {
+            ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    /// Send the client's packets with the given DSCP, e.g. 46 (expedited forwarding), to test a
+    /// rule that matches or rewrites `ip dscp` or `ip6 dscp`.
+    pub fn dscp(self, dscp: u8) -> Probe {
+        Probe {
+            dscp: Some(dscp),
+            ..self
+        }
+    }
+
+    /// Classify the connection as `Unreachable` if it has not resolved after the given timeout,
+    /// rather than after the default of 5 seconds.
+    pub fn timeout(self, timeout: Duration) -> Probe {
+        Probe {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Wait for the connection to resolve by itself rather than classifying it as `Unreachable`
+    /// after a timeout, e.g. to inspect a hung connection in a debugger.
+    ///
+    /// A probe whose packets are dropped will never complete.
+    pub fn no_timeout(self) -> Probe {
+        Probe {
+            timeout: None,
+            ..self
+        }
+    }
+
+    /// Wait in two phases: up to `fast` for the connection to be accepted or refused, which is
+    /// as long as it normally takes, and then up to `drop` in total before classifying it as
+    /// `Unreachable`, i.e. dropped.
+    ///
+    /// A connection that resolves in the second phase is reported as `ProbeOutcome::slow`, e.g.
+    /// to find rules that delay rather than drop, or to tune `fast`. `drop` replaces the timeout,
+    /// and must not be shorter than `fast`.
+    pub fn deadlines(self, fast: Duration, drop: Duration) -> Probe {
+        Probe {
+            timeout: Some(drop),
+            fast_deadline: Some(fast),
+            ..self
+        }
+    }
+
+    /// Fail the probe with `TimedOut` if it is refused, but only after the given duration, e.g. to
+    /// catch a rate limit that delays rejections, which should be near instant. Other effects are
+    /// reported as usual, so compare the effect to `Refused` as well.
+    pub fn expect_refused_within(self, limit: Duration) -> Probe {
+        Probe {
+            refused_within: Some(limit),
+            ..self
+        }
+    }
+}
+
+/// A compact form listing the spec followed by any non-default options, e.g.
+/// `tcp:80 source_port=1234 retries=2`.
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let default = Probe::new(self.spec);
+        write!(f, "{}", self.spec)?;
+        if let Some(port) = self.source_port {
+            write!(f, " source_port={}", port)?;
+        }
+        if self.kernel_source_addr {
+            write!(f, " kernel_source_addr")?;
+        }
+        if self.udp_refused_as_unreachable {
+            write!(f, " udp_refused_as_unreachable")?;
+        }
+        if self.retries != default.retries {
+            write!(f, " retries={}", self.retries)?;
+        }
+        if self.external_server {
+            write!(f, " external_server")?;
+        }
+        if let Some((uid, gid)) = self.user {
+            write!(f, " user={}:{}", uid, gid)?;
+        }
+        if let Some(path) = &self.cgroup {
+            write!(f, " cgroup={}", path)?;
+        }
+        if self.timeout != default.timeout {
+            match self.timeout {
+                Some(timeout) => write!(f, " timeout_ms={}", timeout.as_millis())?,
+                None => write!(f, " no_timeout")?,
+            }
+        }
+        if let Some(fast_deadline) = self.fast_deadline {
+            write!(f, " fast_deadline_ms={}", fast_deadline.as_millis())?;
+        }
+        if let Some(limit) = self.refused_within {
+            write!(f, " refused_within_ms={}", limit.as_millis())?;
+        }
+        if self.syn_only {
+            write!(f, " syn_only")?;
+        }
+        if self.out_of_state {
+            write!(f, " out_of_state")?;
+        }
+        if self.bidirectional {
+            write!(f, " require_bidirectional")?;
+        }
+        if let Some(bytes) = self.window_clamp {
+            write!(f, " window_clamp={}", bytes)?;
+        }
+        if let Some(algorithm) = &self.congestion {
+            write!(f, " congestion={}", algorithm)?;
+        }
+        if let Some(len) = self.payload_len {
+            write!(f, " payload_len={}", len)?;
+        }
+        if let Some(len) = self.total_len {
+            write!(f, " total_length={}", len)?;
+        }
+        if let Some(enabled) = self.dont_fragment {
+            write!(f, " dont_fragment={}", enabled)?;
+        }
+        if let Some(addr) = self.broadcast {
+            write!(f, " broadcast={}", addr)?;
+        }
+        if let Some(count) = self.reuseport_listeners {
+            write!(f, " reuseport_listeners={}", count)?;
+        }
+        if let Some(port) = self.server_port {
+            write!(f, " server_port={}", port)?;
+        }
+        if let Some(mark) = self.fwmark {
+            write!(f, " fwmark={}", mark)?;
+        }
+        if let Some(ttl) = self.ttl {
+            write!(f, " ttl={}", ttl)?;
+        }
+        if let Some(dscp) = self.dscp {
+            write!(f, " dscp={}", dscp)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the format produced by `Display`, or with the options separated by commas rather than
+/// spaces, e.g. `tcp:80,sport=1234,dscp=46`, where `sport` is an alias of `source_port`.
+impl FromStr for Probe {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Probe, io::Error> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid probe {:?}: {}", s, reason),
+            )
+        };
+        let mut tokens = s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|token| !token.is_empty());
+        let mut probe = Probe::new(
+            tokens
+                .next()
+                .ok_or_else(|| invalid("expected a connection spec"))?
+                .parse()?,
+        );
+        for token in tokens {
+            let (name, value) = match token.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (token, None),
+            };
+            let number = |value: Option<&str>| -> Result<u64, io::Error> {
+                value
+                    .and_then(|value| value.parse().ok())
+                    .ok_or_else(|| invalid(&format!("expected a number for {}", name)))
+            };
+            let port = |value| {
+                let n = number(value)?;
+                u16::try_from(n).map_err(|_| invalid(&format!("invalid port for {}", name)))
+            };
+            probe = match (name, value) {
+                ("source_port", _) | ("sport", _) => probe.source_port(port(value)?),
+                ("kernel_source_addr", None) => probe.kernel_source_addr(),
+                ("udp_refused_as_unreachable", None) => probe.udp_refused_as_unreachable(true),
+                ("retries", _) => {
+                    let retries = number(value)?;
+                    probe.retries(u32::try_from(retries).map_err(|_| invalid("invalid retries"))?)
+                }
+                ("external_server", None) => probe.external_server(),
+                ("user", Some(value)) => {
+                    let (uid, gid) = value
+                        .split_once(':')
+                        .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)))
+                        .ok_or_else(|| invalid("expected user=<uid>:<gid>"))?;
+                    probe.as_user(uid, gid)
+                }
+                ("cgroup", Some(path)) => probe.in_cgroup(path),
+                ("timeout_ms", _) => Probe {
+                    timeout: Some(Duration::from_millis(number(value)?)),
+                    ..probe
+                },
+                ("no_timeout", None) => probe.no_timeout(),
+                ("fast_deadline_ms", _) => Probe {
+                    fast_deadline: Some(Duration::from_millis(number(value)?)),
+                    ..probe
+                },
+                ("refused_within_ms", _) => {
+                    probe.expect_refused_within(Duration::from_millis(number(value)?))
+                }
+                ("syn_only", None) => probe.syn_only(),
+                ("out_of_state", None) => probe.out_of_state(),
+                ("require_bidirectional", None) => probe.require_bidirectional(),
+                ("window_clamp", _) => {
+                    let bytes = number(value)?;
+                    probe.window_clamp(
+                        u32::try_from(bytes).map_err(|_| invalid("invalid window_clamp"))?,
+                    )
+                }
+                ("congestion", Some(algorithm)) => probe.congestion(algorithm),
+                ("payload_len", _) => {
+                    let len = number(value)?;
+                    probe.payload_len(
+                        usize::try_from(len).map_err(|_| invalid("invalid payload_len"))?,
+                    )
+                }
+                ("total_length", _) => {
+                    let len = number(value)?;
+                    probe.total_length(
+                        usize::try_from(len).map_err(|_| invalid("invalid total_length"))?,
+                    )
+                }
+                ("dont_fragment", Some(enabled)) => probe.dont_fragment(
+                    enabled
+                        .parse()
+                        .map_err(|_| invalid("expected dont_fragment=<true|false>"))?,
+                ),
+                ("broadcast", Some(addr)) => probe.broadcast(
+                    addr.parse()
+                        .map_err(|_| invalid("expected broadcast=<ipv4 address>"))?,
+                ),
+                ("reuseport_listeners", _) => match usize::try_from(number(value)?) {
+                    Ok(count) if count > 0 => probe.reuseport_listeners(count),
+                    _ => return Err(invalid("invalid reuseport_listeners")),
+                },
+                ("server_port", _) => probe.server_port(port(value)?),
+                ("fwmark", _) => {
+                    let mark = number(value)?;
+                    probe.fwmark(u32::try_from(mark).map_err(|_| invalid("invalid fwmark"))?)
+                }
+                ("ttl", _) => {
+                    let ttl = number(value)?;
+                    probe.ttl(u8::try_from(ttl).map_err(|_| invalid("invalid ttl"))?)
+                }
+                ("dscp", _) => match number(value)? {
+                    dscp if dscp < 64 => probe.dscp(dscp as u8),
+                    _ => return Err(invalid("invalid dscp, which has 6 bits")),
+                },
+                _ => return Err(invalid(&format!("unknown option {:?}", token))),
+            };
+        }
+        Ok(probe)
+    }
+}
+
+/// Everything needed to reproduce a probe over a path, e.g. from a log of a failed test, in a
+/// compact form such as `wan 198.51.100.7 -> router 198.51.100.1 tcp:80 retries=2`.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ProbeRecord {
+    pub source_name: String,
+    pub source_addr: IpAddr,
+    pub target_name: String,
+    pub target_addr: IpAddr,
+    pub probe: Probe,
+}
+
+impl ProbeRecord {
+    pub fn new(path: &dyn ConnPath, probe: &Probe) -> ProbeRecord {
+        ProbeRecord {
+            source_name: path.source_name().into(),
+            source_addr: path.source_addr(),
+            target_name: path.target_name().into(),
+            target_addr: path.target_addr(),
+            probe: probe.clone(),
+        }
+    }
+}
+
+impl fmt::Display for ProbeRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} -> {} {} {}",
+            self.source_name, self.source_addr, self.target_name, self.target_addr, self.probe
+        )
+    }
+}
+
+/// Parses the format produced by `Display`.
+impl FromStr for ProbeRecord {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<ProbeRecord, io::Error> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid probe record {:?}: expected <source> <addr> -> <target> <addr> <probe>",
+                    s
+                ),
+            )
+        };
+        let tokens: Vec<&str> = s.splitn(6, char::is_whitespace).collect();
+        match tokens[..] {
+            [source_name, source_addr, "->", target_name, target_addr, probe] => Ok(ProbeRecord {
+                source_name: source_name.into(),
+                source_addr: source_addr.parse().map_err(|_| invalid())?,
+                target_name: target_name.into(),
+                target_addr: target_addr.parse().map_err(|_| invalid())?,
+                probe: probe.parse()?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl From<ConnSpec> for Probe {
+    fn from(spec: ConnSpec) -> Probe {
+        Probe::new(spec)
+    }
+}
+
+/// The effect of a probe, along with how long it took to determine it.
+///
+/// A refusal typically takes milliseconds, whereas a drop is only detected by timing out.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ProbeOutcome {
+    pub effect: ConnEffect,
+    pub elapsed: Duration,
+    /// The DSCP that the server received, e.g. to check that a rule preserved or rewrote it.
+    /// Only the UDP server observes it.
+    pub dscp: Option<u8>,
+    /// The index of the socket that received the connection, if the probe used
+    /// `Probe::reuseport_listeners`.
+    pub listener: Option<usize>,
+    /// Whether the effect was only reached after the fast deadline of `Probe::deadlines`. A drop,
+    /// which is only detected at the drop deadline, is not slow.
+    pub slow: bool,
+}
+
+/// A connection opened by `ConnPath::open_connection`, which is closed when dropped.
+#[async_trait]
+pub trait OpenConnection: Send {
+    /// Send a cookie and wait for the server to echo it, returning the effect. The first exchange
+    /// reports whether the connection was established. Once an exchange fails, the connection is
+    /// abandoned, and later exchanges have the same effect.
+    async fn exchange(&mut self) -> Result<ConnEffect, io::Error>;
+}
+
+/// How the data connection of a `ConnPath::probe_related` attempt is made.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FtpMode {
+    /// The target announces the data port in reply to `PASV`, or `EPSV` for IPv6, and the source
+    /// connects to it.
+    Passive,
+    /// The source announces the data port with `PORT`, or `EPRT` for IPv6, and the target
+    /// connects back to it, so the data connection's `source_addr` is that of the target.
+    Active,
+}
+
+/// The effects of a `ConnPath::probe_related` attempt.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct RelatedEffects {
+    pub control: ConnEffect,
+    /// The effect of the data connection, or `None` if it was not attempted because the control
+    /// connection failed.
+    pub data: Option<ConnEffect>,
+}
+
+/// The result of `ConnPath::connect_happy_eyeballs`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct HappyEyeballsOutcome {
+    /// The target address of the attempt that succeeded, or of the IPv4 attempt if neither did.
+    pub target_addr: IpAddr,
+    pub effect: ConnEffect,
+    /// The time from the start of the IPv6 attempt to the end of the race.
+    pub elapsed: Duration,
+    /// When the IPv4 attempt started, after the start of the IPv6 attempt, or `None` if IPv6
+    /// succeeded before it was needed.
+    pub fallback_after: Option<Duration>,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[non_exhaustive]
 pub enum ConnEffect {
-    Ok { source_addr: IpAddr },
-    Refused,
+    /// The server received the connection from `source_addr` on `target_port`, which differs
+    /// from the port the client connected to if the connection was redirected.
+    Ok {
+        source_addr: IpAddr,
+        target_port: u16,
+    },
+    /// The connection was refused, by the target or by a rule that rejects it. For UDP, raw IP
+    /// and ICMP probes, `icmp` is the ICMP error that the client received, if any; use
+    /// `ConnEffect::same_kind` to compare effects regardless of the type of rejection.
+    Refused { icmp: Option<IcmpError> },
+    /// The connection timed out, or the client was told that the host or network is unreachable
+    /// (`EHOSTUNREACH` or `ENETUNREACH`), e.g. because the router has no route to the target or
+    /// no neighbour answered for it.
     Unreachable,
+    /// The TTL or hop limit of a UDP or raw IP probe expired before it reached the target, and
+    /// the router `from` reported it with an ICMP time exceeded, e.g. with `Probe::ttl`.
+    TtlExpired { from: IpAddr },
+    /// The TCP connection was established, but reset before the server received the data, e.g.
+    /// by a rule that allows the handshake but rejects the data with a reset.
+    ResetDuringTransfer,
+}
+
+impl ConnEffect {
+    /// Whether the effects are the same apart from the ICMP error behind a refusal, e.g. to check
+    /// an effect against an expectation that does not say how the probe should be rejected.
+    pub fn same_kind(&self, other: &ConnEffect) -> bool {
+        match (self, other) {
+            (ConnEffect::Refused { .. }, ConnEffect::Refused { .. }) => true,
+            _ => self == other,
+        }
+    }
+}
+
+/// An ICMP error received by the client, as reported by the kernel's socket error queue.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct IcmpError {
+    /// The ICMP (or ICMPv6) type, e.g. 3 (destination unreachable) for ICMP.
+    pub icmp_type: u8,
+    /// The ICMP (or ICMPv6) code, e.g. 3 (port unreachable) or 13 (administratively
+    /// prohibited) for ICMP.
+    pub code: u8,
+    /// The address of the host that sent the error.
+    pub from: IpAddr,
+}
+
+/// The client failed to bind its socket to the source address, e.g. because a policy restricts
+/// which local ports may be bound, rather than the connection failing. It is the inner error of
+/// the `io::Error` that the probe returns, which has the kind of the bind error.
+#[derive(Debug)]
+pub struct ClientBindError {
+    /// The address that the client tried to bind.
+    pub addr: SocketAddr,
+    message: String,
+    err: io::Error,
+}
+
+impl ClientBindError {
+    /// Find the bind error behind an error returned by a probe, if that is why it failed.
+    pub fn find(err: &io::Error) -> Option<&ClientBindError> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    pub(crate) fn new(addr: SocketAddr, message: String, err: io::Error) -> ClientBindError {
+        ClientBindError { addr, message, err }
+    }
+}
+
+impl fmt::Display for ClientBindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.message, self.err)
+    }
+}
+
+impl Error for ClientBindError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv6Addr;
+
+    /// A path that takes the given time to reach the given effect in each address family.
+    #[derive(Copy, Clone)]
+    struct FakeDualStackPath {
+        ipv6: bool,
+        v6: (ConnEffect, Duration),
+        v4: (ConnEffect, Duration),
+    }
+
+    #[async_trait]
+    impl ConnPath for FakeDualStackPath {
+        fn source_name(&self) -> &str {
+            "source"
+        }
+
+        fn source_addr(&self) -> IpAddr {
+            match self.ipv6 {
+                true => Ipv6Addr::LOCALHOST.into(),
+                false => Ipv4Addr::LOCALHOST.into(),
+            }
+        }
+
+        fn target_name(&self) -> &str {
+            "target"
+        }
+
+        fn target_addr(&self) -> IpAddr {
+            self.source_addr()
+        }
+
+        fn other_family(&self) -> Option<Box<dyn ConnPath + Send + '_>> {
+            Some(Box::new(FakeDualStackPath {
+                ipv6: !self.ipv6,
+                ..*self
+            }))
+        }
+
+        async fn probe_outcome(&self, _probe: &Probe) -> Result<ProbeOutcome, io::Error> {
+            let (effect, elapsed) = if self.ipv6 { self.v6 } else { self.v4 };
+            tokio::time::sleep(elapsed).await;
+            Ok(ProbeOutcome {
+                effect,
+                elapsed,
+                dscp: None,
+                listener: None,
+                slow: false,
+            })
+        }
+    }
+
+    fn ok_after(addr: IpAddr, millis: u64) -> (ConnEffect, Duration) {
+        let effect = ConnEffect::Ok {
+            source_addr: addr,
+            target_port: 80,
+        };
+        (effect, Duration::from_millis(millis))
+    }
+
+    #[test]
+    fn header_lens() {
+        let ipv4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let ipv6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let tcp = ConnSpec::Tcp { port: 80 };
+        assert_eq!(40, tcp.header_len(ipv4, false));
+        assert_eq!(52, tcp.header_len(ipv4, true));
+        assert_eq!(72, tcp.header_len(ipv6, true));
+        assert_eq!(28, ConnSpec::Udp { port: 53 }.header_len(ipv4, true));
+        assert_eq!(48, ConnSpec::Udp { port: 53 }.header_len(ipv6, false));
+        assert_eq!(20, ConnSpec::Ip { protocol: 50 }.header_len(ipv4, false));
+    }
+
+    #[test]
+    fn effects_of_same_kind() {
+        let from = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let port_unreachable = ConnEffect::Refused {
+            icmp: Some(IcmpError {
+                icmp_type: 3,
+                code: 3,
+                from,
+            }),
+        };
+        let admin_prohibited = ConnEffect::Refused {
+            icmp: Some(IcmpError {
+                icmp_type: 3,
+                code: 13,
+                from,
+            }),
+        };
+        assert_ne!(port_unreachable, admin_prohibited);
+        assert!(port_unreachable.same_kind(&admin_prohibited));
+        assert!(port_unreachable.same_kind(&ConnEffect::Refused { icmp: None }));
+        assert!(!port_unreachable.same_kind(&ConnEffect::Unreachable));
+    }
+
+    #[test]
+    fn probe_round_trip() -> Result<(), io::Error> {
+        let probes = vec![
+            Probe::new(ConnSpec::Tcp { port: 80 }),
+            Probe::new(ConnSpec::Udp { port: 53 })
+                .source_port(1234)
+                .udp_refused_as_unreachable(true)
+                .retries(2)
+                .as_user(1000, 100)
+                .in_cgroup("/firebreak/probe")
+                .no_timeout()
+                .server_port(5353)
+                .fwmark(7)
+                .ttl(1)
+                .dscp(46)
+                .payload_len(3000)
+                .reuseport_listeners(4)
+                .dont_fragment(false),
+            Probe::new(ConnSpec::Udp { port: 67 }).broadcast(Ipv4Addr::BROADCAST),
+            Probe::new(ConnSpec::Tcp { port: 22 }).external_server(),
+            Probe::new(ConnSpec::Tcp { port: 26 }).require_bidirectional(),
+            Probe::new(ConnSpec::Tcp { port: 28 }).out_of_state(),
+            Probe::new(ConnSpec::Tcp { port: 23 })
+                .syn_only()
+                .window_clamp(0),
+            Probe::new(ConnSpec::Tcp { port: 24 })
+                .congestion("bbr")
+                .payload_len(8192),
+            Probe::new(ConnSpec::Udp { port: 25 })
+                .kernel_source_addr()
+                .total_length(128),
+            Probe::new(ConnSpec::Udp { port: 27 }).expect_refused_within(Duration::from_millis(50)),
+            Probe::new(ConnSpec::Ip { protocol: 50 }),
+            Probe::new(ConnSpec::Udp { port: 29 }).timeout(Duration::from_millis(1500)),
+            Probe::new(ConnSpec::Tcp { port: 443 })
+                .deadlines(Duration::from_millis(200), Duration::from_secs(2)),
+        ];
+        for probe in probes {
+            assert_eq!(probe, probe.to_string().parse()?);
+        }
+        assert_eq!(
+            "udp:53 source_port=1234 retries=2",
+            Probe::new(ConnSpec::Udp { port: 53 })
+                .source_port(1234)
+                .retries(2)
+                .to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn probe_parse_error() {
+        for s in &[
+            "",
+            "tcp:80 retries",
+            "tcp:80 source_port=65536",
+            "tcp:80 bogus",
+            "tcp:80,dscp=64",
+            "icmp:timestamp",
+            "sctp:38412",
+        ] {
+            let err = s.parse::<Probe>().expect_err(s);
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    fn probe_with_commas() -> Result<(), io::Error> {
+        assert_eq!(
+            Probe::new(ConnSpec::Tcp { port: 80 })
+                .source_port(1234)
+                .dscp(46),
+            "tcp:80,dscp=46,sport=1234".parse()?
+        );
+        assert_eq!(ConnSpec::Ip { protocol: 47 }, "proto:47".parse()?);
+        assert_eq!(
+            ConnSpec::Icmp {
+                kind: IcmpKind::Echo
+            },
+            "icmp".parse()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn probe_record_round_trip() -> Result<(), io::Error> {
+        let s = "wan 2001:db8::7 -> router 2001:db8::1 tcp:80 retries=2";
+        let record: ProbeRecord = s.parse()?;
+        assert_eq!(
+            ProbeRecord {
+                source_name: "wan".into(),
+                source_addr: "2001:db8::7".parse().unwrap(),
+                target_name: "router".into(),
+                target_addr: "2001:db8::1".parse().unwrap(),
+                probe: Probe::new(ConnSpec::Tcp { port: 80 }).retries(2),
+            },
+            record
+        );
+        assert_eq!(s, record.to_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn probe_stream_at_interval() -> Result<(), io::Error> {
+        let path = FakeDualStackPath {
+            ipv6: false,
+            v6: (ConnEffect::Unreachable, Duration::from_secs(2)),
+            v4: ok_after(Ipv4Addr::LOCALHOST.into(), 10),
+        };
+        let interval = Duration::from_millis(50);
+        let probes = path
+            .probe_stream(ConnSpec::Tcp { port: 80 }, interval)
+            .take(3)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(3, probes.len());
+        assert!(probes.iter().all(|&(_, effect)| effect == path.v4.0));
+        // The timer has millisecond resolution, so it may fire a little early
+        for pair in probes.windows(2) {
+            assert!(pair[1].0 - pair[0].0 >= interval - Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_prefers_ipv6() -> Result<(), io::Error> {
+        let path = FakeDualStackPath {
+            ipv6: false,
+            v6: ok_after(Ipv6Addr::LOCALHOST.into(), 10),
+            v4: ok_after(Ipv4Addr::LOCALHOST.into(), 0),
+        };
+        let outcome = path.connect_happy_eyeballs(80).await?;
+        assert_eq!(IpAddr::from(Ipv6Addr::LOCALHOST), outcome.target_addr);
+        assert_eq!(None, outcome.fallback_after);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_falls_back_after_delay() -> Result<(), io::Error> {
+        let path = FakeDualStackPath {
+            ipv6: true,
+            v6: (ConnEffect::Unreachable, Duration::from_secs(2)),
+            v4: ok_after(Ipv4Addr::LOCALHOST.into(), 10),
+        };
+        let outcome = path.connect_happy_eyeballs(80).await?;
+        assert_eq!(IpAddr::from(Ipv4Addr::LOCALHOST), outcome.target_addr);
+        assert_eq!(path.v4.0, outcome.effect);
+        let fallback_after = outcome.fallback_after.unwrap();
+        assert!(fallback_after >= HAPPY_EYEBALLS_DELAY);
+        assert!(outcome.elapsed < Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_falls_back_when_ipv6_refused() -> Result<(), io::Error> {
+        let path = FakeDualStackPath {
+            ipv6: true,
+            v6: (
+                ConnEffect::Refused { icmp: None },
+                Duration::from_millis(10),
+            ),
+            v4: (ConnEffect::Unreachable, Duration::from_millis(10)),
+        };
+        let outcome = path.connect_happy_eyeballs(80).await?;
+        assert_eq!(IpAddr::from(Ipv4Addr::LOCALHOST), outcome.target_addr);
+        assert_eq!(ConnEffect::Unreachable, outcome.effect);
+        assert!(outcome.fallback_after.unwrap() < HAPPY_EYEBALLS_DELAY);
+        Ok(())
+    }
 }
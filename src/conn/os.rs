@@ -1,20 +1,42 @@
 use std::prelude::v1::*;
 
 use async_trait::async_trait;
+use futures::channel::oneshot;
 use futures::future::{AbortHandle, Abortable, Aborted};
 use futures::prelude::*;
 use futures::{try_join, FutureExt};
 use log::*;
+use std::fs;
 use std::io;
-use std::net::IpAddr;
-use tokio::net::{TcpListener, TcpSocket, UdpSocket};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::unix::AsyncFd;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
 use tokio::prelude::*;
+use tokio::task::JoinHandle;
 use tokio::time::error::Elapsed;
-use tokio::time::{timeout, Duration};
+use tokio::time::{sleep, timeout, timeout_at, Duration, Instant};
 
+use crate::conn::matrix::SynFloodReport;
 use crate::conn::*;
 use crate::os::OsNs;
 
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How often to check a raw socket for an ICMP error, which does not make it readable.
+const ERROR_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
 pub struct OsNsConnPath<'a> {
     source_name: &'a str,
     source: &'a OsNs,
@@ -22,6 +44,9 @@ pub struct OsNsConnPath<'a> {
     target_name: &'a str,
     target: &'a OsNs,
     target_addr: IpAddr,
+    transparent_listen_addr: Option<SocketAddr>,
+    source_device: Option<&'a str>,
+    other_family_addrs: Option<(IpAddr, IpAddr)>,
 }
 
 impl<'a> OsNsConnPath<'a> {
@@ -40,8 +65,309 @@ impl<'a> OsNsConnPath<'a> {
             target_name,
             target,
             target_addr,
+            transparent_listen_addr: None,
+            source_device: None,
+            other_family_addrs: None,
+        }
+    }
+
+    /// A path between namespaces that the caller manages itself, rather than through `OsHost`,
+    /// named `source` and `target` in logs. The addresses must already be assigned and routable.
+    ///
+    /// Fails unless the addresses are in the same family.
+    pub fn between(
+        source: &'a OsNs,
+        source_addr: IpAddr,
+        target: &'a OsNs,
+        target_addr: IpAddr,
+    ) -> Result<OsNsConnPath<'a>, io::Error> {
+        if source_addr.is_ipv4() != target_addr.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Addresses {} and {} are in different families",
+                    source_addr, target_addr
+                ),
+            ));
+        }
+        Ok(OsNsConnPath::new(
+            "source",
+            source,
+            source_addr,
+            "target",
+            target,
+            target_addr,
+        ))
+    }
+
+    /// Listen on the given address with `IP_TRANSPARENT`, rather than on the target address.
+    ///
+    /// The client still connects to the target address, so the connection only succeeds if the
+    /// firewall diverts it to the listener, e.g. with a `tproxy` rule.
+    pub fn with_transparent_listener(self, listen_addr: SocketAddr) -> OsNsConnPath<'a> {
+        OsNsConnPath {
+            transparent_listen_addr: Some(listen_addr),
+            ..self
+        }
+    }
+
+    /// Send from the given device with `SO_BINDTODEVICE`, whatever the routing table says.
+    pub fn with_source_device(self, device: &'a str) -> OsNsConnPath<'a> {
+        OsNsConnPath {
+            source_device: Some(device),
+            ..self
+        }
+    }
+
+    /// Also connect between the given source and target addresses, in the other address family,
+    /// for `ConnPath::other_family`.
+    pub fn with_other_family(self, source_addr: IpAddr, target_addr: IpAddr) -> OsNsConnPath<'a> {
+        OsNsConnPath {
+            other_family_addrs: Some((source_addr, target_addr)),
+            ..self
+        }
+    }
+
+    /// Bind the probe's server in the target namespace, and return a handle to wait for the given
+    /// cookie, so that the client can be run separately with `run_client`, e.g. by another
+    /// process that agreed on the cookie.
+    ///
+    /// The server runs in the background on the runtime, so that it accepts and answers the
+    /// client before the handle is awaited, as a TCP client waits for the server to close.
+    pub async fn start_server(
+        &self,
+        probe: &Probe,
+        cookie: u128,
+    ) -> Result<ServerHandle, io::Error> {
+        if probe.external_server || probe.reuseport_listeners.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("A separate server is not supported for {:?}", probe),
+            ));
+        }
+        // The spawned server outlives the borrow of the probe, so owns a copy of it
+        let spec = probe.spec;
+        let owned = probe.clone();
+        let handle = match spec {
+            ConnSpec::Tcp { port } if probe.syn_only => {
+                let socket = bind_server(&TcpSyn { port, probe }, self).await?;
+                ServerHandle::spawn(async move {
+                    TcpSyn {
+                        port,
+                        probe: &owned,
+                    }
+                    .server(socket, cookie)
+                    .await
+                })
+            }
+            ConnSpec::Tcp { port } if probe.out_of_state => {
+                let socket = bind_server(&TcpOutOfState { port, probe }, self).await?;
+                ServerHandle::spawn(async move {
+                    TcpOutOfState {
+                        port,
+                        probe: &owned,
+                    }
+                    .server(socket, cookie)
+                    .await
+                })
+            }
+            ConnSpec::Tcp { port } => {
+                let socket = bind_server(&Tcp { port, probe }, self).await?;
+                ServerHandle::spawn(async move {
+                    Tcp {
+                        port,
+                        probe: &owned,
+                    }
+                    .server(socket, cookie)
+                    .await
+                })
+            }
+            ConnSpec::Udp { port } => {
+                let socket = bind_server(&Udp { port, probe }, self).await?;
+                ServerHandle::spawn(async move {
+                    Udp {
+                        port,
+                        probe: &owned,
+                    }
+                    .server(socket, cookie)
+                    .await
+                })
+            }
+            ConnSpec::Ip { protocol } => {
+                let socket = bind_server(&Ip { protocol, probe }, self).await?;
+                ServerHandle::spawn(async move {
+                    Ip {
+                        protocol,
+                        probe: &owned,
+                    }
+                    .server(socket, cookie)
+                    .await
+                })
+            }
+            ConnSpec::Icmp { kind } => {
+                let socket = bind_server(&Icmp { kind, probe }, self).await?;
+                ServerHandle::spawn(async move {
+                    Icmp {
+                        kind,
+                        probe: &owned,
+                    }
+                    .server(socket, cookie)
+                    .await
+                })
+            }
+        };
+        debug!("Started server");
+        Ok(handle)
+    }
+
+    /// Run just the probe's client, sending the given cookie to a server started separately,
+    /// e.g. with `start_server`, within the probe's timeout.
+    ///
+    /// The client alone can only tell that it sent the cookie, which is reported as `Ok` with
+    /// the client's own address, so the server's handle must confirm that it arrived.
+    pub async fn run_client(&self, probe: &Probe, cookie: u128) -> Result<ConnEffect, io::Error> {
+        let client = async {
+            let (status, port) = match probe.spec {
+                ConnSpec::Tcp { port } if probe.syn_only => {
+                    (TcpSyn { port, probe }.client(self, cookie).await?, port)
+                }
+                ConnSpec::Tcp { port } if probe.out_of_state => (
+                    TcpOutOfState { port, probe }.client(self, cookie).await?,
+                    port,
+                ),
+                ConnSpec::Tcp { port } => (Tcp { port, probe }.client(self, cookie).await?, port),
+                ConnSpec::Udp { port } => (Udp { port, probe }.client(self, cookie).await?, port),
+                ConnSpec::Ip { protocol } => {
+                    (Ip { protocol, probe }.client(self, cookie).await?, 0)
+                }
+                ConnSpec::Icmp { kind } => (Icmp { kind, probe }.client(self, cookie).await?, 0),
+            };
+            Ok(client_effect(status, self, port))
+        };
+        let effect = match probe.timeout {
+            Some(duration) => {
+                timeout(duration, client)
+                    .unwrap_or_else(|Elapsed { .. }| Ok(ConnEffect::Unreachable))
+                    .await
+            }
+            None => client.await,
+        };
+        match effect {
+            Err(err) if is_unreachable_error(&err) => Ok(ConnEffect::Unreachable),
+            other => other,
+        }
+    }
+}
+
+impl<'a> OsNsConnPath<'a> {
+    /// The probe with the payload length that gives the packet carrying its cookie the given
+    /// total length.
+    fn pad_to_total_len(&self, probe: &Probe, total_len: usize) -> Result<Probe, io::Error> {
+        if probe.payload_len.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Both a payload and a total length for {:?}", probe),
+            ));
+        }
+        let tcp_timestamps = matches!(probe.spec, ConnSpec::Tcp { .. })
+            && tcp_timestamps(self.source)?
+            && tcp_timestamps(self.target)?;
+        let header_len = probe.spec.header_len(self.target_addr, tcp_timestamps);
+        let payload_len = total_len.checked_sub(header_len).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The total length of {:?} is shorter than its {} bytes of headers",
+                    probe, header_len
+                ),
+            )
+        })?;
+        Ok(probe.clone().payload_len(payload_len))
+    }
+}
+
+/// A server started with `OsNsConnPath::start_server`, which is closed when dropped.
+pub struct ServerHandle {
+    server: JoinHandle<Result<ServerStatus, io::Error>>,
+    abort_handle: AbortHandle,
+}
+
+impl ServerHandle {
+    fn spawn<F>(server: F) -> ServerHandle
+    where
+        F: Future<Output = Result<ServerStatus, io::Error>> + Send + 'static,
+    {
+        let (abort_handle, abort_reg) = AbortHandle::new_pair();
+        let server = Abortable::new(server, abort_reg).unwrap_or_else(|_: Aborted| {
+            debug!("Aborted server");
+            Ok(ServerStatus::Aborted)
+        });
+        ServerHandle {
+            server: tokio::spawn(server),
+            abort_handle,
         }
     }
+
+    /// Wait until the server receives the cookie, and return the effect that it observed, which
+    /// is `Ok` or `ResetDuringTransfer`. This waits forever if the cookie never arrives, so the
+    /// caller should impose a timeout.
+    pub async fn received(mut self) -> Result<ConnEffect, io::Error> {
+        // The task is only cancelled by its runtime shutting down, so this fails if it panicked
+        let status = (&mut self.server).await.expect("The server task failed")?;
+        match status {
+            ServerStatus::ReceivedCookie(rx) => Ok(ConnEffect::Ok {
+                source_addr: rx.peer_addr,
+                target_port: rx.local_port,
+            }),
+            ServerStatus::Reset => Ok(ConnEffect::ResetDuringTransfer),
+            ServerStatus::Aborted => unreachable!("The server is only aborted when dropped"),
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.abort_handle.abort();
+    }
+}
+
+/// Bind the connector's server in the target namespace of the path.
+async fn bind_server<C: OsNsConnector>(
+    connector: &C,
+    path: &OsNsConnPath<'_>,
+) -> Result<C::ServerSocket, io::Error> {
+    let (listen_addr, transparent) = connector.listen_addr(path);
+    let socket = connector
+        .bind_server(path.target, listen_addr, transparent)
+        .await?;
+    debug!("Bound server");
+    Ok(socket)
+}
+
+/// The port of a probe that can make exchanges over a connection, which must be plain TCP.
+fn exchanges_port(probe: &Probe) -> Result<u16, io::Error> {
+    match probe.spec {
+        ConnSpec::Tcp { port } if !probe.raw_tcp() && !probe.external_server => Ok(port),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Exchanges are not supported for {:?}", probe),
+        )),
+    }
+}
+
+/// The effect of a probe as classified by the client alone, which cannot tell whether a cookie
+/// that it sent arrived.
+fn client_effect(status: ClientStatus, path: &OsNsConnPath<'_>, port: u16) -> ConnEffect {
+    match status {
+        ClientStatus::SentCookie(_) => ConnEffect::Ok {
+            source_addr: path.source_addr,
+            target_port: port,
+        },
+        ClientStatus::Refused(icmp) => ConnEffect::Refused { icmp },
+        ClientStatus::Unreachable => ConnEffect::Unreachable,
+        ClientStatus::TtlExpired(from) => ConnEffect::TtlExpired { from },
+        ClientStatus::Reset => ConnEffect::ResetDuringTransfer,
+    }
 }
 
 #[async_trait]
@@ -62,28 +388,378 @@ impl<'a> ConnPath for OsNsConnPath<'a> {
         self.target_addr
     }
 
-    async fn connect(&self, spec: ConnSpec) -> Result<ConnEffect, io::Error> {
+    fn other_family(&self) -> Option<Box<dyn ConnPath + Send + '_>> {
+        let (source_addr, target_addr) = self.other_family_addrs?;
+        Some(Box::new(OsNsConnPath {
+            source_addr,
+            target_addr,
+            // A transparent listener only has an address in this family
+            transparent_listen_addr: None,
+            other_family_addrs: Some((self.source_addr, self.target_addr)),
+            ..*self
+        }))
+    }
+
+    fn reversed(&self) -> Option<Box<dyn ConnPath + Send + '_>> {
+        Some(Box::new(OsNsConnPath {
+            source_name: self.target_name,
+            source: self.target,
+            source_addr: self.target_addr,
+            target_name: self.source_name,
+            target: self.source,
+            target_addr: self.source_addr,
+            // A transparent listener and a pinned device each belong to one end
+            transparent_listen_addr: None,
+            source_device: None,
+            other_family_addrs: self
+                .other_family_addrs
+                .map(|(source_addr, target_addr)| (target_addr, source_addr)),
+        }))
+    }
+
+    fn effective_mtu(&self) -> Result<u32, io::Error> {
+        let target_addr = self.target_addr.to_string();
+        let source_addr = self.source_addr.to_string();
+        let mut args = vec!["route", "get", &target_addr, "from", &source_addr];
+        if let Some(device) = self.source_device {
+            args.extend(&["oif", device]);
+        }
+        let route = self.source.scoped_process("ip", &args)?;
+        if let Some(mtu) = parse_ip_field(&route, "mtu") {
+            return Ok(mtu);
+        }
+        let device = parse_ip_field::<String>(&route, "dev").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No device in route: {}", route),
+            )
+        })?;
+        let link = self
+            .source
+            .scoped_process("ip", &["-o", "link", "show", "dev", &device])?;
+        parse_ip_field(&link, "mtu").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No MTU in link: {}", link),
+            )
+        })
+    }
+
+    async fn probe_exchanges(
+        &self,
+        probe: &Probe,
+        count: usize,
+    ) -> Result<Vec<ConnEffect>, io::Error> {
+        let port = exchanges_port(probe)?;
+        info!(
+            "Attempting {} exchanges from {} ({}) to {} ({}) via {:?}",
+            count, self.source_name, self.source_addr, self.target_name, self.target_addr, probe
+        );
+        let effects = Tcp { port, probe }.exchanges(self, count).await?;
+        info!(
+            "Exchanges from {} ({}) to {} ({}) via {:?} had effects: {:?}",
+            self.source_name, self.source_addr, self.target_name, self.target_addr, probe, effects
+        );
+        Ok(effects)
+    }
+
+    async fn open_connection(&self, probe: &Probe) -> Result<Box<dyn OpenConnection>, io::Error> {
+        let port = exchanges_port(probe)?;
+        info!(
+            "Opening connection from {} ({}) to {} ({}) via {:?}",
+            self.source_name, self.source_addr, self.target_name, self.target_addr, probe
+        );
+        Ok(Box::new(Tcp { port, probe }.open(self).await?))
+    }
+
+    async fn syn_flood(
+        &self,
+        probe: &Probe,
+        count: usize,
+        rate: u32,
+    ) -> Result<SynFloodReport, io::Error> {
+        let (port, wait) = match (probe.spec, probe.timeout) {
+            (ConnSpec::Tcp { port }, Some(wait))
+                if probe.syn_only
+                    && !probe.external_server
+                    && self.transparent_listen_addr.is_none()
+                    && count > 0
+                    && rate > 0 =>
+            {
+                (port, wait)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "SYN floods of {} at {}/s are not supported for {:?}",
+                        count, rate, probe
+                    ),
+                ))
+            }
+        };
+        info!(
+            "Flooding {} SYNs at {}/s from {} ({}) to {} ({}) via {:?}",
+            count,
+            rate,
+            self.source_name,
+            self.source_addr,
+            self.target_name,
+            self.target_addr,
+            probe
+        );
+        let start = Instant::now();
+        let effects = TcpSyn { port, probe }
+            .flood(self, count, rate, wait)
+            .await?;
+        let report = SynFloodReport {
+            effects,
+            elapsed: start.elapsed(),
+        };
+        info!(
+            "SYN flood from {} ({}) to {} ({}) via {:?}: {}",
+            self.source_name, self.source_addr, self.target_name, self.target_addr, probe, report
+        );
+        Ok(report)
+    }
+
+    async fn probe_related(
+        &self,
+        probe: &Probe,
+        mode: FtpMode,
+        data_port: u16,
+    ) -> Result<RelatedEffects, io::Error> {
+        let port = match probe.spec {
+            ConnSpec::Tcp { port }
+                if !probe.raw_tcp() && !probe.external_server && port != data_port =>
+            {
+                port
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{:?} related connections to port {} are not supported for {:?}",
+                        mode, data_port, probe
+                    ),
+                ))
+            }
+        };
+        info!(
+            "Attempting a {:?} related connection to port {} from {} ({}) to {} ({}) via {:?}",
+            mode,
+            data_port,
+            self.source_name,
+            self.source_addr,
+            self.target_name,
+            self.target_addr,
+            probe
+        );
+        let connector = Tcp { port, probe };
+        let effects = match mode {
+            FtpMode::Passive => connector.passive_related(self, data_port).await?,
+            FtpMode::Active => connector.active_related(self, data_port).await?,
+        };
+        info!(
+            "Related connection from {} ({}) to {} ({}) via {:?} had effects: {:?}",
+            self.source_name, self.source_addr, self.target_name, self.target_addr, probe, effects
+        );
+        Ok(effects)
+    }
+
+    async fn probe_outcome(&self, probe: &Probe) -> Result<ProbeOutcome, io::Error> {
         info!(
             "Attempting to connect from {} ({}) to {} ({}) via {:?}",
-            self.source_name, self.source_addr, self.target_name, self.target_addr, spec
+            self.source_name, self.source_addr, self.target_name, self.target_addr, probe
         );
-        let timeout = Duration::from_secs(5);
-        let effect = match spec {
-            ConnSpec::Tcp { port } => Tcp { port }.connect_with_timeout(&self, timeout).await,
-            ConnSpec::Udp { port } => Udp { port }.connect_with_timeout(&self, timeout).await,
-        }?;
+        let padded;
+        let probe = match probe.total_len {
+            Some(total_len) => {
+                padded = self.pad_to_total_len(probe, total_len)?;
+                &padded
+            }
+            None => probe,
+        };
+        if probe.external_server && !matches!(probe.spec, ConnSpec::Tcp { .. }) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("External servers are not supported for {:?}", probe.spec),
+            ));
+        }
+        if probe.out_of_state && (probe.syn_only || !matches!(probe.spec, ConnSpec::Tcp { .. })) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Out-of-state segments are not supported for {:?}", probe),
+            ));
+        }
+        if probe.reuseport_listeners.is_some()
+            && (probe.raw_tcp()
+                || !matches!(probe.spec, ConnSpec::Tcp { .. } | ConnSpec::Udp { .. }))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Reuseport groups are not supported for {:?}", probe),
+            ));
+        }
+        if probe.reuseport_listeners == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A reuseport group needs at least one listener",
+            ));
+        }
+        if probe.bidirectional && (probe.raw_tcp() || !matches!(probe.spec, ConnSpec::Tcp { .. })) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Bidirectional data is not supported for {:?}", probe),
+            ));
+        }
+        if probe.kernel_source_addr && probe.raw_tcp() {
+            // The segment is built by hand, with the source address in its checksum
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Kernel source addresses are not supported for {:?}", probe),
+            ));
+        }
+        if probe.congestion.is_some()
+            && (probe.raw_tcp() || !matches!(probe.spec, ConnSpec::Tcp { .. }))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Congestion control is not supported for {:?}", probe),
+            ));
+        }
+        if matches!(probe.dscp, Some(dscp) if dscp >= 64) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid DSCP for {:?}, which has 6 bits", probe),
+            ));
+        }
+        if probe.dont_fragment.is_some() && !matches!(probe.spec, ConnSpec::Udp { .. }) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The don't-fragment bit is not supported for {:?}",
+                    probe.spec
+                ),
+            ));
+        }
+        if probe.broadcast.is_some()
+            && (!matches!(probe.spec, ConnSpec::Udp { .. })
+                || !self.target_addr.is_ipv4()
+                || self.transparent_listen_addr.is_some())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Broadcast is not supported for {:?} to {}",
+                    probe, self.target_addr
+                ),
+            ));
+        }
+        if probe.payload_len.is_some()
+            && (probe.raw_tcp()
+                || !matches!(probe.spec, ConnSpec::Tcp { .. } | ConnSpec::Udp { .. }))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Padding is not supported for {:?}", probe.spec),
+            ));
+        }
+        if let (Some(fast), Some(drop)) = (probe.fast_deadline, probe.timeout) {
+            if fast > drop {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("The fast deadline of {:?} exceeds its drop deadline", probe),
+                ));
+            }
+        }
+        if matches!(probe.payload_len, Some(len) if len < COOKIE_LEN) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The payload of {:?} is shorter than the cookie", probe),
+            ));
+        }
+        let start = Instant::now();
+        let mut retries = probe.retries;
+        let (effect, received) = loop {
+            let effect = match probe.spec {
+                ConnSpec::Tcp { port } if probe.syn_only => {
+                    TcpSyn { port, probe }.connect_with_deadlines(&self).await
+                }
+                ConnSpec::Tcp { port } if probe.out_of_state => {
+                    TcpOutOfState { port, probe }
+                        .connect_with_deadlines(&self)
+                        .await
+                }
+                ConnSpec::Tcp { port } => Tcp { port, probe }.connect_with_deadlines(&self).await,
+                ConnSpec::Udp { port } => Udp { port, probe }.connect_with_deadlines(&self).await,
+                ConnSpec::Ip { protocol } => {
+                    Ip { protocol, probe }.connect_with_deadlines(&self).await
+                }
+                ConnSpec::Icmp { kind } => Icmp { kind, probe }.connect_with_deadlines(&self).await,
+            };
+            match effect {
+                Err(err) if is_unreachable_error(&err) && retries > 0 => {
+                    debug!("Unreachable, retrying: {}", err);
+                    retries -= 1;
+                    sleep(RETRY_DELAY).await;
+                }
+                Err(err) if is_unreachable_error(&err) => break (ConnEffect::Unreachable, None),
+                other => break other?,
+            }
+        };
+        let elapsed = start.elapsed();
+        let slow = effect != ConnEffect::Unreachable
+            && matches!(probe.fast_deadline, Some(fast) if elapsed > fast);
         info!(
-            "Attempt to connect from {} ({}) to {} ({}) via {:?} had effect: {:?}",
-            self.source_name, self.source_addr, self.target_name, self.target_addr, spec, effect,
+            "Attempt to connect from {} ({}) to {} ({}) via {:?} had effect: {:?} after {:?}",
+            self.source_name,
+            self.source_addr,
+            self.target_name,
+            self.target_addr,
+            probe,
+            effect,
+            elapsed,
         );
-        Ok(effect)
+        debug!("To reproduce: {}", ProbeRecord::new(self, probe));
+        if let (ConnEffect::Refused { .. }, Some(limit)) = (effect, probe.refused_within) {
+            if elapsed > limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "Refused after {:?}, expected within {:?}: {}",
+                        elapsed,
+                        limit,
+                        ProbeRecord::new(self, probe)
+                    ),
+                ));
+            }
+        }
+        Ok(ProbeOutcome {
+            effect,
+            elapsed,
+            dscp: received.and_then(|received| received.dscp),
+            listener: received.and_then(|received| received.listener),
+            slow,
+        })
     }
 }
 
+/// The value following the given keyword in the output of `ip route get` or `ip link show`, e.g.
+/// `1280` in `... cache expires 590sec mtu 1280`.
+fn parse_ip_field<T: FromStr>(output: &str, keyword: &str) -> Option<T> {
+    let mut words = output.split_whitespace();
+    words.find(|&word| word == keyword)?;
+    words.next()?.parse().ok()
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 enum ClientStatus {
     SentCookie(SentCookie),
-    Refused,
+    Refused(Option<IcmpError>),
+    Unreachable,
+    TtlExpired(IpAddr),
+    Reset,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -94,6 +770,7 @@ struct SentCookie {
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 enum ServerStatus {
     ReceivedCookie(ReceivedCookie),
+    Reset,
     Aborted,
 }
 
@@ -101,269 +778,3138 @@ enum ServerStatus {
 struct ReceivedCookie {
     cookie: u128,
     peer_addr: IpAddr,
+    local_port: u16,
+    /// The DSCP of the packet that carried the cookie, if the server observes it.
+    dscp: Option<u8>,
+    /// The index of the socket that received the cookie, if the server is a reuseport group.
+    listener: Option<usize>,
 }
 
 #[async_trait]
 trait OsNsConnector: Sized + Sync {
     type ServerSocket: Send;
 
+    fn port(&self) -> u16;
+    fn probe(&self) -> &Probe;
+
     async fn bind_server(
         &self,
         target: &OsNs,
-        target_addr: IpAddr,
+        listen_addr: SocketAddr,
+        transparent: bool,
     ) -> Result<Self::ServerSocket, io::Error>;
 
-    async fn server(&self, socket: Self::ServerSocket) -> Result<ServerStatus, io::Error>;
+    /// Receive the given cookie, ignoring anything else, which may come from concurrent probes
+    /// that share the namespace.
+    async fn server(
+        &self,
+        socket: Self::ServerSocket,
+        cookie: u128,
+    ) -> Result<ServerStatus, io::Error>;
 
     async fn client(
         &self,
-        source: &OsNs,
-        source_addr: IpAddr,
-        target_addr: IpAddr,
+        path: &OsNsConnPath<'_>,
+        cookie: u128,
     ) -> Result<ClientStatus, io::Error>;
 
-    async fn connect_with_timeout<'a>(
+    /// The address that the server listens on, and whether it is transparent.
+    fn listen_addr(&self, path: &OsNsConnPath<'_>) -> (SocketAddr, bool) {
+        match path.transparent_listen_addr {
+            Some(listen_addr) => (listen_addr, true),
+            None => {
+                let port = self.probe().server_port.unwrap_or_else(|| self.port());
+                ((self.probe().target_addr(path), port).into(), false)
+            }
+        }
+    }
+
+    async fn connect_with_deadlines<'a>(
         &self,
         path: &OsNsConnPath<'a>,
-        duration: Duration,
-    ) -> Result<ConnEffect, io::Error> {
-        timeout(duration, self.connect(path))
-            .unwrap_or_else(|Elapsed { .. }| Ok(ConnEffect::Unreachable))
-            .await
+    ) -> Result<(ConnEffect, Option<ReceivedCookie>), io::Error> {
+        let start = Instant::now();
+        let mut connect = self.connect_observing_server(path).boxed();
+        if let Some(fast_deadline) = self.probe().fast_deadline {
+            match timeout(fast_deadline, &mut connect).await {
+                Ok(effect) => return effect,
+                Err(Elapsed { .. }) => {
+                    debug!(
+                        "No effect within {:?}, waiting until dropped",
+                        fast_deadline
+                    )
+                }
+            }
+        }
+        match self.probe().timeout {
+            Some(duration) => {
+                timeout(duration.saturating_sub(start.elapsed()), connect)
+                    .unwrap_or_else(|Elapsed { .. }| Ok((ConnEffect::Unreachable, None)))
+                    .await
+            }
+            None => connect.await,
+        }
     }
 
     async fn connect<'a>(&self, path: &OsNsConnPath<'a>) -> Result<ConnEffect, io::Error> {
+        Ok(self.connect_observing_server(path).await?.0)
+    }
+
+    /// Like `connect`, but also returns what the server received, if the effect is `Ok`.
+    async fn connect_observing_server<'a>(
+        &self,
+        path: &OsNsConnPath<'a>,
+    ) -> Result<(ConnEffect, Option<ReceivedCookie>), io::Error> {
+        let cookie = new_cookie();
+        if self.probe().external_server {
+            debug!("Running client against external server...");
+            let effect = client_effect(self.client(path, cookie).await?, path, self.port());
+            return Ok((effect, None));
+        }
+
         // Ensure the server is bound, with any errors handled, before we start the client
         debug!("Binding server...");
-        let listener = self.bind_server(path.target, path.target_addr).await?;
+        let (listen_addr, transparent) = self.listen_addr(path);
+        let mut listeners = vec![];
+        for _ in 0..self.probe().reuseport_listeners.unwrap_or(1) {
+            listeners.push(
+                self.bind_server(path.target, listen_addr, transparent)
+                    .await?,
+            );
+        }
         debug!("Bound server");
 
         let (server_abort_handle, server_abort_reg) = AbortHandle::new_pair();
 
-        let server =
-            Abortable::new(self.server(listener), server_abort_reg).unwrap_or_else(|_: Aborted| {
-                debug!("Aborted server");
-                Ok(ServerStatus::Aborted)
-            });
-
-        let client = self
-            .client(path.source, path.source_addr, path.target_addr)
-            .inspect(|r| match r {
-                Ok(ClientStatus::SentCookie(_)) => (),
-                Ok(ClientStatus::Refused) | Err(_) => {
-                    server_abort_handle.abort();
+        let servers: Vec<_> = listeners
+            .into_iter()
+            .enumerate()
+            .map(|(i, listener)| {
+                let server = self.server(listener, cookie);
+                async move { Ok::<_, io::Error>((i, server.await?)) }.boxed()
+            })
+            .collect();
+        let reuseport = self.probe().reuseport_listeners.is_some();
+        let server = async move {
+            // The first socket to receive the cookie wins, and the others are dropped
+            let ((i, status), _) = future::select_ok(servers).await?;
+            Ok(match status {
+                ServerStatus::ReceivedCookie(rx) if reuseport => {
+                    ServerStatus::ReceivedCookie(ReceivedCookie {
+                        listener: Some(i),
+                        ..rx
+                    })
                 }
-            });
+                status => status,
+            })
+        };
+        let server = Abortable::new(server, server_abort_reg).unwrap_or_else(|_: Aborted| {
+            debug!("Aborted server");
+            Ok(ServerStatus::Aborted)
+        });
+
+        let client = self.client(path, cookie).inspect(|r| match r {
+            Ok(ClientStatus::SentCookie(_)) => (),
+            Ok(ClientStatus::Refused(_))
+            | Ok(ClientStatus::Unreachable)
+            | Ok(ClientStatus::TtlExpired(_))
+            | Ok(ClientStatus::Reset)
+            | Err(_) => {
+                server_abort_handle.abort();
+            }
+        });
 
         debug!("Running client and server...");
         match try_join!(client, server)? {
             (ClientStatus::SentCookie(tx), ServerStatus::ReceivedCookie(rx)) => {
                 assert_eq!(rx.cookie, tx.cookie);
-                Ok(ConnEffect::Ok {
+                let effect = ConnEffect::Ok {
                     source_addr: rx.peer_addr,
-                })
+                    target_port: rx.local_port,
+                };
+                Ok((effect, Some(rx)))
+            }
+            (ClientStatus::Refused(icmp), ServerStatus::Aborted) => {
+                Ok((ConnEffect::Refused { icmp }, None))
+            }
+            (ClientStatus::Unreachable, ServerStatus::Aborted) => {
+                Ok((ConnEffect::Unreachable, None))
+            }
+            (ClientStatus::TtlExpired(from), ServerStatus::Aborted) => {
+                Ok((ConnEffect::TtlExpired { from }, None))
+            }
+            // The server may have received the cookie before the client saw the reset
+            (ClientStatus::Reset, _) | (ClientStatus::SentCookie(_), ServerStatus::Reset) => {
+                Ok((ConnEffect::ResetDuringTransfer, None))
             }
-            (ClientStatus::Refused, ServerStatus::Aborted) => Ok(ConnEffect::Refused),
             other => unreachable!("Invalid state: {:?}", other),
         }
     }
 }
 
-struct Tcp {
+struct Tcp<'a> {
     port: u16,
+    probe: &'a Probe,
 }
 
-struct Udp {
+struct Udp<'a> {
     port: u16,
+    probe: &'a Probe,
 }
 
-#[async_trait]
-impl OsNsConnector for Tcp {
-    type ServerSocket = TcpListener;
-
-    async fn bind_server(
-        &self,
-        target: &OsNs,
-        target_addr: IpAddr,
-    ) -> Result<TcpListener, io::Error> {
-        let socket = target.scoped(|| match target_addr {
-            IpAddr::V4(_) => TcpSocket::new_v4(),
-            IpAddr::V6(_) => TcpSocket::new_v6(),
-        })?;
-        socket.bind((target_addr, self.port).into())?;
-        socket.listen(1)
+impl<'a> Tcp<'a> {
+    /// Connect a client socket with the probe's options.
+    async fn connect_stream(&self, path: &OsNsConnPath<'_>) -> Result<TcpStream, io::Error> {
+        let socket = path
+            .source
+            .scoped(|| {
+                self.probe.as_thread_user(|| match path.target_addr {
+                    IpAddr::V4(_) => TcpSocket::new_v4(),
+                    IpAddr::V6(_) => TcpSocket::new_v6(),
+                })
+            })
+            .map_err(socket_error(path.source, "create TCP client socket".into()))?;
+        self.probe.set_client_options(&socket, path)?;
+        if let Some(bytes) = self.probe.window_clamp {
+            let bytes = libc::c_int::try_from(bytes).unwrap_or(libc::c_int::MAX);
+            setsockopt(&socket, libc::IPPROTO_TCP, libc::TCP_WINDOW_CLAMP, bytes)?;
+        }
+        if let Some(algorithm) = &self.probe.congestion {
+            set_congestion(&socket, algorithm)?;
+        }
+        let bind_addr = self.probe.client_bind_addr(path.source_addr);
+        socket
+            .bind(bind_addr)
+            .map_err(client_bind_error(path.source, "TCP".into(), bind_addr))?;
+        socket.connect((path.target_addr, self.port).into()).await
     }
 
-    async fn server(&self, socket: TcpListener) -> Result<ServerStatus, io::Error> {
-        let (mut stream, peer_addr) = socket.accept().await?;
-        debug!("Accepted connection");
-        let cookie = stream.read_u128().await?;
-        debug!("Received cookie {} from {}", cookie, peer_addr);
-        Ok(ServerStatus::ReceivedCookie(ReceivedCookie {
-            cookie,
-            peer_addr: peer_addr.ip(),
-        }))
-    }
+    /// Open a connection, over which exchanges are made one at a time: the client sends a cookie
+    /// and waits for the server to echo it. The server runs as a separate task until the
+    /// connection is dropped.
+    async fn open(&self, path: &OsNsConnPath<'_>) -> Result<OsOpenConnection, io::Error> {
+        let cookie = new_cookie();
+        let listen_addr = (
+            path.target_addr,
+            self.probe.server_port.unwrap_or(self.port),
+        )
+            .into();
+        let listener = self.bind_server(path.target, listen_addr, false).await?;
 
-    async fn client(
-        &self,
-        source: &OsNs,
-        _source_addr: IpAddr,
-        target_addr: IpAddr,
-    ) -> Result<ClientStatus, io::Error> {
-        debug!("Connecting");
-        let socket = source.scoped(|| match target_addr {
-            IpAddr::V4(_) => TcpSocket::new_v4(),
-            IpAddr::V6(_) => TcpSocket::new_v6(),
-        })?;
-        match socket.connect((target_addr, self.port).into()).await {
-            Ok(mut stream) => {
-                debug!("Connected");
-                let cookie: u128 = rand::random();
-                stream.write_u128(cookie).await?;
-                debug!("Sent cookie: {:?}", cookie);
-                Ok(ClientStatus::SentCookie(SentCookie { cookie }))
-            }
-            Err(err) if err.raw_os_error() == Some(libc::ECONNREFUSED) => {
-                debug!("Refused");
-                Ok(ClientStatus::Refused)
+        let (server_abort_handle, server_abort_reg) = AbortHandle::new_pair();
+        let (accepted_tx, accepted_rx) = oneshot::channel();
+        let server = async move {
+            let local_port = listener.local_addr()?.port();
+            loop {
+                let (mut stream, peer_addr) = listener.accept().await?;
+                if stream.read_u128().await? != cookie {
+                    debug!("Ignoring connection from {}", peer_addr);
+                    continue;
+                }
+                // The receiver is only dropped once the client is done
+                let _ = accepted_tx.send((peer_addr.ip(), local_port));
+                let mut received = cookie;
+                loop {
+                    stream.write_u128(received).await?;
+                    received = stream.read_u128().await?;
+                }
             }
-            Err(err) => Err(err),
-        }
-    }
-}
-
-#[async_trait]
-impl OsNsConnector for Udp {
-    type ServerSocket = UdpSocket;
+        };
+        tokio::spawn(Abortable::new(server, server_abort_reg).map(
+            |res: Result<Result<(), io::Error>, Aborted>| {
+                if let Ok(Err(err)) = res {
+                    debug!("Server failed: {}", err);
+                }
+            },
+        ));
 
-    async fn bind_server(
-        &self,
-        target: &OsNs,
-        target_addr: IpAddr,
-    ) -> Result<UdpSocket, io::Error> {
-        target
-            .scoped(|| std::net::UdpSocket::bind((target_addr, self.port)))
-            .and_then(UdpSocket::from_std)
-    }
-
-    async fn server(&self, socket: UdpSocket) -> Result<ServerStatus, io::Error> {
-        let mut buf = 0u128.to_be_bytes();
-        let (size, peer_addr) = socket.recv_from(&mut buf).await?;
-        debug!("Received packet");
-        assert_eq!(size, buf.len());
-        let cookie = u128::from_be_bytes(buf);
-        debug!("Received cookie {} from {}", cookie, peer_addr);
-        Ok(ServerStatus::ReceivedCookie(ReceivedCookie {
+        let stream = match self.with_timeout(self.connect_stream(path)).await {
+            Ok(stream) => Ok(stream),
+            Err(err) => Err(exchange_error_effect(err)?),
+        };
+        Ok(OsOpenConnection {
+            stream,
             cookie,
-            peer_addr: peer_addr.ip(),
-        }))
+            exchanged: 0,
+            accepted: accepted_rx.shared(),
+            timeout: self.probe.timeout,
+            server_abort_handle,
+        })
     }
 
-    async fn client(
+    /// Make the given number of exchanges over a single connection, and report the effect of
+    /// each.
+    ///
+    /// The first exchange also establishes the connection. Once an exchange fails, the
+    /// connection is abandoned, and the remaining exchanges have the same effect.
+    async fn exchanges(
         &self,
-        source: &OsNs,
-        source_addr: IpAddr,
-        target_addr: IpAddr,
-    ) -> Result<ClientStatus, io::Error> {
-        debug!("Connecting");
-        let socket: UdpSocket = source
-            .scoped(|| std::net::UdpSocket::bind((source_addr, 0)))
-            .and_then(UdpSocket::from_std)?;
-        socket.connect((target_addr, self.port)).await?;
-        debug!("Connected");
-        let cookie: u128 = rand::random();
-        match socket.send(&cookie.to_be_bytes()).await {
-            Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
-                debug!("Refused locally");
-                return Ok(ClientStatus::Refused);
-            }
-            other => other?,
-        };
-        debug!("Sent cookie: {:?}", cookie);
-        match socket.take_error()? {
-            None => Ok(ClientStatus::SentCookie(SentCookie { cookie })),
-            Some(err) if err.raw_os_error() == Some(libc::ECONNREFUSED) => {
-                debug!("Refused");
-                Ok(ClientStatus::Refused)
-            }
-            Some(err) => Err(err),
+        path: &OsNsConnPath<'_>,
+        count: usize,
+    ) -> Result<Vec<ConnEffect>, io::Error> {
+        let mut conn = self.open(path).await?;
+        let mut effects = vec![];
+        for i in 0..count {
+            let effect = conn.exchange().await?;
+            debug!("Exchange {} had effect: {:?}", i, effect);
+            effects.push(effect);
         }
+        Ok(effects)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Open a control connection, over which the server announces a passive-mode data port as
+    /// an FTP server would, then a data connection to that port, which carries the cookie, and
+    /// report the effect of each.
+    async fn passive_related(
+        &self,
+        path: &OsNsConnPath<'_>,
+        data_port: u16,
+    ) -> Result<RelatedEffects, io::Error> {
+        let cookie = new_cookie();
+        let control_listener = self
+            .bind_server(path.target, (path.target_addr, self.port).into(), false)
+            .await?;
+        let data_listener = self
+            .bind_server(path.target, (path.target_addr, data_port).into(), false)
+            .await?;
+        let reply = &passive_mode_reply(path.target_addr, data_port);
 
-    use lazy_static::lazy_static;
-    use std::net::{Ipv4Addr, Ipv6Addr};
+        let (server_abort_handle, server_abort_reg) = AbortHandle::new_pair();
+        let (control_tx, control_rx) = oneshot::channel();
+        let (data_tx, data_rx) = oneshot::channel();
+        let server = Abortable::new(
+            async move {
+                let (stream, peer_addr) = control_listener.accept().await?;
+                let mut stream = tokio::io::BufReader::new(stream);
+                let mut command = String::new();
+                stream.read_line(&mut command).await?;
+                debug!("Received {:?} from {}", command.trim_end(), peer_addr);
+                stream.write_all(reply.as_bytes()).await?;
+                let _ = control_tx.send((peer_addr.ip(), self.port));
+                let (mut data_stream, peer_addr) = loop {
+                    let (mut stream, peer_addr) = data_listener.accept().await?;
+                    if stream.read_u128().await? == cookie {
+                        break (stream, peer_addr);
+                    }
+                    debug!("Ignoring connection from {}", peer_addr);
+                };
+                let _ = data_tx.send((peer_addr.ip(), data_port));
+                data_stream.write_u128(cookie).await?;
+                // Hold both connections open until the client is done
+                future::pending().await
+            },
+            server_abort_reg,
+        )
+        .unwrap_or_else(|_: Aborted| Ok::<(), io::Error>(()));
+
+        let client = async {
+            let control = async {
+                let mut stream = self.connect_stream(path).await?;
+                let command = match path.target_addr {
+                    IpAddr::V4(_) => "PASV\r\n",
+                    IpAddr::V6(_) => "EPSV\r\n",
+                };
+                stream.write_all(command.as_bytes()).await?;
+                let mut stream = tokio::io::BufReader::new(stream);
+                let mut line = String::new();
+                stream.read_line(&mut line).await?;
+                if line != *reply {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unexpected reply {:?}", line),
+                    ));
+                }
+                Ok(stream)
+            };
+            let _control_stream = match self.with_timeout(control).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    return Ok(RelatedEffects {
+                        control: exchange_error_effect(err)?,
+                        data: None,
+                    })
+                }
+            };
+            let (source_addr, target_port) = control_rx.await.map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The server replied without accepting",
+                )
+            })?;
+            let control = ConnEffect::Ok {
+                source_addr,
+                target_port,
+            };
+            let data_connector = Tcp {
+                port: data_port,
+                probe: self.probe,
+            };
+            let data = async {
+                let mut stream = data_connector.connect_stream(path).await?;
+                stream.write_u128(cookie).await?;
+                let echoed = stream.read_u128().await?;
+                check_echo(cookie, echoed)
+            };
+            let data = match self.with_timeout(data).await {
+                Ok(()) => {
+                    let (source_addr, target_port) = data_rx.await.map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "The server echoed without accepting",
+                        )
+                    })?;
+                    ConnEffect::Ok {
+                        source_addr,
+                        target_port,
+                    }
+                }
+                Err(err) => exchange_error_effect(err)?,
+            };
+            Ok(RelatedEffects {
+                control,
+                data: Some(data),
+            })
+        }
+        .inspect(|_| server_abort_handle.abort());
+
+        Ok(try_join!(client, server)?.0)
+    }
+
+    /// Like `passive_related`, but the client announces a data port of its own, as an FTP client
+    /// in active mode would, and the server opens the data connection to it, which carries the
+    /// cookie.
+    async fn active_related(
+        &self,
+        path: &OsNsConnPath<'_>,
+        data_port: u16,
+    ) -> Result<RelatedEffects, io::Error> {
+        let cookie = new_cookie();
+        let control_listener = self
+            .bind_server(path.target, (path.target_addr, self.port).into(), false)
+            .await?;
+        let data_listener = self
+            .bind_server(path.source, (path.source_addr, data_port).into(), false)
+            .await?;
+        let command = &active_mode_command(path.source_addr, data_port);
+
+        let (server_abort_handle, server_abort_reg) = AbortHandle::new_pair();
+        let (control_tx, control_rx) = oneshot::channel();
+        let (data_tx, data_rx) = oneshot::channel();
+        let server = Abortable::new(
+            async move {
+                let (stream, peer_addr) = control_listener.accept().await?;
+                let mut stream = tokio::io::BufReader::new(stream);
+                let mut line = String::new();
+                stream.read_line(&mut line).await?;
+                debug!("Received {:?} from {}", line.trim_end(), peer_addr);
+                // Connect to the address as received, which a NAT helper may have rewritten
+                let data_addr = parse_active_mode_command(&line).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unexpected command {:?}", line),
+                    )
+                })?;
+                stream.write_all(b"200 Command okay.\r\n").await?;
+                let _ = control_tx.send((peer_addr.ip(), self.port));
+                let data = async {
+                    let socket = path
+                        .target
+                        .scoped(|| match data_addr {
+                            SocketAddr::V4(_) => TcpSocket::new_v4(),
+                            SocketAddr::V6(_) => TcpSocket::new_v6(),
+                        })
+                        .map_err(socket_error(path.target, "create TCP data socket".into()))?;
+                    socket
+                        .bind((path.target_addr, 0).into())
+                        .map_err(socket_error(
+                            path.target,
+                            format!("bind TCP data socket to {}", path.target_addr),
+                        ))?;
+                    let mut stream = socket.connect(data_addr).await?;
+                    stream.write_u128(cookie).await?;
+                    let echoed = stream.read_u128().await?;
+                    check_echo(cookie, echoed)
+                };
+                let _ = data_tx.send(data.await);
+                // Hold the control connection open until the client is done
+                future::pending().await
+            },
+            server_abort_reg,
+        )
+        .unwrap_or_else(|_: Aborted| Ok::<(), io::Error>(()));
+
+        let client = async {
+            let control = async {
+                let mut stream = self.connect_stream(path).await?;
+                stream.write_all(command.as_bytes()).await?;
+                let mut stream = tokio::io::BufReader::new(stream);
+                let mut line = String::new();
+                stream.read_line(&mut line).await?;
+                if !line.starts_with("200 ") {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unexpected reply {:?}", line),
+                    ));
+                }
+                Ok(stream)
+            };
+            let _control_stream = match self.with_timeout(control).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    return Ok(RelatedEffects {
+                        control: exchange_error_effect(err)?,
+                        data: None,
+                    })
+                }
+            };
+            let (source_addr, target_port) = control_rx.await.map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The server replied without accepting",
+                )
+            })?;
+            let control = ConnEffect::Ok {
+                source_addr,
+                target_port,
+            };
+            let data = async {
+                let accepted = async {
+                    loop {
+                        let (mut stream, peer_addr) = data_listener.accept().await?;
+                        if stream.read_u128().await? == cookie {
+                            stream.write_u128(cookie).await?;
+                            return Ok::<_, io::Error>(peer_addr.ip());
+                        }
+                        debug!("Ignoring connection from {}", peer_addr);
+                    }
+                };
+                let connected = data_rx.map(|res| {
+                    res.unwrap_or_else(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "The server finished without connecting",
+                        ))
+                    })
+                });
+                Ok(try_join!(accepted, connected)?.0)
+            };
+            let data = match self.with_timeout(data).await {
+                Ok(source_addr) => ConnEffect::Ok {
+                    source_addr,
+                    target_port: data_port,
+                },
+                Err(err) => exchange_error_effect(err)?,
+            };
+            Ok(RelatedEffects {
+                control,
+                data: Some(data),
+            })
+        }
+        .inspect(|_| server_abort_handle.abort());
+
+        Ok(try_join!(client, server)?.0)
+    }
+
+    /// Run the future, failing with `ETIMEDOUT` after the probe's timeout, if any.
+    async fn with_timeout<F, T>(&self, f: F) -> Result<T, io::Error>
+    where
+        F: Future<Output = Result<T, io::Error>>,
+    {
+        with_timeout(self.probe.timeout, f).await
+    }
+}
+
+/// A connection opened by `Tcp::open`, whose server is aborted when it is dropped.
+struct OsOpenConnection {
+    /// The connected stream, or the effect of the exchange that failed.
+    stream: Result<TcpStream, ConnEffect>,
+    cookie: u128,
+    exchanged: u128,
+    accepted: future::Shared<oneshot::Receiver<(IpAddr, u16)>>,
+    timeout: Option<Duration>,
+    server_abort_handle: AbortHandle,
+}
+
+#[async_trait]
+impl OpenConnection for OsOpenConnection {
+    async fn exchange(&mut self) -> Result<ConnEffect, io::Error> {
+        let stream = match &mut self.stream {
+            Ok(stream) => stream,
+            Err(effect) => return Ok(*effect),
+        };
+        let sent = self.cookie.wrapping_add(self.exchanged);
+        self.exchanged += 1;
+        let exchange = async {
+            stream.write_u128(sent).await?;
+            let echoed = stream.read_u128().await?;
+            check_echo(sent, echoed)
+        };
+        let effect = match with_timeout(self.timeout, exchange).await {
+            Ok(()) => {
+                let (source_addr, target_port) = self.accepted.clone().await.map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "The server echoed without accepting",
+                    )
+                })?;
+                ConnEffect::Ok {
+                    source_addr,
+                    target_port,
+                }
+            }
+            Err(err) => exchange_error_effect(err)?,
+        };
+        if !matches!(effect, ConnEffect::Ok { .. }) {
+            self.stream = Err(effect);
+        }
+        Ok(effect)
+    }
+}
+
+impl Drop for OsOpenConnection {
+    fn drop(&mut self) {
+        self.server_abort_handle.abort();
+    }
+}
+
+/// Run the future, failing with `ETIMEDOUT` after the given timeout, if any.
+async fn with_timeout<F, T>(duration: Option<Duration>, f: F) -> Result<T, io::Error>
+where
+    F: Future<Output = Result<T, io::Error>>,
+{
+    match duration {
+        Some(duration) => timeout(duration, f)
+            .await
+            .unwrap_or_else(|Elapsed { .. }| Err(io::Error::from_raw_os_error(libc::ETIMEDOUT))),
+        None => f.await,
+    }
+}
+
+struct Ip<'a> {
+    protocol: u8,
+    probe: &'a Probe,
+}
+
+/// An ICMP message, or an ICMPv6 message to an IPv6 address. The target's kernel answers an echo
+/// request, so the server only observes it with a raw socket, and the client waits for the reply.
+struct Icmp<'a> {
+    kind: IcmpKind,
+    probe: &'a Probe,
+}
+
+/// A TCP connection that is never established: the client sends a bare SYN, with the cookie as
+/// its sequence number, and the reply is a SYN-ACK if the server's listener is reachable.
+struct TcpSyn<'a> {
+    port: u16,
+    probe: &'a Probe,
+}
+
+/// A TCP segment outside any connection: the client sends a lone FIN-ACK, with the cookie as its
+/// sequence number, and the server observes it with a raw socket. With no connection to belong
+/// to, conntrack classifies it as invalid, even if it allows picking up connections midstream.
+struct TcpOutOfState<'a> {
+    port: u16,
+    probe: &'a Probe,
+}
+
+#[async_trait]
+impl<'a> OsNsConnector for Tcp<'a> {
+    type ServerSocket = TcpListener;
+
+    fn port(&self) -> u16 {
+        self.port
+    }
+
+    fn probe(&self) -> &Probe {
+        self.probe
+    }
+
+    async fn bind_server(
+        &self,
+        target: &OsNs,
+        listen_addr: SocketAddr,
+        transparent: bool,
+    ) -> Result<TcpListener, io::Error> {
+        let socket = target
+            .scoped(|| match listen_addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4(),
+                SocketAddr::V6(_) => TcpSocket::new_v6(),
+            })
+            .map_err(socket_error(target, "create TCP server socket".into()))?;
+        if transparent {
+            set_transparent(&socket, listen_addr.ip())?;
+        }
+        if self.probe.reuseport_listeners.is_some() {
+            set_reuseport(&socket)?;
+        }
+        socket.bind(listen_addr).map_err(socket_error(
+            target,
+            format!("bind TCP server socket to {}", listen_addr),
+        ))?;
+        socket
+            .listen(1)
+            .map_err(socket_error(target, format!("listen on {}", listen_addr)))
+    }
+
+    async fn server(&self, socket: TcpListener, cookie: u128) -> Result<ServerStatus, io::Error> {
+        let local_port = socket.local_addr()?.port();
+        loop {
+            let (mut stream, peer_addr) = socket.accept().await?;
+            debug!("Accepted connection");
+            let received = match stream.read_u128().await {
+                Ok(received) => received,
+                Err(err) if err.raw_os_error() == Some(libc::ECONNRESET) => {
+                    debug!("Reset");
+                    return Ok(ServerStatus::Reset);
+                }
+                Err(err) => return Err(err),
+            };
+            if received != cookie {
+                debug!("Ignoring cookie {} from {}", received, peer_addr);
+                continue;
+            }
+            debug!("Received cookie {} from {}", cookie, peer_addr);
+            if let Some(len) = self.probe.payload_len {
+                // The padding must arrive in full, e.g. so that a path MTU black hole is detected
+                let mut padding = vec![0u8; len - COOKIE_LEN];
+                match stream.read_exact(&mut padding).await {
+                    Ok(_) => debug!("Received padding"),
+                    Err(err) if err.raw_os_error() == Some(libc::ECONNRESET) => {
+                        debug!("Reset");
+                        return Ok(ServerStatus::Reset);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            if self.probe.bidirectional {
+                stream.write_u128(cookie).await?;
+                debug!("Echoed cookie");
+            }
+            return Ok(ServerStatus::ReceivedCookie(ReceivedCookie {
+                cookie,
+                peer_addr: peer_addr.ip(),
+                local_port,
+                dscp: None,
+                listener: None,
+            }));
+        }
+    }
+
+    async fn client(
+        &self,
+        path: &OsNsConnPath<'_>,
+        cookie: u128,
+    ) -> Result<ClientStatus, io::Error> {
+        debug!("Connecting");
+        match self.connect_stream(path).await {
+            Ok(mut stream) => {
+                debug!("Connected");
+                let transfer = async {
+                    stream.write_u128(cookie).await?;
+                    debug!("Sent cookie: {:?}", cookie);
+                    if let Some(len) = self.probe.payload_len {
+                        stream.write_all(&vec![0u8; len - COOKIE_LEN]).await?;
+                        debug!("Sent padding");
+                    }
+                    if self.probe.bidirectional {
+                        check_echo(cookie, stream.read_u128().await?)?;
+                        debug!("Received echo");
+                    } else if !self.probe.external_server {
+                        // Wait for the server to close the connection, in case the cookie is
+                        // answered with a reset
+                        if stream.read(&mut [0u8; 1]).await? == 0 {
+                            debug!("Closed");
+                        }
+                    }
+                    Ok::<(), io::Error>(())
+                };
+                match transfer.await {
+                    Ok(()) => Ok(ClientStatus::SentCookie(SentCookie { cookie })),
+                    Err(err) if err.raw_os_error() == Some(libc::ECONNRESET) => {
+                        debug!("Reset");
+                        Ok(ClientStatus::Reset)
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            Err(err) if err.raw_os_error() == Some(libc::ECONNREFUSED) => {
+                debug!("Refused");
+                Ok(ClientStatus::Refused(None))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> OsNsConnector for Udp<'a> {
+    type ServerSocket = AsyncFd<std::net::UdpSocket>;
+
+    fn port(&self) -> u16 {
+        self.port
+    }
+
+    fn probe(&self) -> &Probe {
+        self.probe
+    }
+
+    async fn bind_server(
+        &self,
+        target: &OsNs,
+        listen_addr: SocketAddr,
+        transparent: bool,
+    ) -> Result<Self::ServerSocket, io::Error> {
+        let reuseport = self.probe.reuseport_listeners.is_some();
+        let socket = target
+            .scoped(|| {
+                if transparent || reuseport {
+                    // The socket options must be set before the socket is bound
+                    let socket = new_udp_socket(listen_addr.ip())?;
+                    if transparent {
+                        set_transparent(&socket, listen_addr.ip())?;
+                    }
+                    if reuseport {
+                        set_reuseport(&socket)?;
+                    }
+                    bind(&socket, listen_addr)?;
+                    Ok(socket)
+                } else {
+                    std::net::UdpSocket::bind(listen_addr)
+                }
+            })
+            .map_err(socket_error(
+                target,
+                format!("bind UDP server socket to {}", listen_addr),
+            ))?;
+        set_recv_tos(&socket, listen_addr.ip())?;
+        socket.set_nonblocking(true)?;
+        AsyncFd::new(socket)
+    }
+
+    async fn server(
+        &self,
+        socket: Self::ServerSocket,
+        cookie: u128,
+    ) -> Result<ServerStatus, io::Error> {
+        let local_port = socket.get_ref().local_addr()?.port();
+        // Large enough for any reassembled datagram
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (size, peer_addr, tos) = socket
+                .readable()
+                .await?
+                .with_io(|| recv_with_tos(socket.get_ref(), &mut buf))?;
+            debug!("Received packet");
+            if parse_cookie(&buf[..size]) != Some(cookie) {
+                debug!("Ignoring packet of size {} from {}", size, peer_addr);
+                continue;
+            }
+            debug!("Received cookie {} from {}", cookie, peer_addr);
+            return Ok(ServerStatus::ReceivedCookie(ReceivedCookie {
+                cookie,
+                peer_addr,
+                local_port,
+                dscp: tos.map(|tos| tos >> 2),
+                listener: None,
+            }));
+        }
+    }
+
+    async fn client(
+        &self,
+        path: &OsNsConnPath<'_>,
+        cookie: u128,
+    ) -> Result<ClientStatus, io::Error> {
+        debug!("Connecting");
+        let bind_addr = self.probe.client_bind_addr(path.source_addr);
+        let socket: UdpSocket = path
+            .source
+            .scoped(|| {
+                self.probe
+                    .as_thread_user(|| std::net::UdpSocket::bind(bind_addr))
+                    .map_err(client_bind_error(path.source, "UDP".into(), bind_addr))
+            })
+            .and_then(UdpSocket::from_std)?;
+        self.probe.set_client_options(&socket, path)?;
+        set_recv_err(&socket, path.target_addr)?;
+        let mut payload = cookie.to_be_bytes().to_vec();
+        if let Some(len) = self.probe.payload_len {
+            payload.resize(len, 0);
+        }
+        let dont_fragment = match self.probe.dont_fragment {
+            Some(enabled) => Some(enabled),
+            // Fragment rather than fail with `EMSGSIZE`, as IPv6 does by default
+            None if self.probe.payload_len.is_some() && path.target_addr.is_ipv4() => Some(false),
+            None => None,
+        };
+        if let Some(enabled) = dont_fragment {
+            set_dont_fragment(&socket, path.target_addr, enabled)?;
+        }
+        if self.probe.broadcast.is_some() {
+            setsockopt(
+                &socket,
+                libc::SOL_SOCKET,
+                libc::SO_BROADCAST,
+                1 as libc::c_int,
+            )?;
+        }
+        socket
+            .connect((self.probe.target_addr(path), self.port))
+            .await?;
+        debug!("Connected");
+        match socket.send(&payload).await {
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+                debug!("Refused locally");
+                return Ok(ClientStatus::Refused(None));
+            }
+            Err(err) if err.raw_os_error() == Some(libc::EMSGSIZE) => {
+                debug!("Too large to send without fragmenting");
+                return Ok(ClientStatus::Unreachable);
+            }
+            other => other?,
+        };
+        debug!("Sent cookie: {:?}", cookie);
+        match socket.take_error()? {
+            None => Ok(ClientStatus::SentCookie(SentCookie { cookie })),
+            Some(err) if err.raw_os_error() == Some(libc::ECONNREFUSED) => {
+                if self.probe.udp_refused_as_unreachable {
+                    debug!("Refused, treating as unreachable");
+                    Ok(ClientStatus::Unreachable)
+                } else {
+                    debug!("Refused");
+                    Ok(ClientStatus::Refused(recv_icmp_error(&socket)?))
+                }
+            }
+            Some(err) => ttl_expired_status(&socket, err),
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> OsNsConnector for Ip<'a> {
+    type ServerSocket = AsyncFd<RawSocket>;
+
+    fn port(&self) -> u16 {
+        0
+    }
+
+    fn probe(&self) -> &Probe {
+        self.probe
+    }
+
+    async fn bind_server(
+        &self,
+        target: &OsNs,
+        listen_addr: SocketAddr,
+        transparent: bool,
+    ) -> Result<AsyncFd<RawSocket>, io::Error> {
+        let socket = target
+            .scoped(|| {
+                let socket = RawSocket::new(listen_addr.ip(), self.protocol)?;
+                if transparent {
+                    set_transparent(&socket, listen_addr.ip())?;
+                }
+                bind(&socket, listen_addr)?;
+                Ok(socket)
+            })
+            .map_err(socket_error(
+                target,
+                format!(
+                    "bind IP protocol {} server socket to {}",
+                    self.protocol, listen_addr
+                ),
+            ))?;
+        AsyncFd::new(socket)
+    }
+
+    async fn server(
+        &self,
+        socket: AsyncFd<RawSocket>,
+        cookie: u128,
+    ) -> Result<ServerStatus, io::Error> {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (size, peer_addr) = socket
+                .readable()
+                .await?
+                .with_io(|| socket.get_ref().recv_from(&mut buf))?;
+            debug!("Received packet");
+            let payload = ip_payload(&buf[..size], peer_addr);
+            // Any packet with the protocol is received, not only those sent to this socket
+            if parse_cookie(payload) != Some(cookie) {
+                debug!(
+                    "Ignoring packet of size {} from {}",
+                    payload.len(),
+                    peer_addr
+                );
+                continue;
+            }
+            debug!("Received cookie {} from {}", cookie, peer_addr);
+            return Ok(ServerStatus::ReceivedCookie(ReceivedCookie {
+                cookie,
+                peer_addr,
+                local_port: 0,
+                dscp: None,
+                listener: None,
+            }));
+        }
+    }
+
+    async fn client(
+        &self,
+        path: &OsNsConnPath<'_>,
+        cookie: u128,
+    ) -> Result<ClientStatus, io::Error> {
+        debug!("Connecting");
+        let bind_addr = self.probe.client_bind_addr(path.source_addr);
+        let socket = path.source.scoped(|| {
+            let socket = self
+                .probe
+                .as_thread_user(|| RawSocket::new(path.target_addr, self.protocol))
+                .map_err(socket_error(
+                    path.source,
+                    format!("create IP protocol {} client socket", self.protocol),
+                ))?;
+            bind(&socket, bind_addr).map_err(client_bind_error(
+                path.source,
+                format!("IP protocol {}", self.protocol),
+                bind_addr,
+            ))?;
+            Ok(socket)
+        })?;
+        self.probe.set_client_options(&socket, path)?;
+        set_recv_err(&socket, path.target_addr)?;
+        connect(&socket, (path.target_addr, 0).into())?;
+        debug!("Connected");
+        match socket.send(&cookie.to_be_bytes()) {
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+                debug!("Refused locally");
+                return Ok(ClientStatus::Refused(None));
+            }
+            other => other?,
+        };
+        debug!("Sent cookie: {:?}", cookie);
+        match socket.take_error()? {
+            None => Ok(ClientStatus::SentCookie(SentCookie { cookie })),
+            // An ICMP port unreachable or protocol unreachable, respectively
+            Some(err)
+                if err.raw_os_error() == Some(libc::ECONNREFUSED)
+                    || err.raw_os_error() == Some(libc::ENOPROTOOPT) =>
+            {
+                debug!("Refused");
+                Ok(ClientStatus::Refused(recv_icmp_error(&socket)?))
+            }
+            Some(err) => ttl_expired_status(&socket, err),
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> OsNsConnector for TcpSyn<'a> {
+    type ServerSocket = (TcpListener, AsyncFd<RawSocket>);
+
+    fn port(&self) -> u16 {
+        self.port
+    }
+
+    fn probe(&self) -> &Probe {
+        self.probe
+    }
+
+    async fn bind_server(
+        &self,
+        target: &OsNs,
+        listen_addr: SocketAddr,
+        transparent: bool,
+    ) -> Result<Self::ServerSocket, io::Error> {
+        // The listener answers the SYN, and the raw socket observes it
+        let listener = Tcp {
+            port: self.port,
+            probe: self.probe,
+        }
+        .bind_server(target, listen_addr, transparent)
+        .await?;
+        let socket = Ip {
+            protocol: libc::IPPROTO_TCP as u8,
+            probe: self.probe,
+        }
+        .bind_server(target, listen_addr, transparent)
+        .await?;
+        Ok((listener, socket))
+    }
+
+    async fn server(
+        &self,
+        (listener, socket): Self::ServerSocket,
+        cookie: u128,
+    ) -> Result<ServerStatus, io::Error> {
+        let local_port = listener.local_addr()?.port();
+        let mut buf = [0u8; 1500];
+        loop {
+            let (size, peer_addr) = socket
+                .readable()
+                .await?
+                .with_io(|| socket.get_ref().recv_from(&mut buf))?;
+            // Any TCP segment is received, including those for other ports
+            match parse_tcp_header(ip_payload(&buf[..size], peer_addr)) {
+                Some(header)
+                    if header.flags & (TCP_SYN | TCP_ACK) == TCP_SYN
+                        && header.target_port == local_port
+                        && header.seq == cookie as u32 =>
+                {
+                    debug!("Received SYN with cookie {} from {}", cookie, peer_addr);
+                    return Ok(ServerStatus::ReceivedCookie(ReceivedCookie {
+                        cookie,
+                        peer_addr,
+                        local_port,
+                        dscp: None,
+                        listener: None,
+                    }));
+                }
+                _ => debug!("Ignoring TCP segment from {}", peer_addr),
+            }
+        }
+    }
+
+    async fn client(
+        &self,
+        path: &OsNsConnPath<'_>,
+        cookie: u128,
+    ) -> Result<ClientStatus, io::Error> {
+        debug!("Connecting");
+        let reserved = self.reserve_source_port(path)?;
+        let source_port = reserved.local_addr()?.port();
+        let socket = self.raw_socket(path)?;
+        debug!("Connected");
+
+        let seq = cookie as u32;
+        let syn = syn_segment(
+            (path.source_addr, source_port).into(),
+            (path.target_addr, self.port).into(),
+            seq,
+            self.window(),
+        );
+        match socket.get_ref().send(&syn) {
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+                debug!("Refused locally");
+                return Ok(ClientStatus::Refused(None));
+            }
+            other => other?,
+        };
+        debug!("Sent SYN with cookie: {:?}", cookie);
+
+        let reply = async {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (size, peer_addr) = match timeout(ERROR_POLL_INTERVAL, socket.readable()).await
+                {
+                    Ok(guard) => guard?.with_io(|| socket.get_ref().recv_from(&mut buf))?,
+                    Err(Elapsed { .. }) => match socket.get_ref().take_error()? {
+                        Some(err) => return Err(err),
+                        None => continue,
+                    },
+                };
+                match parse_tcp_header(ip_payload(&buf[..size], peer_addr)) {
+                    Some(header)
+                        if header.source_port == self.port
+                            && header.target_port == source_port
+                            && header.ack == seq.wrapping_add(1) =>
+                    {
+                        return Ok(header.flags)
+                    }
+                    _ => debug!("Ignoring TCP segment from {}", peer_addr),
+                }
+            }
+        };
+        match reply.await {
+            Ok(flags) if flags & TCP_RST != 0 => {
+                debug!("Refused");
+                Ok(ClientStatus::Refused(None))
+            }
+            Ok(flags) if flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK => {
+                debug!("Received SYN-ACK");
+                Ok(ClientStatus::SentCookie(SentCookie { cookie }))
+            }
+            Ok(flags) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected reply to SYN with flags {:#04x}", flags),
+            )),
+            // An ICMP port unreachable
+            Err(err) if err.raw_os_error() == Some(libc::ECONNREFUSED) => {
+                debug!("Refused");
+                Ok(ClientStatus::Refused(None))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<'a> TcpSyn<'a> {
+    /// Reserve a source port with a listener, which resets the SYN-ACK since it does not match a
+    /// connection, and so the target does not keep the connection half-open.
+    fn reserve_source_port(
+        &self,
+        path: &OsNsConnPath<'_>,
+    ) -> Result<std::net::TcpListener, io::Error> {
+        let bind_addr = self.probe.client_bind_addr(path.source_addr);
+        path.source.scoped(|| {
+            self.probe
+                .as_thread_user(|| std::net::TcpListener::bind(bind_addr))
+                .map_err(client_bind_error(path.source, "TCP".into(), bind_addr))
+        })
+    }
+
+    /// A raw socket from the source to the target, to send SYNs and receive the replies.
+    fn raw_socket(&self, path: &OsNsConnPath<'_>) -> Result<AsyncFd<RawSocket>, io::Error> {
+        let socket = path.source.scoped(|| {
+            let socket = self
+                .probe
+                .as_thread_user(|| RawSocket::new(path.target_addr, libc::IPPROTO_TCP as u8))
+                .map_err(socket_error(
+                    path.source,
+                    "create raw TCP client socket".into(),
+                ))?;
+            let bind_addr = (path.source_addr, 0).into();
+            bind(&socket, bind_addr).map_err(client_bind_error(
+                path.source,
+                "raw TCP".into(),
+                bind_addr,
+            ))?;
+            Ok(socket)
+        })?;
+        self.probe.set_client_options(&socket, path)?;
+        connect(&socket, (path.target_addr, 0).into())?;
+        AsyncFd::new(socket)
+    }
+
+    fn window(&self) -> u16 {
+        self.probe
+            .window_clamp
+            .map_or(u16::MAX, |bytes| u16::try_from(bytes).unwrap_or(u16::MAX))
+    }
+
+    /// Send `count` SYNs at `rate` per second, each from its own source port, then wait for the
+    /// probe's timeout, and return how each was answered.
+    ///
+    /// Only the replies are observed, so the source address of an `Ok` is that of the path.
+    async fn flood(
+        &self,
+        path: &OsNsConnPath<'_>,
+        count: usize,
+        rate: u32,
+        wait: Duration,
+    ) -> Result<Vec<ConnEffect>, io::Error> {
+        // The listener answers the SYNs, with a backlog that holds all of them
+        let listen_addr = SocketAddr::from((
+            path.target_addr,
+            self.probe.server_port.unwrap_or(self.port),
+        ));
+        let listener = path
+            .target
+            .scoped(|| match listen_addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4(),
+                SocketAddr::V6(_) => TcpSocket::new_v6(),
+            })
+            .map_err(socket_error(path.target, "create TCP server socket".into()))?;
+        listener.bind(listen_addr).map_err(socket_error(
+            path.target,
+            format!("bind TCP server socket to {}", listen_addr),
+        ))?;
+        let _listener = listener.listen(u32::try_from(count).unwrap_or(u32::MAX))?;
+
+        let reserved = (0..count)
+            .map(|_| self.reserve_source_port(path))
+            .collect::<Result<Vec<_>, _>>()?;
+        let source_ports = reserved
+            .iter()
+            .map(|listener| Ok(listener.local_addr()?.port()))
+            .collect::<Result<Vec<_>, io::Error>>()?;
+        let socket = self.raw_socket(path)?;
+
+        let first_seq = new_cookie() as u32;
+        let interval = Duration::from_secs(1) / rate;
+        let start = Instant::now();
+        let mut effects = vec![None; count];
+        let mut sent = 0;
+        let mut buf = [0u8; 1500];
+        loop {
+            let send_at = start + interval * sent as u32;
+            let now = Instant::now();
+            if sent < count && now >= send_at {
+                let syn = syn_segment(
+                    (path.source_addr, source_ports[sent]).into(),
+                    (path.target_addr, self.port).into(),
+                    first_seq.wrapping_add(sent as u32),
+                    self.window(),
+                );
+                match socket.get_ref().send(&syn) {
+                    Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+                        effects[sent] = Some(ConnEffect::Refused { icmp: None });
+                    }
+                    other => {
+                        other?;
+                    }
+                }
+                sent += 1;
+                continue;
+            }
+            let wake_at = match sent < count {
+                true => send_at,
+                false => start + interval * (count - 1) as u32 + wait,
+            };
+            if sent == count && now >= wake_at {
+                break;
+            }
+            let (size, peer_addr) = match timeout_at(wake_at, socket.readable()).await {
+                Ok(guard) => guard?.with_io(|| socket.get_ref().recv_from(&mut buf))?,
+                Err(Elapsed { .. }) => continue,
+            };
+            let header = match parse_tcp_header(ip_payload(&buf[..size], peer_addr)) {
+                Some(header) if header.source_port == self.port => header,
+                _ => continue,
+            };
+            let i = match source_ports
+                .iter()
+                .position(|&port| port == header.target_port)
+            {
+                Some(i) if header.ack == first_seq.wrapping_add(i as u32 + 1) => i,
+                _ => continue,
+            };
+            let effect = if header.flags & TCP_RST != 0 {
+                ConnEffect::Refused { icmp: None }
+            } else if header.flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK {
+                ConnEffect::Ok {
+                    source_addr: path.source_addr,
+                    target_port: self.port,
+                }
+            } else {
+                continue;
+            };
+            effects[i].get_or_insert(effect);
+        }
+        Ok(effects
+            .into_iter()
+            .map(|effect| effect.unwrap_or(ConnEffect::Unreachable))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl<'a> OsNsConnector for TcpOutOfState<'a> {
+    type ServerSocket = (u16, AsyncFd<RawSocket>);
+
+    fn port(&self) -> u16 {
+        self.port
+    }
+
+    fn probe(&self) -> &Probe {
+        self.probe
+    }
+
+    async fn bind_server(
+        &self,
+        target: &OsNs,
+        listen_addr: SocketAddr,
+        transparent: bool,
+    ) -> Result<Self::ServerSocket, io::Error> {
+        // No listener is needed, since the target resets the segment either way
+        let socket = Ip {
+            protocol: libc::IPPROTO_TCP as u8,
+            probe: self.probe,
+        }
+        .bind_server(target, listen_addr, transparent)
+        .await?;
+        Ok((listen_addr.port(), socket))
+    }
+
+    async fn server(
+        &self,
+        (local_port, socket): Self::ServerSocket,
+        cookie: u128,
+    ) -> Result<ServerStatus, io::Error> {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (size, peer_addr) = socket
+                .readable()
+                .await?
+                .with_io(|| socket.get_ref().recv_from(&mut buf))?;
+            match parse_tcp_header(ip_payload(&buf[..size], peer_addr)) {
+                Some(header)
+                    if header.flags & (TCP_SYN | TCP_RST | TCP_FIN | TCP_ACK)
+                        == TCP_FIN | TCP_ACK
+                        && header.target_port == local_port
+                        && header.seq == cookie as u32 =>
+                {
+                    debug!("Received FIN-ACK with cookie {} from {}", cookie, peer_addr);
+                    return Ok(ServerStatus::ReceivedCookie(ReceivedCookie {
+                        cookie,
+                        peer_addr,
+                        local_port,
+                        dscp: None,
+                        listener: None,
+                    }));
+                }
+                _ => debug!("Ignoring TCP segment from {}", peer_addr),
+            }
+        }
+    }
+
+    async fn client(
+        &self,
+        path: &OsNsConnPath<'_>,
+        cookie: u128,
+    ) -> Result<ClientStatus, io::Error> {
+        debug!("Connecting");
+        let syn = TcpSyn {
+            port: self.port,
+            probe: self.probe,
+        };
+        let reserved = syn.reserve_source_port(path)?;
+        let source_port = reserved.local_addr()?.port();
+        let socket = syn.raw_socket(path)?;
+        debug!("Connected");
+
+        let segment = tcp_segment(
+            (path.source_addr, source_port).into(),
+            (path.target_addr, self.port).into(),
+            TCP_FIN | TCP_ACK,
+            cookie as u32,
+            new_cookie() as u32,
+            syn.window(),
+        );
+        match socket.get_ref().send(&segment) {
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+                debug!("Refused locally");
+                return Ok(ClientStatus::Refused(None));
+            }
+            other => other?,
+        };
+        debug!("Sent FIN-ACK with cookie: {:?}", cookie);
+        Ok(ClientStatus::SentCookie(SentCookie { cookie }))
+    }
+}
+
+#[async_trait]
+impl<'a> OsNsConnector for Icmp<'a> {
+    type ServerSocket = AsyncFd<RawSocket>;
+
+    fn port(&self) -> u16 {
+        0
+    }
+
+    fn probe(&self) -> &Probe {
+        self.probe
+    }
+
+    async fn bind_server(
+        &self,
+        target: &OsNs,
+        listen_addr: SocketAddr,
+        transparent: bool,
+    ) -> Result<AsyncFd<RawSocket>, io::Error> {
+        Ip {
+            protocol: icmp_protocol(listen_addr.ip()),
+            probe: self.probe,
+        }
+        .bind_server(target, listen_addr, transparent)
+        .await
+    }
+
+    async fn server(
+        &self,
+        socket: AsyncFd<RawSocket>,
+        cookie: u128,
+    ) -> Result<ServerStatus, io::Error> {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (size, peer_addr) = socket
+                .readable()
+                .await?
+                .with_io(|| socket.get_ref().recv_from(&mut buf))?;
+            // Any ICMP message is received, including the replies to other probes
+            match parse_echo(ip_payload(&buf[..size], peer_addr)) {
+                Some(echo)
+                    if echo.icmp_type == echo_request_type(peer_addr) && echo.cookie == cookie =>
+                {
+                    debug!(
+                        "Received echo request with cookie {} from {}",
+                        cookie, peer_addr
+                    );
+                    return Ok(ServerStatus::ReceivedCookie(ReceivedCookie {
+                        cookie,
+                        peer_addr,
+                        local_port: 0,
+                        dscp: None,
+                        listener: None,
+                    }));
+                }
+                _ => debug!("Ignoring ICMP message from {}", peer_addr),
+            }
+        }
+    }
+
+    async fn client(
+        &self,
+        path: &OsNsConnPath<'_>,
+        cookie: u128,
+    ) -> Result<ClientStatus, io::Error> {
+        debug!("Connecting");
+        let protocol = icmp_protocol(path.target_addr);
+        let bind_addr = self.probe.client_bind_addr(path.source_addr);
+        let socket = path.source.scoped(|| {
+            let socket = self
+                .probe
+                .as_thread_user(|| RawSocket::new(path.target_addr, protocol))
+                .map_err(socket_error(
+                    path.source,
+                    "create ICMP client socket".into(),
+                ))?;
+            bind(&socket, bind_addr).map_err(client_bind_error(
+                path.source,
+                "ICMP".into(),
+                bind_addr,
+            ))?;
+            Ok(socket)
+        })?;
+        self.probe.set_client_options(&socket, path)?;
+        set_recv_err(&socket, path.target_addr)?;
+        connect(&socket, (path.target_addr, 0).into())?;
+        let socket = AsyncFd::new(socket)?;
+        debug!("Connected");
+
+        let message = match self.kind {
+            IcmpKind::Echo => echo_request(path.target_addr, cookie),
+        };
+        match socket.get_ref().send(&message) {
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+                debug!("Refused locally");
+                return Ok(ClientStatus::Refused(None));
+            }
+            other => other?,
+        };
+        debug!("Sent echo request with cookie: {:?}", cookie);
+
+        let reply = async {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (size, peer_addr) = match timeout(ERROR_POLL_INTERVAL, socket.readable()).await
+                {
+                    Ok(guard) => guard?.with_io(|| socket.get_ref().recv_from(&mut buf))?,
+                    Err(Elapsed { .. }) => match socket.get_ref().take_error()? {
+                        Some(err) => return Err(err),
+                        None => continue,
+                    },
+                };
+                match parse_echo(ip_payload(&buf[..size], peer_addr)) {
+                    Some(echo)
+                        if echo.icmp_type == echo_reply_type(peer_addr)
+                            && echo.cookie == cookie =>
+                    {
+                        return Ok(())
+                    }
+                    _ => debug!("Ignoring ICMP message from {}", peer_addr),
+                }
+            }
+        };
+        match reply.await {
+            Ok(()) => {
+                debug!("Received echo reply");
+                Ok(ClientStatus::SentCookie(SentCookie { cookie }))
+            }
+            Err(err) => match recv_icmp_error(socket.get_ref())? {
+                // A port unreachable is how `reject` answers by default
+                Some(icmp)
+                    if err.raw_os_error() == Some(libc::ECONNREFUSED)
+                        || is_admin_prohibited(&icmp) =>
+                {
+                    debug!("Refused");
+                    Ok(ClientStatus::Refused(Some(icmp)))
+                }
+                Some(icmp) if is_time_exceeded(&icmp) => {
+                    debug!("TTL expired at {}", icmp.from);
+                    Ok(ClientStatus::TtlExpired(icmp.from))
+                }
+                None if err.raw_os_error() == Some(libc::ECONNREFUSED) => {
+                    debug!("Refused");
+                    Ok(ClientStatus::Refused(None))
+                }
+                // Any other destination unreachable, which is reported as unreachable
+                _ => Err(err),
+            },
+        }
+    }
+}
+
+impl ProbeRecord {
+    /// Repeat the probe from the source namespace to the target namespace, which should be set up
+    /// with the same addresses and firewall as the original.
+    pub async fn replay(&self, source: &OsNs, target: &OsNs) -> Result<ProbeOutcome, io::Error> {
+        OsNsConnPath::new(
+            &self.source_name,
+            source,
+            self.source_addr,
+            &self.target_name,
+            target,
+            self.target_addr,
+        )
+        .probe_outcome(&self.probe)
+        .await
+    }
+}
+
+impl Probe {
+    /// Whether TCP is sent as segments built by hand, rather than over a socket's connection.
+    fn raw_tcp(&self) -> bool {
+        self.syn_only || self.out_of_state
+    }
+
+    fn client_bind_addr(&self, source_addr: IpAddr) -> SocketAddr {
+        let addr = match source_addr {
+            IpAddr::V4(_) if self.kernel_source_addr => Ipv4Addr::UNSPECIFIED.into(),
+            IpAddr::V6(_) if self.kernel_source_addr => Ipv6Addr::UNSPECIFIED.into(),
+            _ => source_addr,
+        };
+        (addr, self.source_port.unwrap_or(0)).into()
+    }
+
+    /// The address that the client sends to, and the server listens on.
+    fn target_addr(&self, path: &OsNsConnPath<'_>) -> IpAddr {
+        self.broadcast.map_or(path.target_addr, IpAddr::V4)
+    }
+
+    fn set_client_options<S: AsRawFd>(
+        &self,
+        socket: &S,
+        path: &OsNsConnPath<'_>,
+    ) -> Result<(), io::Error> {
+        if let Some(mark) = self.fwmark {
+            setsockopt(socket, libc::SOL_SOCKET, libc::SO_MARK, mark as libc::c_int)?;
+        }
+        if let Some(device) = path.source_device {
+            bind_to_device(socket, device)?;
+        }
+        if let Some(ttl) = self.ttl {
+            let ttl = libc::c_int::from(ttl);
+            match path.target_addr {
+                IpAddr::V4(_) => setsockopt(socket, libc::IPPROTO_IP, libc::IP_TTL, ttl)?,
+                IpAddr::V6(_) => {
+                    setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, ttl)?
+                }
+            }
+        }
+        if let Some(dscp) = self.dscp {
+            // The DSCP is the upper 6 bits of the TOS or traffic class, above the ECN bits
+            let tos = libc::c_int::from(dscp) << 2;
+            match path.target_addr {
+                IpAddr::V4(_) => setsockopt(socket, libc::IPPROTO_IP, libc::IP_TOS, tos)?,
+                IpAddr::V6(_) => setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the function with the probe's effective user and group, if any, restoring them
+    /// afterwards, and in the probe's cgroup, if any.
+    ///
+    /// This uses the raw syscalls, since the libc wrappers change the credentials of every thread
+    /// in the process, whereas this should only affect the current namespace thread.
+    fn as_thread_user<F, T>(&self, f: F) -> Result<T, io::Error>
+    where
+        F: FnOnce() -> Result<T, io::Error>,
+    {
+        if let Some(path) = &self.cgroup {
+            // The namespace thread is never reused, so it is left in the cgroup
+            enter_thread_cgroup(path)?;
+        }
+        let (uid, gid) = match self.user {
+            Some(user) => user,
+            None => return f(),
+        };
+        let (old_uid, old_gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let keep = libc::uid_t::MAX;
+        set_thread_creds(libc::SYS_setresgid, keep, gid).and_then(|()| {
+            set_thread_creds(libc::SYS_setresuid, keep, uid).inspect_err(|_| {
+                // Leave the thread as it was, rather than with only its group switched
+                set_thread_creds(libc::SYS_setresgid, keep, old_gid)
+                    .expect("Failed to restore thread credentials");
+            })
+        }).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "Failed to switch to uid {} gid {}, which may not be mapped in the user namespace: {}",
+                    uid, gid, err
+                ),
+            )
+        })?;
+        let ret = f();
+        set_thread_creds(libc::SYS_setresuid, keep, old_uid)
+            .and_then(|()| set_thread_creds(libc::SYS_setresgid, keep, old_gid))
+            .expect("Failed to restore thread credentials");
+        ret
+    }
+}
+
+/// Set the effective id with `setresuid` or `setresgid`, keeping the real and saved ids, so that
+/// the change can be reverted.
+fn set_thread_creds(syscall: libc::c_long, keep: u32, id: u32) -> Result<(), io::Error> {
+    unsafe {
+        let res = libc::syscall(syscall, keep, id, keep);
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        assert_eq!(res, 0);
+    }
+    Ok(())
+}
+
+/// Move the current thread into the given threaded cgroup v2, relative to the root of the
+/// hierarchy, wherever it is mounted.
+fn enter_thread_cgroup(path: &str) -> Result<(), io::Error> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let root = parse_cgroup2_mount(&mountinfo).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "No cgroup v2 hierarchy is mounted")
+    })?;
+    let threads = format!("{}/{}/cgroup.threads", root, path.trim_start_matches('/'));
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+    fs::write(&threads, tid.to_string()).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("Failed to move thread into cgroup {}: {}", path, err),
+        )
+    })
+}
+
+/// The mount point of the cgroup v2 hierarchy in `/proc/self/mountinfo`, whose fifth field is the
+/// mount point, and whose file system type follows the `-` separator.
+fn parse_cgroup2_mount(mountinfo: &str) -> Option<&str> {
+    mountinfo.lines().find_map(|line| {
+        let (fields, fs_fields) = line.split_once(" - ")?;
+        if fs_fields.split_whitespace().next()? != "cgroup2" {
+            return None;
+        }
+        fields.split_whitespace().nth(4)
+    })
+}
+
+static NEXT_COOKIE_NONCE: AtomicU64 = AtomicU64::new(0);
+
+/// A cookie that is unique within the process, and random so that it is very likely unique
+/// across processes too.
+fn new_cookie() -> u128 {
+    let nonce = NEXT_COOKIE_NONCE.fetch_add(1, Ordering::Relaxed);
+    (u128::from(rand::random::<u64>()) << 64) | u128::from(nonce)
+}
+
+/// The cookie at the start of the payload, which may be padded with zeros.
+fn parse_cookie(payload: &[u8]) -> Option<u128> {
+    let mut cookie = 0u128.to_be_bytes();
+    let len = cookie.len();
+    if payload.len() < len || payload[len..].iter().any(|&b| b != 0) {
+        return None;
+    }
+    cookie.copy_from_slice(&payload[..len]);
+    Some(u128::from_be_bytes(cookie))
+}
+
+/// The payload of a packet received by a raw socket. IPv4 raw sockets receive the IP header,
+/// whereas IPv6 raw sockets do not.
+fn ip_payload(packet: &[u8], peer_addr: IpAddr) -> &[u8] {
+    match peer_addr {
+        IpAddr::V4(_) => &packet[usize::from(packet[0] & 0x0f) * 4..],
+        IpAddr::V6(_) => packet,
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct TcpHeader {
+    source_port: u16,
+    target_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+}
+
+fn parse_tcp_header(segment: &[u8]) -> Option<TcpHeader> {
+    if segment.len() < 20 {
+        return None;
+    }
+    let u16_at = |i: usize| u16::from_be_bytes([segment[i], segment[i + 1]]);
+    let u32_at = |i: usize| u32::from(u16_at(i)) << 16 | u32::from(u16_at(i + 2));
+    Some(TcpHeader {
+        source_port: u16_at(0),
+        target_port: u16_at(2),
+        seq: u32_at(4),
+        ack: u32_at(8),
+        flags: segment[13],
+    })
+}
+
+/// A TCP segment with only the SYN flag, and no options.
+fn syn_segment(source: SocketAddr, target: SocketAddr, seq: u32, window: u16) -> [u8; 20] {
+    tcp_segment(source, target, TCP_SYN, seq, 0, window)
+}
+
+/// A TCP segment with the given flags, and no options or data.
+fn tcp_segment(
+    source: SocketAddr,
+    target: SocketAddr,
+    flags: u8,
+    seq: u32,
+    ack: u32,
+    window: u16,
+) -> [u8; 20] {
+    let mut segment = [0u8; 20];
+    segment[0..2].copy_from_slice(&source.port().to_be_bytes());
+    segment[2..4].copy_from_slice(&target.port().to_be_bytes());
+    segment[4..8].copy_from_slice(&seq.to_be_bytes());
+    segment[8..12].copy_from_slice(&ack.to_be_bytes());
+    // The data offset, in 32-bit words
+    segment[12] = 5 << 4;
+    segment[13] = flags;
+    segment[14..16].copy_from_slice(&window.to_be_bytes());
+    let checksum = tcp_checksum(source.ip(), target.ip(), &segment);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    segment
+}
+
+/// The checksum of a TCP segment, including the pseudo-header. Raw sockets leave this to the
+/// sender.
+fn tcp_checksum(source: IpAddr, target: IpAddr, segment: &[u8]) -> u16 {
+    let mut pseudo_header = vec![];
+    match (source, target) {
+        (IpAddr::V4(source), IpAddr::V4(target)) => {
+            pseudo_header.extend_from_slice(&source.octets());
+            pseudo_header.extend_from_slice(&target.octets());
+            pseudo_header.extend_from_slice(&[0, libc::IPPROTO_TCP as u8]);
+            pseudo_header.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        }
+        (IpAddr::V6(source), IpAddr::V6(target)) => {
+            pseudo_header.extend_from_slice(&source.octets());
+            pseudo_header.extend_from_slice(&target.octets());
+            pseudo_header.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, libc::IPPROTO_TCP as u8]);
+        }
+        _ => panic!("Mismatched address families: {} and {}", source, target),
+    }
+    // The pseudo-header has an even length, so the segment's words stay aligned
+    pseudo_header.extend_from_slice(segment);
+    internet_checksum(&pseudo_header)
+}
+
+/// The ones' complement of the ones' complement sum of the data's 16-bit words, as used by IP,
+/// ICMP, TCP and UDP.
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|word| u32::from(word[0]) << 8 | u32::from(word.get(1).copied().unwrap_or(0)))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The IP protocol of ICMP in the address's family: ICMP, or ICMPv6.
+fn icmp_protocol(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => libc::IPPROTO_ICMP as u8,
+        IpAddr::V6(_) => libc::IPPROTO_ICMPV6 as u8,
+    }
+}
+
+fn echo_request_type(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => ICMP_ECHO_REQUEST,
+        IpAddr::V6(_) => ICMPV6_ECHO_REQUEST,
+    }
+}
+
+fn echo_reply_type(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => ICMP_ECHO_REPLY,
+        IpAddr::V6(_) => ICMPV6_ECHO_REPLY,
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct Echo {
+    icmp_type: u8,
+    cookie: u128,
+}
+
+/// An echo request to the given address, with the cookie as its data, and with its identifier
+/// and sequence number taken from the cookie, which conntrack uses to match the echo reply.
+///
+/// The kernel computes the checksum of ICMPv6, which covers a pseudo-header, but not of ICMP.
+fn echo_request(target: IpAddr, cookie: u128) -> [u8; 8 + COOKIE_LEN] {
+    let mut message = [0u8; 8 + COOKIE_LEN];
+    message[0] = echo_request_type(target);
+    message[4..6].copy_from_slice(&((cookie >> 64) as u16).to_be_bytes());
+    message[6..8].copy_from_slice(&(cookie as u16).to_be_bytes());
+    message[8..].copy_from_slice(&cookie.to_be_bytes());
+    if target.is_ipv4() {
+        let checksum = internet_checksum(&message);
+        message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    message
+}
+
+/// An echo request or reply, whose data is a cookie that agrees with its identifier and sequence
+/// number.
+fn parse_echo(message: &[u8]) -> Option<Echo> {
+    if message.len() < 8 || message[1] != 0 {
+        return None;
+    }
+    let cookie = parse_cookie(&message[8..])?;
+    if message[4..6] != ((cookie >> 64) as u16).to_be_bytes()
+        || message[6..8] != (cookie as u16).to_be_bytes()
+    {
+        return None;
+    }
+    Some(Echo {
+        icmp_type: message[0],
+        cookie,
+    })
+}
+
+/// The reply of an FTP server that is entering passive mode on the given data port, in the form
+/// that the conntrack FTP helper parses: `227` for IPv4 and the extended `229` for IPv6.
+fn passive_mode_reply(addr: IpAddr, port: u16) -> String {
+    match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!(
+                "227 Entering Passive Mode ({},{},{},{},{},{}).\r\n",
+                a,
+                b,
+                c,
+                d,
+                port >> 8,
+                port & 0xff
+            )
+        }
+        IpAddr::V6(_) => format!("229 Entering Extended Passive Mode (|||{}|)\r\n", port),
+    }
+}
+
+/// The command of an FTP client that is announcing the given data address in active mode, in
+/// the form that the conntrack FTP helper parses: `PORT` for IPv4 and the extended `EPRT` for
+/// IPv6.
+fn active_mode_command(addr: IpAddr, port: u16) -> String {
+    match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!(
+                "PORT {},{},{},{},{},{}\r\n",
+                a,
+                b,
+                c,
+                d,
+                port >> 8,
+                port & 0xff
+            )
+        }
+        IpAddr::V6(addr) => format!("EPRT |2|{}|{}|\r\n", addr, port),
+    }
+}
+
+/// The data address announced by an `active_mode_command`.
+fn parse_active_mode_command(line: &str) -> Option<SocketAddr> {
+    let line = line.trim_end();
+    if let Some(args) = line.strip_prefix("PORT ") {
+        let fields = args
+            .split(',')
+            .map(|field| field.parse().ok())
+            .collect::<Option<Vec<u8>>>()?;
+        match fields[..] {
+            [a, b, c, d, high, low] => Some(SocketAddr::new(
+                Ipv4Addr::new(a, b, c, d).into(),
+                u16::from(high) << 8 | u16::from(low),
+            )),
+            _ => None,
+        }
+    } else if let Some(args) = line.strip_prefix("EPRT ") {
+        // The first character is the delimiter, normally `|`
+        let delimiter = args.chars().next()?;
+        match args.split(delimiter).collect::<Vec<_>>()[..] {
+            ["", "1", addr, port, ""] | ["", "2", addr, port, ""] => {
+                Some(SocketAddr::new(addr.parse().ok()?, port.parse().ok()?))
+            }
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+/// Fail with `InvalidData` unless the peer echoed the cookie back.
+fn check_echo(cookie: u128, echoed: u128) -> Result<(), io::Error> {
+    if echoed != cookie {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Expected echo of cookie {}, got {}", cookie, echoed),
+        ));
+    }
+    Ok(())
+}
+
+/// The effect of an exchange that failed with the given error, or the error if it is unexpected.
+fn exchange_error_effect(err: io::Error) -> Result<ConnEffect, io::Error> {
+    match err.raw_os_error() {
+        Some(libc::ECONNREFUSED) => Ok(ConnEffect::Refused { icmp: None }),
+        Some(libc::ECONNRESET) => Ok(ConnEffect::ResetDuringTransfer),
+        Some(libc::ETIMEDOUT) => Ok(ConnEffect::Unreachable),
+        _ if is_unreachable_error(&err) => Ok(ConnEffect::Unreachable),
+        // The server closed the connection, having seen the data
+        _ if err.kind() == io::ErrorKind::UnexpectedEof => Ok(ConnEffect::ResetDuringTransfer),
+        _ => Err(err),
+    }
+}
+
+fn is_unreachable_error(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EHOSTUNREACH) | Some(libc::ENETUNREACH)
+    )
+}
+
+/// Classify a socket error as an expired TTL if the ICMP error behind it was a time exceeded,
+/// and otherwise return it.
+fn ttl_expired_status<S: AsRawFd>(socket: &S, err: io::Error) -> Result<ClientStatus, io::Error> {
+    if err.raw_os_error() == Some(libc::EHOSTUNREACH) {
+        match recv_icmp_error(socket)? {
+            Some(icmp) if is_time_exceeded(&icmp) => {
+                debug!("TTL expired at {}", icmp.from);
+                return Ok(ClientStatus::TtlExpired(icmp.from));
+            }
+            _ => {}
+        }
+    }
+    Err(err)
+}
+
+/// Whether the ICMP error is a time exceeded: ICMP type 11, or ICMPv6 type 3.
+fn is_time_exceeded(icmp: &IcmpError) -> bool {
+    icmp.icmp_type == if icmp.from.is_ipv4() { 11 } else { 3 }
+}
+
+/// Whether the ICMP error says that a firewall prohibited the packet: ICMP destination
+/// unreachable (3) with code 9, 10 or 13, or ICMPv6 destination unreachable (1) with code 1.
+fn is_admin_prohibited(icmp: &IcmpError) -> bool {
+    if icmp.from.is_ipv4() {
+        icmp.icmp_type == 3 && matches!(icmp.code, 9 | 10 | 13)
+    } else {
+        icmp.icmp_type == 1 && icmp.code == 1
+    }
+}
+
+/// Whether TCP in the namespace offers the timestamps option, which adds 12 bytes to the header
+/// of every segment once both ends agree to it.
+fn tcp_timestamps(ns: &OsNs) -> Result<bool, io::Error> {
+    let value = ns.scoped(|| fs::read_to_string("/proc/sys/net/ipv4/tcp_timestamps"))?;
+    Ok(value.trim() != "0")
+}
+
+/// Like `socket_error`, but for binding a client socket to `addr`, so that the error is a
+/// `ClientBindError`.
+fn client_bind_error(
+    ns: &OsNs,
+    socket: String,
+    addr: SocketAddr,
+) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |err| {
+        let message = format!(
+            "Failed to bind {} client socket to {} in namespace {}",
+            socket,
+            addr,
+            ns.path().display()
+        );
+        io::Error::new(err.kind(), ClientBindError::new(addr, message, err))
+    }
+}
+
+/// Give an error in setting up a socket the context that a bare errno lacks, e.g. `Failed to
+/// bind UDP server socket to 198.51.100.1:53 in namespace /proc/...: Cannot assign requested
+/// address`. The kind of the error is kept.
+fn socket_error(ns: &OsNs, operation: String) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |err| {
+        io::Error::new(
+            err.kind(),
+            format!(
+                "Failed to {} in namespace {}: {}",
+                operation,
+                ns.path().display(),
+                err
+            ),
+        )
+    }
+}
+
+fn new_udp_socket(addr: IpAddr) -> Result<std::net::UdpSocket, io::Error> {
+    let domain = match addr {
+        IpAddr::V4(_) => libc::AF_INET,
+        IpAddr::V6(_) => libc::AF_INET6,
+    };
+    unsafe {
+        let fd = libc::socket(
+            domain,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        );
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(std::net::UdpSocket::from_raw_fd(fd))
+    }
+}
+
+/// A raw IP socket for a single protocol, which neither std nor tokio provide.
+#[derive(Debug)]
+struct RawSocket {
+    fd: RawFd,
+}
+
+impl RawSocket {
+    fn new(addr: IpAddr, protocol: u8) -> Result<RawSocket, io::Error> {
+        let domain = match addr {
+            IpAddr::V4(_) => libc::AF_INET,
+            IpAddr::V6(_) => libc::AF_INET6,
+        };
+        unsafe {
+            let fd = libc::socket(
+                domain,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::c_int::from(protocol),
+            );
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawSocket { fd })
+        }
+    }
+
+    fn send(&self, buf: &[u8]) -> Result<usize, io::Error> {
+        unsafe {
+            let res = libc::send(self.fd, buf.as_ptr() as *const libc::c_void, buf.len(), 0);
+            if res == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(res as usize)
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, IpAddr), io::Error> {
+        unsafe {
+            let mut sockaddr: libc::sockaddr_storage = mem::zeroed();
+            let mut len = mem::size_of_val(&sockaddr) as libc::socklen_t;
+            let res = libc::recvfrom(
+                self.fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+                &mut sockaddr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                &mut len,
+            );
+            if res == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok((res as usize, sockaddr_ip(&sockaddr)))
+        }
+    }
+
+    fn take_error(&self) -> Result<Option<io::Error>, io::Error> {
+        unsafe {
+            let mut err: libc::c_int = 0;
+            let mut len = mem::size_of_val(&err) as libc::socklen_t;
+            let res = libc::getsockopt(
+                self.fd,
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut err as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            );
+            if res == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            assert_eq!(res, 0);
+            Ok(if err == 0 {
+                None
+            } else {
+                Some(io::Error::from_raw_os_error(err))
+            })
+        }
+    }
+}
+
+impl AsRawFd for RawSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for RawSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Receive a datagram with `recvmsg`, along with the TOS or traffic class byte that it arrived
+/// with, if the socket has `IP_RECVTOS` or `IPV6_RECVTCLASS` set.
+fn recv_with_tos<S: AsRawFd>(
+    socket: &S,
+    buf: &mut [u8],
+) -> Result<(usize, IpAddr, Option<u8>), io::Error> {
+    unsafe {
+        let mut sockaddr: libc::sockaddr_storage = mem::zeroed();
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // Aligned for a cmsghdr
+        let mut control = [0u64; 8];
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = &mut sockaddr as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of_val(&sockaddr) as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let res = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut tos = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_TOS) => tos = Some(*data),
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    tos = Some((data as *const libc::c_int).read_unaligned() as u8)
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        Ok((res as usize, sockaddr_ip(&sockaddr), tos))
+    }
+}
+
+/// Receive the ICMP error at the head of the socket's error queue, if any, which requires
+/// `IP_RECVERR` or `IPV6_RECVERR`.
+fn recv_icmp_error<S: AsRawFd>(socket: &S) -> Result<Option<IcmpError>, io::Error> {
+    unsafe {
+        // The error queue also returns the offending datagram, which is not needed
+        let mut buf = [0u8; 16];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // Aligned for a cmsghdr, with room for a sock_extended_err and a sockaddr_in6
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let flags = libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT;
+        if libc::recvmsg(socket.as_raw_fd(), &mut msg, flags) == -1 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(err),
+            };
+        }
+        let mut icmp = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_RECVERR) | (libc::IPPROTO_IPV6, libc::IPV6_RECVERR) => {
+                    icmp = icmp_error(libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err)
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        Ok(icmp)
+    }
+}
+
+/// The ICMP error described by an extended socket error, or `None` if it did not come from an
+/// ICMP message.
+///
+/// # Safety
+///
+/// The pointer must point to a `sock_extended_err` followed by its offender address.
+unsafe fn icmp_error(ee: *const libc::sock_extended_err) -> Option<IcmpError> {
+    let err = ee.read_unaligned();
+    if err.ee_origin != libc::SO_EE_ORIGIN_ICMP && err.ee_origin != libc::SO_EE_ORIGIN_ICMP6 {
+        return None;
+    }
+    let offender = libc::SO_EE_OFFENDER(ee) as *const u8;
+    let mut sockaddr: libc::sockaddr_storage = mem::zeroed();
+    let family = (offender as *const libc::sockaddr)
+        .read_unaligned()
+        .sa_family;
+    let len = match libc::c_int::from(family) {
+        libc::AF_INET => mem::size_of::<libc::sockaddr_in>(),
+        libc::AF_INET6 => mem::size_of::<libc::sockaddr_in6>(),
+        _ => return None,
+    };
+    let dest = &mut sockaddr as *mut libc::sockaddr_storage as *mut u8;
+    std::ptr::copy_nonoverlapping(offender, dest, len);
+    Some(IcmpError {
+        icmp_type: err.ee_type,
+        code: err.ee_code,
+        from: sockaddr_ip(&sockaddr),
+    })
+}
+
+/// The address of a `sockaddr_in` or `sockaddr_in6`.
+///
+/// # Safety
+///
+/// The family must match the contents.
+unsafe fn sockaddr_ip(sockaddr: &libc::sockaddr_storage) -> IpAddr {
+    match libc::c_int::from(sockaddr.ss_family) {
+        libc::AF_INET => {
+            let sockaddr = &*(sockaddr as *const _ as *const libc::sockaddr_in);
+            IpAddr::from(u32::from_be(sockaddr.sin_addr.s_addr).to_be_bytes())
+        }
+        libc::AF_INET6 => {
+            let sockaddr = &*(sockaddr as *const _ as *const libc::sockaddr_in6);
+            IpAddr::from(sockaddr.sin6_addr.s6_addr)
+        }
+        family => unreachable!("Unexpected address family: {}", family),
+    }
+}
+
+fn bind<S: AsRawFd>(socket: &S, addr: SocketAddr) -> Result<(), io::Error> {
+    with_sockaddr(addr, |sockaddr, len| unsafe {
+        libc::bind(socket.as_raw_fd(), sockaddr, len)
+    })
+}
+
+fn connect<S: AsRawFd>(socket: &S, addr: SocketAddr) -> Result<(), io::Error> {
+    with_sockaddr(addr, |sockaddr, len| unsafe {
+        libc::connect(socket.as_raw_fd(), sockaddr, len)
+    })
+}
+
+/// Call a libc function that takes a `sockaddr`, such as `bind`, with the given address.
+fn with_sockaddr<F>(addr: SocketAddr, f: F) -> Result<(), io::Error>
+where
+    F: FnOnce(*const libc::sockaddr, libc::socklen_t) -> libc::c_int,
+{
+    let res = match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(*addr.ip()).to_be(),
+                },
+                sin_zero: [0; 8],
+            };
+            f(
+                &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+                mem::size_of_val(&sockaddr) as libc::socklen_t,
+            )
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            f(
+                &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                mem::size_of_val(&sockaddr) as libc::socklen_t,
+            )
+        }
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    assert_eq!(res, 0);
+    Ok(())
+}
+
+fn setsockopt<S: AsRawFd, T>(
+    socket: &S,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: T,
+) -> Result<(), io::Error> {
+    unsafe {
+        let res = libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        );
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        assert_eq!(res, 0);
+    }
+    Ok(())
+}
+
+fn bind_to_device<S: AsRawFd>(socket: &S, device: &str) -> Result<(), io::Error> {
+    setsockopt_str(socket, libc::SOL_SOCKET, libc::SO_BINDTODEVICE, device)
+}
+
+fn set_congestion<S: AsRawFd>(socket: &S, algorithm: &str) -> Result<(), io::Error> {
+    setsockopt_str(socket, libc::IPPROTO_TCP, libc::TCP_CONGESTION, algorithm).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("Failed to set congestion control {:?}: {}", algorithm, err),
+        )
+    })
+}
+
+/// Like `setsockopt`, but for an option whose value is a name, without a terminating nul.
+fn setsockopt_str<S: AsRawFd>(
+    socket: &S,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &str,
+) -> Result<(), io::Error> {
+    unsafe {
+        let res = libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        );
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        assert_eq!(res, 0);
+    }
+    Ok(())
+}
+
+fn set_recv_tos<S: AsRawFd>(socket: &S, addr: IpAddr) -> Result<(), io::Error> {
+    let enable: libc::c_int = 1;
+    match addr {
+        IpAddr::V4(_) => setsockopt(socket, libc::IPPROTO_IP, libc::IP_RECVTOS, enable),
+        IpAddr::V6(_) => setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, enable),
+    }
+}
+
+/// Queue ICMP errors for the socket, so that `recv_icmp_error` can report them.
+fn set_recv_err<S: AsRawFd>(socket: &S, addr: IpAddr) -> Result<(), io::Error> {
+    let enable: libc::c_int = 1;
+    match addr {
+        IpAddr::V4(_) => setsockopt(socket, libc::IPPROTO_IP, libc::IP_RECVERR, enable),
+        IpAddr::V6(_) => setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVERR, enable),
+    }
+}
+
+fn set_dont_fragment<S: AsRawFd>(socket: &S, addr: IpAddr, enabled: bool) -> Result<(), io::Error> {
+    let (level, name, value) = match (addr, enabled) {
+        (IpAddr::V4(_), true) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        ),
+        (IpAddr::V4(_), false) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DONT,
+        ),
+        (IpAddr::V6(_), true) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        ),
+        (IpAddr::V6(_), false) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DONT,
+        ),
+    };
+    setsockopt(socket, level, name, value)
+}
+
+fn set_reuseport<S: AsRawFd>(socket: &S) -> Result<(), io::Error> {
+    let enable: libc::c_int = 1;
+    setsockopt(socket, libc::SOL_SOCKET, libc::SO_REUSEPORT, enable)
+}
+
+fn set_transparent<S: AsRawFd>(socket: &S, addr: IpAddr) -> Result<(), io::Error> {
+    let enable: libc::c_int = 1;
+    match addr {
+        IpAddr::V4(_) => setsockopt(socket, libc::SOL_IP, libc::IP_TRANSPARENT, enable),
+        IpAddr::V6(_) => setsockopt(socket, libc::SOL_IPV6, libc::IPV6_TRANSPARENT, enable),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::future;
+    use indoc::indoc;
+    use lazy_static::lazy_static;
 
     use crate::INIT;
 
-    lazy_static! {
-        static ref NS: OsNs = {
-            *INIT;
-            let ns = OsNs::new_net().expect("Failed to create new network namespace");
-            ns.scoped_process("ip", &["link", "set", "lo", "up"])
-                .expect("Failed to enable loopback interface in network namespace");
-            ns
-        };
-        static ref IPV4_LOCALHOST_CONN_PATH: OsNsConnPath<'static> = {
-            OsNsConnPath::new(
-                "source",
-                &NS,
-                IpAddr::V4(Ipv4Addr::LOCALHOST),
-                "target",
-                &NS,
-                IpAddr::V4(Ipv4Addr::LOCALHOST),
-            )
-        };
-        static ref IPV6_LOCALHOST_CONN_PATH: OsNsConnPath<'static> = {
-            OsNsConnPath::new(
-                "source",
-                &NS,
-                IpAddr::V6(Ipv6Addr::LOCALHOST),
-                "target",
-                &NS,
-                IpAddr::V6(Ipv6Addr::LOCALHOST),
-            )
+    lazy_static! {
+        static ref NS: OsNs = {
+            *INIT;
+            let ns = OsNs::new_net().expect("Failed to create new network namespace");
+            ns.scoped_process("ip", &["link", "set", "lo", "up"])
+                .expect("Failed to enable loopback interface in network namespace");
+            ns
+        };
+        static ref IPV4_LOCALHOST_CONN_PATH: OsNsConnPath<'static> = {
+            OsNsConnPath::new(
+                "source",
+                &NS,
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                "target",
+                &NS,
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+            )
+        };
+        static ref IPV6_LOCALHOST_CONN_PATH: OsNsConnPath<'static> = {
+            OsNsConnPath::new(
+                "source",
+                &NS,
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+                "target",
+                &NS,
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+            )
+        };
+    }
+
+    #[tokio::test]
+    async fn tcp_v4_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Tcp { port: 1 });
+        let connector = Tcp {
+            port: 1,
+            probe: &probe,
+        };
+        let effect = connector.connect(&IPV4_LOCALHOST_CONN_PATH).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 1,
+            },
+            effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_v6_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Tcp { port: 1 });
+        let connector = Tcp {
+            port: 1,
+            probe: &probe,
+        };
+        let effect = connector.connect(&IPV6_LOCALHOST_CONN_PATH).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V6(Ipv6Addr::LOCALHOST),
+                target_port: 1,
+            },
+            effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 1 });
+        let connector = Udp {
+            port: 1,
+            probe: &probe,
+        };
+        let effect = connector.connect(&IPV4_LOCALHOST_CONN_PATH).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 1,
+            },
+            effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v6_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 1 });
+        let connector = Udp {
+            port: 1,
+            probe: &probe,
+        };
+        let effect = connector.connect(&IPV6_LOCALHOST_CONN_PATH).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V6(Ipv6Addr::LOCALHOST),
+                target_port: 1,
+            },
+            effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ip_v4_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Ip { protocol: 253 });
+        let effect = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 0,
+            },
+            effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ip_v6_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Ip { protocol: 253 });
+        let effect = IPV6_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V6(Ipv6Addr::LOCALHOST),
+                target_port: 0,
+            },
+            effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn icmp_v4_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Icmp {
+            kind: IcmpKind::Echo,
+        });
+        let effect = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 0,
+            },
+            effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn icmp_v6_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Icmp {
+            kind: IcmpKind::Echo,
+        });
+        let effect = IPV6_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V6(Ipv6Addr::LOCALHOST),
+                target_port: 0,
+            },
+            effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ip_v4_ok_concurrent() -> Result<(), io::Error> {
+        // Each server receives every packet with the protocol, so must skip the others' cookies
+        let probe = Probe::new(ConnSpec::Ip { protocol: 253 });
+        let effects =
+            future::try_join_all((0..4).map(|_| IPV4_LOCALHOST_CONN_PATH.probe(&probe))).await?;
+        for effect in effects {
+            assert_eq!(
+                ConnEffect::Ok {
+                    source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    target_port: 0,
+                },
+                effect
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_server_ignores_other_cookies() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 5 });
+        let connector = Udp {
+            port: 5,
+            probe: &probe,
+        };
+        let listen_addr = (Ipv4Addr::LOCALHOST, 5).into();
+        let server_socket = connector.bind_server(&NS, listen_addr, false).await?;
+        let client_socket = NS.scoped(|| std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)))?;
+        let cookie = new_cookie();
+        for payload in &[
+            &b"short"[..],
+            &new_cookie().to_be_bytes(),
+            &cookie.to_be_bytes(),
+        ] {
+            client_socket.send_to(payload, listen_addr)?;
+        }
+        assert_eq!(
+            ServerStatus::ReceivedCookie(ReceivedCookie {
+                cookie,
+                peer_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                local_port: 5,
+                dscp: Some(0),
+                listener: None,
+            }),
+            connector.server(server_socket, cookie).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_server_reports_dscp() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 10 });
+        let connector = Udp {
+            port: 10,
+            probe: &probe,
+        };
+        // Expedited forwarding, shifted past the ECN bits
+        let tos: libc::c_int = 46 << 2;
+        for &addr in &[
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ] {
+            let listen_addr = (addr, 10).into();
+            let server_socket = connector.bind_server(&NS, listen_addr, false).await?;
+            let client_socket = NS.scoped(|| std::net::UdpSocket::bind((addr, 0)))?;
+            match addr {
+                IpAddr::V4(_) => setsockopt(&client_socket, libc::IPPROTO_IP, libc::IP_TOS, tos)?,
+                IpAddr::V6(_) => {
+                    setsockopt(&client_socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)?
+                }
+            }
+            let cookie = new_cookie();
+            client_socket.send_to(&cookie.to_be_bytes(), listen_addr)?;
+            match connector.server(server_socket, cookie).await? {
+                ServerStatus::ReceivedCookie(rx) => assert_eq!(Some(46), rx.dscp),
+                other => panic!("Unexpected status: {:?}", other),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn udp_v4_ok_blocking() -> Result<(), io::Error> {
+        let effect = IPV4_LOCALHOST_CONN_PATH.connect_blocking(ConnSpec::Udp { port: 1 })?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 1,
+            },
+            effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_ok_no_timeout() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 1 }).no_timeout();
+        let effect = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 1,
+            },
+            effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_syn_v4_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Tcp { port: 6 }).syn_only();
+        let effect = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 6,
+            },
+            effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_syn_v6_refused() -> Result<(), io::Error> {
+        // Without a redirect, nothing listens on the target port, so the SYN is reset
+        let probe = Probe::new(ConnSpec::Tcp { port: 7 })
+            .syn_only()
+            .server_port(8);
+        let effect = IPV6_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        assert_eq!(ConnEffect::Refused { icmp: None }, effect);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_v4_out_of_state_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Tcp { port: 31 }).out_of_state();
+        let effect = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 31,
+            },
+            effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn out_of_state_needs_tcp() -> Result<(), io::Error> {
+        for probe in &[
+            Probe::new(ConnSpec::Udp { port: 32 }).out_of_state(),
+            Probe::new(ConnSpec::Tcp { port: 32 })
+                .out_of_state()
+                .syn_only(),
+        ] {
+            let err = IPV4_LOCALHOST_CONN_PATH
+                .probe(probe)
+                .await
+                .expect_err("Out-of-state segment was supported");
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_v4_congestion_ok() -> Result<(), io::Error> {
+        // Reno is built into every kernel
+        let probe = Probe::new(ConnSpec::Tcp { port: 12 }).congestion("reno");
+        let effect = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 12,
+            },
+            effect
+        );
+
+        let probe = Probe::new(ConnSpec::Tcp { port: 12 }).congestion("nonexistent");
+        let err = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await.unwrap_err();
+        assert_eq!(Some(libc::ENOENT), err.raw_os_error());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_v4_exchanges_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Tcp { port: 15 });
+        let effects = IPV4_LOCALHOST_CONN_PATH.probe_exchanges(&probe, 3).await?;
+        let ok = ConnEffect::Ok {
+            source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            target_port: 15,
         };
+        assert_eq!(vec![ok; 3], effects);
+        Ok(())
     }
 
     #[tokio::test]
-    async fn tcp_v4_ok() -> Result<(), io::Error> {
-        let connector = Tcp { port: 1 };
-        let effect = connector.connect(&IPV4_LOCALHOST_CONN_PATH).await?;
+    async fn udp_exchanges_unsupported() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 15 });
+        let err = IPV4_LOCALHOST_CONN_PATH
+            .probe_exchanges(&probe, 3)
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_v4_window_clamp_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Tcp { port: 9 }).window_clamp(1);
+        let effect = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?;
         assert_eq!(
             ConnEffect::Ok {
-                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST)
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 9,
             },
             effect
         );
         Ok(())
     }
 
+    #[test]
+    fn parse_route_mtu() {
+        let cached =
+            "2001:db8::2 from 2001:db8::1 dev wan proto kernel src 2001:db8::1 metric 256 \
+            expires 592sec mtu 1280 pref medium\n";
+        assert_eq!(Some(1280), parse_ip_field(cached, "mtu"));
+        assert_eq!(Some("wan".to_owned()), parse_ip_field(cached, "dev"));
+        let uncached = "198.51.100.2 dev wan src 198.51.100.1 uid 0 \n    cache \n";
+        assert_eq!(None, parse_ip_field::<u32>(uncached, "mtu"));
+    }
+
+    #[test]
+    fn passive_mode_replies() {
+        assert_eq!(
+            "227 Entering Passive Mode (192,0,2,1,8,89).\r\n",
+            passive_mode_reply("192.0.2.1".parse().unwrap(), 2137)
+        );
+        assert_eq!(
+            "229 Entering Extended Passive Mode (|||2137|)\r\n",
+            passive_mode_reply("2001:db8::1".parse().unwrap(), 2137)
+        );
+    }
+
+    #[test]
+    fn active_mode_commands() {
+        for &addr in &["192.0.2.1:2137", "[2001:db8::1]:2137"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let command = active_mode_command(addr.ip(), addr.port());
+            assert_eq!(Some(addr), parse_active_mode_command(&command));
+        }
+        assert_eq!(
+            "PORT 192,0,2,1,8,89\r\n",
+            active_mode_command("192.0.2.1".parse().unwrap(), 2137)
+        );
+        assert_eq!(
+            Some("[2001:db8::1]:2137".parse().unwrap()),
+            parse_active_mode_command("EPRT !2!2001:db8::1!2137!\r\n")
+        );
+        assert_eq!(None, parse_active_mode_command("PORT 192,0,2,1,8\r\n"));
+        assert_eq!(None, parse_active_mode_command("PASV\r\n"));
+    }
+
+    #[test]
+    fn syn_segment_checksum() {
+        for (source, target) in &[
+            ("192.0.2.1:1234", "198.51.100.1:80"),
+            ("[2001:db8::1]:1234", "[2001:db8::2]:80"),
+        ] {
+            let (source, target) = (source.parse().unwrap(), target.parse().unwrap());
+            let segment = syn_segment(source, target, 0xdead_beef, 0);
+            // The checksum of a segment that includes its checksum is zero
+            assert_eq!(0, tcp_checksum(source.ip(), target.ip(), &segment));
+            assert_eq!(
+                Some(TcpHeader {
+                    source_port: 1234,
+                    target_port: 80,
+                    seq: 0xdead_beef,
+                    ack: 0,
+                    flags: TCP_SYN,
+                }),
+                parse_tcp_header(&segment)
+            );
+        }
+    }
+
+    #[test]
+    fn echo_request_checksum() {
+        let cookie = new_cookie();
+        let request = echo_request(IpAddr::V4(Ipv4Addr::LOCALHOST), cookie);
+        // The checksum of a message that includes its checksum is zero
+        assert_eq!(0, internet_checksum(&request));
+        assert_eq!(
+            Some(Echo {
+                icmp_type: ICMP_ECHO_REQUEST,
+                cookie,
+            }),
+            parse_echo(&request)
+        );
+        // The identifier and sequence number must agree with the cookie
+        let mut other = request;
+        other[7] ^= 1;
+        assert_eq!(None, parse_echo(&other));
+    }
+
     #[tokio::test]
-    async fn tcp_v6_ok() -> Result<(), io::Error> {
-        let connector = Tcp { port: 1 };
-        let effect = connector.connect(&IPV6_LOCALHOST_CONN_PATH).await?;
+    async fn udp_v4_ok_within_fast_deadline() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 1 })
+            .deadlines(Duration::from_millis(500), Duration::from_secs(5));
+        let outcome = IPV4_LOCALHOST_CONN_PATH.probe_outcome(&probe).await?;
+        assert!(matches!(outcome.effect, ConnEffect::Ok { .. }));
+        assert!(outcome.elapsed < Duration::from_millis(500));
+        assert!(!outcome.slow);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_ok_after_fast_deadline() -> Result<(), io::Error> {
+        // The server cannot receive the cookie before the client has even sent it
+        let probe = Probe::new(ConnSpec::Udp { port: 1 })
+            .deadlines(Duration::from_nanos(1), Duration::from_secs(5));
+        let outcome = IPV4_LOCALHOST_CONN_PATH.probe_outcome(&probe).await?;
+        assert!(matches!(outcome.effect, ConnEffect::Ok { .. }));
+        assert!(outcome.slow);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fast_deadline_after_drop_deadline() -> Result<(), io::Error> {
+        for probe in &[
+            Probe::new(ConnSpec::Udp { port: 1 })
+                .deadlines(Duration::from_secs(5), Duration::from_millis(500)),
+            "udp:1 timeout_ms=500 fast_deadline_ms=5000".parse()?,
+        ] {
+            let err = IPV4_LOCALHOST_CONN_PATH.probe(probe).await.unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_reuseport_listeners() -> Result<(), io::Error> {
+        let mut listeners = vec![];
+        // The kernel hashes each flow to a listener, so vary the source port
+        for source_port in 40000..40016 {
+            let probe = Probe::new(ConnSpec::Udp { port: 11 })
+                .source_port(source_port)
+                .reuseport_listeners(4);
+            let outcome = IPV4_LOCALHOST_CONN_PATH.probe_outcome(&probe).await?;
+            assert!(matches!(outcome.effect, ConnEffect::Ok { .. }));
+            listeners.push(outcome.listener.unwrap());
+        }
+        assert!(listeners.iter().all(|&listener| listener < 4));
+        listeners.sort_unstable();
+        listeners.dedup();
+        assert!(
+            listeners.len() > 1,
+            "Only listener {:?} was used",
+            listeners
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_echo_mismatch() {
+        assert!(check_echo(42, 42).is_ok());
+        let err = check_echo(42, 43).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[tokio::test]
+    async fn empty_reuseport_group() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 11 }).reuseport_listeners(0);
+        let err = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_timeout_sweep() -> Result<(), io::Error> {
+        let timeouts = [Duration::from_millis(100), Duration::from_secs(1)];
+        let effects = IPV4_LOCALHOST_CONN_PATH
+            .connect_timeout_sweep(ConnSpec::Udp { port: 13 }, &timeouts)
+            .await?;
+        let ok = ConnEffect::Ok {
+            source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            target_port: 13,
+        };
+        assert_eq!(vec![ok, ok], effects);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_connect_with_timeout() -> Result<(), io::Error> {
+        let effect = IPV4_LOCALHOST_CONN_PATH
+            .connect_with_timeout(ConnSpec::Udp { port: 14 }, Duration::from_millis(500))
+            .await?;
         assert_eq!(
             ConnEffect::Ok {
-                source_addr: IpAddr::V6(Ipv6Addr::LOCALHOST)
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 14,
             },
             effect
         );
         Ok(())
     }
 
+    #[test]
+    fn parse_cgroup2_mount_point() {
+        let mountinfo = indoc! {"
+            30 24 0:26 / /sys/fs/cgroup ro,nosuid,nodev,noexec shared:9 - tmpfs tmpfs ro,mode=755
+            31 30 0:27 / /sys/fs/cgroup/unified rw,nosuid,nodev,noexec shared:10 - cgroup2 cgroup2 rw
+        "};
+        assert_eq!(
+            Some("/sys/fs/cgroup/unified"),
+            parse_cgroup2_mount(mountinfo)
+        );
+        assert_eq!(None, parse_cgroup2_mount(mountinfo.lines().next().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn udp_v4_missing_cgroup() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 14 }).in_cgroup("/firebreak_missing");
+        let err = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await.unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        Ok(())
+    }
+
     #[tokio::test]
-    async fn udp_v4_ok() -> Result<(), io::Error> {
-        let connector = Udp { port: 1 };
-        let effect = connector.connect(&IPV4_LOCALHOST_CONN_PATH).await?;
+    async fn udp_v4_server_port() -> Result<(), io::Error> {
+        // Without a redirect, the client's packet never reaches the server
+        let probe = Probe::new(ConnSpec::Udp { port: 3 })
+            .server_port(4)
+            .udp_refused_as_unreachable(true);
+        let effect = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        assert_eq!(ConnEffect::Unreachable, effect);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_replay() -> Result<(), io::Error> {
+        let record: ProbeRecord = "source 127.0.0.1 -> target 127.0.0.1 udp:1 retries=1".parse()?;
+        let outcome = record.replay(&NS, &NS).await?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 1,
+            },
+            outcome.effect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_bind_error() -> Result<(), io::Error> {
+        let path = OsNsConnPath::new(
+            "source",
+            &NS,
+            "192.0.2.1".parse().unwrap(),
+            "target",
+            &NS,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+        );
+        let err = path
+            .connect(ConnSpec::Udp { port: 1 })
+            .await
+            .expect_err("Bound client to an address that does not exist");
+        assert_eq!(io::ErrorKind::AddrNotAvailable, err.kind());
+        assert!(err.to_string().contains("192.0.2.1:0"));
+        let bind_err = ClientBindError::find(&err).expect("Not a bind error");
+        assert_eq!("192.0.2.1:0".parse::<SocketAddr>().unwrap(), bind_err.addr);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_v4_external_server_ok() -> Result<(), io::Error> {
+        let _listener = NS.scoped(|| std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 2)))?;
+        let probe = Probe::new(ConnSpec::Tcp { port: 2 }).external_server();
+        let effect = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?;
         assert_eq!(
             ConnEffect::Ok {
-                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST)
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 2,
             },
             effect
         );
@@ -371,15 +3917,268 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn udp_v6_ok() -> Result<(), io::Error> {
-        let connector = Udp { port: 1 };
-        let effect = connector.connect(&IPV6_LOCALHOST_CONN_PATH).await?;
+    async fn tcp_v4_external_server_refused() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Tcp { port: 3 }).external_server();
+        let effect = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        assert_eq!(ConnEffect::Refused { icmp: None }, effect);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_refused_with_icmp_port_unreachable() -> Result<(), io::Error> {
+        // Without a redirect, nothing listens on the target port
+        let probe = Probe::new(ConnSpec::Udp { port: 16 }).server_port(17);
+        let effect = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        let icmp = IcmpError {
+            icmp_type: 3,
+            code: 3,
+            from: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        };
+        assert!(matches!(effect, ConnEffect::Refused { icmp: Some(i) } if i == icmp));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v6_refused_with_icmp_port_unreachable() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 16 }).server_port(17);
+        let effect = IPV6_LOCALHOST_CONN_PATH.probe(&probe).await?;
+        let icmp = IcmpError {
+            icmp_type: 1,
+            code: 4,
+            from: IpAddr::V6(Ipv6Addr::LOCALHOST),
+        };
+        assert!(matches!(effect, ConnEffect::Refused { icmp: Some(i) } if i == icmp));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_refused_within() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 20 }).server_port(21);
+        let effect = IPV4_LOCALHOST_CONN_PATH
+            .probe(&probe.clone().expect_refused_within(Duration::from_secs(1)))
+            .await?;
+        assert!(matches!(effect, ConnEffect::Refused { .. }));
+
+        // No refusal is that fast
+        let err = IPV4_LOCALHOST_CONN_PATH
+            .probe(&probe.expect_refused_within(Duration::from_nanos(1)))
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_v4_bidirectional_ok() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Tcp { port: 19 }).require_bidirectional();
+        let effect = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?;
         assert_eq!(
             ConnEffect::Ok {
-                source_addr: IpAddr::V6(Ipv6Addr::LOCALHOST)
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 19,
             },
             effect
         );
         Ok(())
     }
+
+    #[tokio::test]
+    async fn udp_v4_bidirectional_unsupported() {
+        let probe = Probe::new(ConnSpec::Udp { port: 1 }).require_bidirectional();
+        let err = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[tokio::test]
+    async fn udp_v4_payload_shorter_than_cookie() {
+        let probe = Probe::new(ConnSpec::Udp { port: 1 }).payload_len(COOKIE_LEN - 1);
+        let err = IPV4_LOCALHOST_CONN_PATH.probe(&probe).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[tokio::test]
+    async fn udp_v4_total_length() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 29 }).total_length(128);
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                target_port: 29,
+            },
+            IPV4_LOCALHOST_CONN_PATH.probe(&probe).await?
+        );
+
+        // The headers alone are 28 bytes, and the cookie 16 more
+        for probe in &[
+            Probe::new(ConnSpec::Udp { port: 29 }).total_length(27),
+            Probe::new(ConnSpec::Udp { port: 29 }).total_length(28 + COOKIE_LEN - 1),
+            Probe::new(ConnSpec::Udp { port: 29 })
+                .total_length(128)
+                .payload_len(100),
+        ] {
+            let err = IPV4_LOCALHOST_CONN_PATH.probe(probe).await.unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_separate_server_and_client() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 18 });
+        let server = IPV4_LOCALHOST_CONN_PATH.start_server(&probe, 42).await?;
+        let expected = ConnEffect::Ok {
+            source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            target_port: 18,
+        };
+        assert_eq!(
+            expected,
+            IPV4_LOCALHOST_CONN_PATH.run_client(&probe, 42).await?
+        );
+        assert_eq!(expected, server.received().await?);
+
+        // Once the server is dropped, nothing listens on the port
+        assert!(matches!(
+            IPV4_LOCALHOST_CONN_PATH.run_client(&probe, 42).await?,
+            ConnEffect::Refused { .. }
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_v4_separate_server_and_client() -> Result<(), io::Error> {
+        // The client waits for the server to close, so the server must run before it is awaited
+        let probe = Probe::new(ConnSpec::Tcp { port: 19 });
+        let server = IPV4_LOCALHOST_CONN_PATH.start_server(&probe, 43).await?;
+        let expected = ConnEffect::Ok {
+            source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            target_port: 19,
+        };
+        assert_eq!(
+            expected,
+            IPV4_LOCALHOST_CONN_PATH.run_client(&probe, 43).await?
+        );
+        assert_eq!(expected, server.received().await?);
+
+        // Once the server is dropped, nothing listens on the port
+        assert_eq!(
+            ConnEffect::Refused { icmp: None },
+            IPV4_LOCALHOST_CONN_PATH.run_client(&probe, 43).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_external_server_unsupported() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 1 }).external_server();
+        let err = IPV4_LOCALHOST_CONN_PATH
+            .probe(&probe)
+            .await
+            .expect_err("External UDP server was supported");
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_v4_related() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Tcp { port: 22 });
+        let effects = IPV4_LOCALHOST_CONN_PATH
+            .probe_related(&probe, FtpMode::Passive, 23)
+            .await?;
+        let expected = |target_port| ConnEffect::Ok {
+            source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            target_port,
+        };
+        assert_eq!(expected(22), effects.control);
+        assert_eq!(Some(expected(23)), effects.data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_v4_active_related() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Tcp { port: 27 });
+        let effects = IPV4_LOCALHOST_CONN_PATH
+            .probe_related(&probe, FtpMode::Active, 28)
+            .await?;
+        let expected = |target_port| ConnEffect::Ok {
+            source_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            target_port,
+        };
+        assert_eq!(expected(27), effects.control);
+        assert_eq!(Some(expected(28)), effects.data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_v4_syn_flood() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Tcp { port: 24 }).syn_only();
+        let report = IPV4_LOCALHOST_CONN_PATH.syn_flood(&probe, 10, 1000).await?;
+        assert_eq!(10, report.syn_acked());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_v4_syn_flood_needs_syn_only() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Tcp { port: 24 });
+        let err = IPV4_LOCALHOST_CONN_PATH
+            .syn_flood(&probe, 10, 1000)
+            .await
+            .expect_err("SYN flood of full connections was supported");
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_dscp() -> Result<(), io::Error> {
+        let probe = Probe::new(ConnSpec::Udp { port: 25 }).dscp(46);
+        let outcome = IPV4_LOCALHOST_CONN_PATH.probe_outcome(&probe).await?;
+        assert_eq!(Some(46), outcome.dscp);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_between() -> Result<(), io::Error> {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let path = OsNsConnPath::between(&NS, localhost, &NS, localhost)?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: localhost,
+                target_port: 26,
+            },
+            path.connect(ConnSpec::Udp { port: 26 }).await?
+        );
+
+        let err = OsNsConnPath::between(&NS, localhost, &NS, Ipv6Addr::LOCALHOST.into())
+            .err()
+            .expect("Addresses in different families were accepted");
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_v4_symmetric() -> Result<(), io::Error> {
+        let path = IPV4_LOCALHOST_CONN_PATH.reversed().unwrap();
+        assert_eq!(IPV4_LOCALHOST_CONN_PATH.source_addr, path.target_addr());
+        matrix::assert_symmetric(&*IPV4_LOCALHOST_CONN_PATH, ConnSpec::Udp { port: 30 }).await
+    }
+
+    #[tokio::test]
+    async fn server_bind_error_has_context() -> Result<(), io::Error> {
+        let target_addr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        let path = OsNsConnPath::between(&NS, Ipv4Addr::LOCALHOST.into(), &NS, target_addr)?;
+        let err = path
+            .connect(ConnSpec::Udp { port: 53 })
+            .await
+            .expect_err("Bound to an address that the namespace does not have");
+        assert_eq!(io::ErrorKind::AddrNotAvailable, err.kind());
+        assert!(ClientBindError::find(&err).is_none());
+        let message = err.to_string();
+        assert!(
+            message.starts_with(&format!(
+                "Failed to bind UDP server socket to 198.51.100.1:53 in namespace {}: ",
+                NS.path().display()
+            )),
+            "{}",
+            message
+        );
+        Ok(())
+    }
 }
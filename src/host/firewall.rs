@@ -0,0 +1,890 @@
+use std::prelude::v1::*;
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::conn::matrix::connect_all;
+use crate::conn::{ConnEffect, ConnPath, ConnSpec, IcmpKind, Probe, ProbeOutcome};
+use crate::host::{nft_depth_after, Host};
+
+/// A handle on the nft ruleset of a host, obtained with `Host::firewall`.
+///
+/// Tables include their family, e.g. `inet filter`.
+pub struct Firewall<'a, H: Host> {
+    host: &'a mut H,
+}
+
+/// The values of a named nft counter.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct Counter {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// A netfilter hook, in canonical order, as a packet traverses them within a host: `prerouting`
+/// then `input` or `forward` for a received packet, and `output` for a sent packet, and then
+/// `postrouting` for a packet that leaves the host.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Hook {
+    Prerouting,
+    Input,
+    Forward,
+    Output,
+    Postrouting,
+}
+
+impl Hook {
+    pub const ALL: [Hook; 5] = [
+        Hook::Prerouting,
+        Hook::Input,
+        Hook::Forward,
+        Hook::Output,
+        Hook::Postrouting,
+    ];
+}
+
+/// The name of the hook in nft
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Hook::Prerouting => "prerouting",
+            Hook::Input => "input",
+            Hook::Forward => "forward",
+            Hook::Output => "output",
+            Hook::Postrouting => "postrouting",
+        })
+    }
+}
+
+const TRACE_TABLE: &str = "inet firebreak_trace";
+
+/// A copy of a ruleset, which can be restored later with `Firewall::restore`.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Snapshot {
+    ruleset: String,
+}
+
+impl<'a, H: Host> Firewall<'a, H> {
+    pub(crate) fn new(host: &'a mut H) -> Self {
+        Firewall { host }
+    }
+
+    pub fn load<R: io::Read + Send>(&mut self, rules: R) -> Result<(), io::Error> {
+        self.host.load_nft_rules(rules)
+    }
+
+    pub fn list(&self) -> Result<String, io::Error> {
+        self.host.list_nft_rules()
+    }
+
+    /// Whether the given chain has a rule containing the given text.
+    pub fn check(&self, table: &str, chain: &str, rule_substring: &str) -> Result<bool, io::Error> {
+        self.host.has_nft_rule(table, chain, rule_substring)
+    }
+
+    /// Append a rule to the given chain, returning its handle for `delete_rule`.
+    pub fn add_rule(&mut self, table: &str, chain: &str, rule: &str) -> Result<u64, io::Error> {
+        let command = format!("add rule {} {} {}", table, chain, rule);
+        let echo = self.host.load_nft_rules_echo(command.as_bytes())?;
+        parse_handle(&echo).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("nft did not echo a handle for {:?}: {}", command, echo),
+            )
+        })
+    }
+
+    pub fn delete_rule(&mut self, table: &str, chain: &str, handle: u64) -> Result<(), io::Error> {
+        self.host
+            .apply_nft_batch(&[format!("delete rule {} {} handle {}", table, chain, handle)])
+    }
+
+    pub fn add_set_element(
+        &mut self,
+        table: &str,
+        set: &str,
+        element: &str,
+    ) -> Result<(), io::Error> {
+        self.host
+            .apply_nft_batch(&[format!("add element {} {} {{ {} }}", table, set, element)])
+    }
+
+    pub fn counter(&self, table: &str, name: &str) -> Result<Counter, io::Error> {
+        nft_ruleset_counter(&self.list()?, table, name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No counter {} in table {}", name, table),
+            )
+        })
+    }
+
+    /// The values of the `counter` statement of the rule with the given handle, e.g. from
+    /// `add_rule`.
+    pub fn rule_counter(
+        &self,
+        table: &str,
+        chain: &str,
+        handle: u64,
+    ) -> Result<Counter, io::Error> {
+        let ruleset = self.host.list_nft_rules_with_handles()?;
+        let rule = nft_ruleset_rule(&ruleset, table, chain, handle).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "No rule with handle {} in chain {} {}",
+                    handle, table, chain
+                ),
+            )
+        })?;
+        parse_counter_statement(rule).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Rule has no counter: {}", rule),
+            )
+        })
+    }
+
+    /// Assert that the rule with the given handle counted the expected number of packets, e.g.
+    /// zero to show that an earlier rule matched them instead.
+    pub fn assert_counter(
+        &self,
+        table: &str,
+        chain: &str,
+        handle: u64,
+        expected_packets: u64,
+    ) -> Result<(), io::Error> {
+        let packets = self.rule_counter(table, chain, handle)?.packets;
+        assert_eq!(
+            expected_packets, packets,
+            "Rule with handle {} in chain {} {} counted {} packets, expected {}",
+            handle, table, chain, packets, expected_packets
+        );
+        Ok(())
+    }
+
+    /// Remove every table, and so every rule.
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.host.apply_nft_batch(&["flush ruleset".into()])
+    }
+
+    pub fn snapshot(&self) -> Result<Snapshot, io::Error> {
+        Ok(Snapshot {
+            ruleset: self.list()?,
+        })
+    }
+
+    /// Count the packets that match the spec, i.e. those from client to server, in every hook,
+    /// so that `traced_hooks` can report which hooks they traversed.
+    ///
+    /// The counters are in a table of their own, whose chains run before the default priorities,
+    /// so that a packet is counted even if it is dropped by the hook. Any previous trace is
+    /// replaced.
+    pub fn trace_hooks(&mut self, spec: ConnSpec) -> Result<(), io::Error> {
+        let matches = spec_match(spec);
+        let mut commands = vec![
+            // Adding the table first ensures that deleting it succeeds
+            format!("add table {}", TRACE_TABLE),
+            format!("delete table {}", TRACE_TABLE),
+            format!("add table {}", TRACE_TABLE),
+        ];
+        for hook in &Hook::ALL {
+            commands.push(format!("add counter {} {}", TRACE_TABLE, hook));
+            commands.push(format!(
+                "add chain {} {} {{ type filter hook {} priority raw - 1; }}",
+                TRACE_TABLE, hook, hook
+            ));
+            commands.push(format!(
+                "add rule {} {} {} counter name {}",
+                TRACE_TABLE, hook, matches, hook
+            ));
+        }
+        self.host.apply_nft_batch(&commands)
+    }
+
+    /// The hooks that packets traversed since `trace_hooks`.
+    ///
+    /// The counters only record whether each hook was traversed, so the set is in the canonical
+    /// order of `Hook`, which need not be the order in which the packets traversed them, e.g.
+    /// when a locally generated packet is looped back to `input`.
+    pub fn traced_hooks(&self) -> Result<BTreeSet<Hook>, io::Error> {
+        let mut ret = BTreeSet::new();
+        for &hook in &Hook::ALL {
+            if self.counter(TRACE_TABLE, &hook.to_string())?.packets > 0 {
+                ret.insert(hook);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Replace the ruleset with the given rules, atomically.
+    pub fn replace(&mut self, rules: &str) -> Result<(), io::Error> {
+        self.host
+            .apply_nft_batch(&["flush ruleset".into(), rules.into()])
+    }
+
+    /// Replace the ruleset with the snapshot, atomically.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), io::Error> {
+        self.replace(&snapshot.ruleset)
+    }
+}
+
+/// A probe whose effect differs between two rulesets.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct EffectChange {
+    /// The index of the probe in the cases
+    pub index: usize,
+    pub probe: Probe,
+    pub old: ConnEffect,
+    pub new: ConnEffect,
+}
+
+/// Run the probes under the old rules and then under the new rules, returning those whose effect
+/// changed, e.g. to check that a rule change only affects the intended flows.
+///
+/// The host's ruleset is restored afterwards. The paths must not borrow the host, so that its
+/// rules can be replaced, e.g. they can be forward paths.
+pub async fn diff_policy<H: Host>(
+    host: &mut H,
+    old_rules: &str,
+    new_rules: &str,
+    cases: &[(&dyn ConnPath, Probe)],
+) -> Result<Vec<EffectChange>, io::Error> {
+    diff_configs(
+        host,
+        |host| host.firewall().replace(old_rules),
+        |host| host.firewall().replace(new_rules),
+        cases,
+    )
+    .await
+}
+
+/// Like `diff_policy`, but with the old and new firewalls set up by the given functions, e.g. to
+/// check that a ruleset rewritten for another tool, or loaded another way, has the same effects.
+/// Each function must replace whatever ruleset it finds.
+///
+/// Only the nft ruleset is restored afterwards, which includes any rules that the functions
+/// load with the nft-based iptables.
+pub async fn diff_configs<H, O, N>(
+    host: &mut H,
+    configure_old: O,
+    configure_new: N,
+    cases: &[(&dyn ConnPath, Probe)],
+) -> Result<Vec<EffectChange>, io::Error>
+where
+    H: Host,
+    O: FnOnce(&mut H) -> Result<(), io::Error>,
+    N: FnOnce(&mut H) -> Result<(), io::Error>,
+{
+    let snapshot = host.firewall().snapshot()?;
+    let outcomes = async {
+        configure_old(host)?;
+        let old_outcomes = connect_all(cases).await?;
+        configure_new(host)?;
+        let new_outcomes = connect_all(cases).await?;
+        Ok::<_, io::Error>((old_outcomes, new_outcomes))
+    }
+    .await;
+    host.firewall().restore(&snapshot)?;
+    let (old_outcomes, new_outcomes) = outcomes?;
+    Ok(cases
+        .iter()
+        .zip(old_outcomes.iter().zip(&new_outcomes))
+        .enumerate()
+        .filter(|(_, (_, (old, new)))| old.effect != new.effect)
+        .map(|(index, ((_, probe), (old, new)))| EffectChange {
+            index,
+            probe: probe.clone(),
+            old: old.effect,
+            new: new.effect,
+        })
+        .collect())
+}
+
+/// The given rules, preceded by a `define` for each of the variables, in order of name, and with
+/// each relative `include` path made absolute against `include_dir`, since nft would otherwise
+/// resolve it against the working directory of whichever process runs it.
+pub(crate) fn with_defines(
+    rules: &str,
+    defines: &HashMap<String, String>,
+    include_dir: &Path,
+) -> Result<String, io::Error> {
+    let mut names: Vec<_> = defines.keys().collect();
+    names.sort();
+    let mut out = String::new();
+    for name in names {
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid nft variable name: {:?}", name),
+            ));
+        }
+        out.push_str(&format!("define {} = {}\n", name, defines[name]));
+    }
+    for line in rules.lines() {
+        let indent = &line[..line.len() - line.trim_start().len()];
+        match line
+            .trim_start()
+            .strip_prefix("include \"")
+            .and_then(|rest| rest.split_once('"'))
+        {
+            Some((path, rest)) if Path::new(path).is_relative() => {
+                let path = include_dir.join(path);
+                out.push_str(&format!("{}include {:?}{}\n", indent, path, rest));
+            }
+            _ => {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// A match for the packets of the spec from client to server.
+pub(crate) fn spec_match(spec: ConnSpec) -> String {
+    match spec {
+        ConnSpec::Tcp { port } => format!("tcp dport {}", port),
+        ConnSpec::Udp { port } => format!("udp dport {}", port),
+        ConnSpec::Ip { protocol } => format!("meta l4proto {}", protocol),
+        // The type is the first byte of either header, and covers both families in one match:
+        // echo request is 8 in ICMP, and 128 in ICMPv6
+        ConnSpec::Icmp {
+            kind: IcmpKind::Echo,
+        } => "meta l4proto { icmp, ipv6-icmp } @th,0,8 { 8, 128 }".into(),
+    }
+}
+
+/// An event reported by `nft monitor trace` for a packet with `meta nftrace` set.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct TraceEvent {
+    /// Identifies the packet, which may be traced through several chains.
+    pub id: String,
+    /// The table, including its family, e.g. `inet filter`.
+    pub table: String,
+    pub chain: String,
+    pub kind: TraceKind,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum TraceKind {
+    /// The packet entered the chain, with the given headers.
+    Packet(String),
+    /// The packet matched the rule, with the given verdict, e.g. `continue` or `drop`. The
+    /// handle is reported if the monitor ran with `-a`.
+    Rule {
+        rule: String,
+        handle: Option<u64>,
+        verdict: String,
+    },
+    /// The chain returned the verdict, e.g. after a `jump`.
+    Verdict(String),
+    /// The packet reached the end of a base chain, and so its policy applied.
+    Policy(String),
+}
+
+/// Parse the output of `nft monitor trace`, ignoring lines that are not trace events.
+pub(crate) fn parse_trace(output: &str) -> Vec<TraceEvent> {
+    output.lines().filter_map(parse_trace_event).collect()
+}
+
+pub(crate) fn parse_trace_event(line: &str) -> Option<TraceEvent> {
+    let rest = line.strip_prefix("trace id ")?;
+    let mut words = rest.splitn(5, ' ');
+    let id = words.next()?;
+    let family = words.next()?;
+    let table = words.next()?;
+    let chain = words.next()?;
+    let rest = words.next()?;
+    let kind = if let Some(packet) = rest.strip_prefix("packet: ") {
+        TraceKind::Packet(packet.into())
+    } else if let Some(rule) = rest.strip_prefix("rule ") {
+        let (rule, verdict) = rule.rsplit_once(" (verdict ")?;
+        let (rule, handle) = match rule.rsplit_once(" # handle ") {
+            Some((rule, handle)) => (rule, Some(handle.parse().ok()?)),
+            None => (rule, None),
+        };
+        TraceKind::Rule {
+            rule: rule.into(),
+            handle,
+            verdict: verdict.strip_suffix(')')?.into(),
+        }
+    } else if let Some(verdict) = rest.strip_prefix("verdict ") {
+        TraceKind::Verdict(verdict.into())
+    } else if let Some(policy) = rest.strip_prefix("policy ") {
+        TraceKind::Policy(policy.into())
+    } else {
+        return None;
+    };
+    Some(TraceEvent {
+        id: id.into(),
+        table: format!("{} {}", family, table),
+        chain: chain.into(),
+        kind,
+    })
+}
+
+/// The rule, or the policy of a base chain, whose verdict decided the fate of a traced packet.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct TracedVerdict {
+    /// The table, including its family, e.g. `inet filter`.
+    pub table: String,
+    pub chain: String,
+    /// The rule, as listed, or `None` if the chain's policy applied.
+    pub rule: Option<String>,
+    pub handle: Option<u64>,
+    /// The verdict, e.g. `accept` or `drop`, which is also reported for `reject`.
+    pub verdict: String,
+}
+
+/// The outcome of a probe along with the verdict that decided it, as returned by
+/// `OsHost::trace_probe_outcome`.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct TracedOutcome {
+    pub outcome: ProbeOutcome,
+    /// The verdict for the first packet of the probe, or `None` if it was not traced, e.g.
+    /// because it was accepted without passing through any base chain.
+    pub verdict: Option<TracedVerdict>,
+}
+
+/// The last verdict that accepted or dropped the first traced packet, which decided its fate,
+/// since a packet that is accepted still traverses the base chains of later hooks and tables.
+pub fn final_verdict(events: &[TraceEvent]) -> Option<TracedVerdict> {
+    let id = &events.first()?.id;
+    let verdict = |event: &TraceEvent, rule, handle, verdict: &str| TracedVerdict {
+        table: event.table.clone(),
+        chain: event.chain.clone(),
+        rule,
+        handle,
+        verdict: verdict.into(),
+    };
+    events
+        .iter()
+        .rev()
+        .filter(|event| &event.id == id)
+        .find_map(|event| match &event.kind {
+            TraceKind::Rule {
+                rule,
+                handle,
+                verdict: v,
+            } if matches!(v.as_str(), "accept" | "drop" | "queue") => {
+                Some(verdict(event, Some(rule.clone()), *handle, v))
+            }
+            TraceKind::Policy(v) => Some(verdict(event, None, None, v)),
+            _ => None,
+        })
+}
+
+/// The handle of the first object echoed by `nft --echo --handle`, e.g. on `... # handle 4`.
+fn parse_handle(echo: &str) -> Option<u64> {
+    echo.lines().find_map(|line| {
+        let (_, handle) = line.split_once("# handle ")?;
+        handle.trim().parse().ok()
+    })
+}
+
+/// The rule with the given handle, as listed by `nft -a`, without its handle comment.
+fn nft_ruleset_rule<'a>(
+    ruleset: &'a str,
+    table: &str,
+    chain: &str,
+    handle: u64,
+) -> Option<&'a str> {
+    let table_header = format!("table {} {{", table);
+    let chain_header = format!("chain {} {{", chain);
+    let handle_comment = format!(" # handle {}", handle);
+    let mut depth = 0;
+    let mut in_table = false;
+    let mut in_chain = false;
+    for line in ruleset.lines().map(str::trim) {
+        match depth {
+            0 => in_table = line.starts_with(&table_header),
+            1 => in_chain = in_table && line.starts_with(&chain_header),
+            2 if in_chain => {
+                if let Some(rule) = line.strip_suffix(&handle_comment) {
+                    return Some(rule);
+                }
+            }
+            _ => {}
+        }
+        depth = nft_depth_after(depth, line);
+    }
+    None
+}
+
+/// The values of an anonymous `counter` statement, e.g. in `tcp dport 22 counter packets 3 bytes
+/// 180 drop`.
+fn parse_counter_statement(rule: &str) -> Option<Counter> {
+    let mut words = rule.split_whitespace();
+    words.find(|&word| word == "counter")?;
+    match (words.next()?, words.next()?, words.next()?, words.next()?) {
+        ("packets", packets, "bytes", bytes) => Some(Counter {
+            packets: packets.parse().ok()?,
+            bytes: bytes.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+fn nft_ruleset_counter(ruleset: &str, table: &str, name: &str) -> Option<Counter> {
+    let table_header = format!("table {} {{", table);
+    let counter_header = format!("counter {} {{", name);
+    let mut depth = 0;
+    let mut in_table = false;
+    let mut in_counter = false;
+    for line in ruleset.lines().map(str::trim) {
+        match depth {
+            0 => in_table = line == table_header,
+            1 => in_counter = in_table && line == counter_header,
+            2 if in_counter => {
+                let words: Vec<&str> = line.split_whitespace().collect();
+                if let ["packets", packets, "bytes", bytes] = words[..] {
+                    return Some(Counter {
+                        packets: packets.parse().ok()?,
+                        bytes: bytes.parse().ok()?,
+                    });
+                }
+            }
+            _ => {}
+        }
+        depth = nft_depth_after(depth, line);
+    }
+    None
+}
+
+/// The named counters in a ruleset in the JSON format of `nft -j`, keyed by table and name.
+///
+/// Counter objects are flat, so this scans for them rather than parsing the whole document. The
+/// anonymous `counter` statements of rules have no name, and are ignored.
+pub(crate) fn parse_json_counters(json: &str) -> Result<HashMap<String, Counter>, io::Error> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid counter in nft JSON output",
+        )
+    };
+    let mut ret = HashMap::new();
+    let mut rest = json;
+    while let Some(i) = rest.find("\"counter\"") {
+        rest = rest[i + "\"counter\"".len()..].trim_start();
+        // A rule that refers to a named counter has a string value instead
+        let body = match rest
+            .strip_prefix(':')
+            .and_then(|rest| rest.trim_start().strip_prefix('{'))
+        {
+            Some(body) => body,
+            None => continue,
+        };
+        let (fields, after) = parse_flat_json_object(body).ok_or_else(invalid)?;
+        rest = after;
+        let field = |key| {
+            fields
+                .iter()
+                .find(|(field_key, _)| field_key == key)
+                .map(|(_, value)| value.as_str())
+        };
+        if let (Some(family), Some(table), Some(name)) =
+            (field("family"), field("table"), field("name"))
+        {
+            let number = |key| field(key).and_then(|value| value.parse().ok());
+            let counter = Counter {
+                packets: number("packets").ok_or_else(invalid)?,
+                bytes: number("bytes").ok_or_else(invalid)?,
+            };
+            ret.insert(format!("{} {} {}", family, table, name), counter);
+        }
+    }
+    Ok(ret)
+}
+
+/// The fields of a JSON object without nested objects or arrays, starting after its `{`, with
+/// strings unquoted and other values as written, and the input after its `}`.
+fn parse_flat_json_object(s: &str) -> Option<(Vec<(String, String)>, &str)> {
+    let mut fields = vec![];
+    let mut rest = s.trim_start();
+    if let Some(after) = rest.strip_prefix('}') {
+        return Some((fields, after));
+    }
+    loop {
+        let (key, after) = parse_json_string(rest)?;
+        rest = after.trim_start().strip_prefix(':')?.trim_start();
+        let (value, after) = if rest.starts_with('"') {
+            parse_json_string(rest)?
+        } else {
+            let end = rest.find(|c: char| c == ',' || c == '}' || c.is_whitespace())?;
+            (rest[..end].to_owned(), &rest[end..])
+        };
+        fields.push((key, value));
+        rest = after.trim_start();
+        match rest.strip_prefix(',') {
+            Some(after) => rest = after.trim_start(),
+            None => return Some((fields, rest.strip_prefix('}')?)),
+        }
+    }
+}
+
+/// A JSON string at the start of the input, unescaped, and the input after it. Unicode escapes
+/// are not supported, since nft does not produce them for names.
+fn parse_json_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut ret = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            // After the opening quote and the closing quote
+            '"' => return Some((ret, &s[i + 2..])),
+            '\\' => ret.push(match chars.next()?.1 {
+                'n' => '\n',
+                't' => '\t',
+                'u' => return None,
+                c => c,
+            }),
+            c => ret.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+
+    #[test]
+    fn rules_with_defines() {
+        let defines: HashMap<_, _> = vec![
+            ("ports".to_owned(), "{ 22, 80 }".to_owned()),
+            ("lan_addr".to_owned(), "10.0.0.1".to_owned()),
+        ]
+        .into_iter()
+        .collect();
+        let rules = indoc!(
+            r#"
+            include "common.nft"
+            include "/etc/nftables.d/*.nft"
+            table inet filter {
+              include "chains/input.nft" # the input chain
+            }
+            "#
+        );
+        assert_eq!(
+            indoc!(
+                r#"
+                define lan_addr = 10.0.0.1
+                define ports = { 22, 80 }
+                include "/srv/rules/common.nft"
+                include "/etc/nftables.d/*.nft"
+                table inet filter {
+                  include "/srv/rules/chains/input.nft" # the input chain
+                }
+                "#
+            ),
+            with_defines(rules, &defines, Path::new("/srv/rules")).unwrap()
+        );
+        let invalid: HashMap<_, _> = vec![("lan-addr".to_owned(), "10.0.0.1".to_owned())]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            with_defines("", &invalid, Path::new("/"))
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn parse_handle_from_echo() {
+        assert_eq!(
+            Some(4),
+            parse_handle("add rule inet filter input tcp dport 22 drop # handle 4\n")
+        );
+        assert_eq!(None, parse_handle("add table inet filter\n"));
+    }
+
+    #[test]
+    fn parse_trace_output() {
+        let output = indoc! {r#"
+            trace id 9d8b4d5b inet firebreak_nftrace prerouting packet: iif "lan" ip saddr 203.0.113.2 ip daddr 198.51.100.2 udp dport 53
+            trace id 9d8b4d5b inet firebreak_nftrace prerouting rule udp dport 53 meta nftrace set 1 (verdict continue)
+            trace id 9d8b4d5b inet filter forward rule udp dport 53 drop (verdict drop)
+            trace id 9d8b4d5b inet filter input policy accept
+            trace id 9d8b4d5b inet filter lan verdict return
+            Ctrl-C to stop
+        "#};
+        let event = |table: &str, chain: &str, kind| TraceEvent {
+            id: "9d8b4d5b".into(),
+            table: table.into(),
+            chain: chain.into(),
+            kind,
+        };
+        assert_eq!(
+            vec![
+                event(
+                    "inet firebreak_nftrace",
+                    "prerouting",
+                    TraceKind::Packet(
+                        r#"iif "lan" ip saddr 203.0.113.2 ip daddr 198.51.100.2 udp dport 53"#
+                            .into()
+                    )
+                ),
+                event(
+                    "inet firebreak_nftrace",
+                    "prerouting",
+                    TraceKind::Rule {
+                        rule: "udp dport 53 meta nftrace set 1".into(),
+                        handle: None,
+                        verdict: "continue".into()
+                    }
+                ),
+                event(
+                    "inet filter",
+                    "forward",
+                    TraceKind::Rule {
+                        rule: "udp dport 53 drop".into(),
+                        handle: None,
+                        verdict: "drop".into()
+                    }
+                ),
+                event("inet filter", "input", TraceKind::Policy("accept".into())),
+                event("inet filter", "lan", TraceKind::Verdict("return".into())),
+            ],
+            parse_trace(output)
+        );
+    }
+
+    #[test]
+    fn final_verdict_of_traced_packet() {
+        let output = indoc! {r#"
+            trace id 1 inet firebreak_nftrace prerouting rule udp dport 53 meta nftrace set 1 # handle 2 (verdict continue)
+            trace id 1 inet filter prerouting policy accept
+            trace id 1 inet filter input rule jump lan # handle 5 (verdict jump lan)
+            trace id 1 inet filter lan rule udp dport 53 counter packets 1 bytes 42 reject # handle 7 (verdict drop)
+            trace id 2 inet filter input policy accept
+        "#};
+        let events = parse_trace(output);
+        assert_eq!(
+            TraceKind::Rule {
+                rule: "jump lan".into(),
+                handle: Some(5),
+                verdict: "jump lan".into()
+            },
+            events[2].kind
+        );
+        assert_eq!(
+            Some(TracedVerdict {
+                table: "inet filter".into(),
+                chain: "lan".into(),
+                rule: Some("udp dport 53 counter packets 1 bytes 42 reject".into()),
+                handle: Some(7),
+                verdict: "drop".into(),
+            }),
+            final_verdict(&events)
+        );
+        // Without a rule, the last policy decides
+        assert_eq!(
+            Some(TracedVerdict {
+                table: "inet filter".into(),
+                chain: "prerouting".into(),
+                rule: None,
+                handle: None,
+                verdict: "accept".into(),
+            }),
+            final_verdict(&events[..2])
+        );
+        assert_eq!(None, final_verdict(&[]));
+    }
+
+    #[test]
+    fn rule_counter_by_handle() {
+        let ruleset = indoc! {r#"
+            table inet filter { # handle 1
+                chain input { # handle 1
+                    type filter hook input priority filter; policy accept;
+                    tcp dport 22 counter packets 3 bytes 180 accept # handle 2
+                    tcp dport 22 counter packets 0 bytes 0 drop # handle 3
+                    tcp dport 80 accept # handle 4
+                }
+            }
+        "#};
+        let rule = nft_ruleset_rule(ruleset, "inet filter", "input", 3);
+        assert_eq!(Some("tcp dport 22 counter packets 0 bytes 0 drop"), rule);
+        assert_eq!(
+            Some(Counter::default()),
+            parse_counter_statement(rule.unwrap())
+        );
+        assert_eq!(
+            Some(Counter {
+                packets: 3,
+                bytes: 180
+            }),
+            nft_ruleset_rule(ruleset, "inet filter", "input", 2).and_then(parse_counter_statement)
+        );
+        assert_eq!(
+            None,
+            nft_ruleset_rule(ruleset, "inet filter", "input", 4).and_then(parse_counter_statement)
+        );
+        assert_eq!(None, nft_ruleset_rule(ruleset, "inet filter", "input", 5));
+        assert_eq!(None, nft_ruleset_rule(ruleset, "inet filter", "output", 2));
+    }
+
+    #[test]
+    fn counter() {
+        let ruleset = indoc! {r#"
+            table inet filter {
+                counter ssh {
+                    packets 3 bytes 180
+                }
+
+                chain input {
+                    type filter hook input priority filter; policy accept;
+                    tcp dport 22 counter name "ssh"
+                }
+            }
+            table inet nat {
+                counter ssh {
+                    packets 0 bytes 0
+                }
+            }
+        "#};
+        assert_eq!(
+            Some(Counter {
+                packets: 3,
+                bytes: 180
+            }),
+            nft_ruleset_counter(ruleset, "inet filter", "ssh")
+        );
+        assert_eq!(
+            Some(Counter::default()),
+            nft_ruleset_counter(ruleset, "inet nat", "ssh")
+        );
+        assert_eq!(None, nft_ruleset_counter(ruleset, "inet filter", "http"));
+    }
+
+    #[test]
+    fn json_counters() -> Result<(), io::Error> {
+        let json = concat!(
+            r#"{"nftables": [{"metainfo": {"version": "1.0.2", "json_schema_version": 1}}, "#,
+            r#"{"table": {"family": "inet", "name": "filter", "handle": 1}}, "#,
+            r#"{"counter": {"family": "inet", "name": "ssh", "table": "filter", "handle": 2, "#,
+            r#""comment": "port 22, \"ssh\"", "packets": 3, "bytes": 180}}, "#,
+            r#"{"counter": {"family": "inet", "name": "http", "table": "filter", "handle": 3, "#,
+            r#""packets": 0, "bytes": 0}}, "#,
+            r#"{"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 4, "#,
+            r#""expr": [{"counter": "ssh"}, {"counter": {"packets": 7, "bytes": 420}}]}}]}"#,
+        );
+        let counters = parse_json_counters(json)?;
+        assert_eq!(2, counters.len());
+        assert_eq!(
+            Some(&Counter {
+                packets: 3,
+                bytes: 180
+            }),
+            counters.get("inet filter ssh")
+        );
+        assert_eq!(Some(&Counter::default()), counters.get("inet filter http"));
+        Ok(())
+    }
+}
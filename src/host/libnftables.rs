@@ -0,0 +1,133 @@
+//! Running nft commands with libnftables, the library behind the nft binary, which talks to
+//! netfilter over netlink itself, for environments that have the library but not the binary.
+//!
+//! The library is loaded when first needed, so that it is not required at build time.
+
+use std::prelude::v1::*;
+
+use lazy_static::lazy_static;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+
+const LIBRARY_NAME: &str = "libnftables.so.1";
+
+/// Flags for `nft_ctx_output_set_flags`, from `nftables/libnftables.h`.
+pub const OUTPUT_HANDLE: c_uint = 1 << 3;
+pub const OUTPUT_JSON: c_uint = 1 << 4;
+pub const OUTPUT_ECHO: c_uint = 1 << 5;
+
+#[repr(C)]
+struct NftCtx {
+    _private: [u8; 0],
+}
+
+struct Library {
+    ctx_new: unsafe extern "C" fn(c_uint) -> *mut NftCtx,
+    ctx_free: unsafe extern "C" fn(*mut NftCtx),
+    ctx_output_set_flags: unsafe extern "C" fn(*mut NftCtx, c_uint),
+    ctx_buffer_output: unsafe extern "C" fn(*mut NftCtx) -> c_int,
+    ctx_buffer_error: unsafe extern "C" fn(*mut NftCtx) -> c_int,
+    ctx_get_output_buffer: unsafe extern "C" fn(*mut NftCtx) -> *const c_char,
+    ctx_get_error_buffer: unsafe extern "C" fn(*mut NftCtx) -> *const c_char,
+    run_cmd_from_buffer: unsafe extern "C" fn(*mut NftCtx, *const c_char) -> c_int,
+}
+
+lazy_static! {
+    /// The library, or why it could not be loaded. It is never unloaded.
+    static ref LIBRARY: Result<Library, String> = unsafe { Library::load() };
+}
+
+impl Library {
+    unsafe fn load() -> Result<Library, String> {
+        let name = CString::new(LIBRARY_NAME).unwrap();
+        let handle = libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+        if handle.is_null() {
+            return Err(dlerror());
+        }
+        Ok(Library {
+            ctx_new: symbol(handle, "nft_ctx_new")?,
+            ctx_free: symbol(handle, "nft_ctx_free")?,
+            ctx_output_set_flags: symbol(handle, "nft_ctx_output_set_flags")?,
+            ctx_buffer_output: symbol(handle, "nft_ctx_buffer_output")?,
+            ctx_buffer_error: symbol(handle, "nft_ctx_buffer_error")?,
+            ctx_get_output_buffer: symbol(handle, "nft_ctx_get_output_buffer")?,
+            ctx_get_error_buffer: symbol(handle, "nft_ctx_get_error_buffer")?,
+            run_cmd_from_buffer: symbol(handle, "nft_run_cmd_from_buffer")?,
+        })
+    }
+}
+
+/// Look up a function in the library, which must have type `F`.
+unsafe fn symbol<F: Copy>(handle: *mut c_void, name: &str) -> Result<F, String> {
+    assert_eq!(mem::size_of::<F>(), mem::size_of::<*mut c_void>());
+    let name = CString::new(name).unwrap();
+    let ptr = libc::dlsym(handle, name.as_ptr());
+    if ptr.is_null() {
+        return Err(dlerror());
+    }
+    Ok(mem::transmute_copy(&ptr))
+}
+
+unsafe fn dlerror() -> String {
+    let err = libc::dlerror();
+    if err.is_null() {
+        "unknown error".into()
+    } else {
+        CStr::from_ptr(err).to_string_lossy().into_owned()
+    }
+}
+
+/// Run the given commands, in the nft syntax, with the given output flags, and return the
+/// output. The netlink socket belongs to the calling thread's network namespace, so call this
+/// within `OsNs::scoped`.
+pub fn run(commands: &str, output_flags: c_uint) -> Result<String, io::Error> {
+    let lib = LIBRARY.as_ref().map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Failed to load {}: {}", LIBRARY_NAME, err),
+        )
+    })?;
+    let commands = CString::new(commands).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "nft commands contain a NUL byte",
+        )
+    })?;
+    unsafe {
+        let ctx = (lib.ctx_new)(0);
+        if ctx.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "Failed to create an nft context",
+            ));
+        }
+        (lib.ctx_output_set_flags)(ctx, output_flags);
+        (lib.ctx_buffer_output)(ctx);
+        (lib.ctx_buffer_error)(ctx);
+        let res = (lib.run_cmd_from_buffer)(ctx, commands.as_ptr());
+        let buffer = |get: unsafe extern "C" fn(*mut NftCtx) -> *const c_char| {
+            let ptr = get(ctx);
+            if ptr.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(ptr).to_string_lossy().into_owned()
+            }
+        };
+        let ret = if res == 0 {
+            Ok(buffer(lib.ctx_get_output_buffer))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "nft returned {}:\n{}",
+                    res,
+                    buffer(lib.ctx_get_error_buffer)
+                ),
+            ))
+        };
+        (lib.ctx_free)(ctx);
+        ret
+    }
+}
@@ -2,17 +2,30 @@ use std::prelude::v1::*;
 
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use rand::random;
+use std::collections::HashMap;
 use std::io;
 use std::net::IpAddr;
+use std::path::Path;
 
 use crate::conn::ConnPath;
+use firewall::{Counter, Firewall};
+use snapshot::StateSnapshot;
 
+pub mod firewall;
+mod libnftables;
 pub mod os;
+pub mod snapshot;
+pub mod topology;
 
 pub trait Host: Sized {
     type Interface: Interface;
 
+    /// A host that does not forward packets between its interfaces, e.g. a server or a client.
     fn new(name: String) -> Result<Self, io::Error>;
+    /// A host that forwards packets between its interfaces, in both families, for forward paths.
+    /// Reverse path filtering is disabled, so that only the ruleset drops packets from
+    /// unexpected sources, e.g. with a `fib` rule.
+    fn new_router(name: String) -> Result<Self, io::Error>;
 
     fn name(&self) -> &str;
     fn new_interface(
@@ -21,13 +34,94 @@ pub trait Host: Sized {
         addr_with_net: IpNet,
     ) -> Result<Self::Interface, io::Error>;
 
+    /// Apply the given nft commands atomically, in one transaction, on top of the existing
+    /// ruleset: they add to it unless they begin with `flush ruleset`. Conntrack is untouched
+    /// either way, so established connections keep their state across a reload, and only
+    /// `flush_conntrack` forgets it.
     fn load_nft_rules<R: io::Read + Send>(&mut self, rules: R) -> Result<(), io::Error>;
+    /// Replace the whole ruleset with the given one, atomically, as a service reloading its
+    /// firewall would, so that no packet sees an empty or partial ruleset in between.
+    fn reload_nft_rules(&mut self, rules: &str) -> Result<(), io::Error> {
+        self.load_nft_rules(format!("flush ruleset\n{}", rules).as_bytes())
+    }
+    /// Like `load_nft_rules`, but with a `define` for each of the variables, so that the rules
+    /// can refer to them as e.g. `$lan_addr`, and with relative `include` paths resolved against
+    /// `include_dir`.
+    fn load_nft_rules_with_defines(
+        &mut self,
+        rules: &str,
+        defines: &HashMap<String, String>,
+        include_dir: &Path,
+    ) -> Result<(), io::Error> {
+        self.load_nft_rules(firewall::with_defines(rules, defines, include_dir)?.as_bytes())
+    }
+    /// Like `load_nft_rules`, but returns the commands as echoed by nft, with the handles of the
+    /// objects they added.
+    fn load_nft_rules_echo<R: io::Read + Send>(&mut self, rules: R) -> Result<String, io::Error>;
     fn list_nft_rules(&self) -> Result<String, io::Error>;
+    /// Like `list_nft_rules`, but in the JSON format of `nft -j`.
+    fn list_nft_rules_json(&self) -> Result<String, io::Error>;
+    /// Like `list_nft_rules`, but with the handle of each object in a comment, as by `nft -a`.
+    fn list_nft_rules_with_handles(&self) -> Result<String, io::Error>;
+    /// Apply the given nft commands with a single invocation of nft, which is much faster than
+    /// one invocation per command, and atomic: either all of the commands apply or none do.
+    fn apply_nft_batch(&mut self, commands: &[String]) -> Result<(), io::Error> {
+        self.load_nft_rules(commands.join("\n").as_bytes())
+    }
+    /// Whether the given chain has a rule containing the given text, as listed by nft. The table
+    /// includes its family, e.g. `inet filter`.
+    fn has_nft_rule(
+        &self,
+        table: &str,
+        chain: &str,
+        rule_substring: &str,
+    ) -> Result<bool, io::Error> {
+        Ok(nft_ruleset_has_rule(
+            &self.list_nft_rules()?,
+            table,
+            chain,
+            rule_substring,
+        ))
+    }
+
+    /// The values of every named counter in the ruleset, from a single listing, keyed by table
+    /// and name, e.g. `inet filter ssh`, so that several can be checked against one snapshot.
+    fn all_counters(&self) -> Result<HashMap<String, Counter>, io::Error> {
+        firewall::parse_json_counters(&self.list_nft_rules_json()?)
+    }
+
+    /// A handle for manipulating the host's nft ruleset.
+    fn firewall(&mut self) -> Firewall<'_, Self> {
+        Firewall::new(self)
+    }
+
+    fn conntrack(&self) -> Result<Vec<ConntrackEntry>, io::Error>;
+    fn conntrack_count(&self) -> Result<usize, io::Error>;
+    /// Delete every conntrack entry, as a restart that loses state would, so that established
+    /// connections are next seen as new.
+    fn flush_conntrack(&mut self) -> Result<(), io::Error>;
+
+    /// Capture the links, addresses, routes, neighbours and conntrack entries of the host, e.g.
+    /// to `diff` against a snapshot taken after a probe.
+    fn snapshot(&self) -> Result<StateSnapshot, io::Error>;
+
+    /// Deliver packets with the given mark locally, whatever their destination, so that a `tproxy`
+    /// rule can divert them to a transparent listener.
+    fn add_tproxy_route(&mut self, mark: u32) -> Result<(), io::Error>;
 
     fn input_path<'a>(
         interface: &'a mut Self::Interface,
         host: &'a Self,
     ) -> Result<Box<dyn ConnPath + 'a>, io::Error>;
+    /// Like `input_path`, but from the given source address, which need not be in the interface's
+    /// network. The host has no route back to the source unless one is added separately.
+    fn input_path_from<'a>(
+        interface: &'a mut Self::Interface,
+        host: &'a Self,
+        source_addr: IpAddr,
+    ) -> Result<Box<dyn ConnPath + 'a>, io::Error>;
+    /// A path from the host out of the interface. The client is pinned to the interface, so that
+    /// `oifname` rules see it even if the routing table would choose another interface.
     fn output_path<'a>(
         host: &'a Self,
         interface: &'a mut Self::Interface,
@@ -35,7 +129,43 @@ pub trait Host: Sized {
     fn forward_path<'a>(
         source_interface: &'a mut Self::Interface,
         target_interface: &'a mut Self::Interface,
+    ) -> Result<Box<dyn ConnPath + 'a>, io::Error> {
+        let gateway = source_interface.addr();
+        Self::forward_path_via(source_interface, target_interface, gateway)
+    }
+    /// Like `forward_path`, but the source routes via the given gateway instead of the host. The
+    /// gateway need not exist, nor be in the interface's network, so that a path can be
+    /// misconfigured deliberately.
+    fn forward_path_via<'a>(
+        source_interface: &'a mut Self::Interface,
+        target_interface: &'a mut Self::Interface,
+        gateway: IpAddr,
+    ) -> Result<Box<dyn ConnPath + 'a>, io::Error>;
+    /// Like `forward_path`, but from the given source address, which need not be in the network
+    /// of the source interface, e.g. a spoofed address to test egress filtering. The host has no
+    /// route back to the source unless one is added separately.
+    fn forward_path_from<'a>(
+        source_interface: &'a mut Self::Interface,
+        target_interface: &'a mut Self::Interface,
+        source_addr: IpAddr,
     ) -> Result<Box<dyn ConnPath + 'a>, io::Error>;
+    /// A path from the interface to an address beyond the host, which only succeeds if the host
+    /// diverts the connection to a transparent listener on the given port.
+    fn tproxy_path<'a>(
+        interface: &'a mut Self::Interface,
+        host: &'a Self,
+        original_addr: IpAddr,
+        listen_port: u16,
+    ) -> Result<Box<dyn ConnPath + 'a>, io::Error>;
+}
+
+/// The output format of a listing, e.g. of nft rules.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum OutputFormat {
+    /// The human-readable format
+    Text,
+    /// The JSON format produced with `-j`
+    Json,
 }
 
 pub trait Interface: Sized {
@@ -44,6 +174,77 @@ pub trait Interface: Sized {
     fn addr(&self) -> IpAddr {
         self.addr_with_net().addr()
     }
+    fn mac(&self) -> Result<[u8; 6], io::Error>;
+    fn peer_mac(&self) -> Result<[u8; 6], io::Error>;
+
+    /// Replace the egress qdisc on the host's end of the interface with an `htb` qdisc with
+    /// handle `1:` and the given classes.
+    ///
+    /// Unclassified packets bypass the classes. Packets are classified by their priority, e.g.
+    /// with `meta priority set 1:10`, or by their firewall mark if the class has one.
+    fn set_htb(&mut self, classes: Vec<HtbClass>) -> Result<(), io::Error>;
+    /// The number of packets sent through the given `htb` class.
+    fn htb_class_packets(&self, minor: u16) -> Result<u64, io::Error>;
+
+    /// Set the ephemeral port range of the peer, from which clients on paths from the interface
+    /// take their source ports, e.g. to test a rule that matches the source port.
+    fn set_peer_local_port_range(&mut self, first: u16, last: u16) -> Result<(), io::Error>;
+
+    /// Set the MTU of the host's end of the interface, which may differ from the peer's, e.g.
+    /// to model a link with a smaller MTU than the networks either side of it, as a router sees
+    /// it.
+    fn set_mtu(&mut self, mtu: u32) -> Result<(), io::Error>;
+
+    /// The operational state of the host's end of the interface, which has carrier only if both
+    /// ends are up.
+    fn oper_state(&self) -> Result<OperState, io::Error>;
+    /// Fail unless the interface is `OperState::Up`, since a probe over a link without carrier
+    /// is unreachable for reasons unrelated to the firewall.
+    fn check_carrier(&self) -> Result<(), io::Error> {
+        match self.oper_state()? {
+            OperState::Up => Ok(()),
+            state => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("Interface {} is {:?}", self.name(), state),
+            )),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum OperState {
+    Up,
+    /// The interface is administratively down.
+    Down,
+    /// The interface is up, but has no carrier, e.g. because the other end of a veth is down.
+    LowerLayerDown,
+}
+
+/// A class of an `htb` qdisc, with class id `1:<minor>`, where the minor number is hexadecimal in
+/// the notation of `tc` and `nft`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct HtbClass {
+    pub minor: u16,
+    pub rate_kbit: u64,
+    /// Classify packets with this firewall mark into the class, e.g. those of a `Probe::fwmark`.
+    pub fwmark: Option<u32>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ConntrackEntry {
+    pub l4proto: String,
+    pub state: Option<String>,
+    pub orig: ConntrackTuple,
+    pub reply: ConntrackTuple,
+    pub mark: u32,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ConntrackTuple {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub sport: Option<u16>,
+    pub dport: Option<u16>,
 }
 
 fn random_peer_addr_with_net(addr_with_net: IpNet) -> IpNet {
@@ -72,3 +273,146 @@ fn random_peer_addr_with_net(addr_with_net: IpNet) -> IpNet {
         }
     }
 }
+
+fn nft_ruleset_has_rule(ruleset: &str, table: &str, chain: &str, rule_substring: &str) -> bool {
+    let table_header = format!("table {} {{", table);
+    let chain_header = format!("chain {} {{", chain);
+    let mut depth = 0;
+    let mut in_table = false;
+    let mut in_chain = false;
+    for line in ruleset.lines().map(str::trim) {
+        match depth {
+            0 => in_table = line == table_header,
+            1 => in_chain = in_table && line == chain_header,
+            // The chain's declaration, e.g. `type filter hook input priority filter; policy drop;`,
+            // is not a rule
+            2 if line.starts_with("type ") || line.starts_with("policy ") => {}
+            2 if in_chain && line.contains(rule_substring) => return true,
+            _ => {}
+        }
+        depth = nft_depth_after(depth, line);
+    }
+    false
+}
+
+/// The nesting depth of a ruleset in the format of `nft list` after the given line, counting only
+/// the braces outside quoted strings, e.g. not those in a rule's `comment "..."`.
+pub(crate) fn nft_depth_after(mut depth: usize, line: &str) -> usize {
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '{' if !quoted => depth += 1,
+            '}' if !quoted => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+
+    #[test]
+    fn has_nft_rule() {
+        let ruleset = indoc! {r#"
+            table inet filter {
+                set blocked {
+                    type ipv4_addr
+                }
+
+                chain input {
+                    type filter hook input priority filter; policy accept;
+                    tcp dport { 22, 80 } accept
+                    ip saddr @blocked drop
+                }
+
+                chain forward {
+                    type filter hook forward priority filter; policy accept;
+                }
+
+                chain output {
+                    type filter hook output priority filter; policy drop;
+                    meta l4proto udp accept comment "not }"
+                }
+            }
+            table ip nat {
+                chain input {
+                    udp dport 53 accept
+                }
+            }
+        "#};
+        assert!(nft_ruleset_has_rule(
+            ruleset,
+            "inet filter",
+            "input",
+            "tcp dport"
+        ));
+        assert!(nft_ruleset_has_rule(
+            ruleset,
+            "inet filter",
+            "input",
+            "@blocked drop"
+        ));
+        assert!(!nft_ruleset_has_rule(
+            ruleset,
+            "inet filter",
+            "forward",
+            "tcp dport"
+        ));
+        assert!(!nft_ruleset_has_rule(
+            ruleset,
+            "inet filter",
+            "input",
+            "udp dport"
+        ));
+        assert!(!nft_ruleset_has_rule(
+            ruleset,
+            "inet filter",
+            "blocked",
+            "ipv4_addr"
+        ));
+        assert!(nft_ruleset_has_rule(
+            ruleset,
+            "ip nat",
+            "input",
+            "udp dport"
+        ));
+        assert!(!nft_ruleset_has_rule(
+            ruleset,
+            "ip6 nat",
+            "input",
+            "udp dport"
+        ));
+        // The policy is not a rule
+        assert!(!nft_ruleset_has_rule(
+            ruleset,
+            "inet filter",
+            "output",
+            "drop"
+        ));
+        assert!(!nft_ruleset_has_rule(
+            ruleset,
+            "inet filter",
+            "forward",
+            "filter"
+        ));
+        assert!(nft_ruleset_has_rule(
+            ruleset,
+            "inet filter",
+            "output",
+            "udp accept"
+        ));
+    }
+
+    #[test]
+    fn nft_depth_ignores_quoted_braces() {
+        assert_eq!(2, nft_depth_after(1, "chain input {"));
+        assert_eq!(2, nft_depth_after(2, "tcp dport { 22, 80 } accept"));
+        assert_eq!(2, nft_depth_after(2, r#"accept comment "}""#));
+        assert_eq!(0, nft_depth_after(0, "}"));
+    }
+}
@@ -1,423 +1,4819 @@
 use std::prelude::v1::*;
 
+use futures::channel::mpsc;
+use futures::StreamExt;
 use ipnet::IpNet;
 use log::*;
+use std::collections::HashSet;
 use std::ffi::OsStr;
 use std::fs;
-use std::io;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::conn::os::OsNsConnPath;
+use crate::conn::{Probe, ProbeOutcome};
+use crate::host::firewall::{
+    final_verdict, parse_trace_event, spec_match, Hook, TraceEvent, TracedOutcome,
+};
+use crate::host::libnftables;
 use crate::host::*;
 use crate::os::OsNs;
+use indoc::formatdoc;
+
+const TPROXY_ROUTE_TABLE: &str = "100";
+const NFTRACE_TABLE: &str = "inet firebreak_nftrace";
+/// The chain of the nftrace table that traces marker datagrams, sent on loopback to the discard
+/// port, which show when `nft monitor trace` has reported everything before them.
+const NFTRACE_MARKER_CHAIN: &str = "marker";
+const NFTRACE_MARKER_PORT: u16 = 9;
+const NFTRACE_MARKER_INTERVAL: Duration = Duration::from_millis(10);
+const NFTRACE_MONITOR_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNTRACK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// The IANA port for VXLAN, rather than Linux's legacy default of 8472.
+pub const VXLAN_PORT: u16 = 4789;
 
 #[derive(Debug)]
 pub struct OsHost {
     name: String,
     ns: OsNs,
+    nets: Nets,
+    nft: NftBackend,
+}
+
+/// The networks of a host's interfaces, by interface name, which each interface releases when it
+/// is dropped.
+type Nets = Arc<Mutex<Vec<(String, IpNet)>>>;
+
+/// An interface's claim on the networks recorded under its name, which are released when it is
+/// dropped.
+#[derive(Debug)]
+struct NetClaim {
+    nets: Nets,
+    name: String,
+}
+
+/// How an `OsHost` loads and lists its nft ruleset.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum NftBackend {
+    /// Run the nft binary in the host's namespace.
+    Cli,
+    /// Call libnftables, the library behind the nft binary, from a thread in the host's
+    /// namespace, for environments with the library but not the binary. It accepts and produces
+    /// the same syntax. `OsHost::trace_probe` still requires the binary.
+    Library,
 }
 
 #[derive(Debug)]
 pub struct OsInterface {
     name: String,
+    ns: OsNs,
     addr_with_net: IpNet,
     peer_name: String,
     peer_ns: OsNs,
+    transit: Option<Transit>,
+    /// Addresses of the peer that are tunnel endpoints, which it keeps whenever it is assigned a
+    /// fresh address.
+    peer_endpoints: Vec<IpNet>,
+    /// The interface's address in the other address family, if it was created with
+    /// `OsHost::new_dual_stack_interface`, and the peer's, once it is assigned.
+    other_family: Option<(IpNet, Option<IpNet>)>,
+    claim: NetClaim,
 }
 
-impl Host for OsHost {
-    type Interface = OsInterface;
+/// The addressing of an interface created with `OsHost::new_transit_interface`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct Transit {
+    peer_addr_with_net: IpNet,
+    remote_net: IpNet,
+}
 
-    fn new(name: String) -> Result<Self, io::Error> {
-        let mut ns = OsNs::new_net()?;
-        ns.enable_link("lo")?;
-        ns.enable_ipv4_forwarding()?;
-        ns.enable_ipv6_forwarding()?;
-        Ok(OsHost { name, ns })
-    }
+/// One end of a direct link between two hosts, created with `OsHost::connect_to`.
+#[derive(Debug)]
+pub struct Link {
+    host_name: String,
+    name: String,
+    ns: OsNs,
+    addr_with_net: IpNet,
+    peer_host_name: String,
+    peer_ns: OsNs,
+    peer_addr_with_net: IpNet,
+    _claim: NetClaim,
+}
 
-    fn name(&self) -> &str {
+impl Link {
+    /// The name of the link in this end's host, which is the name of the other host.
+    pub fn name(&self) -> &str {
         &self.name
     }
 
-    fn new_interface(
-        &mut self,
-        name: String,
-        addr_with_net: IpNet,
-    ) -> Result<Self::Interface, io::Error> {
-        let peer_name = self.name.clone();
-        let mut peer_ns = OsNs::new_net()?;
-        peer_ns.enable_link("lo")?;
-
-        self.ns.add_veth_link(&name, &peer_name)?;
-        self.ns.move_link(&peer_name, &mut peer_ns)?;
+    pub fn addr_with_net(&self) -> IpNet {
+        self.addr_with_net
+    }
 
-        self.ns.enable_link(&name)?;
-        self.ns.set_addr(&name, addr_with_net)?;
+    /// A path from this end's host to the other end's host, over the link.
+    pub fn path(&self) -> Box<dyn ConnPath + '_> {
+        Box::new(
+            OsNsConnPath::new(
+                &self.host_name,
+                &self.ns,
+                self.addr_with_net.addr(),
+                &self.peer_host_name,
+                &self.peer_ns,
+                self.peer_addr_with_net.addr(),
+            )
+            .with_source_device(&self.name),
+        )
+    }
+}
 
-        peer_ns.enable_link(&peer_name)?;
+/// The mode of an ipvlan interface.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum IpvlanMode {
+    /// Switch frames between the ipvlan and the parent's link, bypassing the host's firewall.
+    L2,
+    /// Route packets in the host's namespace, which filters them in its output hook but not its
+    /// input hook, and answer no neighbour solicitations, so peers must route to the address.
+    L3,
+}
 
-        Ok(OsInterface {
-            name,
-            addr_with_net,
-            peer_name,
-            peer_ns,
-        })
+impl IpvlanMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            IpvlanMode::L2 => "l2",
+            IpvlanMode::L3 => "l3",
+        }
     }
+}
 
-    fn load_nft_rules<R: io::Read + Send>(&mut self, rules: R) -> Result<(), io::Error> {
-        self.ns.load_nft_rules(rules)
+/// An ipvlan interface created with `OsHost::add_ipvlan`, in a namespace of its own, as in a
+/// container, which shares the MAC of its parent interface.
+#[derive(Debug)]
+pub struct Ipvlan {
+    name: String,
+    ns: OsNs,
+    addr_with_net: IpNet,
+    mode: IpvlanMode,
+    parent_addr: IpAddr,
+}
+
+impl Ipvlan {
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
-    fn list_nft_rules(&self) -> Result<String, io::Error> {
-        self.ns.list_nft_rules()
+    pub fn addr_with_net(&self) -> IpNet {
+        self.addr_with_net
     }
 
-    fn input_path<'a>(
-        interface: &'a mut Self::Interface,
-        host: &'a Self,
+    /// A path from the ipvlan's namespace to the peer of its parent, e.g. from a container to
+    /// the network outside its host.
+    pub fn path_to_peer<'a>(
+        &'a self,
+        parent: &'a mut OsInterface,
     ) -> Result<Box<dyn ConnPath + 'a>, io::Error> {
-        let peer_addr_with_net = random_peer_addr_with_net(interface.addr_with_net);
-        interface
-            .peer_ns
-            .set_addr(&interface.peer_name, peer_addr_with_net)?;
-        trace!("{}:\n{}", interface.name, interface.peer_ns.list_addrs()?);
-        trace!("{}:\n{}", host.name, host.ns.list_addrs()?);
+        let peer_addr = self.prepare_peer(parent)?;
         Ok(Box::new(OsNsConnPath::new(
-            &interface.name,
-            &interface.peer_ns,
-            peer_addr_with_net.addr(),
-            &host.name,
-            &host.ns,
-            interface.addr_with_net.addr(),
+            &self.name,
+            &self.ns,
+            self.addr_with_net.addr(),
+            &parent.name,
+            &parent.peer_ns,
+            peer_addr,
         )))
     }
 
-    fn output_path<'a>(
-        host: &'a Self,
-        interface: &'a mut Self::Interface,
+    /// A path from the peer of the parent to the ipvlan's namespace.
+    pub fn path_from_peer<'a>(
+        &'a self,
+        parent: &'a mut OsInterface,
     ) -> Result<Box<dyn ConnPath + 'a>, io::Error> {
-        let peer_addr_with_net = random_peer_addr_with_net(interface.addr_with_net);
-        interface
-            .peer_ns
-            .set_addr(&interface.peer_name, peer_addr_with_net)?;
-        trace!("{}:\n{}", host.name, host.ns.list_addrs()?);
-        trace!("{}:\n{}", interface.name, interface.peer_ns.list_addrs()?);
+        let peer_addr = self.prepare_peer(parent)?;
         Ok(Box::new(OsNsConnPath::new(
-            &host.name,
-            &host.ns,
-            interface.addr_with_net.addr(),
-            &interface.name,
-            &interface.peer_ns,
-            peer_addr_with_net.addr(),
+            &parent.name,
+            &parent.peer_ns,
+            peer_addr,
+            &self.name,
+            &self.ns,
+            self.addr_with_net.addr(),
         )))
     }
 
-    fn forward_path<'a>(
-        source_interface: &'a mut Self::Interface,
-        target_interface: &'a mut Self::Interface,
-    ) -> Result<Box<dyn ConnPath + 'a>, io::Error> {
-        let source_peer_addr_with_net = random_peer_addr_with_net(source_interface.addr_with_net);
-        source_interface
-            .peer_ns
-            .set_addr(&source_interface.peer_name, source_peer_addr_with_net)?;
-        source_interface
-            .peer_ns
-            .set_default_route(source_interface.addr_with_net.addr())?;
-        let target_peer_addr_with_net = random_peer_addr_with_net(target_interface.addr_with_net);
-        target_interface
-            .peer_ns
-            .set_addr(&target_interface.peer_name, target_peer_addr_with_net)?;
-        target_interface
-            .peer_ns
-            .set_default_route(target_interface.addr_with_net.addr())?;
-        trace!(
-            "{}:\n{}",
-            source_interface.name,
-            source_interface.peer_ns.list_addrs()?
-        );
-        trace!(
-            "{}:\n{}",
-            target_interface.name,
-            target_interface.peer_ns.list_addrs()?
-        );
-        Ok(Box::new(OsNsConnPath::new(
-            &source_interface.name,
-            &source_interface.peer_ns,
-            source_peer_addr_with_net.addr(),
-            &target_interface.name,
-            &target_interface.peer_ns,
-            target_peer_addr_with_net.addr(),
-        )))
+    /// Assign the parent's peer an address, and a route to the ipvlan if it cannot be resolved.
+    fn prepare_peer(&self, parent: &mut OsInterface) -> Result<IpAddr, io::Error> {
+        parent.check_carrier()?;
+        let peer_addr = parent.assign_peer_addr()?.addr();
+        if self.mode == IpvlanMode::L3 {
+            parent.peer_ns.scoped_process(
+                "ip",
+                &[
+                    "route",
+                    "replace",
+                    &IpNet::from(self.addr_with_net.addr()).to_string(),
+                    "via",
+                    &self.parent_addr.to_string(),
+                    "dev",
+                    &parent.peer_name,
+                ],
+            )?;
+        }
+        trace!("{}:\n{}", self.name, self.ns.list_addrs()?);
+        Ok(peer_addr)
     }
 }
 
-impl Interface for OsInterface {
-    fn name(&self) -> &str {
+/// The mode of a macvlan interface.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MacvlanMode {
+    /// Switch frames between macvlans on the same parent, as well as to the parent's link.
+    Bridge,
+    /// Drop frames between macvlans on the same parent, even if the parent's link reflects them.
+    Private,
+}
+
+impl MacvlanMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            MacvlanMode::Bridge => "bridge",
+            MacvlanMode::Private => "private",
+        }
+    }
+}
+
+/// A macvlan interface created with `OsHost::add_macvlan`, in a namespace of its own, as in a
+/// container, with a MAC distinct from that of its parent interface.
+#[derive(Debug)]
+pub struct Macvlan {
+    name: String,
+    ns: OsNs,
+    addr_with_net: IpNet,
+}
+
+impl Macvlan {
+    pub fn name(&self) -> &str {
         &self.name
     }
 
-    fn addr_with_net(&self) -> IpNet {
+    pub fn addr_with_net(&self) -> IpNet {
         self.addr_with_net
     }
+
+    pub fn mac(&self) -> Result<[u8; 6], io::Error> {
+        self.ns.link_mac(&self.name)
+    }
+
+    /// A path from the macvlan's namespace to the peer of its parent, e.g. from a container to
+    /// the network outside its host.
+    pub fn path_to_peer<'a>(
+        &'a self,
+        parent: &'a mut OsInterface,
+    ) -> Result<Box<dyn ConnPath + 'a>, io::Error> {
+        parent.check_carrier()?;
+        let peer_addr = parent.assign_peer_addr()?.addr();
+        Ok(Box::new(OsNsConnPath::new(
+            &self.name,
+            &self.ns,
+            self.addr_with_net.addr(),
+            &parent.name,
+            &parent.peer_ns,
+            peer_addr,
+        )))
+    }
+
+    /// A path from the peer of the parent to the macvlan's namespace.
+    pub fn path_from_peer<'a>(
+        &'a self,
+        parent: &'a mut OsInterface,
+    ) -> Result<Box<dyn ConnPath + 'a>, io::Error> {
+        parent.check_carrier()?;
+        let peer_addr = parent.assign_peer_addr()?.addr();
+        Ok(Box::new(OsNsConnPath::new(
+            &parent.name,
+            &parent.peer_ns,
+            peer_addr,
+            &self.name,
+            &self.ns,
+            self.addr_with_net.addr(),
+        )))
+    }
+
+    /// A path to another macvlan on the same parent, which only connects in `Bridge` mode.
+    pub fn path_to<'a>(&'a self, other: &'a Macvlan) -> Box<dyn ConnPath + 'a> {
+        Box::new(OsNsConnPath::new(
+            &self.name,
+            &self.ns,
+            self.addr_with_net.addr(),
+            &other.name,
+            &other.ns,
+            other.addr_with_net.addr(),
+        ))
+    }
 }
 
-impl OsNs {
-    fn enable_ipv4_forwarding(&mut self) -> Result<(), io::Error> {
-        self.scoped(|| fs::write("/proc/sys/net/ipv4/conf/all/forwarding", "1"))?;
-        Ok(())
+/// The overlay of a VXLAN tunnel created with `OsHost::add_vxlan`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct VxlanConfig {
+    /// The VXLAN network identifier, which both ends of the tunnel share
+    pub vni: u32,
+    /// The host's address on the overlay network
+    pub addr_with_net: IpNet,
+    /// The peer's endpoint on the underlay network, which must be in the underlay's network
+    pub remote: IpAddr,
+}
+
+/// The overlay of a WireGuard tunnel created with `OsHost::add_wireguard`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct WireguardConfig {
+    /// The host's address on the overlay network
+    pub addr_with_net: IpNet,
+    /// The UDP port on which both ends of the tunnel listen on the underlay network
+    pub listen_port: u16,
+}
+
+impl OsHost {
+    /// Like `Host::new`, but with the given nft backend.
+    pub fn with_nft_backend(name: String, nft: NftBackend) -> Result<OsHost, io::Error> {
+        let mut ns = OsNs::new_net()?;
+        ns.enable_link("lo")?;
+        Ok(OsHost {
+            name,
+            ns,
+            nets: Nets::default(),
+            nft,
+        })
     }
 
-    fn enable_ipv6_forwarding(&mut self) -> Result<(), io::Error> {
-        self.scoped(|| fs::write("/proc/sys/net/ipv6/conf/all/forwarding", "1"))?;
-        Ok(())
+    /// Run nft commands with libnftables in the host's namespace.
+    fn run_libnftables<R: io::Read + Send>(
+        &self,
+        mut rules: R,
+        output_flags: u32,
+    ) -> Result<String, io::Error> {
+        let mut commands = String::new();
+        rules.read_to_string(&mut commands)?;
+        self.ns.scoped(|| libnftables::run(&commands, output_flags))
     }
 
-    fn add_veth_link(&mut self, name: &str, peer_name: &str) -> Result<(), io::Error> {
-        self.scoped_process(
-            "ip",
-            &[
-                "link", "add", name, "type", "veth", "peer", "name", peer_name,
-            ],
+    /// Connect the host directly to another host with a veth, rather than via a peer namespace,
+    /// e.g. to test two firewalls that filter each other. The addresses are normally in the same
+    /// network.
+    ///
+    /// Each end of the link is named after the host at the other end.
+    pub fn connect_to(
+        &mut self,
+        other: &mut OsHost,
+        self_addr_with_net: IpNet,
+        other_addr_with_net: IpNet,
+    ) -> Result<(Link, Link), io::Error> {
+        let name = other.name.clone();
+        let other_link_name = self.name.clone();
+        self.check_nets(&[(&name, self_addr_with_net)])?;
+        other.check_nets(&[(&other_link_name, other_addr_with_net)])?;
+
+        self.ns.add_veth_link(&name, &other_link_name)?;
+        self.ns.move_link(&other_link_name, &mut other.ns)?;
+        for (ns, name, addr_with_net) in &mut [
+            (&mut self.ns, &name, self_addr_with_net),
+            (&mut other.ns, &other_link_name, other_addr_with_net),
+        ] {
+            ns.disable_ipv6_autoconf(name)?;
+            ns.enable_link(name)?;
+            ns.set_addr(name, *addr_with_net)?;
+        }
+
+        let link = Link {
+            _claim: self.claim_nets(&name, &[self_addr_with_net]),
+            host_name: self.name.clone(),
+            name,
+            ns: self.ns.try_clone()?,
+            addr_with_net: self_addr_with_net,
+            peer_host_name: other.name.clone(),
+            peer_ns: other.ns.try_clone()?,
+            peer_addr_with_net: other_addr_with_net,
+        };
+        let other_link = Link {
+            _claim: other.claim_nets(&other_link_name, &[other_addr_with_net]),
+            host_name: other.name.clone(),
+            name: other_link_name,
+            ns: other.ns.try_clone()?,
+            addr_with_net: other_addr_with_net,
+            peer_host_name: self.name.clone(),
+            peer_ns: self.ns.try_clone()?,
+            peer_addr_with_net: self_addr_with_net,
+        };
+        Ok((link, other_link))
+    }
+
+    /// Create an interface on a transit network, such as a /30 or /31, whose peer is a router for
+    /// the remote network, e.g. to model `router1 --transit-- router2` where router2 has its own
+    /// LAN. The host routes the remote network via the peer's transit address, and paths over
+    /// the interface use a source or target address in the remote network rather than in the
+    /// transit network.
+    pub fn new_transit_interface(
+        &mut self,
+        name: String,
+        addr_with_net: IpNet,
+        peer_addr_with_net: IpNet,
+        remote_net: IpNet,
+    ) -> Result<OsInterface, io::Error> {
+        if peer_addr_with_net.trunc() != addr_with_net.trunc()
+            || peer_addr_with_net == addr_with_net
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Peer address {} of interface {} is not another address in {}",
+                    peer_addr_with_net,
+                    name,
+                    addr_with_net.trunc()
+                ),
+            ));
+        }
+        self.check_nets(&[(&name, addr_with_net), (&name, remote_net)])?;
+        let peer_ns = OsNs::new_net()?;
+        let mut interface = self.add_interface_with_peer(name, addr_with_net, peer_ns)?;
+        self.ns.add_route_with_src(
+            remote_net.trunc(),
+            Some(peer_addr_with_net.addr()),
+            addr_with_net.addr(),
+            &interface.name,
         )?;
-        Ok(())
+        interface.transit = Some(Transit {
+            peer_addr_with_net,
+            remote_net,
+        });
+        interface.claim.add(remote_net);
+        Ok(interface)
     }
 
-    fn enable_link(&mut self, name: &str) -> Result<(), io::Error> {
-        self.scoped_process("ip", &["link", "set", name, "up"])?;
-        Ok(())
+    /// Create an ipvlan interface on the parent interface, in a new namespace, as a container
+    /// network would. In `L2` mode the address must be in the parent's network, whereas in `L3`
+    /// mode it may be anywhere, since the parent's peer routes to it via the parent.
+    pub fn add_ipvlan(
+        &mut self,
+        name: String,
+        parent: &OsInterface,
+        mode: IpvlanMode,
+        addr_with_net: IpNet,
+    ) -> Result<Ipvlan, io::Error> {
+        if mode == IpvlanMode::L2 && !parent.addr_with_net.contains(&addr_with_net.addr()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Address {} of l2 ipvlan {} is not in network {} of {}",
+                    addr_with_net,
+                    name,
+                    parent.addr_with_net.trunc(),
+                    parent.name
+                ),
+            ));
+        }
+        let ns = self.add_container_link(&name, parent, "ipvlan", mode.as_str(), addr_with_net)?;
+        if mode == IpvlanMode::L3 {
+            // Nothing answers neighbour solicitations, so everything is on link
+            ns.scoped_process("ip", &["route", "add", "default", "dev", &name])?;
+        }
+        Ok(Ipvlan {
+            name,
+            ns,
+            addr_with_net,
+            mode,
+            parent_addr: parent.addr_with_net.addr(),
+        })
     }
 
-    fn move_link(&mut self, name: &str, other: &mut Self) -> Result<(), io::Error> {
-        self.scoped_process(
+    /// Create a macvlan interface on the parent interface, in a new namespace, as a container
+    /// network would. The address must be in the parent's network, so that the parent's peer
+    /// can resolve it.
+    pub fn add_macvlan(
+        &mut self,
+        name: String,
+        parent: &OsInterface,
+        mode: MacvlanMode,
+        addr_with_net: IpNet,
+    ) -> Result<Macvlan, io::Error> {
+        if !parent.addr_with_net.contains(&addr_with_net.addr()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Address {} of macvlan {} is not in network {} of {}",
+                    addr_with_net,
+                    name,
+                    parent.addr_with_net.trunc(),
+                    parent.name
+                ),
+            ));
+        }
+        let ns = self.add_container_link(&name, parent, "macvlan", mode.as_str(), addr_with_net)?;
+        Ok(Macvlan {
+            name,
+            ns,
+            addr_with_net,
+        })
+    }
+
+    /// Create a link of the given type and mode on the parent interface, and move it into a new
+    /// namespace with the given address.
+    fn add_container_link(
+        &mut self,
+        name: &str,
+        parent: &OsInterface,
+        link_type: &str,
+        mode: &str,
+        addr_with_net: IpNet,
+    ) -> Result<OsNs, io::Error> {
+        let mut ns = OsNs::new_net()?;
+        ns.enable_link("lo")?;
+        self.ns.scoped_process(
             "ip",
             &[
-                OsStr::new("link"),
-                OsStr::new("set"),
-                OsStr::new(name),
-                OsStr::new("netns"),
-                other.path().as_os_str(),
+                "link",
+                "add",
+                "link",
+                &parent.name,
+                "name",
+                name,
+                "type",
+                link_type,
+                "mode",
+                mode,
             ],
         )?;
-        Ok(())
+        self.ns.record_link(name);
+        self.ns.move_link(name, &mut ns)?;
+        ns.disable_ipv6_autoconf(name)?;
+        ns.enable_link(name)?;
+        ns.set_addr(name, addr_with_net)?;
+        Ok(ns)
     }
 
-    fn set_addr(&mut self, name: &str, addr: IpNet) -> Result<(), io::Error> {
-        self.scoped_process("ip", &["address", "flush", "dev", name])?;
-        match addr {
-            IpNet::V4(_) => {
-                self.scoped_process("ip", &["address", "add", &addr.to_string(), "dev", name])?
-            }
-            IpNet::V6(_) => {
-                // Disable duplicate address detection (DAD) so we can immediately bind the address
-                self.scoped_process(
-                    "ip",
-                    &["address", "add", &addr.to_string(), "dev", name, "nodad"],
-                )?
-            }
+    /// Fail if the network of a new interface overlaps that of another interface, since then
+    /// routing would choose between them arbitrarily. The networks are only claimed, with
+    /// `claim_nets`, once the interface has been set up.
+    fn check_nets(&self, nets: &[(&str, IpNet)]) -> Result<(), io::Error> {
+        check_nets(&self.nets.lock().unwrap(), nets)
+    }
+
+    /// Record the networks of a new interface, until the returned claim is dropped.
+    fn claim_nets(&self, name: &str, nets: &[IpNet]) -> NetClaim {
+        let claim = NetClaim {
+            nets: self.nets.clone(),
+            name: name.to_owned(),
         };
+        for &net in nets {
+            claim.add(net);
+        }
+        claim
+    }
+
+    /// Create a WireGuard tunnel between the host and the peer of the underlay interface, with
+    /// freshly generated keys, and return it as an interface that can be probed like any other.
+    ///
+    /// The peer's underlay address is assigned now, so that the tunnel has an endpoint. It is
+    /// reassigned if a path is later created over the underlay, which WireGuard tolerates.
+    pub fn add_wireguard(
+        &mut self,
+        name: String,
+        underlay: &mut OsInterface,
+        config: WireguardConfig,
+    ) -> Result<OsInterface, io::Error> {
+        self.check_nets(&[(&name, config.addr_with_net)])?;
+        let peer_name = name.clone();
+        let mut peer_ns = underlay.peer_ns.try_clone()?;
+        let underlay_peer_addr_with_net = random_peer_addr_with_net(underlay.addr_with_net);
+        peer_ns.set_addr(&underlay.peer_name, underlay_peer_addr_with_net)?;
+
+        let private_key = self.ns.scoped_process("wg", &["genkey"])?;
+        let peer_private_key = self.ns.scoped_process("wg", &["genkey"])?;
+        let wg_pubkey = |key: &str| {
+            self.ns
+                .scoped_process_with_input("wg", &["pubkey"], key.as_bytes())
+                .map(|key| key.trim().to_owned())
+        };
+        let public_key = wg_pubkey(&private_key)?;
+        let peer_public_key = wg_pubkey(&peer_private_key)?;
+
+        let wg_conf = formatdoc! {
+            "
+                [Interface]
+                PrivateKey = {private_key}
+                ListenPort = {listen_port}
+
+                [Peer]
+                PublicKey = {peer_public_key}
+                AllowedIPs = {overlay_net}
+                Endpoint = {endpoint}
+            ",
+            private_key = private_key.trim(),
+            listen_port = config.listen_port,
+            peer_public_key = peer_public_key,
+            overlay_net = config.addr_with_net.trunc(),
+            endpoint = SocketAddr::new(underlay_peer_addr_with_net.addr(), config.listen_port),
+        };
+        self.ns.add_wireguard_link(&name, &wg_conf)?;
+        self.ns.enable_link(&name)?;
+        self.ns.set_addr(&name, config.addr_with_net)?;
+
+        // The peer accepts any source, so that it can be used in forwarding paths
+        let peer_wg_conf = formatdoc! {
+            "
+                [Interface]
+                PrivateKey = {private_key}
+                ListenPort = {listen_port}
+
+                [Peer]
+                PublicKey = {public_key}
+                AllowedIPs = 0.0.0.0/0, ::/0
+                Endpoint = {endpoint}
+            ",
+            private_key = peer_private_key.trim(),
+            listen_port = config.listen_port,
+            public_key = public_key,
+            endpoint = SocketAddr::new(underlay.addr_with_net.addr(), config.listen_port),
+        };
+        peer_ns.add_wireguard_link(&peer_name, &peer_wg_conf)?;
+        peer_ns.enable_link(&peer_name)?;
+
+        Ok(OsInterface {
+            ns: self.ns.try_clone()?,
+            addr_with_net: config.addr_with_net,
+            peer_name,
+            peer_ns,
+            transit: None,
+            peer_endpoints: vec![],
+            other_family: None,
+            claim: self.claim_nets(&name, &[config.addr_with_net]),
+            name,
+        })
+    }
+
+    /// Create a VXLAN tunnel between the host and the peer of the underlay interface, on
+    /// `VXLAN_PORT`, and return it as an interface that can be probed like any other. The host's
+    /// endpoint is its underlay address.
+    ///
+    /// The peer keeps its endpoint when paths are created over the underlay, so the underlay's
+    /// firewall, e.g. for UDP to `VXLAN_PORT`, can be probed separately from the overlay's.
+    pub fn add_vxlan(
+        &mut self,
+        name: String,
+        underlay: &mut OsInterface,
+        config: VxlanConfig,
+    ) -> Result<OsInterface, io::Error> {
+        let local = underlay.addr_with_net.addr();
+        if !underlay.addr_with_net.contains(&config.remote) || config.remote == local {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Remote {} of VXLAN {} is not a peer address in network {} of {}",
+                    config.remote,
+                    name,
+                    underlay.addr_with_net.trunc(),
+                    underlay.name
+                ),
+            ));
+        }
+        self.check_nets(&[(&name, config.addr_with_net)])?;
+        let peer_name = name.clone();
+        let mut peer_ns = underlay.peer_ns.try_clone()?;
+        let remote_with_net = format!("{}/{}", config.remote, underlay.addr_with_net.prefix_len())
+            .parse()
+            .expect("Prefix len is known to be valid");
+        underlay.peer_endpoints.push(remote_with_net);
+        underlay.assign_peer_addr()?;
+
+        self.ns
+            .add_vxlan_link(&name, config.vni, local, config.remote, &underlay.name)?;
+        self.ns.disable_ipv6_autoconf(&name)?;
+        self.ns.enable_link(&name)?;
+        self.ns.set_addr(&name, config.addr_with_net)?;
+
+        peer_ns.add_vxlan_link(
+            &peer_name,
+            config.vni,
+            config.remote,
+            local,
+            &underlay.peer_name,
+        )?;
+        peer_ns.disable_ipv6_autoconf(&peer_name)?;
+        peer_ns.enable_link(&peer_name)?;
+
+        Ok(OsInterface {
+            ns: self.ns.try_clone()?,
+            addr_with_net: config.addr_with_net,
+            peer_name,
+            peer_ns,
+            transit: None,
+            peer_endpoints: vec![],
+            other_family: None,
+            claim: self.claim_nets(&name, &[config.addr_with_net]),
+            name,
+        })
+    }
+
+    /// Like `new_interface`, but also give the interface and its peer addresses in the other
+    /// address family, so that its paths can be probed over both, e.g. with
+    /// `ConnPath::connect_happy_eyeballs`.
+    pub fn new_dual_stack_interface(
+        &mut self,
+        name: String,
+        addr_with_net: IpNet,
+        other_addr_with_net: IpNet,
+    ) -> Result<OsInterface, io::Error> {
+        if addr_with_net.addr().is_ipv6() == other_addr_with_net.addr().is_ipv6() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Addresses {} and {} of interface {} are in the same address family",
+                    addr_with_net, other_addr_with_net, name
+                ),
+            ));
+        }
+        self.check_nets(&[(&name, addr_with_net), (&name, other_addr_with_net)])?;
+        let peer_ns = OsNs::new_net()?;
+        let mut interface = self.add_interface_with_peer(name, addr_with_net, peer_ns)?;
+        self.ns
+            .set_addrs(&interface.name, &[addr_with_net, other_addr_with_net])?;
+        interface.other_family = Some((other_addr_with_net, None));
+        interface.claim.add(other_addr_with_net);
+        Ok(interface)
+    }
+
+    /// Like `new_interface` for each of the given interfaces, but create their peer namespaces
+    /// concurrently with `OsNs::new_nets`.
+    pub fn new_interfaces(
+        &mut self,
+        interfaces: Vec<(String, IpNet)>,
+    ) -> Result<Vec<OsInterface>, io::Error> {
+        let nets: Vec<_> = interfaces
+            .iter()
+            .map(|(name, addr_with_net)| (name.as_str(), *addr_with_net))
+            .collect();
+        self.check_nets(&nets)?;
+        let peer_nss = OsNs::new_nets(interfaces.len())?;
+        interfaces
+            .into_iter()
+            .zip(peer_nss)
+            .map(|((name, addr_with_net), peer_ns)| {
+                self.add_interface_with_peer(name, addr_with_net, peer_ns)
+            })
+            .collect()
+    }
+
+    /// Load the kernel module of the given conntrack helper, e.g. `ftp`, so that rules can assign
+    /// it with `ct helper set`. Modules are global, so this affects every namespace.
+    pub fn load_conntrack_helper(&self, helper: &str) -> Result<(), io::Error> {
+        self.ns
+            .scoped_process("modprobe", &[format!("nf_conntrack_{}", helper)])
+            .map(drop)
+    }
+
+    /// Poll the conntrack table until it has an entry that matches the predicate, e.g. one in
+    /// state `ESTABLISHED` for a given tuple, and return it, so that a probe that relies on the
+    /// state does not race with the probe that created it. Fails with `TimedOut` if no entry
+    /// matches within the timeout.
+    pub async fn wait_for_conntrack<P>(
+        &self,
+        predicate: P,
+        timeout: Duration,
+    ) -> Result<ConntrackEntry, io::Error>
+    where
+        P: Fn(&ConntrackEntry) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(entry) = self.conntrack()?.into_iter().find(|entry| predicate(entry)) {
+                return Ok(entry);
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "No matching conntrack entry in {} after {:?}",
+                        self.name, timeout
+                    ),
+                ));
+            }
+            tokio::time::sleep(CONNTRACK_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Run the probe with `meta nftrace` set on its packets from client to server, and return
+    /// the events that `nft monitor trace` reported for them in this host, i.e. the rules they
+    /// matched and the verdicts they received.
+    ///
+    /// The packets are marked by a table of their own, whose chains run before any others.
+    pub async fn trace_probe(
+        &self,
+        path: &dyn ConnPath,
+        probe: &Probe,
+    ) -> Result<Vec<TraceEvent>, io::Error> {
+        Ok(self.trace_probe_events(path, probe).await?.1)
+    }
+
+    /// Like `trace_probe`, but return the outcome of the probe, along with the rule whose verdict
+    /// decided it, e.g. to report which rule refused a connection.
+    pub async fn trace_probe_outcome(
+        &self,
+        path: &dyn ConnPath,
+        probe: &Probe,
+    ) -> Result<TracedOutcome, io::Error> {
+        let (outcome, events) = self.trace_probe_events(path, probe).await?;
+        Ok(TracedOutcome {
+            outcome,
+            verdict: final_verdict(&events),
+        })
+    }
+
+    async fn trace_probe_events(
+        &self,
+        path: &dyn ConnPath,
+        probe: &Probe,
+    ) -> Result<(ProbeOutcome, Vec<TraceEvent>), io::Error> {
+        let mut commands = vec![
+            // Adding the table first ensures that deleting it succeeds
+            format!("add table {}", NFTRACE_TABLE),
+            format!("delete table {}", NFTRACE_TABLE),
+            format!("add table {}", NFTRACE_TABLE),
+        ];
+        // Received packets enter at prerouting, and sent packets at output
+        for hook in &[Hook::Prerouting, Hook::Output] {
+            commands.push(format!(
+                "add chain {} {} {{ type filter hook {} priority raw - 2; }}",
+                NFTRACE_TABLE, hook, hook
+            ));
+            commands.push(format!(
+                "add rule {} {} {} meta nftrace set 1",
+                NFTRACE_TABLE,
+                hook,
+                spec_match(probe.spec())
+            ));
+        }
+        commands.push(format!(
+            "add chain {} {} {{ type filter hook output priority raw - 2; }}",
+            NFTRACE_TABLE, NFTRACE_MARKER_CHAIN
+        ));
+        commands.push(format!(
+            "add rule {} {} oif lo udp dport {} meta nftrace set 1",
+            NFTRACE_TABLE, NFTRACE_MARKER_CHAIN, NFTRACE_MARKER_PORT
+        ));
+
+        let mut monitor = NftraceMonitor::start(&self.ns)?;
+        self.ns
+            .scoped_process_with_input("nft", &["-f", "-"], commands.join("\n").as_bytes())?;
+        monitor.sync().await?;
+        let outcome = path.probe_outcome(probe).await?;
+        let events = monitor.sync().await?;
+        Ok((outcome, events))
+    }
+
+    fn add_interface_with_peer(
+        &mut self,
+        name: String,
+        addr_with_net: IpNet,
+        mut peer_ns: OsNs,
+    ) -> Result<OsInterface, io::Error> {
+        let peer_name = self.name.clone();
+        peer_ns.enable_link("lo")?;
+
+        self.ns.add_veth_link(&name, &peer_name)?;
+        self.ns.move_link(&peer_name, &mut peer_ns)?;
+
+        self.ns.disable_ipv6_autoconf(&name)?;
+        self.ns.enable_link(&name)?;
+        self.ns.set_addr(&name, addr_with_net)?;
+
+        peer_ns.disable_ipv6_autoconf(&peer_name)?;
+        peer_ns.enable_link(&peer_name)?;
+
+        Ok(OsInterface {
+            ns: self.ns.try_clone()?,
+            addr_with_net,
+            peer_name,
+            peer_ns,
+            transit: None,
+            peer_endpoints: vec![],
+            other_family: None,
+            claim: self.claim_nets(&name, &[addr_with_net]),
+            name,
+        })
+    }
+}
+
+impl Host for OsHost {
+    type Interface = OsInterface;
+
+    fn new(name: String) -> Result<Self, io::Error> {
+        OsHost::with_nft_backend(name, NftBackend::Cli)
+    }
+
+    fn new_router(name: String) -> Result<Self, io::Error> {
+        let mut host = OsHost::new(name)?;
+        host.ns.enable_ipv4_forwarding()?;
+        host.ns.enable_ipv6_forwarding()?;
+        host.ns.disable_rp_filter()?;
+        Ok(host)
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn new_interface(
+        &mut self,
+        name: String,
+        addr_with_net: IpNet,
+    ) -> Result<Self::Interface, io::Error> {
+        self.check_nets(&[(&name, addr_with_net)])?;
+        let peer_ns = OsNs::new_net()?;
+        self.add_interface_with_peer(name, addr_with_net, peer_ns)
+    }
+
+    fn load_nft_rules<R: io::Read + Send>(&mut self, rules: R) -> Result<(), io::Error> {
+        match self.nft {
+            NftBackend::Cli => self.ns.load_nft_rules(rules),
+            NftBackend::Library => self.run_libnftables(rules, 0).map(drop),
+        }
+    }
+
+    fn load_nft_rules_echo<R: io::Read + Send>(&mut self, rules: R) -> Result<String, io::Error> {
+        match self.nft {
+            NftBackend::Cli => self.ns.load_nft_rules_echo(rules),
+            NftBackend::Library => {
+                self.run_libnftables(rules, libnftables::OUTPUT_ECHO | libnftables::OUTPUT_HANDLE)
+            }
+        }
+    }
+
+    fn list_nft_rules(&self) -> Result<String, io::Error> {
+        match self.nft {
+            NftBackend::Cli => self.ns.list_nft_rules(),
+            NftBackend::Library => self.run_libnftables("list ruleset".as_bytes(), 0),
+        }
+    }
+
+    fn list_nft_rules_json(&self) -> Result<String, io::Error> {
+        match self.nft {
+            NftBackend::Cli => self.ns.list_nft_rules_json(),
+            NftBackend::Library => {
+                self.run_libnftables("list ruleset".as_bytes(), libnftables::OUTPUT_JSON)
+            }
+        }
+    }
+
+    fn list_nft_rules_with_handles(&self) -> Result<String, io::Error> {
+        match self.nft {
+            NftBackend::Cli => self.ns.list_nft_rules_with_handles(),
+            NftBackend::Library => {
+                self.run_libnftables("list ruleset".as_bytes(), libnftables::OUTPUT_HANDLE)
+            }
+        }
+    }
+
+    fn conntrack(&self) -> Result<Vec<ConntrackEntry>, io::Error> {
+        self.ns.list_conntrack()
+    }
+
+    fn conntrack_count(&self) -> Result<usize, io::Error> {
+        self.ns.conntrack_count()
+    }
+
+    fn flush_conntrack(&mut self) -> Result<(), io::Error> {
+        self.ns.flush_conntrack()
+    }
+
+    fn snapshot(&self) -> Result<StateSnapshot, io::Error> {
+        let lines = |args: &[&str]| {
+            let output = self.ns.scoped_process("ip", args)?;
+            Ok::<_, io::Error>(output.lines().map(str::to_owned).collect::<Vec<_>>())
+        };
+        let mut routes = lines(&["-o", "-4", "route", "show", "table", "all"])?;
+        routes.extend(lines(&["-o", "-6", "route", "show", "table", "all"])?);
+        let neighbors = lines(&["-o", "neigh", "show"])?
+            .iter()
+            .map(|line| snapshot::neighbor_without_state(line))
+            .collect();
+        Ok(StateSnapshot {
+            links: lines(&["-o", "link", "show"])?,
+            addrs: lines(&["-o", "address", "show"])?,
+            routes,
+            neighbors,
+            conntrack: self.ns.list_conntrack()?,
+        })
+    }
+
+    fn add_tproxy_route(&mut self, mark: u32) -> Result<(), io::Error> {
+        self.ns.add_local_route_for_mark(mark)
+    }
+
+    fn input_path<'a>(
+        interface: &'a mut Self::Interface,
+        host: &'a Self,
+    ) -> Result<Box<dyn ConnPath + 'a>, io::Error> {
+        interface.check_carrier()?;
+        let peer_addr_with_net = interface.assign_peer_addr()?;
+        trace!("{}:\n{}", interface.name, interface.peer_ns.list_addrs()?);
+        trace!("{}:\n{}", host.name, host.ns.list_addrs()?);
+        let mut path = OsNsConnPath::new(
+            &interface.name,
+            &interface.peer_ns,
+            peer_addr_with_net.addr(),
+            &host.name,
+            &host.ns,
+            interface.addr_with_net.addr(),
+        );
+        if let Some((addr, peer_addr)) = interface.other_family_addrs() {
+            path = path.with_other_family(peer_addr, addr);
+        }
+        Ok(Box::new(path))
+    }
+
+    fn input_path_from<'a>(
+        interface: &'a mut Self::Interface,
+        host: &'a Self,
+        source_addr: IpAddr,
+    ) -> Result<Box<dyn ConnPath + 'a>, io::Error> {
+        interface.check_carrier()?;
+        interface
+            .peer_ns
+            .set_addr(&interface.peer_name, source_addr.into())?;
+        // The source address is not in the interface's network, so the route must be explicit
+        interface
+            .peer_ns
+            .add_link_route(interface.addr_with_net.trunc(), &interface.peer_name)?;
+        trace!("{}:\n{}", interface.name, interface.peer_ns.list_addrs()?);
+        trace!("{}:\n{}", host.name, host.ns.list_addrs()?);
+        Ok(Box::new(OsNsConnPath::new(
+            &interface.name,
+            &interface.peer_ns,
+            source_addr,
+            &host.name,
+            &host.ns,
+            interface.addr_with_net.addr(),
+        )))
+    }
+
+    fn output_path<'a>(
+        host: &'a Self,
+        interface: &'a mut Self::Interface,
+    ) -> Result<Box<dyn ConnPath + 'a>, io::Error> {
+        interface.check_carrier()?;
+        let peer_addr_with_net = interface.assign_peer_addr()?;
+        trace!("{}:\n{}", host.name, host.ns.list_addrs()?);
+        trace!("{}:\n{}", interface.name, interface.peer_ns.list_addrs()?);
+        let mut path = OsNsConnPath::new(
+            &host.name,
+            &host.ns,
+            interface.addr_with_net.addr(),
+            &interface.name,
+            &interface.peer_ns,
+            peer_addr_with_net.addr(),
+        )
+        .with_source_device(&interface.name);
+        if let Some((addr, peer_addr)) = interface.other_family_addrs() {
+            path = path.with_other_family(addr, peer_addr);
+        }
+        Ok(Box::new(path))
+    }
+
+    fn forward_path_via<'a>(
+        source_interface: &'a mut Self::Interface,
+        target_interface: &'a mut Self::Interface,
+        gateway: IpAddr,
+    ) -> Result<Box<dyn ConnPath + 'a>, io::Error> {
+        source_interface.check_carrier()?;
+        target_interface.check_carrier()?;
+        let source_peer_addr_with_net = source_interface.assign_peer_addr()?;
+        source_interface
+            .peer_ns
+            .set_default_route(gateway, &source_interface.peer_name)?;
+        let target_peer_addr_with_net = target_interface.assign_peer_addr()?;
+        target_interface.peer_ns.set_default_route(
+            target_interface.addr_with_net.addr(),
+            &target_interface.peer_name,
+        )?;
+        // In the other address family, the gateway is always the source interface itself
+        let other_family_addrs = match (
+            source_interface.other_family_addrs(),
+            target_interface.other_family_addrs(),
+        ) {
+            (Some((source_addr, source_peer_addr)), Some((target_addr, target_peer_addr))) => {
+                source_interface
+                    .peer_ns
+                    .set_default_route(source_addr, &source_interface.peer_name)?;
+                target_interface
+                    .peer_ns
+                    .set_default_route(target_addr, &target_interface.peer_name)?;
+                Some((source_peer_addr, target_peer_addr))
+            }
+            _ => None,
+        };
+        trace!(
+            "{}:\n{}",
+            source_interface.name,
+            source_interface.peer_ns.list_addrs()?
+        );
+        trace!(
+            "{}:\n{}",
+            target_interface.name,
+            target_interface.peer_ns.list_addrs()?
+        );
+        let mut path = OsNsConnPath::new(
+            &source_interface.name,
+            &source_interface.peer_ns,
+            source_peer_addr_with_net.addr(),
+            &target_interface.name,
+            &target_interface.peer_ns,
+            target_peer_addr_with_net.addr(),
+        );
+        if let Some((source_addr, target_addr)) = other_family_addrs {
+            path = path.with_other_family(source_addr, target_addr);
+        }
+        Ok(Box::new(path))
+    }
+
+    fn forward_path_from<'a>(
+        source_interface: &'a mut Self::Interface,
+        target_interface: &'a mut Self::Interface,
+        source_addr: IpAddr,
+    ) -> Result<Box<dyn ConnPath + 'a>, io::Error> {
+        source_interface.check_carrier()?;
+        target_interface.check_carrier()?;
+        source_interface
+            .peer_ns
+            .set_addr(&source_interface.peer_name, source_addr.into())?;
+        // The source address is not in the interface's network, so the routes must be explicit
+        source_interface.peer_ns.add_link_route(
+            source_interface.addr_with_net.trunc(),
+            &source_interface.peer_name,
+        )?;
+        source_interface.peer_ns.set_default_route(
+            source_interface.addr_with_net.addr(),
+            &source_interface.peer_name,
+        )?;
+        let target_peer_addr_with_net = target_interface.assign_peer_addr()?;
+        target_interface.peer_ns.set_default_route(
+            target_interface.addr_with_net.addr(),
+            &target_interface.peer_name,
+        )?;
+        trace!(
+            "{}:\n{}",
+            source_interface.name,
+            source_interface.peer_ns.list_addrs()?
+        );
+        trace!(
+            "{}:\n{}",
+            target_interface.name,
+            target_interface.peer_ns.list_addrs()?
+        );
+        Ok(Box::new(OsNsConnPath::new(
+            &source_interface.name,
+            &source_interface.peer_ns,
+            source_addr,
+            &target_interface.name,
+            &target_interface.peer_ns,
+            target_peer_addr_with_net.addr(),
+        )))
+    }
+
+    fn tproxy_path<'a>(
+        interface: &'a mut Self::Interface,
+        host: &'a Self,
+        original_addr: IpAddr,
+        listen_port: u16,
+    ) -> Result<Box<dyn ConnPath + 'a>, io::Error> {
+        interface.check_carrier()?;
+        let peer_addr_with_net = interface.assign_peer_addr()?;
+        interface
+            .peer_ns
+            .set_default_route(interface.addr_with_net.addr(), &interface.peer_name)?;
+        trace!("{}:\n{}", interface.name, interface.peer_ns.list_addrs()?);
+        trace!("{}:\n{}", host.name, host.ns.list_addrs()?);
+        let listen_addr = match original_addr {
+            IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), listen_port),
+            IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), listen_port),
+        };
+        Ok(Box::new(
+            OsNsConnPath::new(
+                &interface.name,
+                &interface.peer_ns,
+                peer_addr_with_net.addr(),
+                &host.name,
+                &host.ns,
+                original_addr,
+            )
+            .with_transparent_listener(listen_addr),
+        ))
+    }
+}
+
+/// A running `nft monitor trace`, which is killed on drop, along with the nftrace table, so that
+/// neither outlives a failed or cancelled trace.
+struct NftraceMonitor<'a> {
+    ns: &'a OsNs,
+    process: Child,
+    lines: mpsc::UnboundedReceiver<io::Result<String>>,
+    /// The trace ids of the marker datagrams seen so far, whose events are not reported.
+    markers: HashSet<String>,
+}
+
+impl<'a> NftraceMonitor<'a> {
+    fn start(ns: &'a OsNs) -> Result<NftraceMonitor<'a>, io::Error> {
+        let mut process = ns.spawn_scoped_process("nft", &["-a", "monitor", "trace"])?;
+        let stdout = process.stdout.take().expect("stdout was piped");
+        let (tx, lines) = mpsc::unbounded();
+        // The thread finishes when the process is killed and its stdout closes
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                if tx.unbounded_send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(NftraceMonitor {
+            ns,
+            process,
+            lines,
+            markers: HashSet::new(),
+        })
+    }
+
+    /// Send marker datagrams until the monitor reports one, and return the events that it
+    /// reported before, other than those of markers. Fails with `TimedOut` if the monitor does
+    /// not report a marker within `NFTRACE_MONITOR_TIMEOUT`, e.g. because it failed to start.
+    async fn sync(&mut self) -> Result<Vec<TraceEvent>, io::Error> {
+        let deadline = Instant::now() + NFTRACE_MONITOR_TIMEOUT;
+        let mut events = vec![];
+        while Instant::now() < deadline {
+            self.send_marker()?;
+            while let Ok(line) =
+                tokio::time::timeout(NFTRACE_MARKER_INTERVAL, self.lines.next()).await
+            {
+                let line = line.unwrap_or_else(|| {
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "nft monitor exited",
+                    ))
+                })?;
+                let event = match parse_trace_event(&line) {
+                    Some(event) => event,
+                    None => continue,
+                };
+                if event.table == NFTRACE_TABLE && event.chain == NFTRACE_MARKER_CHAIN {
+                    self.markers.insert(event.id);
+                    return Ok(events);
+                }
+                if !self.markers.contains(&event.id) {
+                    events.push(event);
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "nft monitor reported no marker after {:?}",
+                NFTRACE_MONITOR_TIMEOUT
+            ),
+        ))
+    }
+
+    fn send_marker(&self) -> Result<(), io::Error> {
+        self.ns.scoped(|| {
+            let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+            socket
+                .send_to(&[], (Ipv4Addr::LOCALHOST, NFTRACE_MARKER_PORT))
+                .map(drop)
+        })
+    }
+}
+
+impl Drop for NftraceMonitor<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.process.kill().and_then(|()| self.process.wait()) {
+            warn!("Failed to stop nft monitor: {}", err);
+        }
+        // Adding the table first ensures that deleting it succeeds, even if it was never added
+        let commands = format!(
+            "add table {}\ndelete table {}",
+            NFTRACE_TABLE, NFTRACE_TABLE
+        );
+        let res = self.ns.scoped(|| {
+            let mut process = Command::new("nft")
+                .args(["-f", "-"])
+                .stdin(Stdio::piped())
+                .spawn()?;
+            let mut stdin = process.stdin.take().expect("stdin was piped");
+            let written = stdin.write_all(commands.as_bytes());
+            drop(stdin);
+            let status = process.wait()?;
+            written.map(|()| status)
+        });
+        match res {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("Failed to delete table {}: {}", NFTRACE_TABLE, status),
+            Err(err) => warn!("Failed to delete table {}: {}", NFTRACE_TABLE, err),
+        }
+    }
+}
+
+impl NetClaim {
+    fn add(&self, addr_with_net: IpNet) {
+        let mut nets = self.nets.lock().unwrap();
+        nets.push((self.name.clone(), addr_with_net.trunc()));
+    }
+
+    fn release(&self) {
+        let mut nets = self.nets.lock().unwrap();
+        nets.retain(|(name, _)| *name != self.name);
+    }
+}
+
+impl Drop for NetClaim {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Fail if any of the new networks overlaps one recorded for another interface, or a new one of
+/// another interface.
+fn check_nets(claimed: &[(String, IpNet)], nets: &[(&str, IpNet)]) -> Result<(), io::Error> {
+    for (i, &(name, addr_with_net)) in nets.iter().enumerate() {
+        let net = addr_with_net.trunc();
+        let others = claimed
+            .iter()
+            .map(|(other_name, other_net)| (other_name.as_str(), *other_net))
+            .chain(
+                nets[..i]
+                    .iter()
+                    .map(|&(other_name, other_net)| (other_name, other_net.trunc())),
+            );
+        if let Some((other_name, other_net)) = others
+            .filter(|&(other_name, _)| other_name != name)
+            .find(|(_, other_net)| {
+                other_net.contains(&net.network()) || net.contains(&other_net.network())
+            })
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Network {} of interface {} overlaps network {} of interface {}",
+                    net, name, other_net, other_name
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl OsInterface {
+    /// Assign the peer a fresh address for a new path, and return it.
+    fn assign_peer_addr(&mut self) -> Result<IpNet, io::Error> {
+        let (peer_addr_with_net, mut addrs) = match self.transit {
+            None => {
+                let peer_addr_with_net = self.random_peer_addr_with_net(self.addr_with_net);
+                let mut addrs = vec![peer_addr_with_net];
+                if let Some((other_addr_with_net, _)) = self.other_family {
+                    let other_peer_addr_with_net =
+                        self.random_peer_addr_with_net(other_addr_with_net);
+                    self.other_family = Some((other_addr_with_net, Some(other_peer_addr_with_net)));
+                    addrs.push(other_peer_addr_with_net);
+                }
+                (peer_addr_with_net, addrs)
+            }
+            Some(transit) => {
+                // The peer routes for the remote network, so it has a host address in that
+                // network as well as its address in the transit network
+                let peer_addr_with_net = self.random_peer_addr_with_net(transit.remote_net);
+                (
+                    peer_addr_with_net,
+                    vec![
+                        transit.peer_addr_with_net,
+                        IpNet::from(peer_addr_with_net.addr()),
+                    ],
+                )
+            }
+        };
+        addrs.extend_from_slice(&self.peer_endpoints);
+        self.peer_ns.set_addrs(&self.peer_name, &addrs)?;
+        Ok(peer_addr_with_net)
+    }
+
+    /// The interface's and the peer's addresses in the other address family, if the interface is
+    /// dual-stack and the peer has been assigned one.
+    fn other_family_addrs(&self) -> Option<(IpAddr, IpAddr)> {
+        match self.other_family {
+            Some((addr_with_net, Some(peer_addr_with_net))) => {
+                Some((addr_with_net.addr(), peer_addr_with_net.addr()))
+            }
+            _ => None,
+        }
+    }
+
+    /// A random peer address in the given network, other than one of the peer's endpoints.
+    fn random_peer_addr_with_net(&self, addr_with_net: IpNet) -> IpNet {
+        loop {
+            let ret = random_peer_addr_with_net(addr_with_net);
+            if !self
+                .peer_endpoints
+                .iter()
+                .any(|endpoint| endpoint.addr() == ret.addr())
+            {
+                return ret;
+            }
+        }
+    }
+
+    /// Replace the addresses of the interface in place, e.g. to sweep a set of source addresses
+    /// without recreating the interface. The first address becomes the interface's address, from
+    /// which the peer's address is chosen when a path is next created.
+    ///
+    /// The new networks replace the old ones on the host, and must not overlap those of its other
+    /// interfaces.
+    pub fn reassign(&mut self, addrs: Vec<IpNet>) -> Result<(), io::Error> {
+        let addr_with_net = *addrs.first().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("No addresses to assign to {}", self.name),
+            )
+        })?;
+        let nets: Vec<_> = addrs.iter().map(|&net| (self.name.as_str(), net)).collect();
+        check_nets(&self.claim.nets.lock().unwrap(), &nets)?;
+        self.ns.set_addrs(&self.name, &addrs)?;
+        self.claim.release();
+        for &net in &addrs {
+            self.claim.add(net);
+        }
+        // The peer's address is in the old network, so it is removed until the next path
+        self.peer_ns
+            .scoped_process("ip", &["address", "flush", "dev", &self.peer_name])?;
+        self.addr_with_net = addr_with_net;
+        // The new addresses replace those in the other address family too
+        self.other_family = None;
+        Ok(())
+    }
+}
+
+impl Interface for OsInterface {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn addr_with_net(&self) -> IpNet {
+        self.addr_with_net
+    }
+
+    fn mac(&self) -> Result<[u8; 6], io::Error> {
+        self.ns.link_mac(&self.name)
+    }
+
+    fn peer_mac(&self) -> Result<[u8; 6], io::Error> {
+        self.peer_ns.link_mac(&self.peer_name)
+    }
+
+    fn set_htb(&mut self, classes: Vec<HtbClass>) -> Result<(), io::Error> {
+        self.ns.set_htb(&self.name, &classes)
+    }
+
+    fn htb_class_packets(&self, minor: u16) -> Result<u64, io::Error> {
+        let classes = self
+            .ns
+            .scoped_process("tc", &["-s", "class", "show", "dev", &self.name])?;
+        parse_htb_class_packets(&classes, minor)
+    }
+
+    fn set_peer_local_port_range(&mut self, first: u16, last: u16) -> Result<(), io::Error> {
+        // This applies to IPv6 too, despite the name
+        self.peer_ns.set_sysctl(
+            "net.ipv4.ip_local_port_range",
+            &format!("{} {}", first, last),
+        )
+    }
+
+    fn set_mtu(&mut self, mtu: u32) -> Result<(), io::Error> {
+        self.ns.set_link_mtu(&self.name, mtu)
+    }
+
+    fn oper_state(&self) -> Result<OperState, io::Error> {
+        // As with the MAC, sysfs would reflect the wrong namespace, so ask `ip`
+        let link = self
+            .ns
+            .scoped_process("ip", &["-o", "link", "show", "dev", &self.name])?;
+        parse_oper_state(&link).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No flags in link: {}", link),
+            )
+        })
+    }
+}
+
+impl OsNs {
+    /// Set a sysctl of the namespace, named as by `sysctl`, e.g. `net.ipv4.ip_forward`.
+    fn set_sysctl(&mut self, name: &str, value: &str) -> Result<(), io::Error> {
+        let path = format!("/proc/sys/{}", name.replace('.', "/"));
+        self.scoped(|| fs::write(&path, value))
+            .map_err(|err| io::Error::new(err.kind(), format!("Failed to set {}: {}", name, err)))
+    }
+
+    fn enable_ipv4_forwarding(&mut self) -> Result<(), io::Error> {
+        self.scoped(|| fs::write("/proc/sys/net/ipv4/conf/all/forwarding", "1"))?;
+        Ok(())
+    }
+
+    fn enable_ipv6_forwarding(&mut self) -> Result<(), io::Error> {
+        self.scoped(|| fs::write("/proc/sys/net/ipv6/conf/all/forwarding", "1"))?;
+        Ok(())
+    }
+
+    /// Disable reverse path filtering on every interface, including those created later. The
+    /// kernel applies the stricter of `all` and the interface's own setting, so both are needed.
+    fn disable_rp_filter(&mut self) -> Result<(), io::Error> {
+        self.set_sysctl("net.ipv4.conf.all.rp_filter", "0")?;
+        self.set_sysctl("net.ipv4.conf.default.rp_filter", "0")
+    }
+
+    /// Ignore router advertisements on the link, so that addresses and routes are only those that
+    /// are explicitly configured.
+    fn disable_ipv6_autoconf(&mut self, name: &str) -> Result<(), io::Error> {
+        self.scoped(|| {
+            fs::write(format!("/proc/sys/net/ipv6/conf/{}/accept_ra", name), "0")?;
+            fs::write(format!("/proc/sys/net/ipv6/conf/{}/autoconf", name), "0")
+        })?;
+        Ok(())
+    }
+
+    fn add_veth_link(&mut self, name: &str, peer_name: &str) -> Result<(), io::Error> {
+        self.scoped_process(
+            "ip",
+            &[
+                "link", "add", name, "type", "veth", "peer", "name", peer_name,
+            ],
+        )?;
+        self.record_link(name);
+        self.record_link(peer_name);
+        Ok(())
+    }
+
+    fn add_wireguard_link(&mut self, name: &str, wg_conf: &str) -> Result<(), io::Error> {
+        self.scoped_process("ip", &["link", "add", name, "type", "wireguard"])?;
+        self.record_link(name);
+        self.scoped_process_with_input("wg", &["setconf", name, "/dev/stdin"], wg_conf.as_bytes())?;
+        Ok(())
+    }
+
+    fn add_vxlan_link(
+        &mut self,
+        name: &str,
+        vni: u32,
+        local: IpAddr,
+        remote: IpAddr,
+        dev: &str,
+    ) -> Result<(), io::Error> {
+        self.scoped_process(
+            "ip",
+            &[
+                "link",
+                "add",
+                name,
+                "type",
+                "vxlan",
+                "id",
+                &vni.to_string(),
+                "local",
+                &local.to_string(),
+                "remote",
+                &remote.to_string(),
+                "dev",
+                dev,
+                "dstport",
+                &VXLAN_PORT.to_string(),
+            ],
+        )?;
+        self.record_link(name);
+        Ok(())
+    }
+
+    fn enable_link(&mut self, name: &str) -> Result<(), io::Error> {
+        self.scoped_process("ip", &["link", "set", name, "up"])?;
+        Ok(())
+    }
+
+    fn set_link_mtu(&mut self, name: &str, mtu: u32) -> Result<(), io::Error> {
+        self.scoped_process("ip", &["link", "set", name, "mtu", &mtu.to_string()])?;
+        Ok(())
+    }
+
+    fn move_link(&mut self, name: &str, other: &mut Self) -> Result<(), io::Error> {
+        self.scoped_process(
+            "ip",
+            &[
+                OsStr::new("link"),
+                OsStr::new("set"),
+                OsStr::new(name),
+                OsStr::new("netns"),
+                other.path().as_os_str(),
+            ],
+        )?;
+        self.record_link_removed(name);
+        other.record_link(name);
+        Ok(())
+    }
+
+    fn link_mac(&self, name: &str) -> Result<[u8; 6], io::Error> {
+        // Note that sysfs reflects the namespace it was mounted in, not the current thread's
+        let link = self.scoped_process("ip", &["link", "show", "dev", name])?;
+        link.split_whitespace()
+            .skip_while(|&token| token != "link/ether")
+            .nth(1)
+            .and_then(parse_mac)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("No MAC address for {}:\n{}", name, link),
+                )
+            })
+    }
+
+    fn set_addr(&mut self, name: &str, addr: IpNet) -> Result<(), io::Error> {
+        self.set_addrs(name, &[addr])
+    }
+
+    fn set_addrs(&mut self, name: &str, addrs: &[IpNet]) -> Result<(), io::Error> {
+        self.scoped_process("ip", &["address", "flush", "dev", name])?;
+        for &addr in addrs {
+            self.add_addr(name, addr)?;
+        }
+        Ok(())
+    }
+
+    fn add_addr(&mut self, name: &str, addr: IpNet) -> Result<(), io::Error> {
+        match addr {
+            IpNet::V4(_) => {
+                // Derive the broadcast address from the prefix, so subnet broadcasts are received
+                self.scoped_process(
+                    "ip",
+                    &[
+                        "address",
+                        "add",
+                        &addr.to_string(),
+                        "broadcast",
+                        "+",
+                        "dev",
+                        name,
+                    ],
+                )?
+            }
+            IpNet::V6(_) => {
+                // Disable duplicate address detection (DAD) so we can immediately bind the address
+                self.scoped_process(
+                    "ip",
+                    &["address", "add", &addr.to_string(), "dev", name, "nodad"],
+                )?
+            }
+        };
+        Ok(())
+    }
+
+    fn list_addrs(&self) -> Result<String, io::Error> {
+        self.list_addrs_as(OutputFormat::Text)
+    }
+
+    fn list_addrs_json(&self) -> Result<String, io::Error> {
+        self.list_addrs_as(OutputFormat::Json)
+    }
+
+    fn list_addrs_as(&self, format: OutputFormat) -> Result<String, io::Error> {
+        self.scoped_process_as(format, "ip", &["address"])
+    }
+
+    fn add_link_route(&mut self, net: IpNet, name: &str) -> Result<(), io::Error> {
+        // Replace rather than add, so that a path can be built more than once
+        self.scoped_process("ip", &["route", "replace", &net.to_string(), "dev", name])?;
+        Ok(())
+    }
+
+    /// Add a route to `dest` with the preferred source address `src`, which the kernel selects
+    /// for a socket that is not bound to an address, e.g. a `Probe::kernel_source_addr`.
+    pub fn add_route_with_src(
+        &mut self,
+        dest: IpNet,
+        via: Option<IpAddr>,
+        src: IpAddr,
+        name: &str,
+    ) -> Result<(), io::Error> {
+        let dest = dest.to_string();
+        let src = src.to_string();
+        let via = via.map(|via| via.to_string());
+        let mut args = vec!["route", "add", &dest];
+        if let Some(via) = &via {
+            args.extend(&["via", via]);
+        }
+        args.extend(&["dev", name, "src", &src]);
+        self.scoped_process("ip", &args)?;
+        Ok(())
+    }
+
+    fn default_route_v4(&self) -> Result<Option<Ipv4Addr>, io::Error> {
+        self.default_route("-4")
+    }
+
+    fn default_route_v6(&self) -> Result<Option<Ipv6Addr>, io::Error> {
+        self.default_route("-6")
+    }
+
+    /// The gateway of the default route for the given family, if any.
+    fn default_route<A: FromStr>(&self, family: &str) -> Result<Option<A>, io::Error> {
+        let routes = self.scoped_process("ip", &[family, "route", "show", "default"])?;
+        let mut via = routes
+            .split_whitespace()
+            .skip_while(|&token| token != "via")
+            .skip(1);
+        match via.next() {
+            None => Ok(None),
+            Some(addr) => addr.parse().map(Some).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid default route: {}", routes),
+                )
+            }),
+        }
+    }
+
+    fn set_default_route(&mut self, addr: IpAddr, name: &str) -> Result<(), io::Error> {
+        // Replace rather than add, so that a path can be built more than once, and assume the
+        // gateway is on link, so that a path can be built with a gateway outside the network
+        self.scoped_process(
+            "ip",
+            &[
+                "route",
+                "replace",
+                "default",
+                "via",
+                &addr.to_string(),
+                "dev",
+                name,
+                "onlink",
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn add_local_route_for_mark(&mut self, mark: u32) -> Result<(), io::Error> {
+        let mark = mark.to_string();
+        for (family, default) in &[("-4", "0.0.0.0/0"), ("-6", "::/0")] {
+            self.scoped_process(
+                "ip",
+                &[
+                    family,
+                    "rule",
+                    "add",
+                    "fwmark",
+                    &mark,
+                    "lookup",
+                    TPROXY_ROUTE_TABLE,
+                ],
+            )?;
+            self.scoped_process(
+                "ip",
+                &[
+                    family,
+                    "route",
+                    "add",
+                    "local",
+                    default,
+                    "dev",
+                    "lo",
+                    "table",
+                    TPROXY_ROUTE_TABLE,
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn set_htb(&mut self, name: &str, classes: &[HtbClass]) -> Result<(), io::Error> {
+        self.scoped_process(
+            "tc",
+            &[
+                "qdisc", "replace", "dev", name, "root", "handle", "1:", "htb",
+            ],
+        )?;
+        for class in classes {
+            let class_id = format!("1:{:x}", class.minor);
+            self.scoped_process(
+                "tc",
+                &[
+                    "class",
+                    "add",
+                    "dev",
+                    name,
+                    "parent",
+                    "1:",
+                    "classid",
+                    &class_id,
+                    "htb",
+                    "rate",
+                    &format!("{}kbit", class.rate_kbit),
+                ],
+            )?;
+            if let Some(mark) = class.fwmark {
+                self.scoped_process(
+                    "tc",
+                    &[
+                        "filter",
+                        "add",
+                        "dev",
+                        name,
+                        "parent",
+                        "1:",
+                        "protocol",
+                        "all",
+                        "handle",
+                        &mark.to_string(),
+                        "fw",
+                        "classid",
+                        &class_id,
+                    ],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load_nft_rules<R: io::Read + Send>(&mut self, rules: R) -> Result<(), io::Error> {
+        self.scoped_process_with_input("nft", &["-f", "-"], rules)?;
+        Ok(())
+    }
+
+    fn load_nft_rules_echo<R: io::Read + Send>(&mut self, rules: R) -> Result<String, io::Error> {
+        self.scoped_process_with_input("nft", &["--echo", "--handle", "-f", "-"], rules)
+    }
+
+    fn list_nft_rules(&self) -> Result<String, io::Error> {
+        self.list_nft_rules_as(OutputFormat::Text)
+    }
+
+    fn list_nft_rules_json(&self) -> Result<String, io::Error> {
+        self.list_nft_rules_as(OutputFormat::Json)
+    }
+
+    fn list_nft_rules_with_handles(&self) -> Result<String, io::Error> {
+        self.scoped_process("nft", &["-a", "list", "ruleset"])
+    }
+
+    fn list_nft_rules_as(&self, format: OutputFormat) -> Result<String, io::Error> {
+        self.scoped_process_as(format, "nft", &["list", "ruleset"])
+    }
+
+    /// Run a program that supports JSON output with `-j`, i.e. `ip` or `nft`.
+    fn scoped_process_as(
+        &self,
+        format: OutputFormat,
+        program: &str,
+        args: &[&str],
+    ) -> Result<String, io::Error> {
+        match format {
+            OutputFormat::Text => self.scoped_process(program, args),
+            OutputFormat::Json => {
+                let ret = self.scoped_process(program, &[&["-j"], args].concat())?;
+                let trimmed = ret.trim();
+                if !(trimmed.starts_with('{') || trimmed.starts_with('[')) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} {:?} did not return JSON: {}", program, args, ret),
+                    ));
+                }
+                Ok(ret)
+            }
+        }
+    }
+
+    fn list_conntrack(&self) -> Result<Vec<ConntrackEntry>, io::Error> {
+        let mut ret = vec![];
+        // Without a family, conntrack only lists IPv4 entries
+        for family in &["ipv4", "ipv6"] {
+            let entries = self.scoped_process("conntrack", &["-L", "-f", family])?;
+            for line in entries.lines() {
+                ret.push(parse_conntrack_entry(line)?);
+            }
+        }
+        Ok(ret)
+    }
+
+    fn conntrack_count(&self) -> Result<usize, io::Error> {
+        let count =
+            self.scoped(|| fs::read_to_string("/proc/sys/net/netfilter/nf_conntrack_count"))?;
+        parse_conntrack_count(&count)
+    }
+
+    fn flush_conntrack(&self) -> Result<(), io::Error> {
+        // Without a family, conntrack only flushes IPv4 entries
+        for family in &["ipv4", "ipv6"] {
+            self.scoped_process("conntrack", &["-F", "-f", family])?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_conntrack_count(s: &str) -> Result<usize, io::Error> {
+    s.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid conntrack count: {}", s),
+        )
+    })
+}
+
+/// Find the packet count of the class in the output of `tc -s class show`.
+fn parse_htb_class_packets(s: &str, minor: u16) -> Result<u64, io::Error> {
+    let header = format!("class htb 1:{:x} ", minor);
+    let mut lines = s.lines().skip_while(|line| !line.starts_with(&header));
+    lines
+        .nth(1)
+        .and_then(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens[..] {
+                ["Sent", _, "bytes", packets, "pkt", ..] => packets.parse().ok(),
+                _ => None,
+            }
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Failed to find packet count of class 1:{:x} in: {}",
+                    minor, s
+                ),
+            )
+        })
+}
+
+/// The operational state from the flags of a link, e.g. `<BROADCAST,MULTICAST,UP,LOWER_UP>`.
+///
+/// Unlike the `state` field, the flags reflect a change of carrier immediately.
+fn parse_oper_state(link: &str) -> Option<OperState> {
+    let flags = link
+        .split_whitespace()
+        .find(|token| token.starts_with('<'))?
+        .strip_prefix('<')?
+        .strip_suffix('>')?;
+    let has_flag = |flag| flags.split(',').any(|f| f == flag);
+    Some(if !has_flag("UP") {
+        OperState::Down
+    } else if !has_flag("LOWER_UP") {
+        OperState::LowerLayerDown
+    } else {
+        OperState::Up
+    })
+}
+
+fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let mut ret = [0; 6];
+    let mut octets = s.split(':');
+    for octet in ret.iter_mut() {
+        *octet = u8::from_str_radix(octets.next()?, 16).ok()?;
+    }
+    match octets.next() {
+        None => Some(ret),
+        Some(_) => None,
+    }
+}
+
+fn parse_conntrack_entry(line: &str) -> Result<ConntrackEntry, io::Error> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid conntrack entry: {}", line),
+        )
+    };
+
+    // The entry starts with the protocol name, protocol number and timeout
+    let mut tokens = line.split_whitespace();
+    let l4proto = tokens.next().ok_or_else(invalid)?.to_string();
+    tokens.next().ok_or_else(invalid)?;
+    tokens.next().ok_or_else(invalid)?;
+
+    // The original and reply tuples repeat the same keys, in that order
+    let mut state = None;
+    let mut srcs: Vec<IpAddr> = vec![];
+    let mut dsts: Vec<IpAddr> = vec![];
+    let mut sports: Vec<u16> = vec![];
+    let mut dports: Vec<u16> = vec![];
+    let mut mark = 0;
+    for token in tokens {
+        match token.split_once('=') {
+            Some(("src", value)) => srcs.push(value.parse().map_err(|_| invalid())?),
+            Some(("dst", value)) => dsts.push(value.parse().map_err(|_| invalid())?),
+            Some(("sport", value)) => sports.push(value.parse().map_err(|_| invalid())?),
+            Some(("dport", value)) => dports.push(value.parse().map_err(|_| invalid())?),
+            Some(("mark", value)) => mark = value.parse().map_err(|_| invalid())?,
+            Some(_) => {}
+            None if token.starts_with('[') => {}
+            None => state = Some(token.to_string()),
+        }
+    }
+
+    let tuple = |i: usize| -> Result<ConntrackTuple, io::Error> {
+        Ok(ConntrackTuple {
+            src: *srcs.get(i).ok_or_else(invalid)?,
+            dst: *dsts.get(i).ok_or_else(invalid)?,
+            sport: sports.get(i).copied(),
+            dport: dports.get(i).copied(),
+        })
+    };
+    Ok(ConntrackEntry {
+        l4proto,
+        state,
+        orig: tuple(0)?,
+        reply: tuple(1)?,
+        mark,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::future;
+    use indoc::{formatdoc, indoc};
+    use lazy_static::lazy_static;
+    use paste::paste;
+    use std::collections::BTreeSet;
+    use std::path::Path;
+
+    use crate::conn::matrix::{connect_all, drop_rate, effects_by_source, MatrixSummary};
+    use crate::conn::{ConnEffect, ConnSpec, FtpMode, IcmpKind, Probe, HAPPY_EYEBALLS_DELAY};
+    use crate::host::firewall::{diff_configs, diff_policy, Counter, Hook, TraceKind};
+    use crate::host::topology::Topology;
+    use crate::os::{resources, Resource};
+    use crate::INIT;
+
+    lazy_static! {
+        static ref IPV4_ADDRS_WITH_NET: Vec<IpNet> = vec![
+            "198.51.100.1/24".parse().unwrap(),
+            "203.0.113.1/24".parse().unwrap(),
+        ];
+        static ref IPV6_ADDRS_WITH_NET: Vec<IpNet> = vec![
+            "2001:db8:1111:1111::1/64".parse().unwrap(),
+            "2001:db8:2222:2222::1/64".parse().unwrap(),
+        ];
+    }
+
+    const TCP_SPEC: ConnSpec = ConnSpec::Tcp { port: 80 };
+    const UDP_SPEC: ConnSpec = ConnSpec::Udp { port: 53 };
+    const ESP_SPEC: ConnSpec = ConnSpec::Ip { protocol: 50 };
+    const ICMP_SPEC: ConnSpec = ConnSpec::Icmp {
+        kind: IcmpKind::Echo,
+    };
+
+    async fn test_input<BF, EF>(
+        addrs_with_net: &[IpNet],
+        probe: Probe,
+        build_rule: BF,
+        expect_effect: EF,
+    ) -> Result<(), io::Error>
+    where
+        BF: Fn(ConnSpec) -> String,
+        EF: Fn(&dyn ConnPath, ConnSpec) -> ConnEffect,
+    {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), addrs_with_net[0])?;
+
+        let rules = formatdoc! {
+            r#"
+                table inet filter {{
+                    chain input {{
+                        type filter hook input priority filter;
+                        {rule}
+                        log prefix "Other packet: " counter accept
+                    }}
+                }}
+            "#,
+            rule = build_rule(probe.spec())
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let expected_conn_effect = expect_effect(&*path, probe.spec());
+
+        let conn_effect = path.probe(&probe).await;
+
+        debug!("Firewall state:\n{}", router.list_nft_rules()?);
+        assert_same_kind(expected_conn_effect, conn_effect?);
+
+        Ok(())
+    }
+
+    /// Like `test_input`, but with the given chain policy, and no catch-all rule after the rule
+    /// under test, so that a probe that the rule does not match meets the policy.
+    async fn test_input_policy<BF, EF>(
+        addrs_with_net: &[IpNet],
+        probe: Probe,
+        policy: &str,
+        build_rule: BF,
+        expect_effect: EF,
+    ) -> Result<(), io::Error>
+    where
+        BF: Fn(ConnSpec) -> String,
+        EF: Fn(&dyn ConnPath, ConnSpec) -> ConnEffect,
+    {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), addrs_with_net[0])?;
+
+        // Unlike ARP, IPv6 neighbour discovery traverses the input hook, so it must be accepted
+        // for the probe to reach the router at all
+        let rules = formatdoc! {
+            r#"
+                table inet filter {{
+                    chain input {{
+                        type filter hook input priority filter; policy {policy};
+                        icmpv6 type {{ nd-neighbor-solicit, nd-neighbor-advert }} counter accept
+                        {rule}
+                    }}
+                }}
+            "#,
+            policy = policy,
+            rule = build_rule(probe.spec())
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let expected_conn_effect = expect_effect(&*path, probe.spec());
+
+        let conn_effect = path.probe(&probe).await;
+
+        debug!("Firewall state:\n{}", router.list_nft_rules()?);
+        assert_same_kind(expected_conn_effect, conn_effect?);
+
+        Ok(())
+    }
+
+    /// Like `test_input`, but with the rule in prerouting, and an input chain that accepts
+    /// everything, so that a drop in prerouting shows that the probe traverses prerouting first.
+    async fn test_prerouting<BF, EF>(
+        addrs_with_net: &[IpNet],
+        probe: Probe,
+        build_rule: BF,
+        expect_effect: EF,
+    ) -> Result<(), io::Error>
+    where
+        BF: Fn(ConnSpec) -> String,
+        EF: Fn(&dyn ConnPath, ConnSpec) -> ConnEffect,
+    {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), addrs_with_net[0])?;
+
+        let rules = formatdoc! {
+            r#"
+                table inet filter {{
+                    chain prerouting {{
+                        type filter hook prerouting priority filter;
+                        {rule}
+                        log prefix "Other packet: " counter accept
+                    }}
+                    chain input {{
+                        type filter hook input priority filter;
+                        {accept}
+                        log prefix "Other packet: " counter accept
+                    }}
+                }}
+            "#,
+            rule = build_rule(probe.spec()),
+            accept = build_accept(probe.spec()),
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let expected_conn_effect = expect_effect(&*path, probe.spec());
+
+        let conn_effect = path.probe(&probe).await;
+
+        debug!("Firewall state:\n{}", router.list_nft_rules()?);
+        assert_same_kind(expected_conn_effect, conn_effect?);
+
+        Ok(())
+    }
+
+    async fn test_output<BF, EF>(
+        addrs_with_net: &[IpNet],
+        probe: Probe,
+        build_rule: BF,
+        expect_effect: EF,
+    ) -> Result<(), io::Error>
+    where
+        BF: Fn(ConnSpec) -> String,
+        EF: Fn(&dyn ConnPath, ConnSpec) -> ConnEffect,
+    {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), addrs_with_net[0])?;
+
+        let rules = formatdoc! {
+            r#"
+                table inet filter {{
+                    chain output {{
+                        type filter hook output priority filter;
+                        {rule}
+                        log prefix "Other packet: " counter accept
+                    }}
+                }}
+            "#,
+            rule = build_rule(probe.spec())
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::output_path(&router, &mut wan)?;
+        let expected_conn_effect = expect_effect(&*path, probe.spec());
+
+        let conn_effect = path.probe(&probe).await;
+
+        debug!("Firewall state:\n{}", router.list_nft_rules()?);
+        assert_same_kind(expected_conn_effect, conn_effect?);
+
+        Ok(())
+    }
+
+    async fn test_forward<BF, EF>(
+        addrs_with_net: &[IpNet],
+        probe: Probe,
+        build_rule: BF,
+        expect_effect: EF,
+    ) -> Result<(), io::Error>
+    where
+        BF: Fn(ConnSpec) -> String,
+        EF: Fn(&dyn ConnPath, ConnSpec) -> ConnEffect,
+    {
+        *INIT;
+
+        let mut router = OsHost::new_router("router".into())?;
+        let mut wan = router.new_interface("wan".into(), addrs_with_net[0])?;
+        let mut lan = router.new_interface("lan".into(), addrs_with_net[1])?;
+
+        let rules = formatdoc! {
+            r#"
+                table inet filter {{
+                    chain forward {{
+                        type filter hook forward priority filter;
+                        {rule}
+                        log prefix "Other packet: " counter accept
+                    }}
+                }}
+            "#,
+            rule = build_rule(probe.spec())
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::forward_path(&mut wan, &mut lan)?;
+        let expected_conn_effect = expect_effect(&*path, probe.spec());
+
+        let conn_effect = path.probe(&probe).await;
+
+        debug!("Firewall state:\n{}", router.list_nft_rules()?);
+        assert_same_kind(expected_conn_effect, conn_effect?);
+
+        Ok(())
+    }
+
+    fn build_accept(spec: ConnSpec) -> String {
+        match spec {
+            ConnSpec::Tcp { port } => format!("tcp dport {} counter accept", port),
+            ConnSpec::Udp { port } => format!("udp dport {} counter accept", port),
+            ConnSpec::Ip { protocol } => format!("meta l4proto {} counter accept", protocol),
+            ConnSpec::Icmp { .. } => format!("{} counter accept", spec_match(spec)),
+        }
+    }
+
+    /// A rule that accepts something other than the probe, so that the probe meets the policy.
+    fn build_other(spec: ConnSpec) -> String {
+        match spec {
+            ConnSpec::Tcp { port } => build_accept(ConnSpec::Tcp {
+                port: port.wrapping_add(1),
+            }),
+            ConnSpec::Udp { port } => build_accept(ConnSpec::Udp {
+                port: port.wrapping_add(1),
+            }),
+            ConnSpec::Ip { protocol } => build_accept(ConnSpec::Ip {
+                protocol: protocol.wrapping_add(1),
+            }),
+            ConnSpec::Icmp { .. } => build_accept(ESP_SPEC),
+        }
+    }
+
+    fn build_drop(spec: ConnSpec) -> String {
+        match spec {
+            ConnSpec::Tcp { port } => format!("tcp dport {} counter drop", port),
+            ConnSpec::Udp { port } => format!("udp dport {} counter drop", port),
+            ConnSpec::Ip { protocol } => format!("meta l4proto {} counter drop", protocol),
+            ConnSpec::Icmp { .. } => format!("{} counter drop", spec_match(spec)),
+        }
+    }
+
+    fn build_reject(spec: ConnSpec) -> String {
+        match spec {
+            ConnSpec::Tcp { port } => format!("tcp dport {} counter reject with tcp reset", port),
+            ConnSpec::Udp { port } => format!("udp dport {} counter reject", port),
+            ConnSpec::Ip { protocol } => format!("meta l4proto {} counter reject", protocol),
+            ConnSpec::Icmp { .. } => format!(
+                "{} counter reject with icmpx type admin-prohibited",
+                spec_match(spec)
+            ),
+        }
+    }
+
+    fn expect_ok(path: &dyn ConnPath, spec: ConnSpec) -> ConnEffect {
+        ConnEffect::Ok {
+            source_addr: path.source_addr(),
+            target_port: spec.port(),
+        }
+    }
+
+    fn expect_unreachable(_path: &dyn ConnPath, _spec: ConnSpec) -> ConnEffect {
+        ConnEffect::Unreachable
+    }
+
+    fn expect_refused(_path: &dyn ConnPath, _spec: ConnSpec) -> ConnEffect {
+        ConnEffect::Refused { icmp: None }
+    }
+
+    /// Like `assert_eq`, but accept a refusal whatever its ICMP error.
+    fn assert_same_kind(expected: ConnEffect, actual: ConnEffect) {
+        assert!(
+            expected.same_kind(&actual),
+            "Expected {:?}, but got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn parse_conntrack_tcp_entry() -> Result<(), io::Error> {
+        let entry = parse_conntrack_entry(
+            "tcp      6 118 ESTABLISHED src=198.51.100.2 dst=198.51.100.1 sport=40000 dport=80 \
+             src=198.51.100.1 dst=198.51.100.2 sport=80 dport=40000 [ASSURED] mark=1 use=1",
+        )?;
+        assert_eq!(
+            ConntrackEntry {
+                l4proto: "tcp".into(),
+                state: Some("ESTABLISHED".into()),
+                orig: ConntrackTuple {
+                    src: "198.51.100.2".parse().unwrap(),
+                    dst: "198.51.100.1".parse().unwrap(),
+                    sport: Some(40000),
+                    dport: Some(80),
+                },
+                reply: ConntrackTuple {
+                    src: "198.51.100.1".parse().unwrap(),
+                    dst: "198.51.100.2".parse().unwrap(),
+                    sport: Some(80),
+                    dport: Some(40000),
+                },
+                mark: 1,
+            },
+            entry
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn list_addrs_json() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+
+        let text = router.ns.list_addrs()?;
+        let json = router.ns.list_addrs_json()?;
+        let addr = wan.addr().to_string();
+        assert!(text.contains(&addr));
+        assert!(json.starts_with('['));
+        assert!(json.contains(&format!("\"local\":\"{}\"", addr)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_htb_class_packets_from_stats() -> Result<(), io::Error> {
+        let classes = indoc! {"
+            class htb 1:10 root prio 0 rate 1Mbit ceil 1Mbit burst 1600b cburst 1600b
+             Sent 0 bytes 0 pkt (dropped 0, overlimits 0 requeues 0)
+             backlog 0b 0p requeues 0
+
+            class htb 1:1a root prio 0 rate 2Mbit ceil 2Mbit burst 1600b cburst 1600b
+             Sent 120 bytes 2 pkt (dropped 0, overlimits 0 requeues 0)
+             backlog 0b 0p requeues 0
+        "};
+        assert_eq!(0, parse_htb_class_packets(classes, 0x10)?);
+        assert_eq!(2, parse_htb_class_packets(classes, 0x1a)?);
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            parse_htb_class_packets(classes, 0x20).unwrap_err().kind()
+        );
+        Ok(())
+    }
+
+    async fn test_htb(
+        probe: Probe,
+        rule: Option<&str>,
+        fwmark: Option<u32>,
+    ) -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        wan.set_htb(vec![
+            HtbClass {
+                minor: 0x10,
+                rate_kbit: 1000,
+                fwmark: None,
+            },
+            HtbClass {
+                minor: 0x20,
+                rate_kbit: 1000,
+                fwmark,
+            },
+        ])?;
+
+        if let Some(rule) = rule {
+            let rules = formatdoc! {
+                r#"
+                    table inet filter {{
+                        chain output {{
+                            type filter hook output priority filter;
+                            {rule}
+                        }}
+                    }}
+                "#,
+                rule = rule,
+            };
+            router.load_nft_rules(rules.as_bytes())?;
+            debug!("Firewall state:\n{}", router.list_nft_rules()?);
+        }
+
+        let path = OsHost::output_path(&router, &mut wan)?;
+        assert_eq!(expect_ok(&*path, probe.spec()), path.probe(&probe).await?);
+        drop(path);
+
+        assert_eq!(0, wan.htb_class_packets(0x10)?);
+        assert!(wan.htb_class_packets(0x20)? > 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_htb_class_from_priority_set_by_output_firewall() -> Result<(), io::Error> {
+        let rule = "meta priority set 1:20 counter accept";
+        test_htb(UDP_SPEC.into(), Some(rule), None).await
+    }
+
+    #[tokio::test]
+    async fn test_htb_class_from_fwmark() -> Result<(), io::Error> {
+        test_htb(Probe::new(UDP_SPEC).fwmark(2), None, Some(2)).await
+    }
+
+    #[tokio::test]
+    async fn no_unexpected_ipv6_addrs() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV6_ADDRS_WITH_NET[0])?;
+        for (ns, name) in &[(&wan.ns, &wan.name), (&wan.peer_ns, &wan.peer_name)] {
+            for sysctl in &["accept_ra", "autoconf"] {
+                let path = format!("/proc/sys/net/ipv6/conf/{}/{}", name, sysctl);
+                assert_eq!("0", ns.scoped(|| fs::read_to_string(&path))?.trim());
+            }
+        }
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        let peer_addr = path.source_addr();
+        drop(path);
+
+        let global_addrs = |ns: &OsNs, name: &str| -> Result<Vec<IpNet>, io::Error> {
+            let addrs = ns.scoped_process(
+                "ip",
+                &[
+                    "-6", "-o", "address", "show", "dev", name, "scope", "global",
+                ],
+            )?;
+            Ok(addrs
+                .lines()
+                .filter_map(|line| line.split_whitespace().nth(3)?.parse().ok())
+                .collect())
+        };
+        assert_eq!(vec![wan.addr_with_net], global_addrs(&wan.ns, &wan.name)?);
+        assert_eq!(
+            vec![peer_addr],
+            global_addrs(&wan.peer_ns, &wan.peer_name)?
+                .iter()
+                .map(IpNet::addr)
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn veth_macs() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+
+        // The kernel assigns random, unicast, locally administered addresses to each end
+        let mac = wan.mac()?;
+        let peer_mac = wan.peer_mac()?;
+        debug!("{:x?} {:x?}", mac, peer_mac);
+        assert_ne!(mac, peer_mac);
+        for mac in &[mac, peer_mac] {
+            assert_eq!(0x02, mac[0] & 0x03);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn nft_batch_is_faster_than_individual_commands() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        router.apply_nft_batch(&[
+            "add table inet filter".into(),
+            "add chain inet filter input { type filter hook input priority filter; }".into(),
+        ])?;
+
+        let commands: Vec<String> = (1000..1050)
+            .map(|port| format!("add rule inet filter input tcp dport {} accept", port))
+            .collect();
+
+        let start = Instant::now();
+        for command in &commands {
+            router.apply_nft_batch(std::slice::from_ref(command))?;
+        }
+        let individual_elapsed = start.elapsed();
+
+        router.apply_nft_batch(&["flush chain inet filter input".into()])?;
+
+        let start = Instant::now();
+        router.apply_nft_batch(&commands)?;
+        let batch_elapsed = start.elapsed();
+
+        debug!(
+            "{} individual commands took {:?}, one batch took {:?}",
+            commands.len(),
+            individual_elapsed,
+            batch_elapsed
+        );
+        assert_eq!(
+            commands.len(),
+            router.list_nft_rules()?.matches("tcp dport").count()
+        );
+        assert!(batch_elapsed < individual_elapsed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_resources_leaked_after_drop() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let ids = [router.ns.id().unwrap(), wan.peer_ns.id().unwrap()];
+        let leaked = || {
+            resources().into_iter().any(|resource| match resource {
+                Resource::Ns { id, .. } | Resource::Link { ns_id: id, .. } => ids.contains(&id),
+            })
+        };
+        assert!(resources().contains(&Resource::Link {
+            ns_id: ids[1],
+            name: "router".into(),
+        }));
+
+        drop(wan);
+        drop(router);
+        assert!(!leaked());
+
+        Ok(())
+    }
+
+    #[test]
+    fn new_interface_overlapping_net() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let _wan = router.new_interface("wan".into(), "198.51.100.1/24".parse().unwrap())?;
+        let err = router
+            .new_interface("vpn".into(), "198.51.100.129/25".parse().unwrap())
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let _lan = router.new_interface("lan".into(), IPV4_ADDRS_WITH_NET[1])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_nets_against_other_interfaces() {
+        let claimed = vec![("wan".to_owned(), "198.51.100.0/24".parse().unwrap())];
+        let addr_with_net = "198.51.100.2/24".parse().unwrap();
+        assert!(check_nets(&claimed, &[("wan", addr_with_net)]).is_ok());
+        assert!(check_nets(&claimed, &[("lan", addr_with_net)]).is_err());
+        let nets = [
+            ("a", "10.0.0.1/24".parse().unwrap()),
+            ("b", "10.0.0.2/25".parse().unwrap()),
+        ];
+        assert!(check_nets(&[], &nets).is_err());
+        assert!(check_nets(&[], &nets[..1]).is_ok());
+    }
+
+    #[test]
+    fn nets_are_released() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        // An interface that was dropped, or whose networks were replaced, no longer claims them
+        let wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        drop(wan);
+        let _vpn = router.new_interface("vpn".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut lan = router.new_interface("lan".into(), IPV4_ADDRS_WITH_NET[1])?;
+        let err = lan
+            .reassign(vec!["198.51.100.129/25".parse().unwrap()])
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        lan.reassign(vec!["192.0.2.1/24".parse().unwrap()])?;
+        let _dmz = router.new_interface("dmz".into(), IPV4_ADDRS_WITH_NET[1])?;
+
+        // Nothing is claimed if any of the interfaces overlap
+        let err = router
+            .new_interfaces(vec![
+                ("a".into(), "10.0.0.1/24".parse().unwrap()),
+                ("b".into(), "10.0.0.2/24".parse().unwrap()),
+            ])
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let _a = router.new_interface("a".into(), "10.0.0.1/24".parse().unwrap())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn library_nft_backend() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::with_nft_backend("router".into(), NftBackend::Library)?;
+        let rules = indoc! {"
+            table inet filter {
+                chain input {
+                    type filter hook input priority 0; policy accept;
+                    udp dport 53 drop
+                }
+            }
+        "};
+        let echo = router.load_nft_rules_echo(rules.as_bytes())?;
+        assert!(echo.contains("# handle"), "{}", echo);
+        assert!(router.has_nft_rule("inet filter", "input", "udp dport 53 drop")?);
+        assert!(router.list_nft_rules_json()?.trim_start().starts_with('{'));
+
+        Ok(())
+    }
+
+    #[test]
+    fn has_nft_rule_after_removal() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        router.apply_nft_batch(&[
+            "add table inet filter".into(),
+            "add chain inet filter input { type filter hook input priority filter; }".into(),
+            "add rule inet filter input tcp dport 22 drop".into(),
+        ])?;
+        assert!(router.has_nft_rule("inet filter", "input", "tcp dport 22 drop")?);
+
+        router.apply_nft_batch(&["flush chain inet filter input".into()])?;
+        assert!(!router.has_nft_rule("inet filter", "input", "tcp dport 22 drop")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn firewall_add_and_delete_rule() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut firewall = router.firewall();
+        firewall.load(
+            indoc! {r#"
+                table inet filter {
+                    counter ssh {}
+                    set blocked {
+                        type ipv4_addr
+                    }
+                    chain input {
+                        type filter hook input priority filter;
+                    }
+                }
+            "#}
+            .as_bytes(),
+        )?;
+        let snapshot = firewall.snapshot()?;
+
+        let handle = firewall.add_rule("inet filter", "input", "tcp dport 22 counter name ssh")?;
+        assert!(firewall.check("inet filter", "input", "tcp dport 22")?);
+        assert_eq!(Counter::default(), firewall.counter("inet filter", "ssh")?);
+        firewall.delete_rule("inet filter", "input", handle)?;
+        assert!(!firewall.check("inet filter", "input", "tcp dport 22")?);
+
+        firewall.add_set_element("inet filter", "blocked", "192.0.2.1")?;
+        assert!(firewall.list()?.contains("192.0.2.1"));
+        firewall.restore(&snapshot)?;
+        assert!(!firewall.list()?.contains("192.0.2.1"));
+
+        firewall.flush()?;
+        assert!(!firewall.list()?.contains("table inet filter"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drop_rule_shadowed_by_accept_counts_nothing() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new_router("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut lan = router.new_interface("lan".into(), IPV4_ADDRS_WITH_NET[1])?;
+        let mut firewall = router.firewall();
+        firewall.load(
+            indoc! {r#"
+                table inet filter {
+                    chain forward {
+                        type filter hook forward priority filter;
+                    }
+                }
+            "#}
+            .as_bytes(),
+        )?;
+        let accept = firewall.add_rule("inet filter", "forward", &build_accept(UDP_SPEC))?;
+        let drop = firewall.add_rule("inet filter", "forward", &build_drop(UDP_SPEC))?;
+
+        let path = OsHost::forward_path(&mut wan, &mut lan)?;
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        let firewall = router.firewall();
+        firewall.assert_counter("inet filter", "forward", drop, 0)?;
+        assert!(
+            firewall
+                .rule_counter("inet filter", "forward", accept)?
+                .packets
+                > 0
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn diff_policy_reports_changed_probes() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut topology =
+            Topology::<OsHost>::router_lan_wan(IPV4_ADDRS_WITH_NET[0], IPV4_ADDRS_WITH_NET[1])?;
+        let old_rules = indoc! {r#"
+            table inet filter {
+                chain forward {
+                    type filter hook forward priority filter; policy accept;
+                }
+            }
+        "#};
+        let new_rules = indoc! {r#"
+            table inet filter {
+                chain forward {
+                    type filter hook forward priority filter; policy accept;
+                    udp dport 53 reject
+                }
+            }
+        "#};
+        let path = OsHost::forward_path(&mut topology.lan, &mut topology.wan)?;
+        let cases = [
+            (&*path, Probe::new(ConnSpec::Udp { port: 53 })),
+            (&*path, Probe::new(ConnSpec::Udp { port: 54 })),
+        ];
+        let changes = diff_policy(&mut topology.router, old_rules, new_rules, &cases).await?;
+        assert_eq!(
+            vec![0],
+            changes
+                .iter()
+                .map(|change| change.index)
+                .collect::<Vec<_>>()
+        );
+        assert!(matches!(changes[0].new, ConnEffect::Refused { .. }));
+        assert!(!topology.router.list_nft_rules()?.contains("udp dport 53"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn diff_policy_reports_changed_rejection() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut topology =
+            Topology::<OsHost>::router_lan_wan(IPV4_ADDRS_WITH_NET[0], IPV4_ADDRS_WITH_NET[1])?;
+        let old_rules = indoc! {r#"
+            table inet filter {
+                chain forward {
+                    type filter hook forward priority filter; policy accept;
+                    udp dport 53 reject
+                }
+            }
+        "#};
+        let new_rules = indoc! {r#"
+            table inet filter {
+                chain forward {
+                    type filter hook forward priority filter; policy accept;
+                    udp dport 53 reject with icmpx type admin-prohibited
+                }
+            }
+        "#};
+        let path = OsHost::forward_path(&mut topology.lan, &mut topology.wan)?;
+        let cases = [(&*path, Probe::new(ConnSpec::Udp { port: 53 }))];
+        let changes = diff_policy(&mut topology.router, old_rules, new_rules, &cases).await?;
+        assert_eq!(1, changes.len(), "Effects changed: {:?}", changes);
+        let codes = match (changes[0].old, changes[0].new) {
+            (ConnEffect::Refused { icmp: Some(old) }, ConnEffect::Refused { icmp: Some(new) }) => {
+                (old.code, new.code)
+            }
+            other => panic!("Expected two ICMP rejections, but got {:?}", other),
+        };
+        // Port unreachable, then administratively prohibited
+        assert_eq!((3, 13), codes);
+        assert!(changes[0].old.same_kind(&changes[0].new));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn diff_configs_of_equivalent_rules() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut topology =
+            Topology::<OsHost>::router_lan_wan(IPV4_ADDRS_WITH_NET[0], IPV4_ADDRS_WITH_NET[1])?;
+        let literal_rules = indoc! {r#"
+            table inet filter {
+                chain forward {
+                    type filter hook forward priority filter; policy accept;
+                    udp dport 53 reject
+                }
+            }
+        "#};
+        let defined_rules = indoc! {r#"
+            table inet filter {
+                chain forward {
+                    type filter hook forward priority filter; policy accept;
+                    udp dport $dns_port reject
+                }
+            }
+        "#};
+        let defines = vec![("dns_port".to_owned(), "53".to_owned())]
+            .into_iter()
+            .collect();
+        let path = OsHost::forward_path(&mut topology.lan, &mut topology.wan)?;
+        let cases = [
+            (&*path, Probe::new(ConnSpec::Udp { port: 53 })),
+            (&*path, Probe::new(ConnSpec::Udp { port: 54 })),
+        ];
+        let changes = diff_configs(
+            &mut topology.router,
+            |router| router.firewall().replace(literal_rules),
+            |router| {
+                router.firewall().flush()?;
+                router.load_nft_rules_with_defines(defined_rules, &defines, Path::new("/"))
+            },
+            &cases,
+        )
+        .await?;
+        assert!(changes.is_empty(), "Effects changed: {:?}", changes);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trace_hooks() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut topology =
+            Topology::<OsHost>::router_lan_wan(IPV4_ADDRS_WITH_NET[0], IPV4_ADDRS_WITH_NET[1])?;
+
+        topology.router.firewall().trace_hooks(UDP_SPEC)?;
+        topology.lan_to_wan()?.connect(UDP_SPEC).await?;
+        assert_eq!(
+            BTreeSet::from([Hook::Prerouting, Hook::Forward, Hook::Postrouting]),
+            topology.router.firewall().traced_hooks()?
+        );
+
+        topology.router.firewall().trace_hooks(UDP_SPEC)?;
+        OsHost::input_path(&mut topology.wan, &topology.router)?
+            .connect(UDP_SPEC)
+            .await?;
+        assert_eq!(
+            BTreeSet::from([Hook::Prerouting, Hook::Input]),
+            topology.router.firewall().traced_hooks()?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_to_other_host() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut alice = OsHost::new("alice".into())?;
+        let mut bob = OsHost::new("bob".into())?;
+        let (alice_link, bob_link) = alice.connect_to(
+            &mut bob,
+            "198.51.100.1/24".parse().unwrap(),
+            "198.51.100.2/24".parse().unwrap(),
+        )?;
+        assert_eq!("bob", alice_link.name());
+        assert_eq!("alice", bob_link.name());
+
+        for link in &[&alice_link, &bob_link] {
+            let path = link.path();
+            assert_eq!(link.addr_with_net().addr(), path.source_addr());
+            assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trace_probe_reports_drop_rule() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new_router("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut lan = router.new_interface("lan".into(), IPV4_ADDRS_WITH_NET[1])?;
+        let rules = formatdoc! {
+            r#"
+                table inet filter {{
+                    chain forward {{
+                        type filter hook forward priority filter;
+                        {rule}
+                    }}
+                }}
+            "#,
+            rule = build_drop(UDP_SPEC)
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::forward_path(&mut wan, &mut lan)?;
+        let events = router.trace_probe(&*path, &Probe::new(UDP_SPEC)).await?;
+        debug!("Trace events: {:?}", events);
+        // The rule is reported as listed, i.e. with the values of its counter
+        assert!(events.iter().any(|event| event.table == "inet filter"
+            && event.chain == "forward"
+            && matches!(&event.kind, TraceKind::Rule { verdict, .. } if verdict == "drop")));
+        assert!(!router.list_nft_rules()?.contains("firebreak_nftrace"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trace_probe_outcome_reports_reject_rule() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new_router("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut lan = router.new_interface("lan".into(), IPV4_ADDRS_WITH_NET[1])?;
+        let rules = formatdoc! {
+            r#"
+                table inet filter {{
+                    chain forward {{
+                        type filter hook forward priority filter;
+                        {rule}
+                    }}
+                }}
+            "#,
+            rule = build_reject(UDP_SPEC)
+        };
+        let echo = router.load_nft_rules_echo(rules.as_bytes())?;
+
+        let path = OsHost::forward_path(&mut wan, &mut lan)?;
+        let traced = router
+            .trace_probe_outcome(&*path, &Probe::new(UDP_SPEC))
+            .await?;
+        assert_same_kind(expect_refused(&*path, UDP_SPEC), traced.outcome.effect);
+        let verdict = traced.verdict.expect("The packet was traced");
+        assert_eq!(
+            ("inet filter", "forward", "drop"),
+            (&*verdict.table, &*verdict.chain, &*verdict.verdict)
+        );
+        let handle = verdict.handle.expect("The rule has a handle");
+        assert!(echo.contains(&format!("# handle {}", handle)), "{}", echo);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trace_probe_cleans_up_after_failure() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let probe = Probe::new(UDP_SPEC).reuseport_listeners(0);
+        let err = router.trace_probe(&*path, &probe).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let tables = router.ns.scoped_process("nft", &["list", "tables"])?;
+        assert!(!tables.contains(NFTRACE_TABLE), "{}", tables);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_oper_state_from_flags() {
+        assert_eq!(
+            Some(OperState::Up),
+            parse_oper_state("2: wan@if2: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 state UP")
+        );
+        assert_eq!(
+            Some(OperState::LowerLayerDown),
+            parse_oper_state("2: wan@if2: <NO-CARRIER,BROADCAST,MULTICAST,UP> mtu 1500")
+        );
+        assert_eq!(
+            Some(OperState::Down),
+            parse_oper_state("2: wan@if2: <BROADCAST,MULTICAST> mtu 1500 state DOWN")
+        );
+        assert_eq!(None, parse_oper_state("2: wan@if2: mtu 1500"));
+    }
+
+    #[test]
+    fn input_path_without_carrier() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        assert_eq!(OperState::Up, wan.oper_state()?);
+
+        wan.peer_ns
+            .scoped_process("ip", &["link", "set", "dev", "router", "down"])?;
+        assert_eq!(OperState::LowerLayerDown, wan.oper_state()?);
+        let err = OsHost::input_path(&mut wan, &router).err().unwrap();
+        assert_eq!(io::ErrorKind::NotConnected, err.kind());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn route_with_preferred_source() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut lan = router.new_interface("lan".into(), IPV6_ADDRS_WITH_NET[0])?;
+        let bound_addr_with_net: IpNet = "2001:db8:1111:1111::2/64".parse().unwrap();
+        let preferred_addr_with_net: IpNet = "2001:db8:1111:1111::3/64".parse().unwrap();
+        let (bound_addr, preferred_addr) =
+            (bound_addr_with_net.addr(), preferred_addr_with_net.addr());
+        lan.peer_ns.set_addr(&lan.peer_name, bound_addr_with_net)?;
+        lan.peer_ns.scoped_process(
+            "ip",
+            &[
+                "address",
+                "add",
+                &preferred_addr_with_net.to_string(),
+                "dev",
+                &lan.peer_name,
+                "nodad",
+            ],
+        )?;
+        lan.peer_ns.add_route_with_src(
+            IpNet::from(lan.addr()),
+            None,
+            preferred_addr,
+            &lan.peer_name,
+        )?;
+
+        let path = OsNsConnPath::new(
+            &lan.name,
+            &lan.peer_ns,
+            bound_addr,
+            &router.name,
+            &router.ns,
+            lan.addr(),
+        );
+        let probe = Probe::new(UDP_SPEC);
+        assert_eq!(expect_ok(&path, UDP_SPEC), path.probe(&probe).await?);
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: preferred_addr,
+                target_port: UDP_SPEC.port(),
+            },
+            path.probe(&probe.kernel_source_addr()).await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn new_interfaces_forward() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new_router("router".into())?;
+        let mut interfaces = router.new_interfaces(vec![
+            ("wan".into(), IPV4_ADDRS_WITH_NET[0]),
+            ("lan".into(), IPV4_ADDRS_WITH_NET[1]),
+        ])?;
+        let mut lan = interfaces.pop().unwrap();
+        let mut wan = interfaces.pop().unwrap();
+        assert_eq!("lan", lan.name());
+
+        let path = OsHost::forward_path(&mut wan, &mut lan)?;
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forward_path_via_missing_gateway_is_unreachable() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new_router("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut lan = router.new_interface("lan".into(), IPV4_ADDRS_WITH_NET[1])?;
+
+        // Outside the network of the interface, and so never answers neighbour solicitations
+        let gateway = "192.0.2.1".parse().unwrap();
+        let path = OsHost::forward_path_via(&mut wan, &mut lan, gateway)?;
+        assert_eq!(ConnEffect::Unreachable, path.connect(UDP_SPEC).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forward_path_ttl_expires_at_router() -> Result<(), io::Error> {
+        *INIT;
+
+        for addrs_with_net in &[&*IPV4_ADDRS_WITH_NET, &*IPV6_ADDRS_WITH_NET] {
+            let mut router = OsHost::new_router("router".into())?;
+            let mut wan = router.new_interface("wan".into(), addrs_with_net[0])?;
+            let mut lan = router.new_interface("lan".into(), addrs_with_net[1])?;
+            let path = OsHost::forward_path(&mut wan, &mut lan)?;
+            for spec in &[UDP_SPEC, ConnSpec::Ip { protocol: 253 }] {
+                assert_eq!(
+                    ConnEffect::TtlExpired {
+                        from: addrs_with_net[0].addr()
+                    },
+                    path.probe(&Probe::new(*spec).ttl(1)).await?
+                );
+            }
+            assert_eq!(
+                expect_ok(&*path, UDP_SPEC),
+                path.probe(&Probe::new(UDP_SPEC).ttl(2)).await?
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transit_interface() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new_router("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let remote_net: IpNet = "10.2.0.0/24".parse().unwrap();
+        let mut transit = router.new_transit_interface(
+            "router2".into(),
+            "192.0.2.1/30".parse().unwrap(),
+            "192.0.2.2/30".parse().unwrap(),
+            remote_net,
+        )?;
+
+        {
+            let path = OsHost::input_path(&mut transit, &router)?;
+            assert!(remote_net.contains(&path.source_addr()));
+            assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        }
+        {
+            let path = OsHost::output_path(&router, &mut transit)?;
+            assert!(remote_net.contains(&path.target_addr()));
+            assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        }
+        {
+            let path = OsHost::forward_path(&mut wan, &mut transit)?;
+            assert!(remote_net.contains(&path.target_addr()));
+            assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        }
+
+        let err = router
+            .new_transit_interface(
+                "router3".into(),
+                "192.0.2.5/30".parse().unwrap(),
+                "192.0.2.9/30".parse().unwrap(),
+                "10.3.0.0/24".parse().unwrap(),
+            )
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = router
+            .new_transit_interface(
+                "router3".into(),
+                "192.0.2.5/30".parse().unwrap(),
+                "192.0.2.6/30".parse().unwrap(),
+                remote_net,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("overlaps"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ipvlan() -> Result<(), io::Error> {
+        *INIT;
+
+        for &(mode, addr) in &[
+            (IpvlanMode::L2, "198.51.100.200/24"),
+            (IpvlanMode::L3, "192.0.2.2/32"),
+        ] {
+            let mut router = OsHost::new("router".into())?;
+            let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+            let ipvlan = router.add_ipvlan("c1".into(), &wan, mode, addr.parse().unwrap())?;
+            assert_eq!("c1", ipvlan.name());
+            {
+                let path = ipvlan.path_to_peer(&mut wan)?;
+                assert_eq!(ipvlan.addr_with_net().addr(), path.source_addr());
+                assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+            }
+            let path = ipvlan.path_from_peer(&mut wan)?;
+            assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn ipvlan_l2_outside_parent_network() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let err = router
+            .add_ipvlan(
+                "c1".into(),
+                &wan,
+                IpvlanMode::L2,
+                "192.0.2.2/24".parse().unwrap(),
+            )
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn macvlan() -> Result<(), io::Error> {
+        *INIT;
+
+        for &mode in &[MacvlanMode::Bridge, MacvlanMode::Private] {
+            let mut router = OsHost::new("router".into())?;
+            let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+            let c1 = router.add_macvlan(
+                "c1".into(),
+                &wan,
+                mode,
+                "198.51.100.201/24".parse().unwrap(),
+            )?;
+            let c2 = router.add_macvlan(
+                "c2".into(),
+                &wan,
+                mode,
+                "198.51.100.202/24".parse().unwrap(),
+            )?;
+            assert_eq!("c1", c1.name());
+            assert_ne!(wan.mac()?, c1.mac()?);
+            {
+                let path = c1.path_to_peer(&mut wan)?;
+                assert_eq!(c1.addr_with_net().addr(), path.source_addr());
+                assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+            }
+            {
+                let path = c1.path_from_peer(&mut wan)?;
+                assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+            }
+            let path = c1.path_to(&c2);
+            let expected = match mode {
+                MacvlanMode::Bridge => expect_ok(&*path, UDP_SPEC),
+                MacvlanMode::Private => expect_unreachable(&*path, UDP_SPEC),
+            };
+            assert_eq!(expected, path.connect(UDP_SPEC).await?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn macvlan_outside_parent_network() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let err = router
+            .add_macvlan(
+                "c1".into(),
+                &wan,
+                MacvlanMode::Bridge,
+                "192.0.2.2/24".parse().unwrap(),
+            )
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        Ok(())
+    }
+
+    #[test]
+    fn forward_path_default_routes() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new_router("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut lan = router.new_interface("lan".into(), IPV6_ADDRS_WITH_NET[1])?;
+        assert_eq!(None, wan.peer_ns.default_route_v4()?);
+
+        drop(OsHost::forward_path(&mut wan, &mut lan)?);
+
+        for interface in &[&wan, &lan] {
+            let default_route: Option<IpAddr> = match interface.addr() {
+                IpAddr::V4(_) => interface.peer_ns.default_route_v4()?.map(IpAddr::V4),
+                IpAddr::V6(_) => interface.peer_ns.default_route_v6()?.map(IpAddr::V6),
+            };
+            assert_eq!(Some(interface.addr()), default_route);
+        }
+        assert_eq!(None, wan.peer_ns.default_route_v6()?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_data_input_firewall_with_tcp_over_ipv4() -> Result<(), io::Error> {
+        // The handshake is allowed, but the segment carrying the cookie is reset
+        test_input(
+            &IPV4_ADDRS_WITH_NET,
+            TCP_SPEC.into(),
+            |spec| {
+                format!(
+                    "tcp dport {} tcp flags & psh == psh counter reject with tcp reset",
+                    spec.port()
+                )
+            },
+            |_, _| ConnEffect::ResetDuringTransfer,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn wireguard_overlay_and_underlay() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut wg = router.add_wireguard(
+            "wg0".into(),
+            &mut wan,
+            WireguardConfig {
+                addr_with_net: "10.0.0.1/24".parse().unwrap(),
+                listen_port: 51820,
+            },
+        )?;
+
+        let path = OsHost::input_path(&mut wg, &router)?;
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        drop(path);
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        drop(path);
+
+        // The tunnel survives the reassignment of the underlay address
+        let path = OsHost::input_path(&mut wg, &router)?;
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn vxlan_overlay_and_underlay() -> Result<(), io::Error> {
+        *INIT;
+
+        for &(underlay_addr, overlay_addr, remote) in &[
+            (IPV4_ADDRS_WITH_NET[0], "10.0.0.1/24", "198.51.100.2"),
+            (
+                IPV6_ADDRS_WITH_NET[0],
+                "fd00::1/64",
+                "2001:db8:1111:1111::2",
+            ),
+        ] {
+            let mut router = OsHost::new("router".into())?;
+            let mut wan = router.new_interface("wan".into(), underlay_addr)?;
+            let mut vxlan = router.add_vxlan(
+                "vxlan0".into(),
+                &mut wan,
+                VxlanConfig {
+                    vni: 42,
+                    addr_with_net: overlay_addr.parse().unwrap(),
+                    remote: remote.parse().unwrap(),
+                },
+            )?;
+
+            let path = OsHost::input_path(&mut vxlan, &router)?;
+            assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+            drop(path);
+
+            let path = OsHost::input_path(&mut wan, &router)?;
+            assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+            drop(path);
+
+            // The peer keeps its endpoint when its underlay address is reassigned
+            let path = OsHost::input_path(&mut vxlan, &router)?;
+            assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn vxlan_remote_outside_underlay_network() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let err = router
+            .add_vxlan(
+                "vxlan0".into(),
+                &mut wan,
+                VxlanConfig {
+                    vni: 42,
+                    addr_with_net: "10.0.0.1/24".parse().unwrap(),
+                    remote: "192.0.2.2".parse().unwrap(),
+                },
+            )
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_saddr_input_firewall_with_two_subnets() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut lan1 = router.new_interface("lan1".into(), "10.1.0.1/16".parse().unwrap())?;
+        let mut lan2 = router.new_interface("lan2".into(), "10.2.0.1/16".parse().unwrap())?;
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain input {
+                        type filter hook input priority filter;
+                        ip saddr 10.1.0.0/16 counter accept
+                        ip saddr 10.2.0.0/16 counter drop
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path1 = OsHost::input_path(&mut lan1, &router)?;
+        let path2 = OsHost::input_path(&mut lan2, &router)?;
+        let effects = effects_by_source(&[&*path1, &*path2], &Probe::new(UDP_SPEC)).await?;
+        assert_eq!(
+            vec![
+                ("lan1".to_owned(), expect_ok(&*path1, UDP_SPEC)),
+                ("lan2".to_owned(), ConnEffect::Unreachable),
+            ],
+            effects.into_iter().collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_falls_back_to_ipv4() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_dual_stack_interface(
+            "wan".into(),
+            IPV4_ADDRS_WITH_NET[0],
+            IPV6_ADDRS_WITH_NET[0],
+        )?;
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain input {
+                        type filter hook input priority filter;
+                        meta nfproto ipv6 tcp dport 80 counter drop
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let outcome = path.connect_happy_eyeballs(TCP_SPEC.port()).await?;
+        assert_eq!(IPV4_ADDRS_WITH_NET[0].addr(), outcome.target_addr);
+        assert_eq!(expect_ok(&*path, TCP_SPEC), outcome.effect);
+        assert!(outcome.fallback_after.unwrap() >= HAPPY_EYEBALLS_DELAY);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ftp_helper_accepts_related_data_connection() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        router.load_conntrack_helper("ftp")?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    ct helper ftp-standard {
+                        type "ftp" protocol tcp;
+                    }
+
+                    chain prerouting {
+                        type filter hook prerouting priority filter;
+                        tcp dport 21 ct helper set "ftp-standard"
+                    }
+
+                    chain input {
+                        type filter hook input priority filter; policy drop;
+                        ct state established,related counter accept
+                        tcp dport 21 counter accept
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let probe = Probe::new(ConnSpec::Tcp { port: 21 });
+        let effects = path.probe_related(&probe, FtpMode::Passive, 2121).await?;
+        assert_eq!(
+            expect_ok(&*path, ConnSpec::Tcp { port: 21 }),
+            effects.control
+        );
+        assert_eq!(
+            Some(expect_ok(&*path, ConnSpec::Tcp { port: 2121 })),
+            effects.data
+        );
+
+        // Without the control connection, the data port is not related to anything
+        assert_eq!(
+            ConnEffect::Unreachable,
+            path.connect(ConnSpec::Tcp { port: 2121 }).await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ftp_helper_accepts_active_data_connection() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        router.load_conntrack_helper("ftp")?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    ct helper ftp-standard {
+                        type "ftp" protocol tcp;
+                    }
+
+                    chain prerouting {
+                        type filter hook prerouting priority filter;
+                        tcp dport 21 ct helper set "ftp-standard"
+                    }
+
+                    chain input {
+                        type filter hook input priority filter; policy drop;
+                        ct state established,related counter accept
+                        tcp dport 21 counter accept
+                    }
+
+                    chain output {
+                        type filter hook output priority filter; policy drop;
+                        ct state established,related counter accept
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        {
+            let path = OsHost::input_path(&mut wan, &router)?;
+            let probe = Probe::new(ConnSpec::Tcp { port: 21 });
+            let effects = path.probe_related(&probe, FtpMode::Active, 2020).await?;
+            assert_eq!(
+                expect_ok(&*path, ConnSpec::Tcp { port: 21 }),
+                effects.control
+            );
+            // The router opens the data connection, from its own address
+            assert_eq!(
+                Some(ConnEffect::Ok {
+                    source_addr: path.target_addr(),
+                    target_port: 2020,
+                }),
+                effects.data
+            );
+        }
+
+        // Without the control connection, the router cannot open one of its own
+        let path = OsHost::output_path(&router, &mut wan)?;
+        assert_eq!(
+            ConnEffect::Unreachable,
+            path.connect(ConnSpec::Tcp { port: 2020 }).await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn snapshot_diff_around_probe() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let before = router.snapshot()?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        let diff = before.diff(&router.snapshot()?);
+        assert!(diff.links.is_empty());
+        assert!(diff.addrs.is_empty());
+        assert!(diff.routes.is_empty());
+        // The probe resolved the peer, and left an entry for its connection
+        assert_eq!(1, diff.neighbors.added.len());
+        assert!(diff.neighbors.added[0].starts_with(&format!("{} dev wan", path.source_addr())));
+        assert!(diff
+            .conntrack
+            .added
+            .iter()
+            .any(|entry| entry.orig.src == path.source_addr()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn syn_flood_rate_limit() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain input {
+                        type filter hook input priority filter;
+                        tcp flags syn limit rate over 10/second burst 5 packets counter drop
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let probe = Probe::new(TCP_SPEC).syn_only();
+        let report = path.syn_flood(&probe, 20, 1000).await?;
+        // The burst is answered, and the rest of the flood is dropped
+        let first_dropped = report.first_dropped().expect("No SYNs were dropped");
+        assert!(first_dropped >= 5);
+        assert_eq!(first_dropped, report.syn_acked());
+        assert_eq!(20 - first_dropped, report.dropped());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn numgen_random_drop_rate() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain input {
+                        type filter hook input priority filter;
+                        icmp type echo-request numgen random mod 100 < 50 counter drop
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let rate = drop_rate(&*path, ICMP_SPEC, 100, 50).await?;
+        // Three standard errors of the fraction dropped
+        rate.assert_within(0.5, 0.15);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn all_counters_after_probe() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    counter dns {
+                    }
+
+                    counter http {
+                    }
+
+                    chain input {
+                        type filter hook input priority filter;
+                        udp dport 53 counter name "dns"
+                        tcp dport 80 counter name "http"
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        let counters = router.all_counters()?;
+        assert_eq!(1, counters["inet filter dns"].packets);
+        assert_eq!(Counter::default(), counters["inet filter http"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn established_connection_survives_reload() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain input {
+                        type filter hook input priority filter; policy drop;
+                        ct state established accept
+                        ct state new tcp dport 80 accept
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let probe = Probe::new(TCP_SPEC);
+        let (mut conn, expected) = {
+            let path = OsHost::input_path(&mut wan, &router)?;
+            (
+                path.open_connection(&probe).await?,
+                expect_ok(&*path, TCP_SPEC),
+            )
+        };
+        assert_eq!(expected, conn.exchange().await?);
+
+        // The new ruleset admits no new connections, but conntrack still knows this one
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain input {
+                        type filter hook input priority filter; policy drop;
+                        ct state established accept
+                    }
+                }
+            "#
+        };
+        router.reload_nft_rules(rules)?;
+        assert_eq!(expected, conn.exchange().await?);
+        {
+            let path = OsHost::input_path(&mut wan, &router)?;
+            assert_eq!(ConnEffect::Unreachable, path.connect(TCP_SPEC).await?);
+        }
+
+        // Once conntrack forgets it, the connection's next packet is new, and dropped
+        router.flush_conntrack()?;
+        assert_eq!(ConnEffect::Unreachable, conn.exchange().await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_routers_forward() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut host = OsHost::new("host".into())?;
+        let mut wan = host.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut lan = host.new_interface("lan".into(), IPV4_ADDRS_WITH_NET[1])?;
+        let path = OsHost::forward_path(&mut wan, &mut lan)?;
+        assert_eq!(ConnEffect::Unreachable, path.connect(UDP_SPEC).await?);
+
+        let mut router = OsHost::new_router("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut lan = router.new_interface("lan".into(), IPV4_ADDRS_WITH_NET[1])?;
+        let path = OsHost::forward_path(&mut wan, &mut lan)?;
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ct_state_invalid_drop_input_firewall() -> Result<(), io::Error> {
+        for addrs_with_net in &[&*IPV4_ADDRS_WITH_NET, &*IPV6_ADDRS_WITH_NET] {
+            let rule = |_| "ct state invalid counter drop".into();
+            test_input(addrs_with_net, Probe::new(TCP_SPEC), rule, expect_ok).await?;
+            test_input(
+                addrs_with_net,
+                Probe::new(TCP_SPEC).out_of_state(),
+                rule,
+                expect_unreachable,
+            )
+            .await?;
+            test_input(
+                addrs_with_net,
+                Probe::new(TCP_SPEC).out_of_state(),
+                |_| "".into(),
+                expect_ok,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn load_nft_rules_with_defines_and_include() -> Result<(), io::Error> {
+        *INIT;
+
+        let dir = std::env::temp_dir().join(format!("firebreak-{}", rand::random::<u64>()));
+        fs::create_dir(&dir)?;
+        fs::write(
+            dir.join("input.nft"),
+            indoc! {"
+                chain input {
+                    type filter hook input priority filter;
+                    tcp dport $blocked_port counter drop
+                }
+            "},
+        )?;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let defines = vec![("blocked_port".to_owned(), "80".to_owned())]
+            .into_iter()
+            .collect();
+        let res = router.load_nft_rules_with_defines(
+            indoc! {r#"
+                table inet filter {
+                    include "input.nft"
+                }
+            "#},
+            &defines,
+            &dir,
+        );
+        fs::remove_dir_all(&dir)?;
+        res?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        assert_eq!(
+            ConnEffect::Unreachable,
+            path.probe(&Probe::new(TCP_SPEC)).await?
+        );
+        assert_eq!(
+            expect_ok(&*path, UDP_SPEC),
+            path.probe(&Probe::new(UDP_SPEC)).await?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn dual_stack_interface_in_one_family() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let err = router
+            .new_dual_stack_interface("wan".into(), IPV4_ADDRS_WITH_NET[0], IPV4_ADDRS_WITH_NET[1])
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn output_path_is_pinned_to_interface() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut vpn = router.new_interface("vpn".into(), IPV4_ADDRS_WITH_NET[1])?;
+        // More specific routes send the VPN's network out of the WAN, so routing alone would
+        // choose the WAN for both
+        for half in IPV4_ADDRS_WITH_NET[1].trunc().subnets(25).unwrap() {
+            router.ns.add_link_route(half, "wan")?;
+        }
+
+        for interface in &mut [&mut wan, &mut vpn] {
+            let path = OsHost::output_path(&router, interface)?;
+            assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oifname_output_firewall() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut vpn = router.new_interface("vpn".into(), IPV4_ADDRS_WITH_NET[1])?;
+
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain output {
+                        type filter hook output priority filter;
+                        oifname "wan" counter accept
+                        oifname "vpn" counter drop
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::output_path(&router, &mut wan)?;
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        let path = OsHost::output_path(&router, &mut vpn)?;
+        assert_eq!(ConnEffect::Unreachable, path.connect(UDP_SPEC).await?);
+
+        debug!("Firewall state:\n{}", router.list_nft_rules()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ct_mark_set_by_input_firewall() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain input {
+                        type filter hook input priority filter;
+                        tcp dport 80 ct mark set 0x1 counter accept
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        assert_eq!(expect_ok(&*path, TCP_SPEC), path.connect(TCP_SPEC).await?);
+
+        let entries = router.conntrack()?;
+        debug!("Conntrack state:\n{:#?}", entries);
+        let entry = entries
+            .iter()
+            .find(|entry| entry.orig.src == path.source_addr() && entry.orig.dport == Some(80))
+            .expect("Probe did not create a conntrack entry");
+        assert_eq!(0x1, entry.mark);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drop_output_firewall_with_matching_source_port() -> Result<(), io::Error> {
+        test_output(
+            &IPV4_ADDRS_WITH_NET,
+            Probe::new(TCP_SPEC).source_port(40000),
+            |_| "tcp sport 40000 counter drop".into(),
+            expect_unreachable,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_drop_output_firewall_with_other_source_port() -> Result<(), io::Error> {
+        test_output(
+            &IPV4_ADDRS_WITH_NET,
+            Probe::new(TCP_SPEC).source_port(40001),
+            |_| "tcp sport 40000 counter drop".into(),
+            expect_ok,
+        )
+        .await
+    }
+
+    fn uid_is_mapped(uid: u32) -> bool {
+        let uid_map = fs::read_to_string("/proc/self/uid_map").expect("Failed to read uid map");
+        uid_map.lines().any(|line| {
+            let fields: Vec<u32> = line
+                .split_whitespace()
+                .map(|field| field.parse().expect("Invalid uid map"))
+                .collect();
+            fields[0] <= uid && uid - fields[0] < fields[2]
+        })
+    }
+
+    #[tokio::test]
+    async fn test_drop_output_firewall_with_matching_skuid() -> Result<(), io::Error> {
+        let probe = Probe::new(TCP_SPEC).as_user(1000, 1000);
+        let build_rule = |_| "meta skuid 1000 counter drop".into();
+        if uid_is_mapped(1000) {
+            test_output(&IPV4_ADDRS_WITH_NET, probe, build_rule, expect_unreachable).await
+        } else {
+            // With `unshare --map-root-user`, only root is mapped
+            let err = test_output(&IPV4_ADDRS_WITH_NET, probe, build_rule, expect_unreachable)
+                .await
+                .expect_err("Switched to an unmapped user");
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drop_output_firewall_with_other_skuid() -> Result<(), io::Error> {
+        test_output(
+            &IPV4_ADDRS_WITH_NET,
+            Probe::new(TCP_SPEC).as_user(0, 0),
+            |_| "meta skuid 1000 counter drop".into(),
+            expect_ok,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_reject_input_firewall_with_udp_refused_as_unreachable() -> Result<(), io::Error> {
+        test_input(
+            &IPV4_ADDRS_WITH_NET,
+            Probe::new(UDP_SPEC).udp_refused_as_unreachable(true),
+            build_reject,
+            expect_unreachable,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_conntrack_count_forward_with_concurrent_tcp() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new_router("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut lan = router.new_interface("lan".into(), IPV4_ADDRS_WITH_NET[1])?;
+
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain forward {
+                        type filter hook forward priority filter;
+                        ct state new counter accept
+                        log prefix "Other packet: " counter accept
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::forward_path(&mut wan, &mut lan)?;
+        let specs: Vec<_> = (8000..8010).map(|port| ConnSpec::Tcp { port }).collect();
+        let effects = future::try_join_all(specs.iter().map(|&spec| path.connect(spec))).await?;
+        assert!(specs
+            .iter()
+            .zip(&effects)
+            .all(|(&spec, &effect)| effect == expect_ok(&*path, spec)));
+
+        // Closed connections linger in conntrack, so each one is still counted
+        assert_eq!(effects.len(), router.conntrack_count()?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wait_for_established_conntrack_entry() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain input {
+                        type filter hook input priority filter;
+                        ct state new counter accept
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        assert_eq!(expect_ok(&*path, TCP_SPEC), path.connect(TCP_SPEC).await?);
+        let source_addr = path.source_addr();
+        let entry = router
+            .wait_for_conntrack(
+                |entry| entry.orig.src == source_addr && entry.orig.dport == Some(TCP_SPEC.port()),
+                Duration::from_secs(1),
+            )
+            .await?;
+        assert_eq!("tcp", entry.l4proto);
+
+        let err = router
+            .wait_for_conntrack(|_| false, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ct_count_forward_firewall_with_concurrent_udp() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new_router("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut lan = router.new_interface("lan".into(), IPV4_ADDRS_WITH_NET[1])?;
+
+        // Use UDP, since `ct count` stops counting TCP connections once they are closed, so the
+        // cap would depend on how the concurrent probes happened to interleave
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain forward {
+                        type filter hook forward priority filter;
+                        udp dport 8000-8009 ct count over 3 counter drop
+                        log prefix "Other packet: " counter accept
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::forward_path(&mut wan, &mut lan)?;
+        let specs: Vec<_> = (8000..8010).map(|port| ConnSpec::Udp { port }).collect();
+        let effects = future::try_join_all(specs.iter().map(|&spec| path.connect(spec))).await?;
+
+        debug!("Firewall state:\n{}", router.list_nft_rules()?);
+        let ok_count = specs
+            .iter()
+            .zip(&effects)
+            .filter(|(&spec, &effect)| effect == expect_ok(&*path, spec))
+            .count();
+        assert_eq!(3, ok_count);
+        assert_eq!(3, router.conntrack_count()?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forward_firewall_matrix_summary() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new_router("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let mut lan = router.new_interface("lan".into(), IPV4_ADDRS_WITH_NET[1])?;
+
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain forward {
+                        type filter hook forward priority filter;
+                        tcp dport 8000 counter accept
+                        tcp dport 8001 counter reject with tcp reset
+                        udp dport 8002 counter reject
+                        log prefix "Other packet: " counter accept
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::forward_path(&mut wan, &mut lan)?;
+        let specs = [
+            ConnSpec::Tcp { port: 8000 },
+            ConnSpec::Tcp { port: 8001 },
+            ConnSpec::Udp { port: 8002 },
+        ];
+        let cases: Vec<_> = specs.iter().map(|&spec| (&*path, spec.into())).collect();
+        let expected = vec![
+            expect_ok(&*path, specs[0]),
+            expect_refused(&*path, specs[1]),
+            expect_refused(&*path, specs[2]),
+        ];
+        let outcomes = connect_all(&cases).await?;
+
+        let summary = MatrixSummary::with_expected(&outcomes, &expected)?;
+        debug!("{}", summary);
+        assert!(summary.unexpected.is_empty());
+        assert_eq!(
+            (1, 2, 0),
+            (summary.ok, summary.refused, summary.unreachable)
+        );
+
         Ok(())
     }
 
-    fn list_addrs(&self) -> Result<String, io::Error> {
-        let ret = self.scoped_process("ip", &["address"])?;
-        Ok(ret)
+    async fn test_input_elapsed(build_rule: fn(ConnSpec) -> String) -> Result<Duration, io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+
+        let rules = formatdoc! {
+            r#"
+                table inet filter {{
+                    chain input {{
+                        type filter hook input priority filter;
+                        {rule}
+                    }}
+                }}
+            "#,
+            rule = build_rule(TCP_SPEC)
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let outcome = path.probe_outcome(&TCP_SPEC.into()).await?;
+        debug!("Outcome: {:?}", outcome);
+        Ok(outcome.elapsed)
     }
 
-    fn set_default_route(&mut self, addr: IpAddr) -> Result<(), io::Error> {
-        self.scoped_process("ip", &["route", "add", "default", "via", &addr.to_string()])?;
+    #[tokio::test]
+    async fn test_reject_input_firewall_is_fast() -> Result<(), io::Error> {
+        assert!(test_input_elapsed(build_reject).await? < Duration::from_secs(1));
         Ok(())
     }
 
-    fn load_nft_rules<R: io::Read + Send>(&mut self, rules: R) -> Result<(), io::Error> {
-        self.scoped_process_with_input("nft", &["-f", "-"], rules)?;
+    #[tokio::test]
+    async fn test_reject_input_firewall_with_host_unreachable_retries() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain input {
+                        type filter hook input priority filter;
+                        tcp dport 80 counter reject with icmpx type host-unreachable
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let outcome = path.probe_outcome(&Probe::new(TCP_SPEC).retries(2)).await?;
+
+        debug!("Firewall state:\n{}", router.list_nft_rules()?);
+        assert_eq!(ConnEffect::Unreachable, outcome.effect);
+        assert!(router.list_nft_rules()?.contains("packets 3 "));
+        assert!(outcome.elapsed >= Duration::from_millis(200));
+
         Ok(())
     }
 
-    fn list_nft_rules(&self) -> Result<String, io::Error> {
-        let ret = self.scoped_process("nft", &["list", "ruleset"])?;
-        Ok(ret)
+    #[tokio::test]
+    async fn effective_mtu() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        router
+            .ns
+            .scoped_process("ip", &["link", "set", "wan", "mtu", "1400"])?;
+        let path = OsHost::output_path(&router, &mut wan)?;
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+        assert_eq!(1400, path.effective_mtu()?);
+
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_mss_clamping_forward_firewall() -> Result<(), io::Error> {
+        *INIT;
 
-    use indoc::formatdoc;
-    use lazy_static::lazy_static;
-    use paste::paste;
+        for &clamp in &[false, true] {
+            let mut router = OsHost::new_router("router".into())?;
+            let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+            let mut lan = router.new_interface("lan".into(), IPV4_ADDRS_WITH_NET[1])?;
+            // Both client and server advertise an MSS for an MTU of 1500
+            lan.set_mtu(1400)?;
+            let rules = formatdoc! {
+                r#"
+                    table inet filter {{
+                        chain forward {{
+                            type filter hook forward priority filter;
+                            {rule}
+                        }}
+                        chain output {{
+                            type filter hook output priority filter;
+                            icmp type destination-unreachable icmp code frag-needed drop
+                        }}
+                    }}
+                "#,
+                rule = if clamp {
+                    "tcp flags syn tcp option maxseg size set rt mtu"
+                } else {
+                    ""
+                }
+            };
+            router.load_nft_rules(rules.as_bytes())?;
 
-    use crate::conn::{ConnEffect, ConnSpec};
-    use crate::INIT;
+            let path = OsHost::forward_path(&mut wan, &mut lan)?;
+            let probe = Probe::new(TCP_SPEC).payload_len(8192);
+            // Without clamping, path MTU discovery fails, since the router cannot report it
+            let expected = if clamp {
+                expect_ok(&*path, TCP_SPEC)
+            } else {
+                ConnEffect::Unreachable
+            };
+            assert_eq!(expected, path.probe(&probe).await?);
+        }
 
-    lazy_static! {
-        static ref IPV4_ADDRS_WITH_NET: Vec<IpNet> = vec![
-            "198.51.100.1/24".parse().unwrap(),
-            "203.0.113.1/24".parse().unwrap(),
-        ];
-        static ref IPV6_ADDRS_WITH_NET: Vec<IpNet> = vec![
-            "2001:db8:1111:1111::1/64".parse().unwrap(),
-            "2001:db8:2222:2222::1/64".parse().unwrap(),
-        ];
+        Ok(())
     }
 
-    const TCP_SPEC: ConnSpec = ConnSpec::Tcp { port: 80 };
-    const UDP_SPEC: ConnSpec = ConnSpec::Udp { port: 53 };
-
-    async fn test_input<BF, EF>(
-        addrs_with_net: &[IpNet],
-        spec: ConnSpec,
-        build_rule: BF,
-        expect_effect: EF,
-    ) -> Result<(), io::Error>
-    where
-        BF: Fn(ConnSpec) -> String,
-        EF: Fn(&dyn ConnPath) -> ConnEffect,
-    {
+    #[tokio::test]
+    async fn connect_with_timeout_classifies_drop() -> Result<(), io::Error> {
         *INIT;
 
         let mut router = OsHost::new("router".into())?;
-        let mut wan = router.new_interface("wan".into(), addrs_with_net[0])?;
-
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
         let rules = formatdoc! {
             r#"
                 table inet filter {{
                     chain input {{
                         type filter hook input priority filter;
                         {rule}
-                        log prefix "Other packet: " counter accept
                     }}
                 }}
             "#,
-            rule = build_rule(spec)
+            rule = build_drop(UDP_SPEC)
         };
         router.load_nft_rules(rules.as_bytes())?;
 
         let path = OsHost::input_path(&mut wan, &router)?;
-        let expected_conn_effect = expect_effect(&*path);
+        let start = Instant::now();
+        let effect = path
+            .connect_with_timeout(UDP_SPEC, Duration::from_millis(200))
+            .await?;
+        assert_eq!(ConnEffect::Unreachable, effect);
+        // Well before the default timeout of 5 seconds
+        assert!(start.elapsed() < Duration::from_secs(2));
 
-        let conn_effect = path.connect(spec).await;
+        Ok(())
+    }
 
-        debug!("Firewall state:\n{}", router.list_nft_rules()?);
-        assert_eq!(expected_conn_effect, conn_effect?);
+    #[tokio::test]
+    async fn fragmented_udp_over_ipv6_is_reassembled() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV6_ADDRS_WITH_NET[0])?;
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let probe = Probe::new(UDP_SPEC).payload_len(3000);
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.probe(&probe).await?);
 
         Ok(())
     }
 
-    async fn test_output<BF, EF>(
-        addrs_with_net: &[IpNet],
-        spec: ConnSpec,
-        build_rule: BF,
+    #[tokio::test]
+    async fn test_frag_accept_input_firewall_with_udp_over_ipv6() -> Result<(), io::Error> {
+        test_input(
+            &IPV6_ADDRS_WITH_NET,
+            Probe::new(UDP_SPEC),
+            |_| "ip6 nexthdr ipv6-frag counter drop".into(),
+            expect_ok,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_frag_drop_input_firewall_with_udp_over_ipv6() -> Result<(), io::Error> {
+        test_input(
+            &IPV6_ADDRS_WITH_NET,
+            Probe::new(UDP_SPEC).payload_len(3000),
+            |_| "ip6 nexthdr ipv6-frag counter drop".into(),
+            |_, _| ConnEffect::Unreachable,
+        )
+        .await
+    }
+
+    /// IPv4 reassembles fragments before the input hook, so fragments can only be filtered in
+    /// prerouting, and only before conntrack's defragmentation at priority -400.
+    async fn test_frag_prerouting_ipv4<EF>(
+        probe: Probe,
+        verdict: &str,
         expect_effect: EF,
     ) -> Result<(), io::Error>
     where
-        BF: Fn(ConnSpec) -> String,
-        EF: Fn(&dyn ConnPath) -> ConnEffect,
+        EF: Fn(&dyn ConnPath, ConnSpec) -> ConnEffect,
     {
         *INIT;
 
         let mut router = OsHost::new("router".into())?;
-        let mut wan = router.new_interface("wan".into(), addrs_with_net[0])?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
 
         let rules = formatdoc! {
             r#"
                 table inet filter {{
-                    chain output {{
-                        type filter hook output priority filter;
-                        {rule}
-                        log prefix "Other packet: " counter accept
+                    chain prerouting {{
+                        type filter hook prerouting priority -450;
+                        ip frag-off & 0x3fff != 0 counter {verdict}
                     }}
                 }}
             "#,
-            rule = build_rule(spec)
+            verdict = verdict
         };
         router.load_nft_rules(rules.as_bytes())?;
 
-        let path = OsHost::output_path(&router, &mut wan)?;
-        let expected_conn_effect = expect_effect(&*path);
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let expected_conn_effect = expect_effect(&*path, probe.spec());
 
-        let conn_effect = path.connect(spec).await;
+        let conn_effect = path.probe(&probe).await;
 
         debug!("Firewall state:\n{}", router.list_nft_rules()?);
-        assert_eq!(expected_conn_effect, conn_effect?);
+        assert_same_kind(expected_conn_effect, conn_effect?);
 
         Ok(())
     }
 
-    async fn test_forward<BF, EF>(
+    #[tokio::test]
+    async fn test_frag_accept_prerouting_firewall_with_udp_over_ipv4() -> Result<(), io::Error> {
+        test_frag_prerouting_ipv4(
+            Probe::new(UDP_SPEC).payload_len(3000).dont_fragment(false),
+            "accept",
+            expect_ok,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_frag_drop_prerouting_firewall_with_udp_over_ipv4() -> Result<(), io::Error> {
+        test_frag_prerouting_ipv4(
+            Probe::new(UDP_SPEC).payload_len(3000).dont_fragment(false),
+            "drop",
+            |_, _| ConnEffect::Unreachable,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_frag_drop_prerouting_firewall_with_small_udp_over_ipv4() -> Result<(), io::Error>
+    {
+        // Small enough not to be fragmented, even though it may be
+        test_frag_prerouting_ipv4(Probe::new(UDP_SPEC).dont_fragment(false), "drop", expect_ok)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_length_accept_input_firewall_with_udp_over_ipv4() -> Result<(), io::Error> {
+        // A total length of exactly 100 bytes
+        test_input(
+            &IPV4_ADDRS_WITH_NET,
+            Probe::new(UDP_SPEC).payload_len(72),
+            |_| "meta length > 100 counter drop".into(),
+            expect_ok,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_length_drop_input_firewall_with_udp_over_ipv4() -> Result<(), io::Error> {
+        test_input(
+            &IPV4_ADDRS_WITH_NET,
+            Probe::new(UDP_SPEC).payload_len(73),
+            |_| "meta length > 100 counter drop".into(),
+            |_, _| ConnEffect::Unreachable,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_exact_length_drop_input_firewall() -> Result<(), io::Error> {
+        for addrs_with_net in &[&*IPV4_ADDRS_WITH_NET, &*IPV6_ADDRS_WITH_NET] {
+            for &spec in &[TCP_SPEC, UDP_SPEC] {
+                for &(total_len, dropped) in &[(127, false), (128, true), (129, false)] {
+                    test_input(
+                        addrs_with_net,
+                        Probe::new(spec).total_length(total_len),
+                        |_| "meta length 128 counter drop".into(),
+                        |path, spec| {
+                            if dropped {
+                                ConnEffect::Unreachable
+                            } else {
+                                expect_ok(path, spec)
+                            }
+                        },
+                    )
+                    .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dont_fragment_oversized_udp() -> Result<(), io::Error> {
+        *INIT;
+
+        for addrs_with_net in &[&*IPV4_ADDRS_WITH_NET, &*IPV6_ADDRS_WITH_NET] {
+            let mut router = OsHost::new("router".into())?;
+            let mut wan = router.new_interface("wan".into(), addrs_with_net[0])?;
+            let path = OsHost::input_path(&mut wan, &router)?;
+            let probe = Probe::new(UDP_SPEC).payload_len(3000);
+            assert_eq!(
+                expect_ok(&*path, UDP_SPEC),
+                path.probe(&probe.clone().dont_fragment(false)).await?
+            );
+            assert_eq!(
+                ConnEffect::Unreachable,
+                path.probe(&probe.dont_fragment(true)).await?
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broadcast_udp() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let path = OsHost::input_path(&mut wan, &router)?;
+        for addr in &["198.51.100.255", "255.255.255.255"] {
+            let probe = Probe::new(UDP_SPEC).broadcast(addr.parse().unwrap());
+            assert_eq!(expect_ok(&*path, UDP_SPEC), path.probe(&probe).await?);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reassign_addresses() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        let addrs: Vec<IpNet> = vec![
+            "192.0.2.1/24".parse().unwrap(),
+            "192.0.2.2/24".parse().unwrap(),
+        ];
+        wan.reassign(addrs.clone())?;
+        assert_eq!(addrs[0], wan.addr_with_net());
+        let listed = router.ns.list_addrs()?;
+        assert!(listed.contains("192.0.2.1/24") && listed.contains("192.0.2.2/24"));
+        assert!(!listed.contains("198.51.100.1"));
+
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            wan.reassign(vec![]).unwrap_err().kind()
+        );
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        assert!(addrs[0].contains(&path.source_addr()));
+        assert_eq!(expect_ok(&*path, UDP_SPEC), path.connect(UDP_SPEC).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_drop_input_firewall() -> Result<(), io::Error> {
+        test_input(
+            &IPV4_ADDRS_WITH_NET,
+            Probe::new(UDP_SPEC).broadcast("198.51.100.255".parse().unwrap()),
+            |_| "meta pkttype broadcast counter drop".into(),
+            expect_unreachable,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_drop_input_firewall_is_slow() -> Result<(), io::Error> {
+        assert!(test_input_elapsed(build_drop).await? >= Duration::from_secs(5));
+        Ok(())
+    }
+
+    async fn test_fib_input<EF>(
         addrs_with_net: &[IpNet],
-        spec: ConnSpec,
-        build_rule: BF,
+        probe: Probe,
+        spoofed_source_addr: Option<IpAddr>,
         expect_effect: EF,
     ) -> Result<(), io::Error>
     where
-        BF: Fn(ConnSpec) -> String,
-        EF: Fn(&dyn ConnPath) -> ConnEffect,
+        EF: Fn(&dyn ConnPath, ConnSpec) -> ConnEffect,
     {
         *INIT;
 
+        // The router only has routes to its directly connected networks, so the FIB lookup finds
+        // an output interface for the interface's own network and nothing else
         let mut router = OsHost::new("router".into())?;
         let mut wan = router.new_interface("wan".into(), addrs_with_net[0])?;
-        let mut lan = router.new_interface("lan".into(), addrs_with_net[1])?;
 
-        let rules = formatdoc! {
+        let rules = indoc! {
             r#"
-                table inet filter {{
-                    chain forward {{
-                        type filter hook forward priority filter;
-                        {rule}
+                table inet filter {
+                    chain prerouting {
+                        type filter hook prerouting priority filter;
+                        fib saddr . iif oif missing counter drop
                         log prefix "Other packet: " counter accept
-                    }}
-                }}
-            "#,
-            rule = build_rule(spec)
+                    }
+                }
+            "#
         };
         router.load_nft_rules(rules.as_bytes())?;
 
-        let path = OsHost::forward_path(&mut wan, &mut lan)?;
-        let expected_conn_effect = expect_effect(&*path);
+        let path = match spoofed_source_addr {
+            Some(source_addr) => OsHost::input_path_from(&mut wan, &router, source_addr)?,
+            None => OsHost::input_path(&mut wan, &router)?,
+        };
+        let expected_conn_effect = expect_effect(&*path, probe.spec());
 
-        let conn_effect = path.connect(spec).await;
+        let conn_effect = path.probe(&probe).await;
 
         debug!("Firewall state:\n{}", router.list_nft_rules()?);
-        assert_eq!(expected_conn_effect, conn_effect?);
+        assert_same_kind(expected_conn_effect, conn_effect?);
 
         Ok(())
     }
 
-    fn build_accept(spec: ConnSpec) -> String {
-        match spec {
-            ConnSpec::Tcp { port } => format!("tcp dport {} counter accept", port),
-            ConnSpec::Udp { port } => format!("udp dport {} counter accept", port),
-        }
+    #[tokio::test]
+    async fn test_fib_input_firewall_with_routed_source_over_ipv4() -> Result<(), io::Error> {
+        test_fib_input(&IPV4_ADDRS_WITH_NET, TCP_SPEC.into(), None, expect_ok).await
     }
 
-    fn build_drop(spec: ConnSpec) -> String {
-        match spec {
-            ConnSpec::Tcp { port } => format!("tcp dport {} counter drop", port),
-            ConnSpec::Udp { port } => format!("udp dport {} counter drop", port),
-        }
+    #[tokio::test]
+    async fn test_fib_input_firewall_with_routed_source_over_ipv6() -> Result<(), io::Error> {
+        test_fib_input(&IPV6_ADDRS_WITH_NET, TCP_SPEC.into(), None, expect_ok).await
     }
 
-    fn build_reject(spec: ConnSpec) -> String {
-        match spec {
-            ConnSpec::Tcp { port } => format!("tcp dport {} counter reject with tcp reset", port),
-            ConnSpec::Udp { port } => format!("udp dport {} counter reject", port),
-        }
+    #[tokio::test]
+    async fn test_fib_input_firewall_with_spoofed_source_over_ipv4() -> Result<(), io::Error> {
+        let source_addr = "192.0.2.1".parse().unwrap();
+        test_fib_input(
+            &IPV4_ADDRS_WITH_NET,
+            TCP_SPEC.into(),
+            Some(source_addr),
+            expect_unreachable,
+        )
+        .await
     }
 
-    fn expect_ok(path: &dyn ConnPath) -> ConnEffect {
-        ConnEffect::Ok {
+    #[tokio::test]
+    async fn test_fib_input_firewall_with_spoofed_source_over_ipv6() -> Result<(), io::Error> {
+        let source_addr = "2001:db8:ffff:ffff::1".parse().unwrap();
+        test_fib_input(
+            &IPV6_ADDRS_WITH_NET,
+            TCP_SPEC.into(),
+            Some(source_addr),
+            expect_unreachable,
+        )
+        .await
+    }
+
+    async fn test_redirect(spec: ConnSpec) -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+
+        let rules = indoc! {
+            r#"
+                table ip nat {
+                    chain prerouting {
+                        type nat hook prerouting priority dstnat;
+                        meta l4proto { tcp, udp } th dport 80 counter redirect to :8080
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::input_path(&mut wan, &router)?;
+        let conn_effect = path.probe(&Probe::new(spec).server_port(8080)).await;
+
+        debug!("Firewall state:\n{}", router.list_nft_rules()?);
+        let expected_conn_effect = ConnEffect::Ok {
             source_addr: path.source_addr(),
+            target_port: 8080,
+        };
+        assert_same_kind(expected_conn_effect, conn_effect?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_port_range_input_firewall_with_tcp_over_ipv4() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain input {
+                        type filter hook input priority filter;
+                        tcp sport < 40000 counter drop
+                    }
+                }
+            "#
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        for &(first, last, matches) in &[(30000, 30999, true), (50000, 50999, false)] {
+            wan.set_peer_local_port_range(first, last)?;
+            let path = OsHost::input_path(&mut wan, &router)?;
+            let expected_conn_effect = if matches {
+                ConnEffect::Unreachable
+            } else {
+                expect_ok(&*path, TCP_SPEC)
+            };
+            assert_eq!(expected_conn_effect, path.connect(TCP_SPEC).await?);
         }
+
+        Ok(())
     }
 
-    fn expect_unreachable(_path: &dyn ConnPath) -> ConnEffect {
-        ConnEffect::Unreachable
+    #[tokio::test]
+    async fn test_redirect_prerouting_firewall_with_tcp_over_ipv4() -> Result<(), io::Error> {
+        test_redirect(ConnSpec::Tcp { port: 80 }).await
+    }
+
+    #[tokio::test]
+    async fn test_redirect_prerouting_firewall_with_udp_over_ipv4() -> Result<(), io::Error> {
+        test_redirect(ConnSpec::Udp { port: 80 }).await
+    }
+
+    async fn test_tproxy(spec: ConnSpec) -> Result<(), io::Error> {
+        *INIT;
+
+        let mut router = OsHost::new("router".into())?;
+        let mut wan = router.new_interface("wan".into(), IPV4_ADDRS_WITH_NET[0])?;
+        router.add_tproxy_route(1)?;
+
+        let rules = formatdoc! {
+            r#"
+                table inet filter {{
+                    chain prerouting {{
+                        type filter hook prerouting priority mangle;
+                        {protocol} dport {port} meta mark set 1 tproxy ip to :8080 counter accept
+                    }}
+                }}
+            "#,
+            protocol = match spec {
+                ConnSpec::Tcp { .. } => "tcp",
+                ConnSpec::Udp { .. } => "udp",
+                ConnSpec::Ip { .. } | ConnSpec::Icmp { .. } => {
+                    unreachable!("tproxy requires a port")
+                }
+            },
+            port = spec.port(),
+        };
+        router.load_nft_rules(rules.as_bytes())?;
+
+        let path = OsHost::tproxy_path(&mut wan, &router, "192.0.2.1".parse().unwrap(), 8080)?;
+        let conn_effect = path.connect(spec).await;
+
+        debug!("Firewall state:\n{}", router.list_nft_rules()?);
+        let expected_conn_effect = ConnEffect::Ok {
+            source_addr: path.source_addr(),
+            target_port: 8080,
+        };
+        assert_same_kind(expected_conn_effect, conn_effect?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tproxy_prerouting_firewall_with_tcp_over_ipv4() -> Result<(), io::Error> {
+        test_tproxy(TCP_SPEC).await
     }
 
-    fn expect_refused(_path: &dyn ConnPath) -> ConnEffect {
-        ConnEffect::Refused
+    #[tokio::test]
+    async fn test_tproxy_prerouting_firewall_with_udp_over_ipv4() -> Result<(), io::Error> {
+        test_tproxy(UDP_SPEC).await
     }
 
     macro_rules! gen_test {
@@ -427,7 +4823,24 @@ mod tests {
                 async fn [< test_ $action _ $direction _firewall _with_ $layer4 _over_ $layer3 >]() -> Result<(), io::Error> {
                     [< test_ $direction >](
                         &[< $layer3:snake:upper _ADDRS_WITH_NET >],
-                        [< $layer4:snake:upper _SPEC >],
+                        [< $layer4:snake:upper _SPEC >].into(),
+                        [< build_ $action >],
+                        [< expect_ $effect >]
+                    ).await
+                }
+            }
+        };
+    }
+
+    macro_rules! gen_policy_test {
+        ($policy:ident, $action:ident, $effect:ident, $layer4:ident, $layer3:ident) => {
+            paste! {
+                #[tokio::test]
+                async fn [< test_ $policy _policy_ $action _input_firewall_with_ $layer4 _over_ $layer3 >]() -> Result<(), io::Error> {
+                    test_input_policy(
+                        &[< $layer3:snake:upper _ADDRS_WITH_NET >],
+                        [< $layer4:snake:upper _SPEC >].into(),
+                        stringify!($policy),
                         [< build_ $action >],
                         [< expect_ $effect >]
                     ).await
@@ -448,6 +4861,28 @@ mod tests {
     gen_test!(input, reject, refused, tcp, ipv6);
     gen_test!(input, reject, refused, udp, ipv4);
     gen_test!(input, reject, refused, udp, ipv6);
+    gen_test!(input, accept, ok, esp, ipv4);
+    gen_test!(input, accept, ok, esp, ipv6);
+    gen_test!(input, drop, unreachable, esp, ipv4);
+    gen_test!(input, drop, unreachable, esp, ipv6);
+    gen_test!(input, reject, refused, esp, ipv4);
+    gen_test!(input, reject, refused, esp, ipv6);
+    gen_test!(input, accept, ok, icmp, ipv4);
+    gen_test!(input, accept, ok, icmp, ipv6);
+    gen_test!(input, drop, unreachable, icmp, ipv4);
+    gen_test!(input, drop, unreachable, icmp, ipv6);
+    gen_test!(input, reject, refused, icmp, ipv4);
+    gen_test!(input, reject, refused, icmp, ipv6);
+
+    // Note that reject is only valid in the input, forward and output hooks
+    gen_test!(prerouting, accept, ok, tcp, ipv4);
+    gen_test!(prerouting, accept, ok, tcp, ipv6);
+    gen_test!(prerouting, accept, ok, udp, ipv4);
+    gen_test!(prerouting, accept, ok, udp, ipv6);
+    gen_test!(prerouting, drop, unreachable, tcp, ipv4);
+    gen_test!(prerouting, drop, unreachable, tcp, ipv6);
+    gen_test!(prerouting, drop, unreachable, udp, ipv4);
+    gen_test!(prerouting, drop, unreachable, udp, ipv6);
 
     // Note that on Linux, output drop has different effects for TCP and UDP
     gen_test!(output, accept, ok, tcp, ipv4);
@@ -475,4 +4910,18 @@ mod tests {
     gen_test!(forward, reject, refused, tcp, ipv6);
     gen_test!(forward, reject, refused, udp, ipv4);
     gen_test!(forward, reject, refused, udp, ipv6);
+
+    // Without a catch-all rule, a probe that no rule matches meets the policy
+    gen_policy_test!(accept, other, ok, tcp, ipv4);
+    gen_policy_test!(accept, other, ok, tcp, ipv6);
+    gen_policy_test!(accept, other, ok, udp, ipv4);
+    gen_policy_test!(accept, other, ok, udp, ipv6);
+    gen_policy_test!(drop, other, unreachable, tcp, ipv4);
+    gen_policy_test!(drop, other, unreachable, tcp, ipv6);
+    gen_policy_test!(drop, other, unreachable, udp, ipv4);
+    gen_policy_test!(drop, other, unreachable, udp, ipv6);
+    gen_policy_test!(drop, accept, ok, tcp, ipv4);
+    gen_policy_test!(drop, accept, ok, tcp, ipv6);
+    gen_policy_test!(drop, accept, ok, udp, ipv4);
+    gen_policy_test!(drop, accept, ok, udp, ipv6);
 }
@@ -0,0 +1,183 @@
+//! The state of a namespace at a point in time, so that a test can assert that a probe did not
+//! change it unexpectedly, e.g. by leaving stray routes, neighbours or conntrack entries.
+
+use std::prelude::v1::*;
+
+use std::fmt;
+
+use crate::host::ConntrackEntry;
+
+/// The links, addresses, routes, neighbours and conntrack entries of a namespace, as captured by
+/// `Host::snapshot`. The others are lines of `ip -o`, those of neighbours without their state,
+/// which changes as they are confirmed and age.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct StateSnapshot {
+    pub links: Vec<String>,
+    pub addrs: Vec<String>,
+    pub routes: Vec<String>,
+    pub neighbors: Vec<String>,
+    pub conntrack: Vec<ConntrackEntry>,
+}
+
+impl StateSnapshot {
+    /// What changed between this snapshot and a later one.
+    pub fn diff(&self, later: &StateSnapshot) -> StateDiff {
+        StateDiff {
+            links: Changes::between(&self.links, &later.links),
+            addrs: Changes::between(&self.addrs, &later.addrs),
+            routes: Changes::between(&self.routes, &later.routes),
+            neighbors: Changes::between(&self.neighbors, &later.neighbors),
+            conntrack: Changes::between(&self.conntrack, &later.conntrack),
+        }
+    }
+}
+
+/// The difference between two `StateSnapshot`s.
+#[derive(Clone, Default, Eq, PartialEq, Debug)]
+pub struct StateDiff {
+    pub links: Changes<String>,
+    pub addrs: Changes<String>,
+    pub routes: Changes<String>,
+    pub neighbors: Changes<String>,
+    pub conntrack: Changes<ConntrackEntry>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+            && self.addrs.is_empty()
+            && self.routes.is_empty()
+            && self.neighbors.is_empty()
+            && self.conntrack.is_empty()
+    }
+}
+
+/// Lists the changes as a unified diff would, e.g. `+route 10.0.0.0/8 dev lan`.
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.links.fmt_lines(f, "link")?;
+        self.addrs.fmt_lines(f, "addr")?;
+        self.routes.fmt_lines(f, "route")?;
+        self.neighbors.fmt_lines(f, "neighbor")?;
+        for entry in &self.conntrack.removed {
+            writeln!(f, "-conntrack {:?}", entry)?;
+        }
+        for entry in &self.conntrack.added {
+            writeln!(f, "+conntrack {:?}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// The items of one kind that appeared and disappeared between two snapshots. An item whose
+/// attributes changed, e.g. a link whose state went down, appears in both.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Changes<T> {
+    pub added: Vec<T>,
+    pub removed: Vec<T>,
+}
+
+impl<T> Default for Changes<T> {
+    fn default() -> Changes<T> {
+        Changes {
+            added: vec![],
+            removed: vec![],
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> Changes<T> {
+    fn between(before: &[T], after: &[T]) -> Changes<T> {
+        Changes {
+            added: after
+                .iter()
+                .filter(|item| !before.contains(item))
+                .cloned()
+                .collect(),
+            removed: before
+                .iter()
+                .filter(|item| !after.contains(item))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+impl<T> Changes<T> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl Changes<String> {
+    fn fmt_lines(&self, f: &mut fmt::Formatter<'_>, kind: &str) -> fmt::Result {
+        for line in &self.removed {
+            writeln!(f, "-{} {}", kind, line)?;
+        }
+        for line in &self.added {
+            writeln!(f, "+{} {}", kind, line)?;
+        }
+        Ok(())
+    }
+}
+
+/// A line of `ip neigh`, without the trailing NUD states, e.g. `REACHABLE` or `STALE`.
+pub(crate) fn neighbor_without_state(line: &str) -> String {
+    let mut words: Vec<_> = line.split_whitespace().collect();
+    while let Some(word) = words.last() {
+        if !word.chars().all(|c| c.is_ascii_uppercase()) {
+            break;
+        }
+        words.pop();
+    }
+    words.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(routes: &[&str], neighbors: &[&str]) -> StateSnapshot {
+        StateSnapshot {
+            links: vec!["1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536".into()],
+            addrs: vec![],
+            routes: routes.iter().map(|&route| route.to_owned()).collect(),
+            neighbors: neighbors
+                .iter()
+                .map(|&line| neighbor_without_state(line))
+                .collect(),
+            conntrack: vec![],
+        }
+    }
+
+    #[test]
+    fn diff_snapshots() {
+        let before = snapshot(
+            &["10.0.0.0/8 dev lan proto kernel scope link src 10.0.0.1"],
+            &["10.0.0.2 dev lan lladdr 02:00:00:00:00:02 REACHABLE"],
+        );
+        let after = snapshot(
+            &[
+                "10.0.0.0/8 dev lan proto kernel scope link src 10.0.0.1",
+                "192.0.2.0/24 via 10.0.0.2 dev lan",
+            ],
+            &["10.0.0.2 dev lan lladdr 02:00:00:00:00:02 STALE"],
+        );
+        assert!(before.diff(&before).is_empty());
+        let diff = before.diff(&after);
+        assert_eq!(
+            StateDiff {
+                routes: Changes {
+                    added: vec!["192.0.2.0/24 via 10.0.0.2 dev lan".into()],
+                    removed: vec![],
+                },
+                ..StateDiff::default()
+            },
+            diff
+        );
+        assert_eq!(
+            "+route 192.0.2.0/24 via 10.0.0.2 dev lan\n",
+            diff.to_string()
+        );
+    }
+}
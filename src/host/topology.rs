@@ -0,0 +1,145 @@
+use std::prelude::v1::*;
+
+use ipnet::IpNet;
+use std::io;
+
+use crate::conn::{ConnEffect, ConnPath, ConnSpec};
+use crate::host::*;
+
+/// A router with a LAN interface and a WAN interface, the starting point for most forwarding and
+/// NAT scenarios.
+#[derive(Debug)]
+pub struct Topology<H: Host> {
+    pub router: H,
+    pub lan: H::Interface,
+    pub wan: H::Interface,
+}
+
+impl<H: Host> Topology<H> {
+    /// Create a router named `router` with interfaces `lan` and `wan`, having the given addresses
+    /// and networks.
+    pub fn router_lan_wan(lan_net: IpNet, wan_net: IpNet) -> Result<Topology<H>, io::Error> {
+        let mut router = H::new_router("router".into())?;
+        let lan = router.new_interface("lan".into(), lan_net)?;
+        let wan = router.new_interface("wan".into(), wan_net)?;
+        Ok(Topology { router, lan, wan })
+    }
+
+    /// A path from a client behind the LAN interface to a server beyond the WAN interface.
+    pub fn lan_to_wan(&mut self) -> Result<Box<dyn ConnPath + '_>, io::Error> {
+        H::forward_path(&mut self.lan, &mut self.wan)
+    }
+
+    /// A path from a client behind the LAN interface, with the given source address, which need
+    /// not be in the LAN's network, to a server beyond the WAN interface.
+    pub fn lan_to_wan_from(
+        &mut self,
+        source_addr: IpAddr,
+    ) -> Result<Box<dyn ConnPath + '_>, io::Error> {
+        H::forward_path_from(&mut self.lan, &mut self.wan, source_addr)
+    }
+
+    /// Connect from the LAN to the WAN from a valid source address, then from the given spoofed
+    /// one, e.g. to test BCP 38 egress filtering, which should pass the first and drop the second.
+    pub async fn egress_validation(
+        &mut self,
+        spec: ConnSpec,
+        spoofed_addr: IpAddr,
+    ) -> Result<EgressValidation, io::Error> {
+        let valid = self.lan_to_wan()?.connect(spec).await?;
+        let spoofed = self.lan_to_wan_from(spoofed_addr)?.connect(spec).await?;
+        Ok(EgressValidation { valid, spoofed })
+    }
+
+    /// A path from a client beyond the WAN interface to a server behind the LAN interface.
+    pub fn wan_to_lan(&mut self) -> Result<Box<dyn ConnPath + '_>, io::Error> {
+        H::forward_path(&mut self.wan, &mut self.lan)
+    }
+}
+
+/// The effects of `Topology::egress_validation`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct EgressValidation {
+    pub valid: ConnEffect,
+    pub spoofed: ConnEffect,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+
+    use crate::host::os::OsHost;
+    use crate::INIT;
+
+    #[tokio::test]
+    async fn router_lan_wan() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut topology = Topology::<OsHost>::router_lan_wan(
+            "192.168.1.1/24".parse().unwrap(),
+            "203.0.113.1/24".parse().unwrap(),
+        )?;
+        assert_eq!("router", topology.router.name());
+
+        let path = topology.lan_to_wan()?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: path.source_addr(),
+                target_port: 53,
+            },
+            path.connect(ConnSpec::Udp { port: 53 }).await?
+        );
+        drop(path);
+
+        let path = topology.wan_to_lan()?;
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: path.source_addr(),
+                target_port: 53,
+            },
+            path.connect(ConnSpec::Udp { port: 53 }).await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn egress_validation() -> Result<(), io::Error> {
+        *INIT;
+
+        let mut topology = Topology::<OsHost>::router_lan_wan(
+            "203.0.113.1/24".parse().unwrap(),
+            "198.51.100.1/24".parse().unwrap(),
+        )?;
+        let spec = ConnSpec::Udp { port: 53 };
+        let spoofed_addr = "192.0.2.7".parse().unwrap();
+        let effects = topology.egress_validation(spec, spoofed_addr).await?;
+        // Without a rule, the router forwards the spoofed source too
+        assert_eq!(
+            ConnEffect::Ok {
+                source_addr: spoofed_addr,
+                target_port: 53,
+            },
+            effects.spoofed
+        );
+
+        let rules = indoc! {
+            r#"
+                table inet filter {
+                    chain forward {
+                        type filter hook forward priority filter;
+                        oifname "wan" ip saddr != 203.0.113.0/24 counter drop
+                    }
+                }
+            "#
+        };
+        topology.router.load_nft_rules(rules.as_bytes())?;
+        let effects = topology.egress_validation(spec, spoofed_addr).await?;
+        assert!(matches!(effects.valid, ConnEffect::Ok { .. }));
+        assert_eq!(ConnEffect::Unreachable, effects.spoofed);
+
+        Ok(())
+    }
+}
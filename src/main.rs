@@ -2,12 +2,17 @@ use std::prelude::v1::*;
 
 use lazy_static::lazy_static;
 use std::io;
+use std::path::PathBuf;
+use std::process;
 
+use crate::host::os::OsHost;
 use crate::os::OsNs;
+use crate::scenario::Scenario;
 
 mod conn;
 mod host;
 mod os;
+mod scenario;
 
 lazy_static! {
     static ref INIT: () = {
@@ -35,7 +40,51 @@ lazy_static! {
     };
 }
 
+enum Format {
+    Text,
+    Json,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: firebreak [--format text|json] <scenario>");
+    process::exit(2)
+}
+
 fn main() -> Result<(), io::Error> {
     *INIT;
+
+    let mut format = Format::Text;
+    let mut scenario_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = match args.next().as_deref() {
+                    Some("text") => Format::Text,
+                    Some("json") => Format::Json,
+                    _ => usage(),
+                }
+            }
+            _ if scenario_path.is_none() && !arg.starts_with('-') => {
+                scenario_path = Some(PathBuf::from(arg))
+            }
+            _ => usage(),
+        }
+    }
+    let scenario = Scenario::load(&scenario_path.unwrap_or_else(|| usage()))?;
+
+    let report = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(scenario.run::<OsHost>())?;
+    match format {
+        Format::Text => print!("{}", report.to_text()),
+        Format::Json => print!("{}", report.to_json()),
+    }
+
+    // Distinguish failed expectations from errors, which exit with status 1
+    if !report.passed() {
+        process::exit(3);
+    }
     Ok(())
 }
@@ -1,20 +1,77 @@
 use std::prelude::v1::*;
 
+use lazy_static::lazy_static;
 use log::*;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::os::unix::prelude::*;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::process::Stdio;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+#[cfg(feature = "host-ns")]
+use crate::conn::os::OsNsConnPath;
+#[cfg(feature = "host-ns")]
+use std::net::IpAddr;
 
 #[derive(Debug)]
 pub struct OsNs {
     fd: File,
     /// Absolute path to the namespace. Valid only for the lifetime of this struct.
     fd_path: Box<Path>,
+    /// The id of the namespace in the registry, unless firebreak did not create it.
+    id: Option<u64>,
+}
+
+/// A resource that firebreak created and that still exists, as listed by `resources`.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub enum Resource {
+    /// A network namespace, which exists as long as firebreak holds a handle to it. The id is
+    /// unique for the life of the process, unlike the inode, which the kernel reuses.
+    Ns { id: u64, inode: u64, handles: usize },
+    /// A link that firebreak created in or moved to the namespace, which is removed with it.
+    Link { ns_id: u64, name: String },
+}
+
+#[derive(Default)]
+struct Registry {
+    namespaces: BTreeMap<u64, RegisteredNs>,
+}
+
+struct RegisteredNs {
+    inode: u64,
+    handles: usize,
+    links: BTreeSet<String>,
+}
+
+static NEXT_NS_ID: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+/// The resources that firebreak created and that still exist, e.g. to assert that a test
+/// released all of its namespaces. Namespaces are listed before their links.
+pub fn resources() -> Vec<Resource> {
+    let registry = REGISTRY.lock().unwrap();
+    let mut ret = vec![];
+    for (&id, ns) in &registry.namespaces {
+        ret.push(Resource::Ns {
+            id,
+            inode: ns.inode,
+            handles: ns.handles,
+        });
+        ret.extend(ns.links.iter().map(|name| Resource::Link {
+            ns_id: id,
+            name: name.clone(),
+        }));
+    }
+    ret
 }
 
 impl OsNs {
@@ -43,33 +100,109 @@ impl OsNs {
     }
 
     pub fn new_net() -> Result<OsNs, io::Error> {
-        std::thread::spawn(|| {
-            unsafe {
-                let res = libc::unshare(libc::CLONE_NEWNET);
-                if res == -1 {
-                    return Err(io::Error::last_os_error());
-                }
-                assert_eq!(res, 0);
+        std::thread::spawn(OsNs::unshare_net).join().unwrap()
+    }
+
+    /// Like `new_net`, but create `count` namespaces concurrently.
+    ///
+    /// Each namespace is created on a thread of its own, so if a user namespace is needed, it
+    /// must already have been entered, e.g. by `INIT`, since that requires a single thread.
+    pub fn new_nets(count: usize) -> Result<Vec<OsNs>, io::Error> {
+        let threads: Vec<_> = (0..count)
+            .map(|_| std::thread::spawn(OsNs::unshare_net))
+            .collect();
+        // Join every thread before returning any error, so that no namespace is leaked
+        let results: Vec<_> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        results.into_iter().collect()
+    }
+
+    /// Move the current thread into a new network namespace, returning a handle to it. The thread
+    /// cannot be used for anything else afterwards.
+    fn unshare_net() -> Result<OsNs, io::Error> {
+        unsafe {
+            let res = libc::unshare(libc::CLONE_NEWNET);
+            if res == -1 {
+                return Err(io::Error::last_os_error());
             }
+            assert_eq!(res, 0);
+        }
+
+        let current_path = "/proc/thread-self/ns/net";
+        let fd = OpenOptions::new().read(true).open(current_path)?;
+        let fd_path = PathBuf::from(format!(
+            "/proc/{}/fd/{}",
+            std::process::id(),
+            fd.as_raw_fd()
+        ))
+        .into_boxed_path();
+        let id = NEXT_NS_ID.fetch_add(1, Ordering::Relaxed);
+        REGISTRY.lock().unwrap().namespaces.insert(
+            id,
+            RegisteredNs {
+                inode: fd.metadata()?.ino(),
+                handles: 1,
+                links: BTreeSet::new(),
+            },
+        );
+        Ok(OsNs {
+            fd,
+            fd_path,
+            id: Some(id),
+        })
+    }
 
-            let current_path = "/proc/thread-self/ns/net";
-            let fd = OpenOptions::new().read(true).open(current_path)?;
-            let fd_path = PathBuf::from(format!(
-                "/proc/{}/fd/{}",
-                std::process::id(),
-                fd.as_raw_fd()
-            ))
-            .into_boxed_path();
-            Ok(OsNs { fd, fd_path })
+    /// Another handle to the same namespace, which keeps it alive independently of this one.
+    pub fn try_clone(&self) -> Result<OsNs, io::Error> {
+        let fd = self.fd.try_clone()?;
+        let fd_path = PathBuf::from(format!(
+            "/proc/{}/fd/{}",
+            std::process::id(),
+            fd.as_raw_fd()
+        ))
+        .into_boxed_path();
+        if let Some(id) = self.id {
+            let mut registry = REGISTRY.lock().unwrap();
+            let ns = registry.namespaces.get_mut(&id).expect("A handle is live");
+            ns.handles += 1;
+        }
+        Ok(OsNs {
+            fd,
+            fd_path,
+            id: self.id,
         })
-        .join()
-        .unwrap()
     }
 
     pub fn path(&self) -> &Path {
         &self.fd_path
     }
 
+    /// The id of the namespace in `resources`, unless firebreak did not create it.
+    pub fn id(&self) -> Option<u64> {
+        self.id
+    }
+
+    /// Record that a link was created in the namespace, or moved to it, so that it is listed by
+    /// `resources`.
+    pub fn record_link(&self, name: &str) {
+        if let Some(id) = self.id {
+            let mut registry = REGISTRY.lock().unwrap();
+            let ns = registry.namespaces.get_mut(&id).expect("A handle is live");
+            ns.links.insert(name.into());
+        }
+    }
+
+    /// Record that a link was removed from the namespace, e.g. because it was moved elsewhere.
+    pub fn record_link_removed(&self, name: &str) {
+        if let Some(id) = self.id {
+            let mut registry = REGISTRY.lock().unwrap();
+            let ns = registry.namespaces.get_mut(&id).expect("A handle is live");
+            ns.links.remove(name);
+        }
+    }
+
     pub fn scoped<'a, F, T>(&self, f: F) -> Result<T, io::Error>
     where
         F: FnOnce() -> Result<T, io::Error>,
@@ -93,7 +226,15 @@ impl OsNs {
             unsafe {
                 let res = libc::setns(self.fd.as_raw_fd(), 0);
                 if res == -1 {
-                    return Err(io::Error::last_os_error());
+                    let err = io::Error::last_os_error();
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!(
+                            "Failed to enter namespace {}: {}",
+                            self.fd_path.display(),
+                            err
+                        ),
+                    ));
                 }
                 assert_eq!(res, 0);
             }
@@ -109,6 +250,21 @@ impl OsNs {
         self.scoped_process_with_input(program, args, <&[u8]>::from(&[]))
     }
 
+    /// Start a program in the namespace without waiting for it, with its stdout piped.
+    pub fn spawn_scoped_process<S>(&self, program: &str, args: &[S]) -> Result<Child, io::Error>
+    where
+        S: AsRef<OsStr> + Debug + Sync,
+    {
+        self.scoped(|| {
+            Command::new(program)
+                .args(args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+        })
+    }
+
     pub fn scoped_process_with_input<S, R>(
         &self,
         program: &str,
@@ -150,6 +306,76 @@ impl OsNs {
     }
 }
 
+impl Drop for OsNs {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut registry = REGISTRY.lock().unwrap();
+            let ns = registry.namespaces.get_mut(&id).expect("A handle is live");
+            ns.handles -= 1;
+            if ns.handles == 0 {
+                // The namespace is destroyed with its last handle, unless something else in the
+                // process, such as a socket, still refers to it
+                registry.namespaces.remove(&id);
+            }
+        }
+    }
+}
+
+/// The network namespace that the process started in, e.g. to probe a real firewall and compare
+/// its effects with those in a new namespace.
+///
+/// The namespace itself is not handed out, only paths to probe from or to it, so the host's
+/// interfaces cannot be modified by mistake.
+/// Entering the namespace requires `CAP_SYS_ADMIN` in the user namespace that owns it, so this
+/// only works if `INIT` did not need to enter a user namespace, e.g. when running as root.
+#[cfg(feature = "host-ns")]
+#[derive(Debug)]
+pub struct HostNs(OsNs);
+
+#[cfg(feature = "host-ns")]
+impl HostNs {
+    pub fn open() -> Result<HostNs, io::Error> {
+        // Threads other than the main thread may have entered other namespaces
+        let fd = OpenOptions::new().read(true).open("/proc/self/ns/net")?;
+        let fd_path = PathBuf::from(format!(
+            "/proc/{}/fd/{}",
+            std::process::id(),
+            fd.as_raw_fd()
+        ))
+        .into_boxed_path();
+        Ok(HostNs(OsNs {
+            fd,
+            fd_path,
+            id: None,
+        }))
+    }
+
+    /// Absolute path to the namespace. Valid only for the lifetime of this struct.
+    pub fn path(&self) -> &Path {
+        self.0.path()
+    }
+
+    /// A path from the host namespace to `target`, as in `OsNsConnPath::between`.
+    pub fn path_to<'a>(
+        &'a self,
+        source_addr: IpAddr,
+        target: &'a OsNs,
+        target_addr: IpAddr,
+    ) -> Result<OsNsConnPath<'a>, io::Error> {
+        OsNsConnPath::between(&self.0, source_addr, target, target_addr)
+    }
+
+    /// A path from `source` to the host namespace, as in `OsNsConnPath::between`.
+    pub fn path_from<'a>(
+        &'a self,
+        source: &'a OsNs,
+        source_addr: IpAddr,
+        target_addr: IpAddr,
+    ) -> Result<OsNsConnPath<'a>, io::Error> {
+        OsNsConnPath::between(source, source_addr, &self.0, target_addr)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -172,4 +398,89 @@ mod tests {
         assert!(links.contains("veth1@veth0"));
         Ok(())
     }
+
+    #[test]
+    fn resources_are_released_with_last_handle() -> Result<(), io::Error> {
+        *INIT;
+        let ns = OsNs::new_net()?;
+        let id = ns.id().unwrap();
+        let clone = ns.try_clone()?;
+        ns.scoped_process(
+            "ip",
+            &[
+                "link", "add", "veth0", "type", "veth", "peer", "name", "veth1",
+            ],
+        )?;
+        ns.record_link("veth0");
+        ns.record_link("veth1");
+        let ours = || -> Vec<Resource> {
+            resources()
+                .into_iter()
+                .filter(|resource| match resource {
+                    Resource::Ns { id: ns_id, .. } | Resource::Link { ns_id, .. } => *ns_id == id,
+                })
+                .collect()
+        };
+        assert_eq!(
+            vec![
+                Resource::Ns {
+                    id,
+                    inode: fs::metadata(ns.path())?.ino(),
+                    handles: 2,
+                },
+                Resource::Link {
+                    ns_id: id,
+                    name: "veth0".into(),
+                },
+                Resource::Link {
+                    ns_id: id,
+                    name: "veth1".into(),
+                },
+            ],
+            ours()
+        );
+
+        drop(ns);
+        assert!(matches!(
+            ours()[..],
+            [Resource::Ns { handles: 1, .. }, _, _]
+        ));
+        drop(clone);
+        assert_eq!(Vec::<Resource>::new(), ours());
+        Ok(())
+    }
+
+    #[test]
+    fn new_nets_are_distinct() -> Result<(), io::Error> {
+        *INIT;
+        let count = 4;
+        let nss = OsNs::new_nets(count)?;
+        assert_eq!(count, nss.len());
+        let mut inodes: Vec<_> = nss
+            .iter()
+            .map(|ns| Ok(fs::metadata(ns.path())?.ino()))
+            .collect::<Result<_, io::Error>>()?;
+        inodes.sort_unstable();
+        inodes.dedup();
+        assert_eq!(count, inodes.len());
+        Ok(())
+    }
+
+    #[cfg(feature = "host-ns")]
+    #[test]
+    fn host_ns_is_not_new() -> Result<(), io::Error> {
+        *INIT;
+        let host = HostNs::open()?;
+        let inode = |path: &Path| Ok::<_, io::Error>(fs::metadata(path)?.ino());
+        assert_eq!(
+            fs::metadata("/proc/self/ns/net")?.ino(),
+            inode(host.path())?
+        );
+        let ns = OsNs::new_net()?;
+        assert_ne!(inode(host.path())?, inode(ns.path())?);
+        let (v4, v6) = ("127.0.0.1".parse().unwrap(), "::1".parse().unwrap());
+        assert!(host.path_to(v4, &ns, v4).is_ok());
+        assert!(host.path_from(&ns, v4, v6).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,647 @@
+use std::prelude::v1::*;
+
+use ipnet::IpNet;
+use log::*;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::conn::matrix::MatrixSummary;
+use crate::conn::{ConnEffect, ConnPath, ConnSpec, Probe, ProbeOutcome};
+use crate::host::Host;
+
+/// A sequence of steps that build hosts, load their rules, and probe them, read from a file in
+/// the following format:
+///
+/// ```text
+/// # Comments and blank lines are ignored
+/// host router
+/// interface router wan 198.51.100.1/24
+/// interface router lan 203.0.113.1/24
+/// rules router router.nft
+/// expect input router wan tcp:22 ok
+/// expect output router wan udp:53 refused
+/// expect forward router wan lan tcp:80 unreachable
+/// ```
+///
+/// Rules files are relative to the directory that contains the scenario.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Scenario {
+    steps: Vec<(usize, Step)>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+enum Step {
+    Host {
+        name: String,
+    },
+    Interface {
+        host: String,
+        name: String,
+        addr_with_net: IpNet,
+    },
+    Rules {
+        host: String,
+        path: PathBuf,
+    },
+    Expect {
+        direction: Direction,
+        host: String,
+        interfaces: Vec<String>,
+        spec: ConnSpec,
+        effect: ExpectedEffect,
+    },
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Direction {
+    Input,
+    Output,
+    Forward,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum ExpectedEffect {
+    Ok,
+    Refused,
+    Unreachable,
+    Reset,
+}
+
+impl ExpectedEffect {
+    fn resolve(self, path: &dyn ConnPath, spec: ConnSpec) -> ConnEffect {
+        match self {
+            ExpectedEffect::Ok => ConnEffect::Ok {
+                source_addr: path.source_addr(),
+                target_port: spec.port(),
+            },
+            ExpectedEffect::Refused => ConnEffect::Refused { icmp: None },
+            ExpectedEffect::Unreachable => ConnEffect::Unreachable,
+            ExpectedEffect::Reset => ConnEffect::ResetDuringTransfer,
+        }
+    }
+}
+
+type BeforeProbe<'a, H> = dyn FnMut(&mut H, &Probe) -> Result<(), io::Error> + 'a;
+type AfterProbe<'a, H> = dyn FnMut(&mut H, &Probe, &ProbeOutcome) -> Result<(), io::Error> + 'a;
+
+/// Callbacks that `Scenario::run_with_hooks` invokes around each probe with the expectation's
+/// host, e.g. to flush conntrack, adjust a set or read a counter between probes. An error from
+/// a callback stops the run.
+pub struct Hooks<'a, H> {
+    before_probe: Option<Box<BeforeProbe<'a, H>>>,
+    after_probe: Option<Box<AfterProbe<'a, H>>>,
+}
+
+impl<'a, H> Default for Hooks<'a, H> {
+    fn default() -> Hooks<'a, H> {
+        Hooks {
+            before_probe: None,
+            after_probe: None,
+        }
+    }
+}
+
+impl<'a, H> Hooks<'a, H> {
+    /// Invoke the callback with the upcoming probe, before its path is created.
+    pub fn before_probe<F>(self, f: F) -> Hooks<'a, H>
+    where
+        F: FnMut(&mut H, &Probe) -> Result<(), io::Error> + 'a,
+    {
+        Hooks {
+            before_probe: Some(Box::new(f)),
+            ..self
+        }
+    }
+
+    /// Invoke the callback with the probe that just completed and its outcome.
+    pub fn after_probe<F>(self, f: F) -> Hooks<'a, H>
+    where
+        F: FnMut(&mut H, &Probe, &ProbeOutcome) -> Result<(), io::Error> + 'a,
+    {
+        Hooks {
+            after_probe: Some(Box::new(f)),
+            ..self
+        }
+    }
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Scenario, io::Error> {
+        let s = fs::read_to_string(path)?;
+        Scenario::parse(&s, path.parent().unwrap_or_else(|| Path::new(".")))
+    }
+
+    pub fn parse(s: &str, base_dir: &Path) -> Result<Scenario, io::Error> {
+        let mut steps = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let line_number = i + 1;
+            let invalid = |reason: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {}: {}: {}", line_number, reason, line),
+                )
+            };
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let step = match words[..] {
+                [] => continue,
+                ["host", name] => Step::Host { name: name.into() },
+                ["interface", host, name, addr_with_net] => Step::Interface {
+                    host: host.into(),
+                    name: name.into(),
+                    addr_with_net: addr_with_net
+                        .parse()
+                        .map_err(|err| invalid(format!("{}", err)))?,
+                },
+                ["rules", host, path] => Step::Rules {
+                    host: host.into(),
+                    path: base_dir.join(path),
+                },
+                ["expect", direction, host, ref rest @ ..] => {
+                    let (direction, interfaces, spec, effect) = match (direction, rest) {
+                        ("input", &[interface, spec, effect]) => {
+                            (Direction::Input, vec![interface], spec, effect)
+                        }
+                        ("output", &[interface, spec, effect]) => {
+                            (Direction::Output, vec![interface], spec, effect)
+                        }
+                        ("forward", &[source, target, spec, effect]) => {
+                            (Direction::Forward, vec![source, target], spec, effect)
+                        }
+                        _ => return Err(invalid("Invalid expectation".into())),
+                    };
+                    Step::Expect {
+                        direction,
+                        host: host.into(),
+                        interfaces: interfaces.into_iter().map(String::from).collect(),
+                        spec: spec.parse().map_err(|err| invalid(format!("{}", err)))?,
+                        effect: match effect {
+                            "ok" => ExpectedEffect::Ok,
+                            "refused" => ExpectedEffect::Refused,
+                            "unreachable" => ExpectedEffect::Unreachable,
+                            "reset" => ExpectedEffect::Reset,
+                            _ => return Err(invalid(format!("Invalid effect {:?}", effect))),
+                        },
+                    }
+                }
+                _ => return Err(invalid("Unrecognized step".into())),
+            };
+            steps.push((line_number, step));
+        }
+        Ok(Scenario { steps })
+    }
+
+    /// Run the steps in order, probing one expectation at a time.
+    pub async fn run<H: Host>(&self) -> Result<Report, io::Error> {
+        self.run_with_hooks::<H>(Hooks::default()).await
+    }
+
+    /// Like `run`, but invoke the hooks around each probe.
+    pub async fn run_with_hooks<H: Host>(
+        &self,
+        mut hooks: Hooks<'_, H>,
+    ) -> Result<Report, io::Error> {
+        let mut hosts: HashMap<&str, H> = HashMap::new();
+        let mut interfaces: HashMap<(&str, &str), H::Interface> = HashMap::new();
+        let mut results = vec![];
+        for (line, step) in &self.steps {
+            let unknown = |kind: &str, name: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Line {}: Unknown {} {:?}", line, kind, name),
+                )
+            };
+            match step {
+                Step::Host { name } => {
+                    // Any host may be named by a forward expectation, so each is a router
+                    hosts.insert(name, H::new_router(name.clone())?);
+                }
+                Step::Interface {
+                    host,
+                    name,
+                    addr_with_net,
+                } => {
+                    let interface = hosts
+                        .get_mut(host.as_str())
+                        .ok_or_else(|| unknown("host", host))?
+                        .new_interface(name.clone(), *addr_with_net)?;
+                    interfaces.insert((host, name), interface);
+                }
+                Step::Rules { host, path } => {
+                    let rules = File::open(path)?;
+                    hosts
+                        .get_mut(host.as_str())
+                        .ok_or_else(|| unknown("host", host))?
+                        .load_nft_rules(rules)?;
+                }
+                Step::Expect {
+                    direction,
+                    host: host_name,
+                    interfaces: interface_names,
+                    spec,
+                    effect,
+                } => {
+                    let probe = Probe::from(*spec);
+                    if let Some(before_probe) = &mut hooks.before_probe {
+                        let host = hosts
+                            .get_mut(host_name.as_str())
+                            .ok_or_else(|| unknown("host", host_name))?;
+                        before_probe(host, &probe)?;
+                    }
+                    let host = hosts
+                        .get(host_name.as_str())
+                        .ok_or_else(|| unknown("host", host_name))?;
+                    // Take the interfaces out of the map, since paths borrow them mutably
+                    let mut path_interfaces = vec![];
+                    for name in interface_names {
+                        path_interfaces.push(
+                            interfaces
+                                .remove(&(host_name.as_str(), name.as_str()))
+                                .ok_or_else(|| unknown("interface", name))?,
+                        );
+                    }
+                    let (expected, outcome) = {
+                        let path = match (direction, &mut path_interfaces[..]) {
+                            (Direction::Input, [interface]) => H::input_path(interface, host)?,
+                            (Direction::Output, [interface]) => H::output_path(host, interface)?,
+                            (Direction::Forward, [source, target]) => {
+                                H::forward_path(source, target)?
+                            }
+                            _ => unreachable!("Checked when parsing"),
+                        };
+                        let expected = effect.resolve(&*path, *spec);
+                        (expected, path.probe_outcome(&probe).await?)
+                    };
+                    for (name, interface) in interface_names.iter().zip(path_interfaces) {
+                        interfaces.insert((host_name, name), interface);
+                    }
+                    if let Some(after_probe) = &mut hooks.after_probe {
+                        // The host was found above
+                        let host = hosts.get_mut(host_name.as_str()).unwrap();
+                        after_probe(host, &probe, &outcome)?;
+                    }
+
+                    let description = format!(
+                        "{} {} {} {}",
+                        match direction {
+                            Direction::Input => "input",
+                            Direction::Output => "output",
+                            Direction::Forward => "forward",
+                        },
+                        host_name,
+                        interface_names.join(" "),
+                        spec
+                    );
+                    debug!("Line {}: {}: {:?}", line, description, outcome);
+                    results.push(ExpectationResult {
+                        line: *line,
+                        description,
+                        expected,
+                        outcome,
+                    });
+                }
+            }
+        }
+        Ok(Report::new(results))
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ExpectationResult {
+    pub line: usize,
+    pub description: String,
+    pub expected: ConnEffect,
+    pub outcome: ProbeOutcome,
+}
+
+impl ExpectationResult {
+    pub fn passed(&self) -> bool {
+        self.outcome.effect.same_kind(&self.expected)
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Report {
+    pub results: Vec<ExpectationResult>,
+    pub summary: MatrixSummary,
+}
+
+impl Report {
+    pub fn new(results: Vec<ExpectationResult>) -> Report {
+        let outcomes: Vec<ProbeOutcome> = results.iter().map(|result| result.outcome).collect();
+        let expected: Vec<ConnEffect> = results.iter().map(|result| result.expected).collect();
+        let summary = MatrixSummary::with_expected(&outcomes, &expected)
+            .expect("Each result has one outcome and one expected effect");
+        Report { results, summary }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.summary.unexpected.is_empty()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut ret = String::new();
+        for result in &self.results {
+            if result.passed() {
+                writeln!(
+                    ret,
+                    "PASS line {}: {}: {:?} after {:?}",
+                    result.line, result.description, result.outcome.effect, result.outcome.elapsed
+                )
+            } else {
+                writeln!(
+                    ret,
+                    "FAIL line {}: {}: expected {:?}, got {:?} after {:?}",
+                    result.line,
+                    result.description,
+                    result.expected,
+                    result.outcome.effect,
+                    result.outcome.elapsed
+                )
+            }
+            .unwrap();
+        }
+        writeln!(ret, "{}", self.summary).unwrap();
+        ret
+    }
+
+    pub fn to_json(&self) -> String {
+        let results: Vec<String> = self
+            .results
+            .iter()
+            .map(|result| {
+                format!(
+                    r#"{{"line":{},"expectation":{},"expected":{},"actual":{},"elapsed_ms":{},"passed":{}}}"#,
+                    result.line,
+                    json_string(&result.description),
+                    json_effect(result.expected),
+                    json_effect(result.outcome.effect),
+                    result.outcome.elapsed.as_millis(),
+                    result.passed()
+                )
+            })
+            .collect();
+        format!(
+            "{{\"passed\":{},\"summary\":{},\"results\":[{}]}}\n",
+            self.passed(),
+            json_string(&self.summary.to_string()),
+            results.join(",")
+        )
+    }
+}
+
+fn json_effect(effect: ConnEffect) -> String {
+    match effect {
+        ConnEffect::Ok {
+            source_addr,
+            target_port,
+        } => format!(
+            r#"{{"effect":"ok","source_addr":{},"target_port":{}}}"#,
+            json_string(&source_addr.to_string()),
+            target_port
+        ),
+        ConnEffect::Refused { icmp: None } => r#"{"effect":"refused"}"#.into(),
+        ConnEffect::Refused { icmp: Some(icmp) } => format!(
+            r#"{{"effect":"refused","icmp_type":{},"icmp_code":{},"icmp_from":{}}}"#,
+            icmp.icmp_type,
+            icmp.code,
+            json_string(&icmp.from.to_string())
+        ),
+        ConnEffect::Unreachable => r#"{"effect":"unreachable"}"#.into(),
+        ConnEffect::TtlExpired { from } => format!(
+            r#"{{"effect":"ttl_expired","from":{}}}"#,
+            json_string(&from.to_string())
+        ),
+        ConnEffect::ResetDuringTransfer => r#"{"effect":"reset"}"#.into(),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(ret, "\\u{:04x}", c as u32).unwrap(),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+    use std::time::Duration;
+
+    use crate::host::os::OsHost;
+    use crate::INIT;
+
+    #[test]
+    fn parse() -> Result<(), io::Error> {
+        let scenario = Scenario::parse(
+            indoc! {"
+                # A router
+                host router
+                interface router wan 198.51.100.1/24
+
+                rules router router.nft
+                expect forward router wan lan tcp:80 unreachable
+            "},
+            Path::new("/scenarios"),
+        )?;
+        assert_eq!(
+            vec![
+                (
+                    2,
+                    Step::Host {
+                        name: "router".into()
+                    }
+                ),
+                (
+                    3,
+                    Step::Interface {
+                        host: "router".into(),
+                        name: "wan".into(),
+                        addr_with_net: "198.51.100.1/24".parse().unwrap(),
+                    }
+                ),
+                (
+                    5,
+                    Step::Rules {
+                        host: "router".into(),
+                        path: "/scenarios/router.nft".into(),
+                    }
+                ),
+                (
+                    6,
+                    Step::Expect {
+                        direction: Direction::Forward,
+                        host: "router".into(),
+                        interfaces: vec!["wan".into(), "lan".into()],
+                        spec: ConnSpec::Tcp { port: 80 },
+                        effect: ExpectedEffect::Unreachable,
+                    }
+                ),
+            ],
+            scenario.steps
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_error() {
+        let err = Scenario::parse(
+            "host router\nexpect input router tcp:80 ok\n",
+            Path::new("."),
+        )
+        .expect_err("Parsed an expectation without an interface");
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().starts_with("Line 2: "));
+    }
+
+    #[test]
+    fn report_json() {
+        let report = Report::new(vec![ExpectationResult {
+            line: 3,
+            description: "input router wan tcp:22".into(),
+            expected: ConnEffect::Refused { icmp: None },
+            outcome: ProbeOutcome {
+                effect: ConnEffect::Unreachable,
+                elapsed: Duration::from_millis(5000),
+                dscp: None,
+                listener: None,
+                slow: false,
+            },
+        }]);
+        assert!(!report.passed());
+        assert_eq!(
+            concat!(
+                r#"{"passed":false,"summary":"0/1 probes as expected (0 ok, 0 refused, 1 unreachable)","#,
+                r#""results":[{"line":3,"expectation":"input router wan tcp:22","expected":{"effect":"refused"},"#,
+                r#""actual":{"effect":"unreachable"},"elapsed_ms":5000,"passed":false}]}"#,
+                "\n"
+            ),
+            report.to_json()
+        );
+    }
+
+    #[tokio::test]
+    async fn run() -> Result<(), io::Error> {
+        *INIT;
+
+        let dir = std::env::temp_dir().join(format!("firebreak-{}", rand::random::<u64>()));
+        fs::create_dir(&dir)?;
+        fs::write(
+            dir.join("router.nft"),
+            indoc! {r#"
+                table inet filter {
+                    chain input {
+                        type filter hook input priority filter;
+                        tcp dport 22 counter accept
+                        counter reject
+                    }
+                }
+            "#},
+        )?;
+        fs::write(
+            dir.join("scenario"),
+            indoc! {"
+                host router
+                interface router wan 198.51.100.1/24
+                rules router router.nft
+                expect input router wan tcp:22 ok
+                expect input router wan udp:53 refused
+            "},
+        )?;
+
+        let report = Scenario::load(&dir.join("scenario"))?
+            .run::<OsHost>()
+            .await?;
+        fs::remove_dir_all(&dir)?;
+
+        debug!("{}", report.to_text());
+        assert!(report.passed());
+        assert_eq!(2, report.results.len());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_with_hooks() -> Result<(), io::Error> {
+        *INIT;
+
+        let scenario = Scenario::parse(
+            indoc! {"
+                host router
+                interface router wan 198.51.100.1/24
+                expect input router wan udp:53 ok
+                expect input router wan udp:54 ok
+            "},
+            Path::new("."),
+        )?;
+        // Both hooks record into the same list
+        let events = std::cell::RefCell::new(vec![]);
+        let hooks = Hooks::default()
+            .before_probe(|host: &mut OsHost, probe| {
+                events
+                    .borrow_mut()
+                    .push(format!("before {} {}", host.name(), probe));
+                Ok(())
+            })
+            .after_probe(|host, probe, outcome| {
+                let ok = matches!(outcome.effect, ConnEffect::Ok { .. });
+                events
+                    .borrow_mut()
+                    .push(format!("after {} {} ok={}", host.name(), probe, ok));
+                Ok(())
+            });
+        let report = scenario.run_with_hooks(hooks).await?;
+        assert!(report.passed());
+        assert_eq!(
+            vec![
+                "before router udp:53",
+                "after router udp:53 ok=true",
+                "before router udp:54",
+                "after router udp:54 ok=true",
+            ],
+            events.into_inner()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_with_failing_hook() -> Result<(), io::Error> {
+        *INIT;
+
+        let scenario = Scenario::parse(
+            indoc! {"
+                host router
+                interface router wan 198.51.100.1/24
+                expect input router wan udp:53 ok
+            "},
+            Path::new("."),
+        )?;
+        let hooks = Hooks::default().before_probe(|_: &mut OsHost, _| {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Setup failed",
+            ))
+        });
+        let err = scenario.run_with_hooks(hooks).await.unwrap_err();
+        assert_eq!("Setup failed", err.to_string());
+
+        Ok(())
+    }
+}